@@ -0,0 +1,193 @@
+use std::path::PathBuf;
+
+use redisql_lib::backup::{BackupDirection, DEFAULT_PAGES_PER_STEP};
+use redisql_lib::config::resolve_file;
+use redisql_lib::redis::argument_to_str;
+use redisql_lib::redis::Command;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::redisql_error::RediSQLError;
+use redisql_lib::timeout::default_timeout;
+
+use crate::common::{parse_timeout, CommandV2};
+
+/// `REDISQL.BACKUP db TO file [PAGES n] [TIMEOUT ms]` and
+/// `REDISQL.LOAD db FROM file [PAGES n] [TIMEOUT ms]`, the name of the
+/// command tells which way the database is copied. The file is in
+/// DATA_DIR.
+///
+/// LOAD is replicated with `PAYLOAD image`, the content of the file, and
+/// refuses the files larger than MAX_LOAD_SIZE.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Backup<'s> {
+    database: &'s str,
+    direction: BackupDirection,
+    path: &'s str,
+    payload: Option<&'s [u8]>,
+    pages: i32,
+    timeout: Option<std::time::Duration>,
+}
+
+impl<'s> Backup<'s> {
+    /// `path` is the file in DATA_DIR.
+    pub fn get_command(
+        self,
+        path: PathBuf,
+        client: BlockedClient,
+    ) -> Command {
+        let replicate = if self.is_load() {
+            Some(self.replicate_args())
+        } else {
+            None
+        };
+        Command::Backup {
+            direction: self.direction,
+            path: path.to_string_lossy().into_owned(),
+            image: self.payload.map(<[u8]>::to_vec),
+            replicate,
+            pages: self.pages,
+            client,
+        }
+    }
+    /// If the database is loaded from the file.
+    pub fn is_load(&self) -> bool {
+        self.direction == BackupDirection::FromFile
+    }
+    /// If the content of the file to load was sent with the command.
+    pub fn has_payload(&self) -> bool {
+        self.payload.is_some()
+    }
+    /// The file in DATA_DIR.
+    pub fn file(&self) -> Result<PathBuf, RediSQLError> {
+        resolve_file(self.path)
+    }
+    /// The arguments of the LOAD that replicas execute, the content of
+    /// the file is added after them with `PAYLOAD`.
+    pub fn replicate_args(&self) -> Vec<String> {
+        vec![
+            self.database.to_string(),
+            "FROM".to_string(),
+            self.path.to_string(),
+            "PAGES".to_string(),
+            self.pages.to_string(),
+        ]
+    }
+    /// How long the client waits for the backup, the backup keeps going
+    /// after the timeout and `BACKUP.STATUS` reports how it ends.
+    pub fn timeout(&self) -> std::time::Duration {
+        self.timeout.unwrap_or_else(default_timeout)
+    }
+}
+
+impl<'s> CommandV2<'s> for Backup<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let mut args_iter = args.into_iter();
+        let (direction, keyword) = match args_iter.next() {
+            Some(name)
+                if name.to_ascii_uppercase().ends_with(b"LOAD") =>
+            {
+                (BackupDirection::FromFile, "FROM")
+            }
+            _ => (BackupDirection::ToFile, "TO"),
+        };
+        let database = match args_iter.next() {
+            Some(name) => argument_to_str(name)?,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        let mut command = Backup {
+            database,
+            direction,
+            path: "",
+            payload: None,
+            pages: DEFAULT_PAGES_PER_STEP,
+            timeout: None,
+        };
+        while let Some(arg) = args_iter.next() {
+            let mut arg_string = String::from(argument_to_str(arg)?);
+            arg_string.make_ascii_uppercase();
+            match arg_string.as_str() {
+                k if k == keyword => match args_iter.next() {
+                    Some(path) => {
+                        command.path = argument_to_str(path)?
+                    }
+                    None => {
+                        return Err(invalid_backup(&format!(
+                            "{} must be followed by the file to use",
+                            keyword
+                        )))
+                    }
+                },
+                "PAYLOAD" if direction == BackupDirection::FromFile => {
+                    match args_iter.next() {
+                        Some(payload) => command.payload = Some(payload),
+                        None => {
+                            return Err(invalid_backup(
+                                "PAYLOAD must be followed by the content of the file",
+                            ))
+                        }
+                    }
+                }
+                "PAGES" => {
+                    command.pages = match args_iter
+                        .next()
+                        .and_then(|p| argument_to_str(p).ok())
+                        .and_then(|p| p.parse::<i32>().ok())
+                    {
+                        Some(pages) if pages > 0 => pages,
+                        _ => return Err(invalid_backup(
+                            "PAGES must be followed by the positive number of pages to copy at each step",
+                        )),
+                    };
+                }
+                "TIMEOUT" => {
+                    let timeout = match args_iter.next() {
+                        Some(t) => Some(argument_to_str(t)?),
+                        None => None,
+                    };
+                    command.timeout = Some(parse_timeout(timeout)?);
+                }
+                _ => {}
+            }
+        }
+        if command.path.is_empty() {
+            return Err(invalid_backup(&format!(
+                "You should provide the file to use with {}",
+                keyword
+            )));
+        }
+        Ok(command)
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
+
+/// `REDISQL.BACKUP.STATUS db`
+#[derive(Debug, PartialEq, Clone)]
+pub struct BackupStatus<'s> {
+    database: &'s str,
+}
+
+impl<'s> CommandV2<'s> for BackupStatus<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let mut args_iter = args.into_iter();
+        args_iter.next();
+        let database = match args_iter.next() {
+            Some(name) => argument_to_str(name)?,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        Ok(BackupStatus { database })
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
+
+fn invalid_backup(debug: &str) -> RediSQLError {
+    RediSQLError::with_code(
+        65,
+        debug.to_string(),
+        "Invalid BACKUP or LOAD arguments".to_string(),
+    )
+}
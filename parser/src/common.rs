@@ -1,10 +1,11 @@
+use redisql_lib::redis::argument_to_str;
 use redisql_lib::redis::RedisKey;
 use redisql_lib::redis_type::Context;
 
 use redisql_lib::redisql_error::RediSQLError;
 
 pub trait CommandV2<'s> {
-    fn parse(args: Vec<&'s str>) -> Result<Self, RediSQLError>
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError>
     where
         Self: std::marker::Sized;
     fn database(&self) -> &str;
@@ -12,3 +13,57 @@ pub trait CommandV2<'s> {
         RedisKey::new(self.database(), ctx)
     }
 }
+
+pub fn arguments_to_str(
+    args: Vec<&[u8]>,
+) -> Result<Vec<&str>, RediSQLError> {
+    args.into_iter().map(argument_to_str).collect()
+}
+
+/// Parse the value of the `TIMEOUT` option, a positive number of
+/// milliseconds.
+pub fn parse_timeout(
+    timeout: Option<&str>,
+) -> Result<std::time::Duration, RediSQLError> {
+    match timeout.and_then(|t| t.parse::<u64>().ok()) {
+        Some(t) if t > 0 => Ok(std::time::Duration::from_millis(t)),
+        _ => Err(RediSQLError::with_code(
+            45,
+            "TIMEOUT must be followed by a positive number of milliseconds".to_string(),
+            "Invalid TIMEOUT".to_string(),
+        )),
+    }
+}
+
+/// How the rows of a result are replied.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    /// An array for each row, after the names and the types of the
+    /// columns.
+    Array,
+    /// A map for each row, from the names of the columns to the values.
+    Map,
+    /// A JSON array with an object for each row.
+    Json,
+    /// A JSON object with the columns, their types and the rows.
+    JsonTable,
+}
+
+/// Parse the value of the `FORMAT` option: ARRAY, MAP, JSON or
+/// JSON_TABLE.
+pub fn parse_format(
+    format: Option<&str>,
+) -> Result<Format, RediSQLError> {
+    match format.map(|f| f.to_ascii_uppercase()).as_deref() {
+        Some("ARRAY") => Ok(Format::Array),
+        Some("MAP") => Ok(Format::Map),
+        Some("JSON") => Ok(Format::Json),
+        Some("JSON_TABLE") => Ok(Format::JsonTable),
+        _ => Err(RediSQLError::with_code(
+            56,
+            "FORMAT must be followed by ARRAY, MAP, JSON or JSON_TABLE"
+                .to_string(),
+            "Invalid FORMAT".to_string(),
+        )),
+    }
+}
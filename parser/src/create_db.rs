@@ -1,16 +1,19 @@
 use redisql_lib::redisql_error::RediSQLError;
 
-use crate::common::CommandV2;
+use crate::common::{arguments_to_str, CommandV2};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct CreateDB<'s> {
     name: &'s str,
     pub path: Option<&'s str>,
     pub can_exists: bool,
+    pub readers: usize,
+    pub cdc_stream: Option<&'s str>,
 }
 
 impl<'s> CommandV2<'s> for CreateDB<'s> {
-    fn parse(args: Vec<&'s str>) -> Result<Self, RediSQLError> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let args = arguments_to_str(args)?;
         let mut args_iter = args.iter();
         args_iter.next();
         let name = match args_iter.next() {
@@ -21,6 +24,8 @@ impl<'s> CommandV2<'s> for CreateDB<'s> {
             name,
             path: None,
             can_exists: true,
+            readers: 0,
+            cdc_stream: None,
         };
         let mut can_exists_flag = false;
         let mut must_create_flag = false;
@@ -40,6 +45,29 @@ impl<'s> CommandV2<'s> for CreateDB<'s> {
                     };
                     createdb.path = Some(path);
                 }
+                "READERS" => {
+                    createdb.readers = match args_iter
+                        .next()
+                        .and_then(|n| n.parse::<usize>().ok())
+                    {
+                        Some(readers) => readers,
+                        None => return Err(RediSQLError::with_code(
+                            48,
+                            "READERS should be followed by the number of reader threads to start".to_string(),
+                            "Invalid READERS".to_string(),
+                        )),
+                    };
+                }
+                "CDC_STREAM" => {
+                    createdb.cdc_stream = match args_iter.next() {
+                        Some(stream) => Some(stream),
+                        None => return Err(RediSQLError::with_code(
+                            50,
+                            "Provide CDC_STREAM option but no stream to use".to_string(),
+                            "No CDC_STREAM provided".to_string(),
+                        )),
+                    };
+                }
                 "CAN_EXIST" => {
                     can_exists_flag = true;
                     createdb.can_exists = true;
@@ -0,0 +1,309 @@
+use redisql_lib::csv_io::{ColumnType, CsvOptions, CsvSource};
+use redisql_lib::redis::argument_to_str;
+use redisql_lib::redis::Command;
+use redisql_lib::redis::ReturnMethod;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::redisql_error::RediSQLError;
+use redisql_lib::timeout::default_timeout;
+
+use crate::common::{parse_timeout, CommandV2};
+
+/// `REDISQL.IMPORT db TABLE t [FORMAT CSV] [NO_HEADER] [DELIMITER c]
+/// [QUOTE c] [TYPES ...] [TIMEOUT ms] PAYLOAD csv | PATH file`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Import<'s> {
+    database: &'s str,
+    table: &'s str,
+    source: Option<CsvSource<'s>>,
+    options: CsvOptions,
+    types: Option<Vec<ColumnType>>,
+    timeout: Option<std::time::Duration>,
+}
+
+impl Import<'static> {
+    pub fn get_command(
+        self,
+        csv: Vec<u8>,
+        timeout: std::time::Instant,
+        client: BlockedClient,
+    ) -> Command {
+        Command::ImportCsv {
+            table: self.table,
+            csv,
+            options: self.options,
+            types: self.types,
+            timeout,
+            return_method: ReturnMethod::ReplyWithHeader,
+            client,
+        }
+    }
+}
+
+impl<'s> Import<'s> {
+    /// If the CSV is passed inline, so that the command can be replicated
+    /// as it is.
+    pub fn is_payload(&self) -> bool {
+        matches!(self.source, Some(CsvSource::Payload(_)))
+    }
+    pub fn source(&self) -> CsvSource<'_> {
+        self.source.unwrap()
+    }
+    /// How long the command can run, the default timeout of the module if
+    /// TIMEOUT is not provided.
+    pub fn timeout(&self) -> std::time::Duration {
+        self.timeout.unwrap_or_else(default_timeout)
+    }
+    /// The arguments of the command that replicas execute, the CSV is
+    /// added after them with `PAYLOAD` once it is read.
+    pub fn replicate_args(&self) -> Vec<String> {
+        let mut args = vec![
+            self.database.to_string(),
+            "TABLE".to_string(),
+            self.table.to_string(),
+            "FORMAT".to_string(),
+            "CSV".to_string(),
+            "DELIMITER".to_string(),
+            (self.options.delimiter as char).to_string(),
+            "QUOTE".to_string(),
+            (self.options.quote as char).to_string(),
+        ];
+        if !self.options.header {
+            args.push("NO_HEADER".to_string());
+        }
+        if let Some(types) = &self.types {
+            args.push("TYPES".to_string());
+            for t in types {
+                let t = match t {
+                    ColumnType::Int => "INT",
+                    ColumnType::Real => "REAL",
+                    ColumnType::Text => "TEXT",
+                    ColumnType::Blob => "BLOB",
+                    ColumnType::Null => "NULL",
+                };
+                args.push(t.to_string());
+            }
+        }
+        args
+    }
+}
+
+impl<'s> CommandV2<'s> for Import<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let mut args_iter = args.into_iter();
+        args_iter.next();
+        let database = match args_iter.next() {
+            Some(name) => argument_to_str(name)?,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        let mut command = Import {
+            database,
+            table: "",
+            source: None,
+            options: CsvOptions::default(),
+            types: None,
+            timeout: None,
+        };
+        while let Some(arg) = args_iter.next() {
+            let mut arg_string = String::from(argument_to_str(arg)?);
+            arg_string.make_ascii_uppercase();
+            match arg_string.as_str() {
+                "TABLE" => match args_iter.next() {
+                    Some(table) => command.table = argument_to_str(table)?,
+                    None => return Err(invalid_options("TABLE must be followed by the name of the table")),
+                },
+                "PAYLOAD" => match args_iter.next() {
+                    Some(payload) => {
+                        command.source = Some(CsvSource::Payload(payload))
+                    }
+                    None => return Err(invalid_options("PAYLOAD must be followed by the CSV to import")),
+                },
+                "PATH" => match args_iter.next() {
+                    Some(path) => {
+                        command.source =
+                            Some(CsvSource::Path(argument_to_str(path)?))
+                    }
+                    None => return Err(invalid_options("PATH must be followed by the file to import")),
+                },
+                "TYPES" => {
+                    let mut declared = Vec::new();
+                    while let Some(t) = args_iter.as_slice().first() {
+                        let t = match t.to_ascii_uppercase().as_slice() {
+                            b"INT" => ColumnType::Int,
+                            b"REAL" => ColumnType::Real,
+                            b"TEXT" => ColumnType::Text,
+                            b"BLOB" => ColumnType::Blob,
+                            b"NULL" => ColumnType::Null,
+                            _ => break,
+                        };
+                        declared.push(t);
+                        args_iter.next();
+                    }
+                    if declared.is_empty() {
+                        return Err(invalid_options("TYPES must be followed by INT, REAL, TEXT, BLOB or NULL for each column"));
+                    }
+                    command.types = Some(declared);
+                }
+                "TIMEOUT" => {
+                    let timeout = match args_iter.next() {
+                        Some(t) => Some(argument_to_str(t)?),
+                        None => None,
+                    };
+                    command.timeout = Some(parse_timeout(timeout)?);
+                }
+                _ => parse_csv_option(
+                    &arg_string,
+                    &mut args_iter,
+                    &mut command.options,
+                )?,
+            }
+        }
+        if command.table.is_empty() {
+            return Err(invalid_options(
+                "You should provide the TABLE where to import the CSV",
+            ));
+        }
+        if command.source.is_none() {
+            return Err(invalid_options(
+                "You should provide the CSV with PAYLOAD, or the file to read with PATH",
+            ));
+        }
+        Ok(command)
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
+
+/// `REDISQL.EXPORT db QUERY q [FORMAT CSV] [PATH file] [NO_HEADER]
+/// [DELIMITER c] [QUOTE c] [TIMEOUT ms]`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Export<'s> {
+    database: &'s str,
+    query: &'s str,
+    path: Option<&'s str>,
+    options: CsvOptions,
+    timeout: Option<std::time::Duration>,
+}
+
+impl Export<'static> {
+    pub fn get_command(
+        self,
+        timeout: std::time::Instant,
+        client: BlockedClient,
+    ) -> Command {
+        Command::ExportCsv {
+            query: self.query,
+            path: self.path,
+            options: self.options,
+            timeout,
+            client,
+        }
+    }
+}
+
+impl<'s> Export<'s> {
+    /// How long the command can run, the default timeout of the module if
+    /// TIMEOUT is not provided.
+    pub fn timeout(&self) -> std::time::Duration {
+        self.timeout.unwrap_or_else(default_timeout)
+    }
+}
+
+impl<'s> CommandV2<'s> for Export<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let mut args_iter = args.into_iter();
+        args_iter.next();
+        let database = match args_iter.next() {
+            Some(name) => argument_to_str(name)?,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        let mut command = Export {
+            database,
+            query: "",
+            path: None,
+            options: CsvOptions::default(),
+            timeout: None,
+        };
+        while let Some(arg) = args_iter.next() {
+            let mut arg_string = String::from(argument_to_str(arg)?);
+            arg_string.make_ascii_uppercase();
+            match arg_string.as_str() {
+                "QUERY" => match args_iter.next() {
+                    Some(query) => command.query = argument_to_str(query)?,
+                    None => return Err(invalid_options("QUERY must be followed by the query to export")),
+                },
+                "PATH" => match args_iter.next() {
+                    Some(path) => command.path = Some(argument_to_str(path)?),
+                    None => return Err(invalid_options("PATH must be followed by the file where to export")),
+                },
+                "TIMEOUT" => {
+                    let timeout = match args_iter.next() {
+                        Some(t) => Some(argument_to_str(t)?),
+                        None => None,
+                    };
+                    command.timeout = Some(parse_timeout(timeout)?);
+                }
+                _ => parse_csv_option(
+                    &arg_string,
+                    &mut args_iter,
+                    &mut command.options,
+                )?,
+            }
+        }
+        if command.query.is_empty() {
+            return Err(invalid_options(
+                "You should provide the QUERY to export",
+            ));
+        }
+        Ok(command)
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
+
+/// Parse the options shared by IMPORT and EXPORT: `FORMAT CSV`,
+/// `NO_HEADER`, `DELIMITER` and `QUOTE`. Other arguments are ignored.
+fn parse_csv_option<'a, I: Iterator<Item = &'a [u8]>>(
+    arg: &str,
+    args_iter: &mut I,
+    options: &mut CsvOptions,
+) -> Result<(), RediSQLError> {
+    match arg {
+        "FORMAT" => match args_iter.next().map(|f| f.to_ascii_uppercase())
+        {
+            Some(ref f) if f == b"CSV" => {}
+            _ => return Err(invalid_options("FORMAT must be followed by CSV, the only format supported")),
+        },
+        "NO_HEADER" => options.header = false,
+        "DELIMITER" => {
+            options.delimiter = single_byte(args_iter.next(), "DELIMITER")?
+        }
+        "QUOTE" => options.quote = single_byte(args_iter.next(), "QUOTE")?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn single_byte(
+    arg: Option<&[u8]>,
+    option: &str,
+) -> Result<u8, RediSQLError> {
+    match arg {
+        Some([c]) if c.is_ascii() => Ok(*c),
+        _ => Err(invalid_options(&format!(
+            "{} must be followed by a single ASCII character",
+            option
+        ))),
+    }
+}
+
+fn invalid_options(debug: &str) -> RediSQLError {
+    RediSQLError::with_code(
+        59,
+        debug.to_string(),
+        "Invalid IMPORT or EXPORT options".to_string(),
+    )
+}
@@ -0,0 +1,128 @@
+use redisql_lib::redis::Command;
+use redisql_lib::redis::ReturnMethod;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::redisql_error::RediSQLError;
+use redisql_lib::timeout::default_timeout;
+
+use crate::common::{arguments_to_str, parse_timeout, CommandV2};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Action {
+    Next,
+    Close,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cursor<'s> {
+    database: &'s str,
+    action: Action,
+    id: u64,
+    count: Option<usize>,
+    no_header: bool,
+    timeout: Option<std::time::Duration>,
+}
+
+impl Cursor<'static> {
+    pub fn get_command(
+        self,
+        timeout: std::time::Instant,
+        client: BlockedClient,
+    ) -> Command {
+        match self.action {
+            Action::Next => Command::FetchCursor {
+                id: self.id,
+                count: self.count,
+                timeout,
+                return_method: if self.no_header {
+                    ReturnMethod::Reply
+                } else {
+                    ReturnMethod::ReplyWithHeader
+                },
+                client,
+            },
+            Action::Close => Command::CloseCursor {
+                id: self.id,
+                client,
+            },
+        }
+    }
+    /// How long the command can run, the default timeout of the module if
+    /// TIMEOUT is not provided.
+    pub fn timeout(&self) -> std::time::Duration {
+        self.timeout.unwrap_or_else(default_timeout)
+    }
+}
+
+impl<'s> CommandV2<'s> for Cursor<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let args = arguments_to_str(args)?;
+        let mut args_iter = args.iter();
+        // the action is the last part of the command name:
+        // REDISQL.CURSOR.NEXT or REDISQL.CURSOR.CLOSE
+        let mut command_name = match args_iter.next() {
+            Some(name) => String::from(*name),
+            None => String::new(),
+        };
+        command_name.make_ascii_uppercase();
+        let action = if command_name.ends_with(".CLOSE") {
+            Action::Close
+        } else {
+            Action::Next
+        };
+        let database = match args_iter.next() {
+            Some(name) => name,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        let id = match args_iter
+            .next()
+            .and_then(|id| id.parse::<u64>().ok())
+            .filter(|id| *id > 0)
+        {
+            Some(id) => id,
+            None => return Err(RediSQLError::with_code(44, "You should provide the cursor returned by the query, a positive integer".to_string(), "Cursor not provided".to_string())),
+        };
+        let mut command = Cursor {
+            database,
+            action,
+            id,
+            count: None,
+            no_header: false,
+            timeout: None,
+        };
+        while let Some(arg) = args_iter.next() {
+            let mut arg_string = String::from(*arg);
+            arg_string.make_ascii_uppercase();
+            match arg_string.as_str() {
+                "COUNT" if action == Action::Next => {
+                    match parse_count(args_iter.next()) {
+                        Some(count) => command.count = Some(count),
+                        None => {
+                            return Err(
+                                RediSQLError::invalid_cursor_count(),
+                            )
+                        }
+                    }
+                }
+                "NO_HEADER" => command.no_header = true,
+                "TIMEOUT" => {
+                    command.timeout = Some(parse_timeout(
+                        args_iter.next().copied(),
+                    )?)
+                }
+                _ => {}
+            }
+        }
+        Ok(command)
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
+
+/// Parse the number of rows of a page, a positive integer.
+fn parse_count(count: Option<&&str>) -> Option<usize> {
+    count
+        .and_then(|c| c.parse::<usize>().ok())
+        .filter(|c| *c > 0)
+}
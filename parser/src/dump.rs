@@ -0,0 +1,160 @@
+use redisql_lib::dump::DumpFormat;
+use redisql_lib::redis::argument_to_str;
+use redisql_lib::redis::Command;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::redisql_error::RediSQLError;
+
+use crate::common::CommandV2;
+
+/// `REDISQL.DUMP db [SQL|SQLITE]`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Dump<'s> {
+    database: &'s str,
+    pub format: DumpFormat,
+}
+
+impl Dump<'static> {
+    pub fn get_command(self, client: BlockedClient) -> Command {
+        Command::Dump {
+            format: self.format,
+            client,
+        }
+    }
+}
+
+impl<'s> CommandV2<'s> for Dump<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let mut args_iter = args.into_iter();
+        args_iter.next();
+        let database = match args_iter.next() {
+            Some(name) => argument_to_str(name)?,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        let format =
+            match args_iter.next().map(|f| f.to_ascii_uppercase()) {
+                None => DumpFormat::Sql,
+                Some(ref f) if f == b"SQL" => DumpFormat::Sql,
+                Some(ref f) if f == b"SQLITE" => DumpFormat::Sqlite,
+                Some(_) => return Err(invalid_dump(
+                    "The format of the dump must be SQL or SQLITE",
+                )),
+            };
+        if args_iter.next().is_some() {
+            return Err(invalid_dump(
+                "DUMP takes only the database and the format",
+            ));
+        }
+        Ok(Dump { database, format })
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
+
+/// `REDISQL.RESTORE newdb payload`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Restore<'s> {
+    database: &'s str,
+    pub payload: &'s [u8],
+}
+
+impl<'s> CommandV2<'s> for Restore<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let mut args_iter = args.into_iter();
+        args_iter.next();
+        let database = match args_iter.next() {
+            Some(name) => argument_to_str(name)?,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        let payload = match args_iter.next() {
+            Some(payload) => payload,
+            None => {
+                return Err(invalid_dump(
+                    "RESTORE must be followed by the database and the payload produced by DUMP",
+                ))
+            }
+        };
+        if args_iter.next().is_some() {
+            return Err(invalid_dump(
+                "RESTORE takes only the database and the payload",
+            ));
+        }
+        Ok(Restore { database, payload })
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
+
+/// `REDISQL.RESTORE.CHUNK newdb index total checksum chunk`, written by
+/// the AOF rewrite, the last chunk restores the database.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RestoreChunk<'s> {
+    database: &'s str,
+    pub index: i64,
+    pub total: i64,
+    pub checksum: i64,
+    pub chunk: &'s [u8],
+}
+
+impl<'s> CommandV2<'s> for RestoreChunk<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let mut args_iter = args.into_iter();
+        args_iter.next();
+        let database = match args_iter.next() {
+            Some(name) => argument_to_str(name)?,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        let mut numbers = [0; 3];
+        for number in numbers.iter_mut() {
+            *number = match args_iter
+                .next()
+                .and_then(|n| argument_to_str(n).ok())
+                .and_then(|n| n.parse::<i64>().ok())
+            {
+                Some(n) => n,
+                None => return Err(invalid_dump(
+                    "RESTORE.CHUNK must be followed by the database, the index of the chunk, the number of chunks, the checksum and the chunk",
+                )),
+            };
+        }
+        let [index, total, checksum] = numbers;
+        if index < 0 || index >= total {
+            return Err(invalid_dump(
+                "The index of the chunk must be between 0 and the number of chunks",
+            ));
+        }
+        let chunk = match args_iter.next() {
+            Some(chunk) => chunk,
+            None => return Err(invalid_dump(
+                "RESTORE.CHUNK must end with the chunk of the image",
+            )),
+        };
+        if args_iter.next().is_some() {
+            return Err(invalid_dump(
+                "RESTORE.CHUNK takes only the database, the index, the number of chunks, the checksum and the chunk",
+            ));
+        }
+        Ok(RestoreChunk {
+            database,
+            index,
+            total,
+            checksum,
+            chunk,
+        })
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
+
+fn invalid_dump(debug: &str) -> RediSQLError {
+    RediSQLError::with_code(
+        62,
+        debug.to_string(),
+        "Invalid DUMP or RESTORE arguments".to_string(),
+    )
+}
@@ -1,17 +1,23 @@
+use std::time::Duration;
+
+use redisql_lib::cursor::CursorQuery;
+use redisql_lib::redis::argument_to_str;
 use redisql_lib::redis::Command;
 use redisql_lib::redis::ReturnMethod;
 use redisql_lib::redis_type::BlockedClient;
 use redisql_lib::redis_type::Context;
 use redisql_lib::redis_type::RMString;
 use redisql_lib::redisql_error::RediSQLError;
+use redisql_lib::sqlite::{Argument, Bindings};
+use redisql_lib::timeout::default_timeout;
 
-use crate::common::CommandV2;
+use crate::common::{parse_format, parse_timeout, CommandV2, Format};
 use redisql_lib::redis_type::ffi::RedisModuleString;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum ToExecute<'s> {
-    Command { query: &'s str, args: Vec<&'s str> },
-    Statement { stmt: &'s str, args: Vec<&'s str> },
+    Command { query: &'s str, args: Bindings<'s> },
+    Statement { stmt: &'s str, args: Bindings<'s> },
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -22,7 +28,14 @@ pub struct Exec<'s> {
     read_only: bool,
     now: bool,
     no_header: bool,
+    format: Format,
+    bigint_as_string: bool,
+    transaction: Option<&'s str>,
     to_execute: Option<ToExecute<'s>>,
+    batch: Option<Vec<Bindings<'s>>>,
+    with_errors: bool,
+    cursor: Option<usize>,
+    timeout: Option<Duration>,
 }
 
 impl Exec<'static> {
@@ -32,10 +45,45 @@ impl Exec<'static> {
         client: BlockedClient,
     ) -> Command {
         let return_method = self.get_return_method();
+        let transaction = self.transaction;
         if self.to_execute.is_none() {
             todo!("to_execute not set");
         }
-        match (self.to_execute.unwrap(), self.read_only) {
+        if let Some(count) = self.cursor {
+            let (query, arguments) = match self.to_execute.unwrap() {
+                ToExecute::Command { query, args } => {
+                    (CursorQuery::Command(query), args)
+                }
+                ToExecute::Statement { stmt, args } => {
+                    (CursorQuery::Statement(stmt), args)
+                }
+            };
+            return Command::OpenCursor {
+                query,
+                arguments,
+                count,
+                timeout,
+                return_method,
+                client,
+            };
+        }
+        let command = match (self.to_execute.unwrap(), self.read_only)
+        {
+            (
+                ToExecute::Statement {
+                    stmt: identifier, ..
+                },
+                false,
+            ) if self.batch.is_some() => {
+                Command::ExecStatementBatch {
+                    identifier,
+                    batch: self.batch.unwrap(),
+                    with_errors: self.with_errors,
+                    timeout,
+                    return_method,
+                    client,
+                }
+            }
             (ToExecute::Command { query: q, args }, true) => {
                 Command::Query {
                     query: q,
@@ -81,6 +129,13 @@ impl Exec<'static> {
                 return_method,
                 client,
             },
+        };
+        match transaction {
+            Some(token) => Command::InTransaction {
+                token,
+                command: Box::new(command),
+            },
+            None => command,
         }
     }
     pub fn get_return_method(&self) -> ReturnMethod {
@@ -89,13 +144,36 @@ impl Exec<'static> {
                 ReturnMethod::Stream { name: s }
             }
             (_, Some(s), _) => ReturnMethod::Stream { name: s },
+            _ if self.format == Format::Map => {
+                ReturnMethod::ReplyWithMap
+            }
+            _ if self.format == Format::Json
+                || self.format == Format::JsonTable =>
+            {
+                ReturnMethod::ReplyWithJson {
+                    table: self.format == Format::JsonTable,
+                    bigint_as_string: self.bigint_as_string,
+                }
+            }
             (_, _, true) => ReturnMethod::Reply,
             (_, _, false) => ReturnMethod::ReplyWithHeader,
         }
     }
+    /// The stream where the rows are added, with `INTO`.
+    pub fn get_into(&self) -> Option<&str> {
+        self.into
+    }
     pub fn is_now(&self) -> bool {
         self.now
     }
+    pub fn transaction(&self) -> Option<&str> {
+        self.transaction
+    }
+    /// How long the command can run, the default timeout of the module if
+    /// TIMEOUT is not provided.
+    pub fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or_else(default_timeout)
+    }
     pub fn get_query(&self) -> Option<&str> {
         match self.to_execute {
             Some(ToExecute::Command { query: q, .. }) => Some(q),
@@ -108,8 +186,18 @@ impl Exec<'static> {
     pub fn is_read_only(&self) -> bool {
         self.read_only
     }
-    pub fn make_into_query(&mut self) {
+    pub fn get_batch(&self) -> Option<&[Bindings<'_>]> {
+        self.batch.as_deref()
+    }
+    pub fn with_errors(&self) -> bool {
+        self.with_errors
+    }
+    pub fn make_into_query(&mut self) -> Result<(), RediSQLError> {
+        if self.batch.is_some() {
+            return Err(RediSQLError::batch_not_supported());
+        }
         self.read_only = true;
+        Ok(())
     }
     pub fn replicate_args(
         &self,
@@ -118,9 +206,6 @@ impl Exec<'static> {
         if self.read_only {
             return None;
         }
-        if self.now {
-            return None;
-        }
         let mut v = Vec::new();
         let to_push = RMString::new(ctx, self.database);
         v.push(to_push.as_ptr());
@@ -143,14 +228,69 @@ impl Exec<'static> {
         let to_push = RMString::new(ctx, s);
         v.push(to_push.as_ptr());
         std::mem::forget(to_push);
-        if !args.is_empty() {
-            let to_push = RMString::new(ctx, "ARGS");
-            v.push(to_push.as_ptr());
-            std::mem::forget(to_push);
-            for arg in args.iter() {
-                let to_push = RMString::new(ctx, arg);
+        // the arguments are replicated with their type prefix
+        let to_push = RMString::new(ctx, "TYPED_ARGS");
+        v.push(to_push.as_ptr());
+        std::mem::forget(to_push);
+        match args {
+            Bindings::Positional(args) if !args.is_empty() => {
+                let to_push = RMString::new(ctx, "ARGS");
+                v.push(to_push.as_ptr());
+                std::mem::forget(to_push);
+                for arg in args.iter() {
+                    let to_push = RMString::from_bytes(
+                        ctx,
+                        &argument_to_bytes(arg),
+                    );
+                    v.push(to_push.as_ptr());
+                    std::mem::forget(to_push);
+                }
+            }
+            Bindings::Positional(_) => {}
+            Bindings::Named(args) => {
+                let to_push = RMString::new(ctx, "NAMED_ARGS");
                 v.push(to_push.as_ptr());
                 std::mem::forget(to_push);
+                for (name, arg) in args.iter() {
+                    let to_push = RMString::new(ctx, name);
+                    v.push(to_push.as_ptr());
+                    std::mem::forget(to_push);
+                    let to_push = RMString::from_bytes(
+                        ctx,
+                        &argument_to_bytes(arg),
+                    );
+                    v.push(to_push.as_ptr());
+                    std::mem::forget(to_push);
+                }
+            }
+        }
+        if let Some(batch) = self.batch.as_ref() {
+            if self.with_errors {
+                let to_push = RMString::new(ctx, "WITH_ERRORS");
+                v.push(to_push.as_ptr());
+                std::mem::forget(to_push);
+            }
+            let to_push = RMString::new(ctx, "BATCH");
+            v.push(to_push.as_ptr());
+            std::mem::forget(to_push);
+            let arity = match batch.first() {
+                Some(Bindings::Positional(args)) => args.len(),
+                _ => 0,
+            };
+            let to_push = RMString::new(ctx, &arity.to_string());
+            v.push(to_push.as_ptr());
+            std::mem::forget(to_push);
+            for args in batch.iter() {
+                if let Bindings::Positional(args) = args {
+                    for arg in args.iter() {
+                        let to_push = RMString::from_bytes(
+                            ctx,
+                            &argument_to_bytes(arg),
+                        );
+                        v.push(to_push.as_ptr());
+                        std::mem::forget(to_push);
+                    }
+                }
             }
         }
         Some(v)
@@ -158,13 +298,15 @@ impl Exec<'static> {
 }
 
 impl<'s> CommandV2<'s> for Exec<'s> {
-    fn parse(args: Vec<&'s str>) -> Result<Self, RediSQLError> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
         let mut args_iter = args.iter();
         args_iter.next();
         let database = match args_iter.next() {
-            Some(db) => db,
+            Some(db) => argument_to_str(db)?,
             None => return Err(RediSQLError::no_database_name()),
         };
+        let mut types: Option<Vec<String>> = None;
+        let mut typed = false;
         let mut exec = Exec {
             database,
             connection: None,
@@ -172,10 +314,17 @@ impl<'s> CommandV2<'s> for Exec<'s> {
             read_only: false,
             now: false,
             no_header: false,
+            format: Format::Array,
+            bigint_as_string: false,
+            transaction: None,
             to_execute: None,
+            batch: None,
+            with_errors: false,
+            cursor: None,
+            timeout: None,
         };
         while let Some(arg) = args_iter.next() {
-            let mut arg_string = String::from(*arg);
+            let mut arg_string = String::from(argument_to_str(arg)?);
             arg_string.make_ascii_uppercase();
             match arg_string.as_str() {
                 "COMMAND" => match exec.to_execute {
@@ -193,7 +342,7 @@ impl<'s> CommandV2<'s> for Exec<'s> {
                     }
                     None => {
                         let query = match args_iter.next() {
-                            Some(q) => q,
+                            Some(q) => argument_to_str(q)?,
                             None => {
                                 return Err(RediSQLError::with_code(
                                     9,
@@ -204,7 +353,7 @@ impl<'s> CommandV2<'s> for Exec<'s> {
                         };
                         exec.to_execute = Some(ToExecute::Command {
                             query,
-                            args: Vec::new(),
+                            args: Bindings::default(),
                         });
                     }
                 },
@@ -223,7 +372,7 @@ impl<'s> CommandV2<'s> for Exec<'s> {
                     }
                     None => {
                         let stmt = match args_iter.next() {
-                            Some(s) => s,
+                            Some(s) => argument_to_str(s)?,
                             None => {
                                 return Err(RediSQLError::with_code(
                                     10,
@@ -236,7 +385,7 @@ impl<'s> CommandV2<'s> for Exec<'s> {
                         exec.to_execute =
                             Some(ToExecute::Statement {
                                 stmt,
-                                args: Vec::new(),
+                                args: Bindings::default(),
                             });
                     }
                 },
@@ -244,7 +393,7 @@ impl<'s> CommandV2<'s> for Exec<'s> {
                 "NOW" => exec.now = true,
                 "INTO" => {
                     let stream = match args_iter.next() {
-                        Some(s) => s,
+                        Some(s) => argument_to_str(s)?,
                         None => {
                             return Err(RediSQLError::with_code(
                                 11,
@@ -256,7 +405,35 @@ impl<'s> CommandV2<'s> for Exec<'s> {
                     exec.into = Some(stream);
                 }
                 "NO_HEADER" => exec.no_header = true,
-                "ARGS" => {
+                "FORMAT" => {
+                    let format = match args_iter.next() {
+                        Some(f) => Some(argument_to_str(f)?),
+                        None => None,
+                    };
+                    exec.format = parse_format(format)?;
+                }
+                "BIGINT_AS_STRING" => exec.bigint_as_string = true,
+                "TIMEOUT" => {
+                    let timeout = match args_iter.next() {
+                        Some(t) => Some(argument_to_str(t)?),
+                        None => None,
+                    };
+                    exec.timeout = Some(parse_timeout(timeout)?);
+                }
+                "TX" => {
+                    let token = match args_iter.next() {
+                        Some(t) => argument_to_str(t)?,
+                        None => {
+                            return Err(RediSQLError::with_code(
+                                34,
+                                "Provided the TX keyword without the token returned by BEGIN".to_string(),
+                                "Transaction token not provided".to_string(),
+                            ))
+                        }
+                    };
+                    exec.transaction = Some(token);
+                }
+                "ARGS" | "NAMED_ARGS" => {
                     let args = match exec.to_execute {
                         None => {
                             return Err(RediSQLError::with_code(24, "You didn't provide neither `COMMAND` nor `STATEMENT` fields".to_string(), "Command incomplete, no `COMMAND` nor `STATEMENT` fields".to_string()));
@@ -270,11 +447,95 @@ impl<'s> CommandV2<'s> for Exec<'s> {
                             ..
                         }) => args,
                     };
-                    let (size, _) = args_iter.size_hint();
-                    args.reserve(size);
-                    while let Some(arg) = args_iter.next() {
-                        args.push(*arg);
+                    // ARGS and NAMED_ARGS consume all the remaining arguments
+                    let rest = args_iter.as_slice();
+                    *args = if arg_string == "ARGS" {
+                        Bindings::Positional(parse_values(
+                            &types, typed, rest,
+                        )?)
+                    } else {
+                        parse_named_values(&types, typed, rest)?
+                    };
+                    break;
+                }
+                "TYPED_ARGS" => typed = true,
+                "WITH_ERRORS" => exec.with_errors = true,
+                "CURSOR" => {
+                    let count = args_iter
+                        .next()
+                        .and_then(|c| argument_to_str(c).ok())
+                        .and_then(|c| c.parse::<usize>().ok())
+                        .filter(|c| *c > 0);
+                    match count {
+                        Some(count) => exec.cursor = Some(count),
+                        None => {
+                            return Err(
+                                RediSQLError::invalid_cursor_count(),
+                            )
+                        }
                     }
+                    // cursors can only read from the database
+                    exec.read_only = true;
+                }
+                "BATCH" => {
+                    if !matches!(
+                        exec.to_execute,
+                        Some(ToExecute::Statement { .. })
+                    ) {
+                        return Err(
+                            RediSQLError::batch_not_supported(),
+                        );
+                    }
+                    let arity = args_iter
+                        .next()
+                        .and_then(|a| argument_to_str(a).ok())
+                        .and_then(|a| a.parse::<usize>().ok())
+                        .filter(|a| *a > 0);
+                    let arity = match arity {
+                        Some(arity) => arity,
+                        None => return Err(RediSQLError::with_code(37, "BATCH must be followed by the number of values of each tuple, a positive integer".to_string(), "Invalid BATCH arity".to_string())),
+                    };
+                    // BATCH consumes all the remaining arguments
+                    let rest = args_iter.as_slice();
+                    if rest.is_empty() || rest.len() % arity != 0 {
+                        return Err(RediSQLError::with_code(
+                            38,
+                            format!(
+                                "Provided {} values, that are not a positive multiple of the BATCH arity {}",
+                                rest.len(),
+                                arity
+                            ),
+                            "BATCH values do not form complete tuples".to_string(),
+                        ));
+                    }
+                    let batch = rest
+                        .chunks(arity)
+                        .map(|tuple| {
+                            parse_values(&types, typed, tuple)
+                                .map(Bindings::Positional)
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    exec.batch = Some(batch);
+                    break;
+                }
+                "TYPES" => {
+                    let mut declared = Vec::new();
+                    while let Some(t) = args_iter.as_slice().first() {
+                        let mut t = String::from(argument_to_str(t)?);
+                        t.make_ascii_uppercase();
+                        match t.as_str() {
+                            "INT" | "REAL" | "TEXT" | "BLOB"
+                            | "NULL" => {
+                                declared.push(t);
+                                args_iter.next();
+                            }
+                            _ => break,
+                        }
+                    }
+                    if declared.is_empty() {
+                        return Err(RediSQLError::with_code(29, "Provided the TYPES keyword without any type, use INT, REAL, TEXT, BLOB or NULL".to_string(), "No TYPES provided".to_string()));
+                    }
+                    types = Some(declared);
                 }
                 _ => {}
             }
@@ -282,15 +543,178 @@ impl<'s> CommandV2<'s> for Exec<'s> {
         if exec.to_execute.is_none() {
             return Err(RediSQLError::with_code(24, "You didn't provide neither `COMMAND` nor `STATEMENT` fields".to_string(), "Command incomplete, no `COMMAND` nor `STATEMENT` fields".to_string()));
         }
+        if exec.with_errors && exec.batch.is_none() {
+            return Err(RediSQLError::batch_not_supported());
+        }
+        if exec.batch.is_some() && exec.read_only {
+            return Err(RediSQLError::batch_not_supported());
+        }
+        if exec.cursor.is_some()
+            && (exec.now
+                || exec.into.is_some()
+                || exec.batch.is_some()
+                || exec.transaction.is_some())
+        {
+            return Err(RediSQLError::with_code(41, "Cursors are kept open by the database thread, they cannot be used with `NOW`, `INTO`, `BATCH` or `TX`".to_string(), "CURSOR not supported".to_string()));
+        }
+        if exec.transaction.is_some() && exec.now {
+            return Err(RediSQLError::with_code(36, "Commands in a transaction are executed in order by the database thread, they cannot be executed `NOW`".to_string(), "TX and NOW used together".to_string()));
+        }
+        if exec.format != Format::Array
+            && (exec.into.is_some() || exec.cursor.is_some())
+        {
+            return Err(RediSQLError::with_code(57, "The rows added to a stream or read with a cursor are always replied as arrays".to_string(), "FORMAT used with INTO or CURSOR".to_string()));
+        }
+        if exec.bigint_as_string
+            && exec.format != Format::Json
+            && exec.format != Format::JsonTable
+        {
+            return Err(RediSQLError::with_code(58, "BIGINT_AS_STRING changes how the integers are written in JSON, it needs FORMAT JSON or FORMAT JSON_TABLE".to_string(), "BIGINT_AS_STRING without FORMAT JSON".to_string()));
+        }
         if exec.into.is_some() && exec.no_header {
             return Err(RediSQLError::with_code(16, "Asked a STREAM without the header".to_string(), "The header is part of the stream, does not make sense to provide a stream without header".to_string()));
         }
-        if exec.into.is_some() && !exec.read_only {
-            return Err(RediSQLError::with_code(17, "STREAM for not READ_ONLY query not supported".to_string(), "Asked a STREAM, but the query is not `READ_ONLY` (flag not set), this is not supported.".to_string()));
-        }
         Ok(exec)
     }
     fn database(&self) -> &str {
         self.database
     }
 }
+
+/// Parse the values of the ARGS section, using the TYPES, if provided,
+/// or the prefixes of the values with TYPED_ARGS.
+fn parse_values<'s>(
+    types: &Option<Vec<String>>,
+    typed: bool,
+    values: &[&'s [u8]],
+) -> Result<Vec<Argument<'s>>, RediSQLError> {
+    match types {
+        None if typed => {
+            values.iter().map(|arg| parse_argument(arg)).collect()
+        }
+        None => Ok(values
+            .iter()
+            .map(|arg| Argument::untyped(arg))
+            .collect()),
+        Some(ref types) => {
+            if values.len() != types.len() {
+                return Err(RediSQLError::with_code(
+                    28,
+                    format!(
+                        "Provided {} TYPES but {} ARGS",
+                        types.len(),
+                        values.len()
+                    ),
+                    "TYPES and ARGS must have the same length"
+                        .to_string(),
+                ));
+            }
+            types
+                .iter()
+                .zip(values.iter())
+                .map(|(t, arg)| parse_typed_argument(t, arg))
+                .collect()
+        }
+    }
+}
+
+/// Parse the NAMED_ARGS section, a list of `name value` pairs.
+fn parse_named_values<'s>(
+    types: &Option<Vec<String>>,
+    typed: bool,
+    pairs: &[&'s [u8]],
+) -> Result<Bindings<'s>, RediSQLError> {
+    if pairs.is_empty() || pairs.len() % 2 != 0 {
+        return Err(RediSQLError::with_code(30, "NAMED_ARGS must be followed by pairs of parameter name and value".to_string(), "NAMED_ARGS without a value for each name".to_string()));
+    }
+    let names = pairs
+        .iter()
+        .step_by(2)
+        .map(|name| argument_to_str(name))
+        .collect::<Result<Vec<_>, _>>()?;
+    let values = pairs.iter().skip(1).step_by(2).cloned();
+    let values =
+        parse_values(types, typed, &values.collect::<Vec<_>>())?;
+    Ok(Bindings::Named(names.into_iter().zip(values).collect()))
+}
+
+/// Parse a single value of the ARGS section with TYPED_ARGS, the type of
+/// the value is forced with a prefix: `INT:42`, `REAL:1.5`, `TEXT:foo`,
+/// `BLOB:<bytes>` or with the bare `NULL`. Values without a prefix are
+/// untyped.
+fn parse_argument(arg: &[u8]) -> Result<Argument<'_>, RediSQLError> {
+    let prefixes: [(&[u8], &str); 4] = [
+        (b"INT:", "INT"),
+        (b"REAL:", "REAL"),
+        (b"TEXT:", "TEXT"),
+        (b"BLOB:", "BLOB"),
+    ];
+    if arg == b"NULL" {
+        return Ok(Argument::Null);
+    }
+    for (prefix, t) in prefixes.iter() {
+        if arg.starts_with(prefix) {
+            return parse_typed_argument(t, &arg[prefix.len()..]);
+        }
+    }
+    Ok(Argument::untyped(arg))
+}
+
+fn parse_typed_argument<'s>(
+    t: &str,
+    arg: &'s [u8],
+) -> Result<Argument<'s>, RediSQLError> {
+    match t {
+        "INT" => match argument_to_str(arg)?.parse::<i64>() {
+            Ok(int) => Ok(Argument::Integer { int }),
+            Err(e) => Err(RediSQLError::with_code(
+                26,
+                format!(
+                    "Impossible to parse the argument as INT: {}",
+                    e
+                ),
+                "Argument declared as INT is not an integer"
+                    .to_string(),
+            )),
+        },
+        "REAL" => match argument_to_str(arg)?.parse::<f64>() {
+            Ok(float) => Ok(Argument::Float { float }),
+            Err(e) => Err(RediSQLError::with_code(
+                27,
+                format!(
+                    "Impossible to parse the argument as REAL: {}",
+                    e
+                ),
+                "Argument declared as REAL is not a number"
+                    .to_string(),
+            )),
+        },
+        "TEXT" => Ok(Argument::Text {
+            text: argument_to_str(arg)?,
+        }),
+        "BLOB" => Ok(Argument::Blob { blob: arg }),
+        _ => Ok(Argument::Null),
+    }
+}
+
+/// The inverse of `parse_argument`, used to replicate the arguments without
+/// losing their types.
+fn argument_to_bytes(arg: &Argument) -> Vec<u8> {
+    match arg {
+        Argument::Integer { int } => {
+            format!("INT:{}", int).into_bytes()
+        }
+        Argument::Float { float } => {
+            format!("REAL:{}", float).into_bytes()
+        }
+        Argument::Text { text } => {
+            format!("TEXT:{}", text).into_bytes()
+        }
+        Argument::Blob { blob } => {
+            let mut v = b"BLOB:".to_vec();
+            v.extend_from_slice(blob);
+            v
+        }
+        Argument::Null => b"NULL".to_vec(),
+    }
+}
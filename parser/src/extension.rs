@@ -0,0 +1,128 @@
+use redisql_lib::redis::argument_to_str;
+use redisql_lib::redis::Command;
+use redisql_lib::redis::ReturnMethod;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::redisql_error::RediSQLError;
+use redisql_lib::timeout::default_timeout;
+
+use crate::common::{parse_timeout, CommandV2};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Action {
+    Load,
+    List,
+}
+
+/// `REDISQL.EXTENSION db LOAD name` and `REDISQL.EXTENSION db LIST`,
+/// both with `[NOW] [TIMEOUT ms]`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Extension<'s> {
+    database: &'s str,
+    action: Action,
+    name: Option<&'s str>,
+    now: bool,
+    timeout: Option<std::time::Duration>,
+}
+
+impl Extension<'static> {
+    pub fn get_command(
+        self,
+        timeout: std::time::Instant,
+        client: BlockedClient,
+    ) -> Command {
+        match self.action {
+            Action::Load => Command::LoadExtension {
+                name: self.name.unwrap(),
+                client,
+            },
+            Action::List => Command::ListExtensions {
+                timeout,
+                return_method: ReturnMethod::ReplyWithHeader,
+                client,
+            },
+        }
+    }
+}
+
+impl<'s> Extension<'s> {
+    pub fn get_action(&self) -> Action {
+        self.action
+    }
+    pub fn name(&self) -> &str {
+        self.name.unwrap()
+    }
+    pub fn is_now(&self) -> bool {
+        self.now
+    }
+    /// How long the command can run, the default timeout of the module if
+    /// TIMEOUT is not provided.
+    pub fn timeout(&self) -> std::time::Duration {
+        self.timeout.unwrap_or_else(default_timeout)
+    }
+}
+
+impl<'s> CommandV2<'s> for Extension<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let mut args_iter = args.into_iter();
+        args_iter.next();
+        let database = match args_iter.next() {
+            Some(name) => argument_to_str(name)?,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        let action = match args_iter
+            .next()
+            .map(|a| a.to_ascii_uppercase())
+            .as_deref()
+        {
+            Some(b"LOAD") => Action::Load,
+            Some(b"LIST") => Action::List,
+            _ => {
+                return Err(RediSQLError::with_code(
+                    75,
+                    "The extension command needs an action, either: LOAD or LIST".to_string(),
+                    "Extension command without a valid action".to_string(),
+                ))
+            }
+        };
+        let name = match action {
+            Action::Load => match args_iter.next() {
+                Some(name) => Some(argument_to_str(name)?),
+                None => {
+                    return Err(RediSQLError::with_code(
+                        75,
+                        "You should provide the name of the extension to load".to_string(),
+                        "Invalid EXTENSION arguments".to_string(),
+                    ))
+                }
+            },
+            Action::List => None,
+        };
+        let mut command = Extension {
+            database,
+            action,
+            name,
+            now: false,
+            timeout: None,
+        };
+        while let Some(arg) = args_iter.next() {
+            let mut arg_string = String::from(argument_to_str(arg)?);
+            arg_string.make_ascii_uppercase();
+            match arg_string.as_str() {
+                "NOW" => command.now = true,
+                "TIMEOUT" => {
+                    let timeout = match args_iter.next() {
+                        Some(t) => Some(argument_to_str(t)?),
+                        None => None,
+                    };
+                    command.timeout = Some(parse_timeout(timeout)?);
+                }
+                _ => {}
+            }
+        }
+        Ok(command)
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
@@ -0,0 +1,218 @@
+use redisql_lib::redis::argument_to_str;
+use redisql_lib::redis::Command;
+use redisql_lib::redis::ReturnMethod;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::redisql_error::RediSQLError;
+use redisql_lib::timeout::default_timeout;
+use redisql_lib::user_functions::{FunctionDefinition, Language};
+
+use crate::common::{parse_timeout, CommandV2};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Action {
+    New,
+    Delete,
+    List,
+}
+
+/// `REDISQL.FUNCTION db NEW name ARITY n [AGGREGATE] [LANGUAGE EXPR]
+/// BODY body [CAN_UPDATE]`, `REDISQL.FUNCTION db DELETE name` and
+/// `REDISQL.FUNCTION db LIST`, all with `[NOW] [TIMEOUT ms]`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function<'s> {
+    database: &'s str,
+    action: Action,
+    name: Option<&'s str>,
+    arity: Option<i32>,
+    aggregate: bool,
+    language: Language,
+    body: Option<&'s str>,
+    can_update: bool,
+    now: bool,
+    timeout: Option<std::time::Duration>,
+}
+
+impl Function<'static> {
+    pub fn get_command(
+        self,
+        timeout: std::time::Instant,
+        client: BlockedClient,
+    ) -> Command {
+        match self.action {
+            Action::New => Command::CreateFunction {
+                definition: self.definition(),
+                can_update: self.can_update,
+                client,
+            },
+            Action::Delete => Command::DeleteFunction {
+                name: self.name.unwrap(),
+                client,
+            },
+            Action::List => Command::ListFunctions {
+                timeout,
+                return_method: ReturnMethod::ReplyWithHeader,
+                client,
+            },
+        }
+    }
+}
+
+impl<'s> Function<'s> {
+    /// The function defined by NEW.
+    pub fn definition(&self) -> FunctionDefinition {
+        FunctionDefinition {
+            name: self.name.unwrap().to_string(),
+            arity: self.arity.unwrap(),
+            aggregate: self.aggregate,
+            language: self.language,
+            body: self.body.unwrap().to_string(),
+        }
+    }
+    pub fn get_action(&self) -> Action {
+        self.action
+    }
+    pub fn name(&self) -> &str {
+        self.name.unwrap()
+    }
+    pub fn can_update(&self) -> bool {
+        self.can_update
+    }
+    pub fn is_now(&self) -> bool {
+        self.now
+    }
+    /// How long the command can run, the default timeout of the module if
+    /// TIMEOUT is not provided.
+    pub fn timeout(&self) -> std::time::Duration {
+        self.timeout.unwrap_or_else(default_timeout)
+    }
+}
+
+fn invalid_function(debug: &str) -> RediSQLError {
+    RediSQLError::with_code(
+        71,
+        debug.to_string(),
+        "Invalid FUNCTION arguments".to_string(),
+    )
+}
+
+impl<'s> CommandV2<'s> for Function<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let mut args_iter = args.into_iter();
+        args_iter.next();
+        let database = match args_iter.next() {
+            Some(name) => argument_to_str(name)?,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        let action = match args_iter
+            .next()
+            .map(|a| a.to_ascii_uppercase())
+            .as_deref()
+        {
+            Some(b"NEW") => Action::New,
+            Some(b"DELETE") => Action::Delete,
+            Some(b"LIST") => Action::List,
+            _ => {
+                return Err(RediSQLError::with_code(
+                    70,
+                    "The function command needs an action, either: NEW, DELETE or LIST".to_string(),
+                    "Function command without a valid action".to_string(),
+                ))
+            }
+        };
+        let name = match action {
+            Action::New | Action::Delete => match args_iter.next() {
+                Some(name) => Some(argument_to_str(name)?),
+                None => {
+                    return Err(invalid_function(
+                        "You should provide the name of the function to operate with",
+                    ))
+                }
+            },
+            Action::List => None,
+        };
+        let mut command = Function {
+            database,
+            action,
+            name,
+            arity: None,
+            aggregate: false,
+            language: Language::Expr,
+            body: None,
+            can_update: false,
+            now: false,
+            timeout: None,
+        };
+        while let Some(arg) = args_iter.next() {
+            let mut arg_string = String::from(argument_to_str(arg)?);
+            arg_string.make_ascii_uppercase();
+            match arg_string.as_str() {
+                "ARITY" => {
+                    command.arity = match args_iter
+                        .next()
+                        .and_then(|a| argument_to_str(a).ok())
+                        .and_then(|a| a.parse::<i32>().ok())
+                    {
+                        Some(arity) => Some(arity),
+                        None => return Err(invalid_function(
+                            "ARITY must be followed by the number of arguments of the function, -1 for any number",
+                        )),
+                    }
+                }
+                "AGGREGATE" => command.aggregate = true,
+                "LANGUAGE" => match args_iter.next() {
+                    Some(language) => {
+                        command.language =
+                            argument_to_str(language)?.parse()?
+                    }
+                    None => {
+                        return Err(invalid_function(
+                            "LANGUAGE must be followed by the language of the body",
+                        ))
+                    }
+                },
+                "BODY" => match args_iter.next() {
+                    Some(body) => {
+                        command.body = Some(argument_to_str(body)?)
+                    }
+                    None => {
+                        return Err(invalid_function(
+                            "BODY must be followed by the body of the function",
+                        ))
+                    }
+                },
+                "CAN_UPDATE" => command.can_update = true,
+                "NOW" => command.now = true,
+                "TIMEOUT" => {
+                    let timeout = match args_iter.next() {
+                        Some(t) => Some(argument_to_str(t)?),
+                        None => None,
+                    };
+                    command.timeout = Some(parse_timeout(timeout)?);
+                }
+                _ => {}
+            }
+        }
+        if command.action == Action::New
+            && (command.arity.is_none() || command.body.is_none())
+        {
+            return Err(invalid_function(
+                "FUNCTION NEW needs the ARITY and the BODY of the function",
+            ));
+        }
+        if command.action != Action::New
+            && (command.can_update
+                || command.aggregate
+                || command.arity.is_some()
+                || command.body.is_some())
+        {
+            return Err(invalid_function(
+                "ARITY, AGGREGATE, BODY and CAN_UPDATE are supported only by FUNCTION NEW",
+            ));
+        }
+        Ok(command)
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
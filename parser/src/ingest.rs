@@ -0,0 +1,194 @@
+use redisql_lib::ingest::{replicate_args, StreamRead, StreamSource};
+use redisql_lib::redis::Command;
+use redisql_lib::redis::ReturnMethod;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::redisql_error::RediSQLError;
+use redisql_lib::timeout::default_timeout;
+
+use crate::common::{arguments_to_str, parse_timeout, CommandV2};
+
+/// How many entries are read when COUNT is not provided.
+pub const DEFAULT_INGEST_COUNT: usize = 1000;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ingest<'s> {
+    database: &'s str,
+    stream: &'s str,
+    table: &'s str,
+    id_column: Option<&'s str>,
+    from: Option<&'s str>,
+    group: Option<(&'s str, &'s str)>,
+    count: usize,
+    no_header: bool,
+    timeout: Option<std::time::Duration>,
+}
+
+impl Ingest<'static> {
+    pub fn get_command(
+        self,
+        read: StreamRead,
+        timeout: std::time::Instant,
+        client: BlockedClient,
+    ) -> Command {
+        let replicate = replicate_args(&self.replicate_args(), &read);
+        Command::IngestStream {
+            table: self.table,
+            id_column: self.id_column,
+            entries: read.entries,
+            replicate,
+            ack: self.group.map(|(group, _)| (self.stream, group)),
+            timeout,
+            return_method: if self.no_header {
+                ReturnMethod::Reply
+            } else {
+                ReturnMethod::ReplyWithHeader
+            },
+            client,
+        }
+    }
+}
+
+impl<'s> Ingest<'s> {
+    pub fn stream(&self) -> &str {
+        self.stream
+    }
+    pub fn source(&self) -> StreamSource<'_> {
+        match self.group {
+            Some((group, consumer)) => {
+                StreamSource::Group { group, consumer }
+            }
+            None => StreamSource::Range {
+                from: self.from.unwrap_or("-"),
+            },
+        }
+    }
+    pub fn count(&self) -> usize {
+        self.count
+    }
+    /// How long the command can run, the default timeout of the module if
+    /// TIMEOUT is not provided.
+    pub fn timeout(&self) -> std::time::Duration {
+        self.timeout.unwrap_or_else(default_timeout)
+    }
+    /// The arguments of the command that replicas execute to ingest the
+    /// same entries, without the range of entries that was read, even if
+    /// it was read from a consumer group.
+    fn replicate_args(&self) -> Vec<String> {
+        let mut args = vec![
+            self.database.to_string(),
+            self.stream.to_string(),
+            "TABLE".to_string(),
+            self.table.to_string(),
+        ];
+        if let Some(id_column) = self.id_column {
+            args.push("ID_COLUMN".to_string());
+            args.push(id_column.to_string());
+        }
+        args
+    }
+}
+
+impl<'s> CommandV2<'s> for Ingest<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let args = arguments_to_str(args)?;
+        let mut args_iter = args.iter();
+        args_iter.next();
+        let database = match args_iter.next() {
+            Some(name) => name,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        let stream = match args_iter.next() {
+            Some(stream) => stream,
+            None => return Err(missing_stream_or_table()),
+        };
+        let mut command = Ingest {
+            database,
+            stream,
+            table: "",
+            id_column: None,
+            from: None,
+            group: None,
+            count: DEFAULT_INGEST_COUNT,
+            no_header: false,
+            timeout: None,
+        };
+        while let Some(arg) = args_iter.next() {
+            let mut arg_string = String::from(*arg);
+            arg_string.make_ascii_uppercase();
+            match arg_string.as_str() {
+                "TABLE" => match args_iter.next() {
+                    Some(table) => command.table = table,
+                    None => return Err(missing_stream_or_table()),
+                },
+                "ID_COLUMN" => match args_iter.next() {
+                    Some(column) => command.id_column = Some(column),
+                    None => return Err(RediSQLError::with_code(
+                        51,
+                        "ID_COLUMN must be followed by the name of the column".to_string(),
+                        "ID_COLUMN without the column".to_string(),
+                    )),
+                },
+                "FROM" => match args_iter.next() {
+                    Some(from) => command.from = Some(from),
+                    None => return Err(invalid_source()),
+                },
+                "GROUP" => {
+                    match (args_iter.next(), args_iter.next()) {
+                        (Some(group), Some(consumer)) => {
+                            command.group = Some((group, consumer))
+                        }
+                        _ => return Err(invalid_source()),
+                    }
+                }
+                "COUNT" => {
+                    match args_iter
+                        .next()
+                        .and_then(|c| c.parse::<usize>().ok())
+                        .filter(|c| *c > 0)
+                    {
+                        Some(count) => command.count = count,
+                        None => return Err(RediSQLError::with_code(
+                            52,
+                            "COUNT must be followed by a positive integer".to_string(),
+                            "Invalid COUNT".to_string(),
+                        )),
+                    }
+                }
+                "NO_HEADER" => command.no_header = true,
+                "TIMEOUT" => {
+                    command.timeout = Some(parse_timeout(
+                        args_iter.next().copied(),
+                    )?)
+                }
+                _ => {}
+            }
+        }
+        if command.table.is_empty() {
+            return Err(missing_stream_or_table());
+        }
+        if command.from.is_some() && command.group.is_some() {
+            return Err(invalid_source());
+        }
+        Ok(command)
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
+
+fn missing_stream_or_table() -> RediSQLError {
+    RediSQLError::with_code(
+        51,
+        "You should provide the stream to read and the TABLE where to write its entries".to_string(),
+        "Stream or table not provided".to_string(),
+    )
+}
+
+fn invalid_source() -> RediSQLError {
+    RediSQLError::with_code(
+        53,
+        "Use either FROM followed by the first id to read, or GROUP followed by the consumer group and the consumer name".to_string(),
+        "Invalid FROM or GROUP".to_string(),
+    )
+}
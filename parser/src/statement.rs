@@ -2,8 +2,9 @@ use redisql_lib::redis::Command;
 use redisql_lib::redis::ReturnMethod;
 use redisql_lib::redis_type::BlockedClient;
 use redisql_lib::redisql_error::RediSQLError;
+use redisql_lib::timeout::default_timeout;
 
-use crate::common::CommandV2;
+use crate::common::{arguments_to_str, parse_timeout, CommandV2};
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Action {
@@ -23,10 +24,15 @@ pub struct Statement<'s> {
     now: bool,
     can_update: bool,
     can_create: bool,
+    timeout: Option<std::time::Duration>,
 }
 
 impl Statement<'static> {
-    pub fn get_command(self, client: BlockedClient) -> Command {
+    pub fn get_command(
+        self,
+        timeout: std::time::Instant,
+        client: BlockedClient,
+    ) -> Command {
         match self.action {
             Action::Delete => Command::DeleteStatement {
                 identifier: self.stmt_name.unwrap(),
@@ -46,10 +52,12 @@ impl Statement<'static> {
             },
             Action::Show => Command::ShowStatement {
                 identifier: self.stmt_name.unwrap(),
+                timeout,
                 return_method: ReturnMethod::ReplyWithHeader,
                 client,
             },
             Action::List => Command::ListStatements {
+                timeout,
                 return_method: ReturnMethod::ReplyWithHeader,
                 client,
             },
@@ -73,10 +81,16 @@ impl Statement<'static> {
     pub fn can_create(&self) -> bool {
         self.can_create
     }
+    /// How long the command can run, the default timeout of the module if
+    /// TIMEOUT is not provided.
+    pub fn timeout(&self) -> std::time::Duration {
+        self.timeout.unwrap_or_else(default_timeout)
+    }
 }
 
 impl<'s> CommandV2<'s> for Statement<'s> {
-    fn parse(args: Vec<&'s str>) -> Result<Self, RediSQLError> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let args = arguments_to_str(args)?;
         let mut args_iter = args.iter();
         args_iter.next();
         let database = match args_iter.next() {
@@ -127,12 +141,18 @@ impl<'s> CommandV2<'s> for Statement<'s> {
             now: false,
             can_update: false,
             can_create: false,
+            timeout: None,
         };
-        for args in args_iter {
+        while let Some(args) = args_iter.next() {
             let mut arg_string = String::from(*args);
             arg_string.make_ascii_uppercase();
             match arg_string.as_str() {
                 "NOW" => command.now = true,
+                "TIMEOUT" => {
+                    command.timeout = Some(parse_timeout(
+                        args_iter.next().copied(),
+                    )?)
+                }
                 "CAN_UPDATE" => command.can_update = true,
                 "CAN_CREATE" => command.can_create = true,
                 _ => {}
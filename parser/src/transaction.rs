@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use redisql_lib::redis::Command;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::redisql_error::RediSQLError;
+use redisql_lib::transaction::DEFAULT_IDLE_TIMEOUT;
+
+use crate::common::{arguments_to_str, CommandV2};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Action {
+    Begin,
+    Commit,
+    Rollback,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Transaction<'s> {
+    database: &'s str,
+    action: Action,
+    token: Option<String>,
+    idle_timeout: Duration,
+}
+
+impl Transaction<'static> {
+    pub fn get_command(self, client: BlockedClient) -> Command {
+        let token = self.token.unwrap();
+        let database = self.database.to_string();
+        match self.action {
+            Action::Begin => Command::BeginTransaction {
+                token,
+                database,
+                client,
+            },
+            Action::Commit => Command::EndTransaction {
+                token,
+                database,
+                commit: true,
+                client: Some(client),
+            },
+            Action::Rollback => Command::EndTransaction {
+                token,
+                database,
+                commit: false,
+                client: Some(client),
+            },
+        }
+    }
+    pub fn get_action(&self) -> Action {
+        self.action
+    }
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_ref().map(String::as_str)
+    }
+    /// The token of a new transaction is generated when it begins.
+    pub fn set_token(&mut self, token: String) {
+        self.token = Some(token);
+    }
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+}
+
+impl<'s> CommandV2<'s> for Transaction<'s> {
+    fn parse(args: Vec<&'s [u8]>) -> Result<Self, RediSQLError> {
+        let args = arguments_to_str(args)?;
+        let mut args_iter = args.iter();
+        args_iter.next();
+        let database = match args_iter.next() {
+            Some(name) => name,
+            None => return Err(RediSQLError::no_database_name()),
+        };
+        let action = match args_iter.next() {
+            None => return Err(RediSQLError::with_code(33, "The transaction command needs an action, either: BEGIN, COMMIT or ROLLBACK".to_string(), "Transaction command without action".to_string())),
+            Some(a) => {
+                let mut action_str = String::from(*a);
+                action_str.make_ascii_uppercase();
+                match action_str.as_str() {
+                    "BEGIN" => Action::Begin,
+                    "COMMIT" => Action::Commit,
+                    "ROLLBACK" => Action::Rollback,
+                    _ => return Err(RediSQLError::with_code(33,
+                            "You provide an action for the transaction that is not supported, use BEGIN, COMMIT or ROLLBACK".to_string(),
+                            "Transaction action unknow".to_string()))
+                }
+            }
+        };
+        let token = match action {
+            Action::Begin => None,
+            Action::Commit | Action::Rollback => match args_iter
+                .next()
+            {
+                Some(token) => Some(token.to_string()),
+                None => return Err(RediSQLError::with_code(
+                    34,
+                    "You should provide the token returned by BEGIN"
+                        .to_string(),
+                    "Transaction token not provided".to_string(),
+                )),
+            },
+        };
+        let mut command = Transaction {
+            database,
+            action,
+            token,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        };
+        while let Some(arg) = args_iter.next() {
+            let mut arg_string = String::from(*arg);
+            arg_string.make_ascii_uppercase();
+            match arg_string.as_str() {
+                "IDLE_TIMEOUT" if action == Action::Begin => {
+                    let timeout = args_iter
+                        .next()
+                        .and_then(|t| t.parse::<u64>().ok())
+                        .filter(|t| *t > 0);
+                    match timeout {
+                        Some(t) => {
+                            command.idle_timeout = Duration::from_millis(t)
+                        }
+                        None => return Err(RediSQLError::with_code(35, "IDLE_TIMEOUT must be followed by a positive number of milliseconds".to_string(), "Invalid IDLE_TIMEOUT".to_string())),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(command)
+    }
+
+    fn database(&self) -> &str {
+        self.database
+    }
+}
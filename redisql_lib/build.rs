@@ -29,6 +29,8 @@ fn main() {
         .define("SQLITE_ENABLE_FTS4", Some("1"))
         .define("SQLITE_ENABLE_FTS5", Some("1"))
         .define("SQLITE_ENABLE_RTREE", Some("1"))
+        .define("SQLITE_ENABLE_PREUPDATE_HOOK", Some("1"))
+        .define("SQLITE_ENABLE_DESERIALIZE", Some("1"))
         .flag_if_supported("-w")
         .compile("libsqlite3.a");
 
@@ -54,6 +56,7 @@ fn main() {
     let bindings = bindgen::Builder::default()
         .parse_callbacks(Box::new(SqliteTypeChooser))
         .header("sqlite_dependencies.h")
+        .clang_arg("-DSQLITE_ENABLE_PREUPDATE_HOOK")
         .generate()
         .expect("Unable to generate bindings for SQLite");
 
@@ -0,0 +1,174 @@
+use std::hash::Hasher;
+use std::os::raw::c_void;
+use std::sync::Mutex;
+
+use fnv::{FnvHashMap, FnvHasher};
+
+use crate::redis_type::{ffi, Context};
+use crate::redisql_error::RediSQLError;
+
+/// How many bytes of the image each `RESTORE.CHUNK` of the AOF carries,
+/// the same database is always split in the same chunks.
+pub const AOF_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How the AOF rewrite writes the databases.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AofRewrite {
+    /// The image of the database, statements included, split in chunks
+    /// of `RESTORE.CHUNK`.
+    Image,
+    /// One `EXEC.NOW` for each statement of the SQL dump of the database.
+    Sql,
+}
+
+/// The checksum of the image, the 64 bit FNV-1a hash of its bytes.
+pub fn checksum(image: &[u8]) -> i64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(image);
+    hasher.finish() as i64
+}
+
+/// The chunks of `image`, at least one even if the image is empty.
+pub fn chunks(image: &[u8]) -> Vec<&[u8]> {
+    if image.is_empty() {
+        return vec![image];
+    }
+    image.chunks(AOF_CHUNK_SIZE).collect()
+}
+
+struct PendingImage {
+    total: i64,
+    checksum: i64,
+    received: i64,
+    image: Vec<u8>,
+}
+
+/// The images that are being replayed from the AOF, by key.
+///
+/// An image leaves the map once complete or at the first error, the
+/// images left incomplete are discarded when the loading of the AOF ends.
+static PENDING_IMAGES: Mutex<
+    Option<FnvHashMap<String, PendingImage>>,
+> = Mutex::new(None);
+
+/// Add a chunk to the image of the key, the whole image, already checked
+/// against its checksum, once the last chunk is added.
+///
+/// The chunks must come in order, a chunk out of order discards the
+/// image received so far.
+pub fn add_chunk(
+    key: &str,
+    index: i64,
+    total: i64,
+    checksum: i64,
+    chunk: &[u8],
+) -> Result<Option<Vec<u8>>, RediSQLError> {
+    let mut pending = PENDING_IMAGES.lock().unwrap();
+    let pending = pending.get_or_insert_with(FnvHashMap::default);
+    if chunk.len() > AOF_CHUNK_SIZE {
+        pending.remove(key);
+        return Err(chunk_out_of_order(format!(
+            "The chunk {} of `{}` is larger than {} bytes",
+            index, key, AOF_CHUNK_SIZE
+        )));
+    }
+    if index == 0 {
+        pending.insert(
+            key.to_string(),
+            PendingImage {
+                total,
+                checksum,
+                received: 0,
+                image: Vec::new(),
+            },
+        );
+    }
+    let mut image = match pending.remove(key) {
+        Some(image) => image,
+        None => {
+            return Err(chunk_out_of_order(format!(
+                "The chunk {} of `{}` arrived before the first one",
+                index, key
+            )))
+        }
+    };
+    if image.received != index
+        || image.total != total
+        || image.checksum != checksum
+    {
+        return Err(chunk_out_of_order(format!(
+            "Expected the chunk {} of {} of `{}`, got the chunk {} of {}",
+            image.received, image.total, key, index, total
+        )));
+    }
+    image.image.extend_from_slice(chunk);
+    image.received += 1;
+    if image.received < image.total {
+        pending.insert(key.to_string(), image);
+        return Ok(None);
+    }
+    if self::checksum(&image.image) != image.checksum {
+        return Err(RediSQLError::with_code(
+            69,
+            format!(
+                "The checksum of the image of `{}` does not match",
+                key
+            ),
+            "Corrupted RESTORE image".to_string(),
+        ));
+    }
+    Ok(Some(image.image))
+}
+
+/// Discard the chunks of the image of the key received so far.
+pub fn discard_image(key: &str) {
+    if let Some(pending) = PENDING_IMAGES.lock().unwrap().as_mut() {
+        pending.remove(key);
+    }
+}
+
+/// Discard the images left incomplete once Redis ends loading the AOF,
+/// their last chunks will never come.
+/// Server events are available only since Redis 6, on older versions
+/// the images are discarded only when their key is restored again.
+pub fn subscribe_to_loading(ctx: &Context) {
+    let event = ffi::RedisModuleEvent {
+        id: ffi::REDISMODULE_EVENT_LOADING as u64,
+        dataver: 1,
+    };
+    match unsafe { ffi::RedisModule_SubscribeToServerEvent } {
+        Some(subscribe) => unsafe {
+            subscribe(ctx.as_ptr(), event, Some(on_loading));
+        },
+        None => debug!("Server events not supported by this Redis"),
+    }
+}
+
+extern "C" fn on_loading(
+    _ctx: *mut ffi::RedisModuleCtx,
+    _eid: ffi::RedisModuleEvent,
+    subevent: u64,
+    _data: *mut c_void,
+) {
+    if subevent != ffi::REDISMODULE_SUBEVENT_LOADING_ENDED as u64
+        && subevent != ffi::REDISMODULE_SUBEVENT_LOADING_FAILED as u64
+    {
+        return;
+    }
+    if let Some(pending) = PENDING_IMAGES.lock().unwrap().take() {
+        for (key, image) in pending {
+            warn!(
+                "The image of `{}` is incomplete, {} chunks of {} received, it is discarded",
+                key, image.received, image.total
+            );
+        }
+    }
+}
+
+fn chunk_out_of_order(debug: String) -> RediSQLError {
+    RediSQLError::with_code(
+        68,
+        debug,
+        "RESTORE.CHUNK out of order".to_string(),
+    )
+}
@@ -0,0 +1,453 @@
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::config::config;
+use crate::redis_type::{ffi, BlockedClient, Context, RMString};
+use crate::redisql_error::RediSQLError;
+use crate::sqlite as sql;
+use crate::sqlite::{
+    Backup, Connection, Entity, QueryResult, SQLiteConnection,
+};
+
+/// How many pages each step of a backup copies, between two steps the
+/// database executes the commands it received.
+pub const DEFAULT_PAGES_PER_STEP: i32 = 100;
+
+/// Which way the pages are copied.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BackupDirection {
+    /// `BACKUP`, from the database to the file.
+    ToFile,
+    /// `LOAD`, from the file to the database, replacing its content.
+    FromFile,
+}
+
+impl BackupDirection {
+    fn operation(self) -> &'static str {
+        match self {
+            BackupDirection::ToFile => "BACKUP",
+            BackupDirection::FromFile => "LOAD",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Status {
+    Running,
+    Done,
+    Failed(String),
+}
+
+/// The progress of the last backup or load of a database.
+#[derive(Debug, Clone)]
+struct Progress {
+    direction: BackupDirection,
+    path: String,
+    status: Status,
+    remaining: i32,
+    total: i32,
+    started: Instant,
+}
+
+/// The progress of the last backup or load, shared between the thread of
+/// the database, which updates it, and the main thread that reports it.
+///
+/// The main thread marks the database as loading as soon as it receives
+/// a LOAD, so that no other command runs, is sent to the readers or is
+/// replicated until the thread of the database is done with it.
+#[derive(Clone, Default)]
+pub struct BackupState {
+    progress: Arc<Mutex<Option<Progress>>>,
+    loading: Arc<AtomicBool>,
+}
+
+impl BackupState {
+    pub fn new() -> Self {
+        BackupState::default()
+    }
+    pub fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::SeqCst)
+    }
+    /// Reject the commands on the database until the LOAD is over.
+    pub fn start_loading(&self) {
+        self.loading.store(true, Ordering::SeqCst);
+    }
+    fn stop_loading(&self) {
+        self.loading.store(false, Ordering::SeqCst);
+    }
+    fn update<F: FnOnce(&mut Progress)>(&self, f: F) {
+        if let Some(progress) = self.progress.lock().unwrap().as_mut()
+        {
+            f(progress)
+        }
+    }
+    /// The reply of `BACKUP.STATUS`, a row with the operation, the file,
+    /// its status and the pages copied, no rows if the database was
+    /// never backed up nor loaded.
+    pub fn status(&self) -> QueryResult {
+        let names = vec![
+            "operation",
+            "path",
+            "status",
+            "remaining",
+            "total",
+            "elapsed_ms",
+            "error",
+        ];
+        let mut array = Vec::new();
+        if let Some(progress) = self.progress.lock().unwrap().as_ref()
+        {
+            let (status, error) = match &progress.status {
+                Status::Running => ("running", Entity::Null),
+                Status::Done => ("done", Entity::Null),
+                Status::Failed(e) => {
+                    ("failed", Entity::Text { text: e.clone() })
+                }
+            };
+            array = vec![
+                Entity::Text {
+                    text: progress.direction.operation().to_string(),
+                },
+                Entity::Text {
+                    text: progress.path.clone(),
+                },
+                Entity::Text {
+                    text: status.to_string(),
+                },
+                Entity::Integer {
+                    int: progress.remaining.into(),
+                },
+                Entity::Integer {
+                    int: progress.total.into(),
+                },
+                Entity::Integer {
+                    int: progress.started.elapsed().as_millis()
+                        as i64,
+                },
+                error,
+            ];
+        }
+        QueryResult::Array {
+            names: names.into_iter().map(String::from).collect(),
+            types: vec![
+                "TEXT", "TEXT", "TEXT", "INT", "INT", "INT", "TEXT",
+            ],
+            array,
+        }
+    }
+}
+
+/// A setting of the metadata table, with its value.
+pub type Metadata = (&'static str, &'static str, Option<String>);
+
+/// A backup, or a load, that copies a few pages at each step.
+pub struct BackupJob {
+    backup: Backup,
+    // the connection to the file must outlive the backup
+    file: Connection,
+    direction: BackupDirection,
+    pages: i32,
+    metadata: Vec<Metadata>,
+}
+
+impl BackupJob {
+    /// Start copying the database to the file, or the image of the file
+    /// to the database.
+    pub fn start(
+        db: &Connection,
+        direction: BackupDirection,
+        path: &str,
+        image: Option<&[u8]>,
+        pages: i32,
+    ) -> Result<BackupJob, RediSQLError> {
+        let file = match image {
+            Some(image) => open_image(path, image)?,
+            None => {
+                Connection::open_connection(path).map_err(|e| {
+                    backup_error(
+                        format!(
+                            "Impossible to open `{}`: {}",
+                            path, e
+                        ),
+                        "Error in opening the file of the backup",
+                    )
+                })?
+            }
+        };
+        let backup = match direction {
+            BackupDirection::ToFile => sql::create_backup(db, &file),
+            BackupDirection::FromFile => {
+                sql::create_backup(&file, db)
+            }
+        }?;
+        Ok(BackupJob {
+            backup,
+            file,
+            direction,
+            pages,
+            metadata: Vec::new(),
+        })
+    }
+    /// Keep a setting of the database, the load overwrites the metadata
+    /// table with the one of the file.
+    pub fn keep_metadata(&mut self, metadata: Metadata) {
+        self.metadata.push(metadata);
+    }
+    /// Finish the backup, the error of the backup if it failed.
+    fn finish(self, db: &Connection) -> Result<(), RediSQLError> {
+        let result = unsafe { sql::BackupFinish(&self.backup) };
+        if result == sql::ffi::SQLITE_OK {
+            return Ok(());
+        }
+        let destination = match self.direction {
+            BackupDirection::ToFile => &self.file,
+            BackupDirection::FromFile => db,
+        };
+        Err(destination.get_last_error().into())
+    }
+}
+
+struct Running {
+    job: BackupJob,
+    client: BlockedClient,
+}
+
+/// A backup that is over, successfully or not.
+pub struct Finished {
+    pub client: BlockedClient,
+    pub direction: BackupDirection,
+    /// The settings kept by the job.
+    pub metadata: Vec<Metadata>,
+    /// The number of pages copied, or the error.
+    pub result: Result<QueryResult, RediSQLError>,
+}
+
+/// The backup running on a database, at most one at the time.
+pub struct Backups {
+    running: Option<Running>,
+    state: BackupState,
+}
+
+impl Backups {
+    pub fn new(state: BackupState) -> Self {
+        Backups {
+            running: None,
+            state,
+        }
+    }
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+    /// While the database is loaded from a file it cannot run other
+    /// commands.
+    pub fn is_loading(&self) -> bool {
+        match &self.running {
+            Some(running) => {
+                running.job.direction == BackupDirection::FromFile
+            }
+            None => false,
+        }
+    }
+    /// The backup or load is over, or it was refused: after a load the
+    /// database accepts the other commands again.
+    pub fn done(&self, direction: BackupDirection) {
+        if direction == BackupDirection::FromFile {
+            self.state.stop_loading();
+        }
+    }
+    /// Run the job, the client is answered once all the pages are
+    /// copied. Only one job runs at the time, check `is_running` first.
+    pub fn run(
+        &mut self,
+        job: BackupJob,
+        path: &str,
+        client: BlockedClient,
+    ) {
+        *self.state.progress.lock().unwrap() = Some(Progress {
+            direction: job.direction,
+            path: path.to_string(),
+            status: Status::Running,
+            remaining: 0,
+            total: 0,
+            started: Instant::now(),
+        });
+        self.running = Some(Running { job, client });
+    }
+    /// Copy the next pages of the running backup, until it is over.
+    pub fn step(&mut self, db: &Connection) -> Option<Finished> {
+        let result = {
+            let running = self.running.as_ref()?;
+            let result = unsafe {
+                sql::BackupStep(
+                    &running.job.backup,
+                    running.job.pages,
+                )
+            };
+            let (remaining, total) =
+                sql::backup_progress(&running.job.backup);
+            self.state.update(|p| {
+                p.remaining = remaining;
+                p.total = total;
+            });
+            if sql::backup_should_step_again(result) {
+                return None;
+            }
+            result
+        };
+        let Running { mut job, client } = self.running.take()?;
+        let direction = job.direction;
+        let metadata = std::mem::take(&mut job.metadata);
+        let total = self
+            .state
+            .progress
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |p| p.total);
+        let finished = job.finish(db).and_then(|_| {
+            if sql::backup_complete_with_done(result) {
+                Ok(())
+            } else {
+                Err(backup_error(
+                    format!(
+                        "The backup stopped with code {}",
+                        result
+                    ),
+                    "Error in copying the pages of the backup",
+                ))
+            }
+        });
+        let status = match &finished {
+            Ok(()) => Status::Done,
+            Err(e) => Status::Failed(e.to_string()),
+        };
+        self.state.update(|p| p.status = status);
+        self.done(direction);
+        let result = finished.map(|_| QueryResult::Array {
+            names: vec!["pages".to_string()],
+            types: vec!["INT"],
+            array: vec![Entity::Integer { int: total.into() }],
+        });
+        Some(Finished {
+            client,
+            direction,
+            metadata,
+            result,
+        })
+    }
+    /// Stop the running backup, if any, the client that started it is
+    /// returned to be answered.
+    pub fn abort(
+        &mut self,
+        db: &Connection,
+    ) -> Option<BlockedClient> {
+        let Running { job, client } = self.running.take()?;
+        self.done(job.direction);
+        let _ = job.finish(db);
+        self.state.update(|p| {
+            p.status =
+                Status::Failed("The database stopped".to_string())
+        });
+        Some(client)
+    }
+}
+
+pub fn backup_in_progress() -> RediSQLError {
+    RediSQLError::with_code(
+        66,
+        "A BACKUP or a LOAD of the database is in progress, check it with BACKUP.STATUS".to_string(),
+        "Backup in progress".to_string(),
+    )
+}
+
+/// Check, before reading it, that the file to LOAD is not larger than
+/// MAX_LOAD_SIZE.
+pub fn check_image_size(path: &Path) -> Result<(), RediSQLError> {
+    let size = std::fs::metadata(path)
+        .map_err(|e| {
+            backup_error(
+                format!(
+                    "Impossible to read `{}`: {}",
+                    path.display(),
+                    e
+                ),
+                "File to LOAD not found",
+            )
+        })?
+        .len();
+    let max = config().max_load_size;
+    if size > max {
+        return Err(RediSQLError::with_code(
+            80,
+            format!(
+                "The file `{}` has {} bytes, MAX_LOAD_SIZE is {}",
+                path.display(),
+                size,
+                max
+            ),
+            "File to LOAD too large".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The content of the file to LOAD, it is read by the thread of the
+/// database and replicated with the command.
+pub fn read_image(path: &Path) -> Result<Vec<u8>, RediSQLError> {
+    std::fs::read(path).map_err(|e| {
+        backup_error(
+            format!("Impossible to read `{}`: {}", path.display(), e),
+            "File to LOAD not found",
+        )
+    })
+}
+
+/// An in-memory database with the content of the file to LOAD.
+fn open_image(
+    path: &str,
+    image: &[u8],
+) -> Result<Connection, RediSQLError> {
+    let file = Connection::open_connection(":memory:")?;
+    sql::deserialize(&file, image).map_err(|e| {
+        backup_error(
+            format!(
+                "The file `{}` is not a SQLite database: {}",
+                path, e
+            ),
+            "Error in opening the file of the backup",
+        )
+    })?;
+    Ok(file)
+}
+
+/// Replicate the load with the content of the file inline, so that
+/// replicas do not need to read the file. `args` are the arguments of
+/// the command before `PAYLOAD`.
+pub fn replicate_load(ctx: &Context, args: &[String], image: &[u8]) {
+    let lock = ctx.lock();
+    let mut strings: Vec<RMString> =
+        args.iter().map(|arg| RMString::new(ctx, arg)).collect();
+    strings.push(RMString::new(ctx, "PAYLOAD"));
+    strings.push(RMString::from_bytes(ctx, image));
+    let mut ptrs: Vec<*mut ffi::RedisModuleString> =
+        strings.iter().map(|s| s.as_ptr()).collect();
+    let command = CString::new("REDISQL.LOAD").unwrap();
+    let format = CString::new("v").unwrap();
+    unsafe {
+        ffi::RedisModule_Replicate.unwrap()(
+            ctx.as_ptr(),
+            command.as_ptr(),
+            format.as_ptr(),
+            ptrs.as_mut_ptr(),
+            ptrs.len(),
+        );
+    }
+    ctx.release(lock);
+}
+
+fn backup_error(debug: String, description: &str) -> RediSQLError {
+    RediSQLError::with_code(67, debug, description.to_string())
+}
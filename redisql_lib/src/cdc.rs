@@ -0,0 +1,369 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::community_statement::MultiStatement;
+use crate::locks::lock_connection;
+use crate::redis::add_entity_to_xadd;
+use crate::redis_type::{is_replica_or_loading, CallReply};
+use crate::redis_type::{Context, XADDCommand};
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::ffi;
+use crate::sqlite::{
+    Connection, Entity, QueryResult, SQLiteConnection, StatementTrait,
+};
+
+/// A row written to the database.
+struct Change {
+    op: &'static str,
+    table: String,
+    rowid: i64,
+    /// The new values of the row, or the old ones if it was deleted.
+    values: Vec<Entity>,
+}
+
+/// What a statement does to a savepoint, the name as it is written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Savepoint {
+    Begin(String),
+    Release(String),
+    RollbackTo(String),
+}
+
+#[derive(Default)]
+struct Changes {
+    /// Written by the transaction in progress.
+    pending: Vec<Change>,
+    /// The savepoints open in the transaction, each one with the number
+    /// of changes pending when it was created.
+    savepoints: Vec<(String, usize)>,
+    /// Passed to the commit hook, kept until the COMMIT succeeds.
+    committing: Vec<Change>,
+    /// Committed and not yet published.
+    committed: Vec<Change>,
+}
+
+impl Changes {
+    fn savepoint(&self, name: &str) -> Option<usize> {
+        self.savepoints
+            .iter()
+            .rposition(|(open, _)| open.eq_ignore_ascii_case(name))
+    }
+    /// Drop the changes of a statement that failed, SQLite rolled it
+    /// back, or rolled back the whole transaction.
+    fn statement_failed(&mut self, mark: usize, autocommit: bool) {
+        self.pending.truncate(mark);
+        let committing = std::mem::take(&mut self.committing);
+        if !autocommit {
+            // a COMMIT that fails leaves the transaction open
+            let pending =
+                std::mem::replace(&mut self.pending, committing);
+            self.pending.extend(pending);
+        }
+    }
+    fn statement_done(&mut self, mark: usize, savepoint: &Savepoint) {
+        match savepoint {
+            Savepoint::Begin(name) => {
+                self.savepoints.push((name.clone(), mark))
+            }
+            Savepoint::Release(name) => {
+                if let Some(i) = self.savepoint(name) {
+                    self.savepoints.truncate(i);
+                }
+            }
+            Savepoint::RollbackTo(name) => {
+                if let Some(i) = self.savepoint(name) {
+                    self.pending.truncate(self.savepoints[i].1);
+                    self.savepoints.truncate(i + 1);
+                }
+            }
+        }
+    }
+}
+
+/// Capture the rows written to the database and publish them to a stream
+/// once they are committed.
+///
+/// Each change is added to the stream as an entry with the fields `op`
+/// (insert, update or delete), `table`, `rowid` and the values of the
+/// columns, in the same format used by `QUERY.INTO`.
+///
+/// The changes are collected by the SQLite preupdate hook. SQLite does
+/// not notify the hooks when it rolls back a single statement or a
+/// savepoint, so the statements are tracked as they run: the changes of
+/// a statement that fails, and the ones after a savepoint that is rolled
+/// back to, are dropped. The changes are published only after the COMMIT
+/// succeeds.
+pub struct ChangeCapture {
+    stream: String,
+    changes: Mutex<Changes>,
+}
+
+/// The captures of the connections, by their SQLite handle.
+static CAPTURES: Mutex<Vec<(usize, Weak<ChangeCapture>)>> =
+    Mutex::new(Vec::new());
+
+fn capture_of(db: *mut ffi::sqlite3) -> Option<Arc<ChangeCapture>> {
+    let captures = CAPTURES.lock().unwrap();
+    captures
+        .iter()
+        .find(|(handle, _)| *handle == db as usize)
+        .and_then(|(_, capture)| capture.upgrade())
+}
+
+thread_local! {
+    /// Set by the authorizer when the statement being prepared on this
+    /// thread creates, releases or rolls back to a savepoint.
+    static SAVEPOINT: RefCell<Option<Savepoint>> = RefCell::new(None);
+}
+
+/// Run `prepare`, and tell what the statement it prepares does to a
+/// savepoint.
+pub fn track_savepoint<T, F: FnOnce() -> T>(
+    prepare: F,
+) -> (T, Option<Savepoint>) {
+    SAVEPOINT.with(|savepoint| savepoint.replace(None));
+    let prepared = prepare();
+    (
+        prepared,
+        SAVEPOINT.with(|savepoint| savepoint.replace(None)),
+    )
+}
+
+/// Record the savepoint authorized while a statement is prepared.
+pub fn authorize_savepoint(operation: &str, name: &str) {
+    let savepoint = match operation {
+        "BEGIN" => Savepoint::Begin(name.to_string()),
+        "RELEASE" => Savepoint::Release(name.to_string()),
+        "ROLLBACK" => Savepoint::RollbackTo(name.to_string()),
+        _ => return,
+    };
+    SAVEPOINT.with(|s| s.replace(Some(savepoint)));
+}
+
+/// Run a step of a statement on the connection, keeping the captured
+/// changes in line with what SQLite rolls back.
+pub fn track_statement<F: FnOnce() -> c_int>(
+    db: *mut ffi::sqlite3,
+    savepoint: Option<&Savepoint>,
+    step: F,
+) -> c_int {
+    let capture = match capture_of(db) {
+        Some(capture) => capture,
+        None => return step(),
+    };
+    let mark = capture.changes.lock().unwrap().pending.len();
+    let rc = step();
+    let autocommit = unsafe { ffi::sqlite3_get_autocommit(db) } != 0;
+    let mut changes = capture.changes.lock().unwrap();
+    match (rc, savepoint) {
+        (ffi::SQLITE_ROW, Some(savepoint))
+        | (ffi::SQLITE_DONE, Some(savepoint))
+        | (ffi::SQLITE_OK, Some(savepoint)) => {
+            changes.statement_done(mark, savepoint)
+        }
+        (ffi::SQLITE_ROW, None)
+        | (ffi::SQLITE_DONE, None)
+        | (ffi::SQLITE_OK, None) => {}
+        _ => changes.statement_failed(mark, autocommit),
+    }
+    rc
+}
+
+impl ChangeCapture {
+    /// Start capturing the changes made through the connection.
+    /// The capture must be kept alive as long as the connection.
+    pub fn register(
+        db: &Arc<Mutex<Connection>>,
+        stream: &str,
+    ) -> Arc<ChangeCapture> {
+        let capture = Arc::new(ChangeCapture {
+            stream: stream.to_string(),
+            changes: Mutex::new(Changes::default()),
+        });
+        let conn = lock_connection(&db).get_db();
+        {
+            let mut captures = CAPTURES.lock().unwrap();
+            captures.retain(|(handle, capture)| {
+                *handle != conn as usize && capture.strong_count() > 0
+            });
+            captures.push((conn as usize, Arc::downgrade(&capture)));
+        }
+        let arg = Arc::as_ptr(&capture) as *mut c_void;
+        unsafe {
+            ffi::sqlite3_preupdate_hook(
+                conn,
+                Some(on_preupdate),
+                arg,
+            );
+            ffi::sqlite3_commit_hook(conn, Some(on_commit), arg);
+            ffi::sqlite3_rollback_hook(conn, Some(on_rollback), arg);
+        }
+        capture
+    }
+    /// Add to the stream the changes committed since the last call.
+    /// Replicas and the AOF already contain the entries added by the
+    /// master, so in those cases the changes are dropped.
+    pub fn publish(
+        &self,
+        ctx: &Context,
+        db: &Arc<Mutex<Connection>>,
+    ) -> Result<(), RediSQLError> {
+        let autocommit = {
+            let conn = lock_connection(&db);
+            unsafe { ffi::sqlite3_get_autocommit(conn.get_db()) != 0 }
+        };
+        let mut tables = {
+            let mut changes = self.changes.lock().unwrap();
+            if autocommit {
+                // the COMMIT is over, or the statement that committed
+                let committing =
+                    std::mem::take(&mut changes.committing);
+                changes.committed.extend(committing);
+            }
+            if is_replica_or_loading(ctx) {
+                changes.committed.clear();
+            }
+            changes
+                .committed
+                .iter()
+                .map(|change| change.table.clone())
+                .collect::<Vec<_>>()
+        };
+        if tables.is_empty() {
+            return Ok(());
+        }
+        tables.sort();
+        tables.dedup();
+        // the changes stay buffered if the columns cannot be read
+        let mut columns: HashMap<String, Vec<String>> =
+            HashMap::new();
+        for table in tables {
+            let names = table_columns(db, &table)?;
+            columns.insert(table, names);
+        }
+        let committed = {
+            let mut changes = self.changes.lock().unwrap();
+            std::mem::take(&mut changes.committed)
+        };
+        let no_names = Vec::new();
+        let lock = ctx.lock();
+        for change in &committed {
+            let names =
+                columns.get(&change.table).unwrap_or(&no_names);
+            let mut xadd = XADDCommand::new(ctx, &self.stream);
+            xadd.add_element("op", change.op);
+            xadd.add_element("table", &change.table);
+            xadd.add_element("rowid", &change.rowid.to_string());
+            for (i, value) in change.values.iter().enumerate() {
+                match names.get(i) {
+                    Some(name) => {
+                        add_entity_to_xadd(&mut xadd, name, value)
+                    }
+                    None => add_entity_to_xadd(
+                        &mut xadd,
+                        &i.to_string(),
+                        value,
+                    ),
+                }
+            }
+            if let CallReply::RError { .. } = xadd.execute(&lock) {
+                ctx.release(lock);
+                return Err(RediSQLError::new(
+                    format!("Error in XADD to {}", self.stream),
+                    "Impossible to publish the changes of the database to the stream".to_string(),
+                ));
+            }
+        }
+        ctx.release(lock);
+        Ok(())
+    }
+}
+
+fn table_columns(
+    db: &Arc<Mutex<Connection>>,
+    table: &str,
+) -> Result<Vec<String>, RediSQLError> {
+    let stmt = MultiStatement::new(
+        db.clone(),
+        "SELECT name FROM pragma_table_info(?1);",
+    )?;
+    stmt.bind_index(1, table)?;
+    let names = match QueryResult::try_from(stmt.execute()?)? {
+        QueryResult::Array { array, .. } => array
+            .into_iter()
+            .filter_map(|name| match name {
+                Entity::Text { text } => Some(text),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    Ok(names)
+}
+
+extern "C" fn on_preupdate(
+    capture: *mut c_void,
+    db: *mut ffi::sqlite3,
+    op: c_int,
+    database: *const c_char,
+    table: *const c_char,
+    old_rowid: ffi::sqlite3_int64,
+    new_rowid: ffi::sqlite3_int64,
+) {
+    let capture = unsafe { &*(capture as *const ChangeCapture) };
+    let database = unsafe { CStr::from_ptr(database) };
+    let table = unsafe { CStr::from_ptr(table) }.to_string_lossy();
+    if database.to_bytes() != b"main" || table == "RediSQLMetadata" {
+        return;
+    }
+    let (op, rowid) = match op {
+        ffi::SQLITE_INSERT => ("insert", new_rowid),
+        ffi::SQLITE_UPDATE => ("update", new_rowid),
+        ffi::SQLITE_DELETE => ("delete", old_rowid),
+        _ => return,
+    };
+    let count = unsafe { ffi::sqlite3_preupdate_count(db) };
+    let mut values = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        let mut value = std::ptr::null_mut();
+        let rc = unsafe {
+            match op {
+                "delete" => {
+                    ffi::sqlite3_preupdate_old(db, i, &mut value)
+                }
+                _ => ffi::sqlite3_preupdate_new(db, i, &mut value),
+            }
+        };
+        values.push(match rc {
+            ffi::SQLITE_OK => Entity::from_value(value),
+            _ => Entity::Null,
+        });
+    }
+    capture.changes.lock().unwrap().pending.push(Change {
+        op,
+        table: table.into_owned(),
+        rowid,
+        values,
+    });
+}
+
+extern "C" fn on_commit(capture: *mut c_void) -> c_int {
+    let capture = unsafe { &*(capture as *const ChangeCapture) };
+    let mut changes = capture.changes.lock().unwrap();
+    let pending = std::mem::take(&mut changes.pending);
+    changes.committing.extend(pending);
+    changes.savepoints.clear();
+    0
+}
+
+extern "C" fn on_rollback(capture: *mut c_void) {
+    let capture = unsafe { &*(capture as *const ChangeCapture) };
+    let mut changes = capture.changes.lock().unwrap();
+    changes.pending.clear();
+    changes.committing.clear();
+    changes.savepoints.clear();
+}
@@ -1,22 +1,25 @@
+use crate::cdc::{self, Savepoint};
+use crate::locks::lock_connection;
+use crate::redis_functions;
 use crate::sqlite::ffi;
 use crate::sqlite::SQLiteConnection;
 use crate::sqlite::StatementTrait;
 use crate::sqlite::{
-    get_last_error_from_db_connection, Connection, Cursor,
+    get_last_error_from_db_connection, Argument, Connection, Cursor,
     SQLite3Error, SQLiteOK,
 };
 
 use std::ffi::{CStr, CString};
 use std::fmt;
 use std::mem;
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_void};
 use std::ptr;
 use std::sync::{Arc, Mutex};
 
 #[derive(Clone, Debug)]
 enum Parameters {
-    Anonymous,
-    Named { index: i32 },
+    Numbered { index: i32 },
+    Named { name: String },
 }
 
 #[derive(Clone)]
@@ -24,11 +27,20 @@ pub struct MultiStatement {
     stmts: Vec<Statement>,
     db: Arc<Mutex<Connection>>,
     number_parameters: i32,
-    _parameters: Vec<Vec<Parameters>>,
+    parameters_names: Vec<String>,
 }
 
 unsafe impl Send for MultiStatement {}
 
+impl MultiStatement {
+    fn has_named_parameters(&self) -> bool {
+        match self.parameters_names.first() {
+            Some(name) => !name.starts_with('?'),
+            None => false,
+        }
+    }
+}
+
 impl<'a> fmt::Display for MultiStatement {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut buffer = String::new();
@@ -45,6 +57,11 @@ impl<'a> fmt::Display for MultiStatement {
 #[derive(Clone)]
 pub struct Statement {
     stmt: Arc<InternalStatement>,
+    /// It calls a function that writes to Redis, so it is not read only
+    /// even if it does not modify the database.
+    writes_redis: bool,
+    /// What it does to a savepoint, followed by the change capture.
+    savepoint: Option<Savepoint>,
 }
 
 struct InternalStatement {
@@ -85,35 +102,42 @@ pub fn generate_statements(
     let mut stmts = Vec::new();
 
     let arc_db = db.clone();
-    let conn = arc_db.lock().unwrap();
+    let conn = lock_connection(&arc_db);
     loop {
         let mut stmt = std::mem::MaybeUninit::uninit();
 
-        let r = unsafe {
-            ffi::sqlite3_prepare_v2(
-                conn.get_db(),
-                next_query,
-                -1,
-                stmt.as_mut_ptr(),
-                &mut next_query,
-            )
-        };
+        let ((r, writes_redis), savepoint) =
+            cdc::track_savepoint(|| {
+                redis_functions::track_writes(|| unsafe {
+                    ffi::sqlite3_prepare_v2(
+                        conn.get_db(),
+                        next_query,
+                        -1,
+                        stmt.as_mut_ptr(),
+                        &mut next_query,
+                    )
+                })
+            });
 
         match r {
             ffi::SQLITE_OK => {
                 let stmt = unsafe { stmt.assume_init() };
                 if !stmt.is_null() {
-                    let stmt = Statement::from_ptr(stmt);
+                    let stmt = Statement::from_ptr(
+                        stmt,
+                        writes_redis,
+                        savepoint,
+                    );
                     stmts.push(stmt);
                 }
                 if unsafe { *next_query } == 0 {
-                    let (num_parameters, parameters) =
+                    let (num_parameters, parameters_names) =
                         count_parameters(&stmts)?;
                     return Ok(MultiStatement {
                         stmts,
                         db,
                         number_parameters: num_parameters,
-                        _parameters: parameters,
+                        parameters_names,
                     });
                 };
             }
@@ -123,18 +147,29 @@ pub fn generate_statements(
 }
 
 impl Statement {
-    fn from_ptr(stmt: *mut ffi::sqlite3_stmt) -> Self {
+    fn from_ptr(
+        stmt: *mut ffi::sqlite3_stmt,
+        writes_redis: bool,
+        savepoint: Option<Savepoint>,
+    ) -> Self {
         Statement {
             stmt: Arc::new(InternalStatement {
                 stmt: ptr::NonNull::new(stmt).unwrap(),
             }),
+            writes_redis,
+            savepoint,
         }
     }
     fn execute(
         &self,
         db: &Connection,
     ) -> Result<Cursor, SQLite3Error> {
-        match unsafe { ffi::sqlite3_step(self.as_ptr()) } {
+        let rc = cdc::track_statement(
+            db.get_db(),
+            self.savepoint.as_ref(),
+            || unsafe { ffi::sqlite3_step(self.as_ptr()) },
+        );
+        match rc {
             ffi::SQLITE_OK => Ok(Cursor::OKCursor {}),
             ffi::SQLITE_DONE => {
                 let modified_rows =
@@ -162,6 +197,37 @@ impl Statement {
     pub fn as_ptr(&self) -> *mut ffi::sqlite3_stmt {
         self.stmt.stmt.as_ptr()
     }
+    /// Look up the index of a named parameter, the name can be provided
+    /// with or without its prefix (`:`, `@` or `$`).
+    /// It returns 0 if the parameter is not used by the statement.
+    fn parameter_index(&self, name: &str) -> i32 {
+        let candidates = match name.chars().next() {
+            Some(':') | Some('@') | Some('$') | Some('?') => {
+                vec![name.to_string()]
+            }
+            _ => vec![
+                format!(":{}", name),
+                format!("@{}", name),
+                format!("${}", name),
+            ],
+        };
+        for candidate in candidates {
+            let candidate = match CString::new(candidate) {
+                Ok(c) => c,
+                Err(_) => return 0,
+            };
+            let index = unsafe {
+                ffi::sqlite3_bind_parameter_index(
+                    self.as_ptr(),
+                    candidate.as_ptr(),
+                )
+            };
+            if index != 0 {
+                return index;
+            }
+        }
+        0
+    }
 }
 
 impl<'a> StatementTrait<'a> for Statement {
@@ -177,19 +243,24 @@ impl<'a> StatementTrait<'a> for Statement {
 
         let mut stmt = std::mem::MaybeUninit::uninit();
 
-        let conn = conn.lock().unwrap();
-        let r = unsafe {
-            ffi::sqlite3_prepare_v2(
-                conn.get_db(),
-                raw_query.as_ptr(),
-                -1,
-                stmt.as_mut_ptr(),
-                ptr::null_mut(),
-            )
-        };
+        let conn = lock_connection(&conn);
+        let ((r, writes_redis), savepoint) =
+            cdc::track_savepoint(|| {
+                redis_functions::track_writes(|| unsafe {
+                    ffi::sqlite3_prepare_v2(
+                        conn.get_db(),
+                        raw_query.as_ptr(),
+                        -1,
+                        stmt.as_mut_ptr(),
+                        ptr::null_mut(),
+                    )
+                })
+            });
         let stmt = unsafe { stmt.assume_init() };
         match r {
-            ffi::SQLITE_OK => Ok(Statement::from_ptr(stmt)),
+            ffi::SQLITE_OK => {
+                Ok(Statement::from_ptr(stmt, writes_redis, savepoint))
+            }
             _ => Err(conn.get_last_error()),
         }
     }
@@ -201,38 +272,61 @@ impl<'a> StatementTrait<'a> for Statement {
         }
     }
 
-    fn bind_texts(
+    fn bind_arguments(
         &self,
-        values: &[&str],
+        values: &[Argument],
     ) -> Result<SQLiteOK, SQLite3Error> {
         let mut index = 0;
         values
             .iter()
             .map(|value| {
                 index += 1;
-                self.bind_index(index, value)
+                self.bind_argument_index(index, value)
             })
             .collect()
     }
 
-    fn bind_index(
+    fn bind_argument_index(
         &self,
         index: i32,
-        value: &str,
+        value: &Argument,
     ) -> Result<SQLiteOK, SQLite3Error> {
         #[allow(non_snake_case)]
         fn SQLITE_TRANSIENT() -> ffi::sqlite3_destructor_type {
             Some(unsafe { mem::transmute(-1isize) })
         }
-        match unsafe {
-            ffi::sqlite3_bind_text(
-                self.as_ptr(),
-                index,
-                value.as_ptr() as *const c_char,
-                value.len() as i32,
-                SQLITE_TRANSIENT(),
-            )
-        } {
+        let result = unsafe {
+            match *value {
+                Argument::Integer { int } => {
+                    ffi::sqlite3_bind_int64(self.as_ptr(), index, int)
+                }
+                Argument::Float { float } => {
+                    ffi::sqlite3_bind_double(
+                        self.as_ptr(),
+                        index,
+                        float,
+                    )
+                }
+                Argument::Text { text } => ffi::sqlite3_bind_text(
+                    self.as_ptr(),
+                    index,
+                    text.as_ptr() as *const c_char,
+                    text.len() as i32,
+                    SQLITE_TRANSIENT(),
+                ),
+                Argument::Blob { blob } => ffi::sqlite3_bind_blob(
+                    self.as_ptr(),
+                    index,
+                    blob.as_ptr() as *const c_void,
+                    blob.len() as i32,
+                    SQLITE_TRANSIENT(),
+                ),
+                Argument::Null => {
+                    ffi::sqlite3_bind_null(self.as_ptr(), index)
+                }
+            }
+        };
+        match result {
             ffi::SQLITE_OK => Ok(SQLiteOK::OK),
 
             // it means that a statement requires less than $index paramenter, it is fine to just
@@ -242,9 +336,25 @@ impl<'a> StatementTrait<'a> for Statement {
         }
     }
 
+    fn bind_named_arguments(
+        &self,
+        values: &[(&str, Argument)],
+    ) -> Result<SQLiteOK, SQLite3Error> {
+        for (name, value) in values {
+            match self.parameter_index(name) {
+                // the parameter is not used by this statement
+                0 => {}
+                index => {
+                    self.bind_argument_index(index, value)?;
+                }
+            }
+        }
+        Ok(SQLiteOK::OK)
+    }
+
     fn is_read_only(&self) -> bool {
         let v = unsafe { ffi::sqlite3_stmt_readonly(self.as_ptr()) };
-        v != 0
+        v != 0 && !self.writes_redis
     }
 
     fn parameters_count(&self) -> u32 {
@@ -252,6 +362,20 @@ impl<'a> StatementTrait<'a> for Statement {
             ffi::sqlite3_bind_parameter_count(self.as_ptr()) as u32
         }
     }
+    fn parameters_names(&self) -> Vec<String> {
+        match get_parameters(self) {
+            Err(_) => vec![],
+            Ok(parameters) => parameters
+                .into_iter()
+                .map(|p| match p {
+                    Parameters::Numbered { index } => {
+                        format!("?{}", index)
+                    }
+                    Parameters::Named { name } => name,
+                })
+                .collect(),
+        }
+    }
     fn sql(&self) -> String {
         unsafe {
             CStr::from_ptr(ffi::sqlite3_sql(self.as_ptr()))
@@ -267,7 +391,7 @@ impl<'a> StatementTrait<'a> for MultiStatement {
     }
     fn execute(&self) -> Result<Cursor, SQLite3Error> {
         let db = self.db.clone();
-        let conn = db.lock().unwrap();
+        let conn = lock_connection(&db);
         debug!("Execute | Acquired db lock");
         let rows_modified_before_executing =
             unsafe { ffi::sqlite3_total_changes(conn.get_db()) };
@@ -307,19 +431,19 @@ impl<'a> StatementTrait<'a> for MultiStatement {
             }
         }
     }
-    fn bind_index(
+    fn bind_argument_index(
         &self,
         index: i32,
-        value: &str,
+        value: &Argument,
     ) -> Result<SQLiteOK, SQLite3Error> {
         for stmt in &self.stmts {
-            stmt.bind_index(index, value)?;
+            stmt.bind_argument_index(index, value)?;
         }
         Ok(SQLiteOK::OK)
     }
-    fn bind_texts(
+    fn bind_arguments(
         &self,
-        values: &[&str],
+        values: &[Argument],
     ) -> Result<SQLiteOK, SQLite3Error> {
         if values.len() != self.number_parameters as usize {
             return Err(SQLite3Error {
@@ -328,8 +452,8 @@ impl<'a> StatementTrait<'a> for MultiStatement {
                     "RediSQL MISUSE, only \
                      parameters in the form \
                      `?NNN`, where `N` is a digit, \
-                     are supported, also no gap \
-                     should be present.",
+                     or named parameters like `:AAA`, \
+                     `@AAA` and `$AAA` are supported.",
                 ),
                 error_string: format!(
                     "Wrong number of parameters, expected {}, provided {}",
@@ -339,8 +463,72 @@ impl<'a> StatementTrait<'a> for MultiStatement {
             });
         }
 
+        if self.has_named_parameters() {
+            let names = self
+                .parameters_names
+                .iter()
+                .map(String::as_str)
+                .zip(values.iter().cloned())
+                .collect::<Vec<_>>();
+            return self.bind_named_arguments(&names);
+        }
+
         for (i, value) in values.iter().enumerate() {
-            self.bind_index(i as i32 + 1, value)?;
+            self.bind_argument_index(i as i32 + 1, value)?;
+        }
+        Ok(SQLiteOK::OK)
+    }
+    fn bind_named_arguments(
+        &self,
+        values: &[(&str, Argument)],
+    ) -> Result<SQLiteOK, SQLite3Error> {
+        let mut bound = Vec::with_capacity(values.len());
+        for (name, _) in values {
+            match self
+                .parameters_names
+                .iter()
+                .find(|p| *p == name || p[1..] == **name)
+            {
+                Some(p) => bound.push(p),
+                None => {
+                    return Err(SQLite3Error {
+                        code: 2022,
+                        error_message: format!(
+                            "The statement does not use the parameter `{}`, its parameters are: {}",
+                            name,
+                            self.parameters_names.join(", ")
+                        ),
+                        error_string: String::from(
+                            "Unknown parameter name",
+                        ),
+                    });
+                }
+            }
+        }
+        bound.sort();
+        bound.dedup();
+        if bound.len() != self.number_parameters as usize {
+            let missing = self
+                .parameters_names
+                .iter()
+                .filter(|p| !bound.contains(p))
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            return Err(SQLite3Error {
+                code: 2023,
+                error_message: format!(
+                    "Missing values for the parameters: {}",
+                    missing.join(", ")
+                ),
+                error_string: format!(
+                    "Wrong number of parameters, expected {}, provided {}",
+                    self.number_parameters,
+                    bound.len()
+                ),
+            });
+        }
+        for stmt in &self.stmts {
+            stmt.bind_named_arguments(values)?;
         }
         Ok(SQLiteOK::OK)
     }
@@ -360,6 +548,9 @@ impl<'a> StatementTrait<'a> for MultiStatement {
         }
         true
     }
+    fn parameters_names(&self) -> Vec<String> {
+        self.parameters_names.clone()
+    }
     fn parameters_count(&self) -> u32 {
         self.stmts
             .iter()
@@ -380,15 +571,16 @@ impl<'a> StatementTrait<'a> for MultiStatement {
 
 fn count_parameters(
     statements: &[Statement],
-) -> Result<(i32, Vec<Vec<Parameters>>), SQLite3Error> {
+) -> Result<(i32, Vec<String>), SQLite3Error> {
     let error_wrong_paramenter = SQLite3Error {
         code: 1021,
         error_message: String::from(
             "RediSQL MISUSE, only \
              parameters in the form \
              `?NNN`, where `N` is a digit, \
-             are supported, also no gap \
-             should be present.",
+             or named parameters like `:AAA`, \
+             `@AAA` and `$AAA` are supported, \
+             the two forms cannot be mixed.",
         ),
         error_string: String::from("Use of invalid parameters"),
     };
@@ -398,42 +590,35 @@ fn count_parameters(
     match parameters {
         Err(e) => Err(e),
         Ok(parameters) => {
-            let mut discriminant: Vec<_> = parameters
-                .iter()
-                .flat_map(|params| {
-                    params.iter().map(|p| mem::discriminant(p))
-                })
-                .collect();
-            discriminant.dedup();
-            if discriminant.len() > 1 {
-                return Err(error_wrong_paramenter);
-            }
-            match discriminant.first() {
-                None => return Ok((0, vec![])),
-                Some(d)
-                    if *d
-                        == mem::discriminant(
-                            &Parameters::Anonymous,
-                        ) =>
-                {
-                    return Err(error_wrong_paramenter);
+            let mut numbered = Vec::new();
+            let mut named: Vec<String> = Vec::new();
+            for p in parameters.into_iter().flatten() {
+                match p {
+                    Parameters::Numbered { index } => {
+                        numbered.push(index)
+                    }
+                    // named parameters are kept in order of appearance
+                    Parameters::Named { name } => {
+                        if !named.contains(&name) {
+                            named.push(name);
+                        }
+                    }
                 }
-                Some(_) => {}
             }
-
-            let mut flatted = parameters
-                .iter()
-                .flat_map(|params| {
-                    params.iter().map(|p| match *p {
-                        Parameters::Anonymous => 0,
-                        Parameters::Named { index } => index,
-                    })
-                })
-                .collect::<Vec<_>>();
-            flatted.sort();
-            flatted.dedup();
-            let count = flatted.len() as i32;
-            Ok((count, parameters))
+            if !numbered.is_empty() && !named.is_empty() {
+                return Err(error_wrong_paramenter);
+            }
+            numbered.sort();
+            numbered.dedup();
+            let names = match named.is_empty() {
+                true => numbered
+                    .iter()
+                    .map(|index| format!("?{}", index))
+                    .collect::<Vec<_>>(),
+                false => named,
+            };
+            let count = names.len() as i32;
+            Ok((count, names))
         }
     }
 }
@@ -445,15 +630,16 @@ fn get_parameter_name(
     let parameter_name_ptr = unsafe {
         ffi::sqlite3_bind_parameter_name(stmt.as_ptr(), index)
     };
-    let index_parameter = unsafe {
-        ffi::sqlite3_bind_parameter_index(
-            stmt.as_ptr(),
-            parameter_name_ptr,
-        )
-    };
-    match index_parameter {
-        0 => Ok(None),
-        n => Ok(Some(Parameters::Named { index: n })),
+    if parameter_name_ptr.is_null() {
+        return Ok(None);
+    }
+    let name = unsafe { CStr::from_ptr(parameter_name_ptr) }
+        .to_string_lossy()
+        .into_owned();
+    if name.starts_with('?') {
+        Ok(Some(Parameters::Numbered { index }))
+    } else {
+        Ok(Some(Parameters::Named { name }))
     }
 }
 
@@ -0,0 +1,297 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use log::LevelFilter;
+
+use crate::aof::AofRewrite;
+use crate::redisql_error::RediSQLError;
+
+/// The configuration of the module, provided as pairs of option name and
+/// value when the module is loaded:
+///
+/// `loadmodule redisql.so LOG_LEVEL warn DEFAULT_TIMEOUT 5000`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// LOG_LEVEL: off, error, warn, info, debug or trace.
+    pub log_level: LevelFilter,
+    /// DEFAULT_TIMEOUT: milliseconds a command can run without an
+    /// explicit TIMEOUT.
+    pub default_timeout: Duration,
+    /// DATA_DIR: directory of the databases created with a relative PATH,
+    /// of the files of IMPORT, EXPORT, BACKUP and LOAD, and of the
+    /// temporary files used to save and load RDB files.
+    pub data_dir: Option<PathBuf>,
+    /// MAX_DATABASES: how many databases can be created, 0 means no limit.
+    pub max_databases: usize,
+    /// TELEMETRY: yes or no, only in the community build.
+    pub telemetry: bool,
+    /// AOF_REWRITE: image, the chunks of the image of the database, or
+    /// sql, the statements of its SQL dump.
+    pub aof_rewrite: AofRewrite,
+    /// EXTENSIONS_DIR: directory of the SQLite extensions that can be
+    /// loaded with `REDISQL.EXTENSION`, no extension can be loaded
+    /// without it.
+    pub extensions_dir: Option<PathBuf>,
+    /// CURSOR_IDLE_TIMEOUT: milliseconds a cursor can stay open without
+    /// being fetched before it is closed.
+    pub cursor_idle_timeout: Duration,
+    /// MAX_OPEN_CURSORS: how many cursors can be open at the same time on
+    /// a database.
+    pub max_open_cursors: usize,
+    /// MAX_LOAD_SIZE: the size in bytes of the largest file LOAD accepts,
+    /// its whole content is replicated and written in the AOF.
+    pub max_load_size: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_level: LevelFilter::Debug,
+            default_timeout: Duration::from_secs(10),
+            data_dir: None,
+            max_databases: 0,
+            telemetry: true,
+            aof_rewrite: AofRewrite::Image,
+            extensions_dir: None,
+            cursor_idle_timeout: Duration::from_secs(60),
+            max_open_cursors: 32,
+            max_load_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+static CONFIG: RwLock<Option<Config>> = RwLock::new(None);
+
+impl Config {
+    /// Parse and validate the arguments passed to `loadmodule`.
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut args_iter = args.iter();
+        while let Some(option) = args_iter.next() {
+            let mut option = option.to_string();
+            option.make_ascii_uppercase();
+            let value = match args_iter.next() {
+                Some(value) => *value,
+                None => {
+                    return Err(format!(
+                        "The option {} requires a value",
+                        option
+                    ))
+                }
+            };
+            match option.as_str() {
+                "LOG_LEVEL" => {
+                    config.log_level = value.parse().map_err(|_| {
+                        format!("LOG_LEVEL must be one of off, error, warn, info, debug or trace, got `{}`", value)
+                    })?
+                }
+                "DEFAULT_TIMEOUT" => match value.parse::<u64>() {
+                    Ok(ms) if ms > 0 => {
+                        config.default_timeout = Duration::from_millis(ms)
+                    }
+                    _ => return Err(format!("DEFAULT_TIMEOUT must be a positive number of milliseconds, got `{}`", value)),
+                },
+                "DATA_DIR" => {
+                    if !Path::new(value).is_dir() {
+                        return Err(format!(
+                            "DATA_DIR `{}` is not an existing directory",
+                            value
+                        ));
+                    }
+                    config.data_dir = Some(PathBuf::from(value));
+                }
+                "MAX_DATABASES" => {
+                    config.max_databases = value.parse().map_err(|_| {
+                        format!("MAX_DATABASES must be a non negative integer, got `{}`", value)
+                    })?
+                }
+                "TELEMETRY" => {
+                    config.telemetry = match value
+                        .to_ascii_lowercase()
+                        .as_str()
+                    {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err(format!("TELEMETRY must be either yes or no, got `{}`", value)),
+                    }
+                }
+                "AOF_REWRITE" => {
+                    config.aof_rewrite = match value
+                        .to_ascii_lowercase()
+                        .as_str()
+                    {
+                        "image" => AofRewrite::Image,
+                        "sql" => AofRewrite::Sql,
+                        _ => return Err(format!("AOF_REWRITE must be either image or sql, got `{}`", value)),
+                    }
+                }
+                "EXTENSIONS_DIR" => {
+                    if !Path::new(value).is_dir() {
+                        return Err(format!(
+                            "EXTENSIONS_DIR `{}` is not an existing directory",
+                            value
+                        ));
+                    }
+                    config.extensions_dir = Some(PathBuf::from(value));
+                }
+                "CURSOR_IDLE_TIMEOUT" => match value.parse::<u64>() {
+                    Ok(ms) if ms > 0 => {
+                        config.cursor_idle_timeout =
+                            Duration::from_millis(ms)
+                    }
+                    _ => return Err(format!("CURSOR_IDLE_TIMEOUT must be a positive number of milliseconds, got `{}`", value)),
+                },
+                "MAX_OPEN_CURSORS" => match value.parse::<usize>() {
+                    Ok(max) if max > 0 => config.max_open_cursors = max,
+                    _ => return Err(format!("MAX_OPEN_CURSORS must be a positive integer, got `{}`", value)),
+                },
+                "MAX_LOAD_SIZE" => match value.parse::<u64>() {
+                    Ok(max) if max > 0 => config.max_load_size = max,
+                    _ => return Err(format!("MAX_LOAD_SIZE must be a positive number of bytes, got `{}`", value)),
+                },
+                _ => {
+                    return Err(format!(
+                        "Unknown option {}, the options supported are LOG_LEVEL, DEFAULT_TIMEOUT, DATA_DIR, MAX_DATABASES, TELEMETRY, AOF_REWRITE, EXTENSIONS_DIR, CURSOR_IDLE_TIMEOUT, MAX_OPEN_CURSORS and MAX_LOAD_SIZE",
+                        option
+                    ))
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// The options with their current values, in the same format used to
+    /// provide them.
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("LOG_LEVEL", self.log_level.to_string().to_lowercase()),
+            (
+                "DEFAULT_TIMEOUT",
+                self.default_timeout.as_millis().to_string(),
+            ),
+            (
+                "DATA_DIR",
+                match &self.data_dir {
+                    Some(dir) => dir.to_string_lossy().into_owned(),
+                    None => String::new(),
+                },
+            ),
+            ("MAX_DATABASES", self.max_databases.to_string()),
+            (
+                "TELEMETRY",
+                if self.telemetry { "yes" } else { "no" }.to_string(),
+            ),
+            (
+                "AOF_REWRITE",
+                match self.aof_rewrite {
+                    AofRewrite::Image => "image",
+                    AofRewrite::Sql => "sql",
+                }
+                .to_string(),
+            ),
+            (
+                "EXTENSIONS_DIR",
+                match &self.extensions_dir {
+                    Some(dir) => dir.to_string_lossy().into_owned(),
+                    None => String::new(),
+                },
+            ),
+            (
+                "CURSOR_IDLE_TIMEOUT",
+                self.cursor_idle_timeout.as_millis().to_string(),
+            ),
+            ("MAX_OPEN_CURSORS", self.max_open_cursors.to_string()),
+            ("MAX_LOAD_SIZE", self.max_load_size.to_string()),
+        ]
+    }
+}
+
+/// Set the configuration of the module, it is done once at load time.
+pub fn set_config(config: Config) {
+    *CONFIG.write().unwrap() = Some(config);
+}
+
+/// The configuration of the module, the default one if the module has
+/// not been configured.
+pub fn config() -> Config {
+    CONFIG.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Resolve the path of a file read or written by a command against
+/// DATA_DIR, or against the working directory of Redis without it.
+///
+/// Clients cannot reach the other files of the server: the path must be
+/// relative and cannot go up with `..`, and once the symbolic links are
+/// followed the file must still be inside the directory. The part of the
+/// path that does not exist yet, like a file to write, cannot be a link.
+pub fn resolve_file(path: &str) -> Result<PathBuf, RediSQLError> {
+    let not_allowed = |debug: String| {
+        RediSQLError::with_code(
+            78,
+            debug,
+            "File not allowed".to_string(),
+        )
+    };
+    let inside = !path.is_empty()
+        && Path::new(path).components().all(|c| {
+            matches!(c, Component::Normal(_) | Component::CurDir)
+        });
+    if !inside {
+        return Err(not_allowed(format!(
+            "The file `{}` must be a relative path inside DATA_DIR, without `..`",
+            path
+        )));
+    }
+    let dir = config().data_dir.unwrap_or_else(|| PathBuf::from("."));
+    let outside = |debug: String| {
+        not_allowed(format!(
+            "The file `{}` is not inside `{}`: {}",
+            path,
+            dir.display(),
+            debug
+        ))
+    };
+    let canonical_dir =
+        dir.canonicalize().map_err(|e| outside(e.to_string()))?;
+    // the nearest ancestor that exists, the rest is appended as it is
+    let file = dir.join(path);
+    let mut existing = file.as_path();
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+    let mut resolved = existing
+        .canonicalize()
+        .map_err(|e| outside(e.to_string()))?;
+    resolved.extend(missing.iter().rev());
+    if !resolved.starts_with(&canonical_dir) {
+        return Err(outside(format!(
+            "it resolves to `{}`",
+            resolved.display()
+        )));
+    }
+    Ok(resolved)
+}
+
+/// Resolve the path of a database file against DATA_DIR.
+/// Absolute paths and in-memory databases are left untouched.
+pub fn resolve_path(path: &str) -> String {
+    let data_dir = match config().data_dir {
+        Some(data_dir) => data_dir,
+        None => return path.to_string(),
+    };
+    if path.starts_with(":memory")
+        || path.starts_with("file:")
+        || Path::new(path).is_absolute()
+    {
+        return path.to_string();
+    }
+    data_dir.join(path).to_string_lossy().into_owned()
+}
@@ -0,0 +1,417 @@
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::community_statement::MultiStatement;
+use crate::config::resolve_file;
+use crate::ingest::{insert_sql, quote_identifier};
+use crate::redis::{
+    do_execute, do_query, is_autocommit, rollback_savepoint,
+    RedisReply, ReturnMethod, Returner,
+};
+use crate::redis_type::{Context, RMString, ReplyWithStringBuffer};
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::ffi;
+use crate::sqlite::{
+    Argument, Bindings, Connection, Cursor, Entity, QueryResult,
+    StatementTrait,
+};
+
+/// The type declared for a column of the CSV with `TYPES`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ColumnType {
+    Int,
+    Real,
+    Text,
+    Blob,
+    Null,
+}
+
+/// How the CSV is read and written.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CsvOptions {
+    /// If the first record holds the names of the columns.
+    pub header: bool,
+    pub delimiter: u8,
+    pub quote: u8,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            header: true,
+            delimiter: b',',
+            quote: b'"',
+        }
+    }
+}
+
+/// Where the CSV to import is read from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CsvSource<'a> {
+    /// The CSV itself, passed as an argument of the command.
+    Payload(&'a [u8]),
+    /// A file in DATA_DIR.
+    Path(&'a str),
+}
+
+impl<'a> CsvSource<'a> {
+    pub fn read(&self) -> Result<Vec<u8>, RediSQLError> {
+        match self {
+            CsvSource::Payload(payload) => Ok(payload.to_vec()),
+            CsvSource::Path(path) => {
+                std::fs::read(resolve_file(path)?)
+                    .map_err(|e| file_error(path, &e.to_string()))
+            }
+        }
+    }
+}
+
+/// The CSV produced by `EXPORT`, replied as a single string.
+pub struct CsvPayload(pub Vec<u8>);
+
+impl RedisReply for CsvPayload {
+    fn reply(&mut self, ctx: &Context) -> i32 {
+        ReplyWithStringBuffer(ctx, &self.0)
+    }
+}
+
+impl Returner for CsvPayload {
+    fn create_data_to_return(
+        self,
+        _ctx: &Context,
+        _return_method: &ReturnMethod,
+        _timeout: std::time::Instant,
+    ) -> Box<Box<dyn RedisReply>> {
+        Box::new(Box::new(self))
+    }
+}
+
+/// Insert each record of the CSV as a row of the table, in a single
+/// transaction.
+///
+/// With the header, the names in the first record are the columns where
+/// the values are written, otherwise the values are written in the
+/// columns of the table, in order.
+/// Without `types`, empty values are NULL and the others are INT or REAL
+/// if they are numbers, TEXT otherwise.
+pub fn import_csv(
+    db: &Arc<Mutex<Connection>>,
+    table: &str,
+    csv: &[u8],
+    options: &CsvOptions,
+    types: Option<&[ColumnType]>,
+) -> Result<QueryResult, RediSQLError> {
+    let no_args = Bindings::default();
+    // outside a transaction the savepoint starts a new one
+    let in_transaction = !is_autocommit(db);
+    do_execute(db, "SAVEPOINT redisql_import", &no_args)?;
+    let imported =
+        match insert_records(db, table, csv, options, types) {
+            Ok(imported) => imported,
+            Err(e) => {
+                rollback_savepoint(
+                    db,
+                    "redisql_import",
+                    in_transaction,
+                )?;
+                return Err(e);
+            }
+        };
+    do_execute(db, "RELEASE redisql_import", &no_args)?;
+    Ok(QueryResult::Array {
+        names: vec!["imported".to_string()],
+        types: vec!["INT"],
+        array: vec![Entity::Integer {
+            int: imported as i64,
+        }],
+    })
+}
+
+fn insert_records(
+    db: &Arc<Mutex<Connection>>,
+    table: &str,
+    csv: &[u8],
+    options: &CsvOptions,
+    types: Option<&[ColumnType]>,
+) -> Result<usize, RediSQLError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(options.header)
+        .delimiter(options.delimiter)
+        .quote(options.quote)
+        .from_reader(csv);
+    let columns: Option<Vec<String>> = if options.header {
+        let header = reader.byte_headers().map_err(csv_error)?;
+        Some(
+            header
+                .iter()
+                .map(|name| {
+                    String::from_utf8_lossy(name).into_owned()
+                })
+                .collect(),
+        )
+    } else {
+        None
+    };
+    let mut stmt: Option<MultiStatement> = None;
+    let mut record = csv::ByteRecord::new();
+    let mut imported = 0;
+    while reader.read_byte_record(&mut record).map_err(csv_error)? {
+        if let Some(types) = types {
+            if types.len() != record.len() {
+                return Err(RediSQLError::with_code(
+                    61,
+                    format!(
+                        "Provided {} TYPES but the record {} has {} values",
+                        types.len(),
+                        imported + 1,
+                        record.len()
+                    ),
+                    "TYPES and the CSV records must have the same length".to_string(),
+                ));
+            }
+        }
+        let values = record
+            .iter()
+            .enumerate()
+            .map(|(i, field)| {
+                to_argument(field, types.map(|types| types[i]))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if stmt.is_none() {
+            let sql = match &columns {
+                Some(columns) => {
+                    let columns: Vec<&str> =
+                        columns.iter().map(|c| c.as_str()).collect();
+                    insert_sql(table, &columns)
+                }
+                None => insert_values_sql(table, values.len()),
+            };
+            stmt = Some(MultiStatement::new(db.clone(), &sql)?);
+        }
+        let stmt = stmt.as_ref().unwrap();
+        stmt.reset();
+        let result =
+            stmt.bind_arguments(&values).and_then(|_| stmt.execute());
+        stmt.reset();
+        result?;
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+fn insert_values_sql(table: &str, len: usize) -> String {
+    let params: Vec<String> =
+        (1..=len).map(|i| format!("?{}", i)).collect();
+    format!(
+        "INSERT INTO {} VALUES ({});",
+        quote_identifier(table),
+        params.join(", ")
+    )
+}
+
+fn to_argument(
+    field: &[u8],
+    column_type: Option<ColumnType>,
+) -> Result<Argument<'_>, RediSQLError> {
+    let invalid = |type_name: &str| {
+        RediSQLError::with_code(
+            61,
+            format!(
+                "The value `{}` is not {}",
+                String::from_utf8_lossy(field),
+                type_name
+            ),
+            "CSV value of the wrong type".to_string(),
+        )
+    };
+    let text = std::str::from_utf8(field);
+    match column_type {
+        _ if field.is_empty()
+            && column_type != Some(ColumnType::Text) =>
+        {
+            Ok(Argument::Null)
+        }
+        None => match text {
+            Ok(text) => Ok(infer_type(text)),
+            Err(_) => Ok(Argument::Blob { blob: field }),
+        },
+        Some(ColumnType::Int) => {
+            match text.ok().and_then(|t| t.parse::<i64>().ok()) {
+                Some(int) => Ok(Argument::Integer { int }),
+                None => Err(invalid("an integer")),
+            }
+        }
+        Some(ColumnType::Real) => {
+            match text.ok().and_then(|t| t.parse::<f64>().ok()) {
+                Some(float) => Ok(Argument::Float { float }),
+                None => Err(invalid("a number")),
+            }
+        }
+        Some(ColumnType::Text) => match text {
+            Ok(text) => Ok(Argument::Text { text }),
+            Err(_) => Err(invalid("valid UTF-8 text")),
+        },
+        Some(ColumnType::Blob) => Ok(Argument::Blob { blob: field }),
+        Some(ColumnType::Null) => Ok(Argument::Null),
+    }
+}
+
+fn infer_type(text: &str) -> Argument<'_> {
+    if let Ok(int) = text.parse::<i64>() {
+        return Argument::Integer { int };
+    }
+    // `parse` also accepts words like `inf` and `NaN`
+    let numeric = text
+        .bytes()
+        .all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b));
+    match text.parse::<f64>() {
+        Ok(float) if numeric => Argument::Float { float },
+        _ => Argument::Text { text },
+    }
+}
+
+/// Write the rows returned by the query in the file in DATA_DIR, the
+/// file is overwritten if it exists.
+pub fn export_to_file(
+    db: &Arc<Mutex<Connection>>,
+    query: &str,
+    path: &str,
+    options: &CsvOptions,
+    timeout: std::time::Instant,
+) -> Result<QueryResult, RediSQLError> {
+    let file = File::create(resolve_file(path)?)
+        .map_err(|e| file_error(path, &e.to_string()))?;
+    let exported = export_csv(db, query, options, timeout, file)?;
+    Ok(QueryResult::Array {
+        names: vec!["exported".to_string()],
+        types: vec!["INT"],
+        array: vec![Entity::Integer {
+            int: exported as i64,
+        }],
+    })
+}
+
+/// The rows returned by the query, as CSV.
+pub fn export_to_payload(
+    db: &Arc<Mutex<Connection>>,
+    query: &str,
+    options: &CsvOptions,
+    timeout: std::time::Instant,
+) -> Result<CsvPayload, RediSQLError> {
+    let mut csv = Vec::new();
+    export_csv(db, query, options, timeout, &mut csv)?;
+    Ok(CsvPayload(csv))
+}
+
+/// Write the rows in CSV, one at a time, and return how many they were.
+/// NULL is written as an empty value and BLOBs as their bytes.
+fn export_csv<W: Write>(
+    db: &Arc<Mutex<Connection>>,
+    query: &str,
+    options: &CsvOptions,
+    timeout: std::time::Instant,
+    output: W,
+) -> Result<usize, RediSQLError> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(options.delimiter)
+        .quote(options.quote)
+        .from_writer(output);
+    let mut exported = 0;
+    let cursor = do_query(db, query, &Bindings::default())?;
+    if let Cursor::RowsCursor {
+        ref stmt,
+        num_columns,
+        mut previous_status,
+        ..
+    } = cursor
+    {
+        if options.header {
+            let names = (0..num_columns).map(|i| unsafe {
+                CStr::from_ptr(ffi::sqlite3_column_name(
+                    stmt.as_ptr(),
+                    i,
+                ))
+                .to_bytes()
+            });
+            writer.write_record(names).map_err(csv_error)?;
+        }
+        while previous_status == ffi::SQLITE_ROW {
+            if std::time::Instant::now() > timeout {
+                return Err(RediSQLError::timeout());
+            }
+            let values = (0..num_columns)
+                .map(|i| to_field(Entity::new(stmt, i)));
+            writer.write_record(values).map_err(csv_error)?;
+            exported += 1;
+            previous_status =
+                unsafe { ffi::sqlite3_step(stmt.as_ptr()) };
+        }
+        if previous_status == ffi::SQLITE_INTERRUPT {
+            return Err(RediSQLError::new(
+                "Query Interrupted".to_string(),
+                "The query was interrupted, most likely because it runs out of time.".to_string(),
+            ));
+        }
+    }
+    writer.flush().map_err(|e| csv_error(e.into()))?;
+    Ok(exported)
+}
+
+fn to_field(entity: Entity) -> Vec<u8> {
+    match entity {
+        Entity::Integer { int } => int.to_string().into_bytes(),
+        // the debug format keeps the decimal point, so that the value is
+        // read back as REAL
+        Entity::Float { float } => {
+            format!("{:?}", float).into_bytes()
+        }
+        Entity::Text { text } => text.into_bytes(),
+        Entity::Blob { blob } => blob,
+        _ => vec![],
+    }
+}
+
+/// Replicate the import with the CSV inline, so that replicas do not need
+/// to read the file. `args` are the arguments of the command before
+/// `PAYLOAD`.
+pub fn replicate_import(ctx: &Context, args: &[String], csv: &[u8]) {
+    let lock = ctx.lock();
+    let mut strings: Vec<RMString> =
+        args.iter().map(|arg| RMString::new(ctx, arg)).collect();
+    strings.push(RMString::new(ctx, "PAYLOAD"));
+    strings.push(RMString::from_bytes(ctx, csv));
+    let mut ptrs: Vec<
+        *mut crate::redis_type::ffi::RedisModuleString,
+    > = strings.iter().map(|s| s.as_ptr()).collect();
+    let command = CString::new("REDISQL.V2.IMPORT").unwrap();
+    let format = CString::new("v").unwrap();
+    unsafe {
+        crate::redis_type::ffi::RedisModule_Replicate.unwrap()(
+            ctx.as_ptr(),
+            command.as_ptr(),
+            format.as_ptr(),
+            ptrs.as_mut_ptr(),
+            ptrs.len(),
+        );
+    }
+    drop(strings);
+    ctx.release(lock);
+}
+
+fn csv_error(e: csv::Error) -> RediSQLError {
+    RediSQLError::with_code(
+        60,
+        format!("Error in the CSV: {}", e),
+        "Impossible to read or write the CSV".to_string(),
+    )
+}
+
+fn file_error(path: &str, error: &str) -> RediSQLError {
+    RediSQLError::with_code(
+        60,
+        format!("Error accessing the file `{}`: {}", path, error),
+        "Impossible to read or write the CSV".to_string(),
+    )
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::time::{Duration, Instant};
+
+use crate::community_statement::Statement;
+use crate::config::config;
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::ffi;
+use crate::sqlite::{
+    get_last_error_from_db_connection, type_to_string, Cursor,
+    Entity, QueryResult,
+};
+
+/// What a new cursor should read from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CursorQuery {
+    Command(&'static str),
+    Statement(&'static str),
+}
+
+struct OpenCursor {
+    /// The id of the client that opened the cursor, the only one that
+    /// can fetch it or close it.
+    owner: u64,
+    stmt: Statement,
+    num_columns: i32,
+    previous_status: i32,
+    count: usize,
+    last_activity: Instant,
+}
+
+impl OpenCursor {
+    fn deadline(&self, idle_timeout: Duration) -> Instant {
+        self.last_activity + idle_timeout
+    }
+    fn is_exhausted(&self) -> bool {
+        self.previous_status != ffi::SQLITE_ROW
+    }
+    /// Read up to `count` rows, it stops early if the timeout expires,
+    /// the rows not read are returned by the next page.
+    fn fetch(
+        &mut self,
+        count: usize,
+        timeout: Instant,
+    ) -> Result<
+        (Vec<String>, Vec<&'static str>, Vec<Entity>),
+        RediSQLError,
+    > {
+        let stmt = self.stmt.as_ptr();
+        let mut names = Vec::with_capacity(self.num_columns as usize);
+        let mut types = Vec::with_capacity(self.num_columns as usize);
+        for i in 0..self.num_columns {
+            let name = unsafe {
+                CStr::from_ptr(ffi::sqlite3_column_name(stmt, i))
+                    .to_string_lossy()
+                    .into_owned()
+            };
+            names.push(name);
+            let t = type_to_string(unsafe {
+                ffi::sqlite3_column_type(stmt, i)
+            });
+            types.push(t);
+        }
+        let mut array = vec![];
+        let mut fetched = 0;
+        while !self.is_exhausted()
+            && fetched < count
+            && Instant::now() < timeout
+        {
+            for i in 0..self.num_columns {
+                array.push(Entity::new(&self.stmt, i));
+            }
+            self.previous_status = unsafe { ffi::sqlite3_step(stmt) };
+            fetched += 1;
+        }
+        match self.previous_status {
+            ffi::SQLITE_ROW | ffi::SQLITE_DONE => {
+                Ok((names, types, array))
+            }
+            ffi::SQLITE_INTERRUPT => Err(RediSQLError::new("Query Interrupted".to_string(), "The query was interrupted, most likely because it runs out of time.".to_string())),
+            _ => Err(unsafe {
+                get_last_error_from_db_connection(
+                    ffi::sqlite3_db_handle(stmt),
+                )
+            }
+            .into()),
+        }
+    }
+    /// Release the statement, so that it does not keep the read
+    /// transaction open.
+    fn close(self) {
+        unsafe {
+            ffi::sqlite3_reset(self.stmt.as_ptr());
+        }
+    }
+}
+
+/// The cursors open on a database.
+///
+/// They are owned by the database worker thread, which is the only one
+/// that can step their statements.
+pub struct OpenCursors {
+    cursors: HashMap<u64, OpenCursor>,
+    last_id: u64,
+    idle_timeout: Duration,
+    max_open: usize,
+}
+
+impl Default for OpenCursors {
+    fn default() -> Self {
+        let config = config();
+        OpenCursors {
+            cursors: HashMap::new(),
+            last_id: 0,
+            idle_timeout: config.cursor_idle_timeout,
+            max_open: config.max_open_cursors,
+        }
+    }
+}
+
+impl OpenCursors {
+    pub fn new() -> Self {
+        OpenCursors::default()
+    }
+    /// Open a new cursor of the client over the rows of the query and
+    /// return its first page.
+    /// If the first page contains all the rows the cursor is not kept
+    /// and the page is returned with the cursor 0.
+    pub fn open(
+        &mut self,
+        owner: u64,
+        cursor: Cursor,
+        count: usize,
+        timeout: Instant,
+    ) -> Result<QueryResult, RediSQLError> {
+        let (stmt, num_columns, previous_status) = match cursor {
+            Cursor::RowsCursor {
+                stmt,
+                num_columns,
+                previous_status,
+                ..
+            } => (stmt, num_columns, previous_status),
+            _ => {
+                return Ok(QueryResult::Page {
+                    cursor: 0,
+                    names: vec![],
+                    types: vec![],
+                    array: vec![],
+                })
+            }
+        };
+        if self.cursors.len() >= self.max_open {
+            unsafe {
+                ffi::sqlite3_reset(stmt.as_ptr());
+            }
+            return Err(RediSQLError::too_many_cursors(
+                self.max_open,
+            ));
+        }
+        self.last_id += 1;
+        let id = self.last_id;
+        self.cursors.insert(
+            id,
+            OpenCursor {
+                owner,
+                stmt,
+                num_columns,
+                previous_status,
+                count,
+                last_activity: Instant::now(),
+            },
+        );
+        self.next(owner, id, None, timeout)
+    }
+    /// Return the next page of the cursor, of `count` rows or of the
+    /// size used when the cursor was open.
+    /// Once all the rows are returned the cursor is closed and the page
+    /// is returned with the cursor 0.
+    /// The cursors of the other clients are not found.
+    pub fn next(
+        &mut self,
+        owner: u64,
+        id: u64,
+        count: Option<usize>,
+        timeout: Instant,
+    ) -> Result<QueryResult, RediSQLError> {
+        let cursor = match self.cursors.get_mut(&id) {
+            Some(cursor) if cursor.owner == owner => cursor,
+            _ => return Err(RediSQLError::cursor_not_found(id)),
+        };
+        cursor.last_activity = Instant::now();
+        let count = count.unwrap_or(cursor.count);
+        let page = cursor.fetch(count, timeout);
+        let exhausted = cursor.is_exhausted();
+        if page.is_err() || exhausted {
+            if let Some(cursor) = self.cursors.remove(&id) {
+                cursor.close();
+            }
+        }
+        let (names, types, array) = page?;
+        Ok(QueryResult::Page {
+            cursor: if exhausted { 0 } else { id },
+            names,
+            types,
+            array,
+        })
+    }
+    pub fn close(
+        &mut self,
+        owner: u64,
+        id: u64,
+    ) -> Result<QueryResult, RediSQLError> {
+        match self.cursors.get(&id) {
+            Some(cursor) if cursor.owner == owner => {
+                if let Some(cursor) = self.cursors.remove(&id) {
+                    cursor.close();
+                }
+                Ok(QueryResult::OK {})
+            }
+            _ => Err(RediSQLError::cursor_not_found(id)),
+        }
+    }
+    /// Close the cursors that have not been fetched for too long.
+    pub fn close_expired(&mut self) {
+        let now = Instant::now();
+        let idle_timeout = self.idle_timeout;
+        let expired = self
+            .cursors
+            .iter()
+            .filter(|(_, c)| c.deadline(idle_timeout) <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            debug!("Closing idle cursor {}", id);
+            if let Some(cursor) = self.cursors.remove(&id) {
+                cursor.close();
+            }
+        }
+    }
+    /// How long the worker thread can wait for the next command before
+    /// a cursor expires.
+    pub fn time_left(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.cursors
+            .values()
+            .map(|c| {
+                c.deadline(self.idle_timeout)
+                    .saturating_duration_since(now)
+            })
+            .min()
+    }
+}
@@ -0,0 +1,98 @@
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use crate::community_statement::MultiStatement;
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::{
+    Connection, Entity, QueryResult, StatementTrait,
+};
+
+/// A sequence of elements of the digest, a row of a table, a stored
+/// statement, a function or an extension.
+pub type Sequence = Vec<Vec<u8>>;
+
+/// The content of the database for `DEBUG DIGEST`: the rows of each user
+/// table, and then the stored statements, functions and extensions.
+///
+/// Tables are in order of name and the rows of each table in order of
+/// content, so two databases with the same content have the same
+/// digest even if the rows were written in a different order. The
+/// metadata table is left out, it holds the path of the database that
+/// is different on each replica, only its statements, functions and
+/// extensions are included. The virtual tables are left out too, their
+/// rows are in Redis.
+pub fn digest_sequences(
+    db: &Arc<Mutex<Connection>>,
+) -> Result<Vec<Sequence>, RediSQLError> {
+    let tables = rows(db, "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' AND name != 'RediSQLMetadata' AND sql NOT LIKE 'CREATE VIRTUAL TABLE%' ORDER BY name;")?;
+    let mut sequences = Vec::new();
+    for table in tables {
+        let name = match table.first() {
+            Some(Entity::Text { text }) => text.clone(),
+            _ => continue,
+        };
+        let query = format!(
+            "SELECT * FROM \"{}\";",
+            name.replace('"', "\"\"")
+        );
+        let mut table_rows: Vec<Sequence> = rows(db, &query)?
+            .iter()
+            .map(|row| row.iter().map(encode).collect())
+            .collect();
+        table_rows.sort();
+        sequences.push(vec![b"table".to_vec(), name.into_bytes()]);
+        sequences.extend(table_rows);
+    }
+    let metadata = rows(db, "SELECT data_type, key, value FROM RediSQLMetadata WHERE data_type IN ('statement', 'function', 'extension') ORDER BY data_type DESC, key;")?;
+    for row in metadata {
+        let mut sequence = match row.first() {
+            Some(Entity::Text { text }) => {
+                vec![text.clone().into_bytes()]
+            }
+            _ => continue,
+        };
+        sequence.extend(row[1..].iter().map(encode));
+        sequences.push(sequence);
+    }
+    Ok(sequences)
+}
+
+fn rows(
+    db: &Arc<Mutex<Connection>>,
+    query: &str,
+) -> Result<Vec<Vec<Entity>>, RediSQLError> {
+    let cursor = MultiStatement::new(db.clone(), query)?.execute()?;
+    match QueryResult::try_from(cursor)? {
+        QueryResult::Array { names, array, .. }
+            if !names.is_empty() =>
+        {
+            Ok(array
+                .chunks(names.len())
+                .map(|row| row.to_vec())
+                .collect())
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// The bytes of a value, prefixed by its type so that the integer `1`
+/// and the text `'1'` are different.
+fn encode(entity: &Entity) -> Vec<u8> {
+    match entity {
+        Entity::Integer { int } => format!("i{}", int).into_bytes(),
+        Entity::Float { float } => {
+            format!("f{}", float.to_bits()).into_bytes()
+        }
+        Entity::Text { text } => {
+            let mut bytes = b"t".to_vec();
+            bytes.extend_from_slice(text.as_bytes());
+            bytes
+        }
+        Entity::Blob { blob } => {
+            let mut bytes = b"b".to_vec();
+            bytes.extend_from_slice(blob);
+            bytes
+        }
+        _ => b"n".to_vec(),
+    }
+}
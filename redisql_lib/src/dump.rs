@@ -0,0 +1,56 @@
+//! The copy of a database produced by `REDISQL.DUMP`.
+
+use std::os::raw;
+
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::{ffi, serialize, Connection, SQLiteConnection};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DumpFormat {
+    /// The SQL statements that recreate the database.
+    Sql,
+    /// The raw image of the database, a SQLite file.
+    Sqlite,
+}
+
+/// The content of the database in the format requested.
+pub fn dump(
+    conn: &Connection,
+    format: DumpFormat,
+) -> Result<Vec<u8>, RediSQLError> {
+    match format {
+        DumpFormat::Sql => dump_sql(conn),
+        DumpFormat::Sqlite => serialize(conn).map_err(|e| e.into()),
+    }
+}
+
+/// The SQL statements that recreate the database, the same produced by
+/// the `.dump` command of the SQLite shell.
+fn dump_sql(conn: &Connection) -> Result<Vec<u8>, RediSQLError> {
+    let fd = unsafe { ffi::start(conn.get_db()) };
+    if fd < 0 {
+        return Err(RediSQLError::new(
+            "Error in opening the pipe for the dump".to_string(),
+            "Impossible to dump the database".to_string(),
+        ));
+    }
+    let mut buffer = [0u8; 4096];
+    let mut sql = Vec::new();
+    loop {
+        let read_bytes = unsafe {
+            ffi::read_from_pipe(
+                fd,
+                buffer.as_mut_ptr() as *mut raw::c_void,
+                buffer.len() as _,
+            )
+        };
+        if read_bytes <= 0 {
+            break;
+        }
+        sql.extend_from_slice(&buffer[..read_bytes as usize]);
+    }
+    unsafe {
+        ffi::close_read_pipe(fd);
+    }
+    Ok(sql)
+}
@@ -0,0 +1,186 @@
+//! The SQLite run-time extensions loaded with `REDISQL.EXTENSION`.
+//!
+//! Only the files in EXTENSIONS_DIR can be loaded, and only through the
+//! C API: the `load_extension()` SQL function stays disabled. An
+//! extension is loaded on the connection of the writer and, before their
+//! next query, on the connections of the readers. Its name is stored in
+//! the metadata table, so it is loaded again when the database is loaded.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::config::config;
+use crate::locks::lock_connection;
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::{
+    ffi, Connection, Entity, QueryResult, SQLiteConnection,
+};
+
+/// The extensions loaded on a database, in order of loading.
+///
+/// SQLite cannot unload an extension, so they are only ever added.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    loaded: Arc<RwLock<Vec<(String, PathBuf)>>>,
+}
+
+impl Extensions {
+    /// Load the extension `name` from EXTENSIONS_DIR, `persist` stores
+    /// its name. Loading an extension already loaded does nothing.
+    pub fn load<F>(
+        &self,
+        db: &Arc<Mutex<Connection>>,
+        name: &str,
+        persist: F,
+    ) -> Result<QueryResult, RediSQLError>
+    where
+        F: FnOnce(&str) -> Result<(), RediSQLError>,
+    {
+        let mut loaded = self.loaded.write().unwrap();
+        if loaded.iter().any(|(loaded, _)| loaded == name) {
+            return Ok(QueryResult::OK {});
+        }
+        let path = resolve(name)?;
+        load_extension(&lock_connection(&db), &path)?;
+        persist(name)?;
+        loaded.push((name.to_string(), path));
+        Ok(QueryResult::OK {})
+    }
+    pub fn list(&self) -> QueryResult {
+        let loaded = self.loaded.read().unwrap();
+        let mut array = Vec::with_capacity(loaded.len() * 2);
+        for (name, path) in loaded.iter() {
+            array.push(Entity::Text { text: name.clone() });
+            array.push(Entity::Text {
+                text: path.to_string_lossy().into_owned(),
+            });
+        }
+        QueryResult::Array {
+            names: vec!["name".to_string(), "path".to_string()],
+            types: vec!["TEXT", "TEXT"],
+            array,
+        }
+    }
+    /// Load on a reader connection the extensions it is missing,
+    /// `loaded` is how many of them it has already loaded.
+    pub fn sync(
+        &self,
+        db: &Arc<Mutex<Connection>>,
+        loaded: &mut usize,
+    ) {
+        let extensions = self.loaded.read().unwrap();
+        if *loaded == extensions.len() {
+            return;
+        }
+        let conn = lock_connection(&db);
+        for (name, path) in &extensions[*loaded..] {
+            if let Err(e) = load_extension(&conn, path) {
+                debug!(
+                    "Error loading the extension {} on a reader: {}",
+                    name, e
+                );
+            }
+        }
+        *loaded = extensions.len();
+    }
+}
+
+fn not_allowed(debug: String) -> RediSQLError {
+    RediSQLError::with_code(
+        76,
+        debug,
+        "Extension not allowed".to_string(),
+    )
+}
+
+/// The file of the extension `name` in EXTENSIONS_DIR, with or without
+/// the extension of the shared libraries of the platform.
+///
+/// The name must be a file name, and the file, once the symbolic links
+/// are followed, must still be inside the directory.
+fn resolve(name: &str) -> Result<PathBuf, RediSQLError> {
+    let dir = match config().extensions_dir {
+        Some(dir) => dir,
+        None => return Err(not_allowed(
+            "No extension can be loaded, EXTENSIONS_DIR is not set"
+                .to_string(),
+        )),
+    };
+    let is_file_name = Path::new(name).file_name()
+        == Some(OsStr::new(name))
+        && !name.contains('\\');
+    if !is_file_name {
+        return Err(not_allowed(format!(
+            "The extension `{}` must be the name of a file in EXTENSIONS_DIR",
+            name
+        )));
+    }
+    let file = [
+        dir.join(name),
+        dir.join(format!("{}{}", name, std::env::consts::DLL_SUFFIX)),
+    ]
+    .iter()
+    .find(|file| file.is_file())
+    .and_then(|file| file.canonicalize().ok());
+    match (file, dir.canonicalize()) {
+        (Some(file), Ok(dir)) if file.starts_with(&dir) => Ok(file),
+        _ => Err(not_allowed(format!(
+            "The extension `{}` is not in EXTENSIONS_DIR `{}`",
+            name,
+            dir.display()
+        ))),
+    }
+}
+
+/// Load the extension with its default entry point, the loading is
+/// enabled only for this call.
+fn load_extension(
+    conn: &Connection,
+    path: &Path,
+) -> Result<(), RediSQLError> {
+    let file =
+        CString::new(path.to_string_lossy().as_bytes()).unwrap();
+    let db = conn.get_db();
+    let mut message: *mut c_char = ptr::null_mut();
+    let rc = unsafe {
+        ffi::sqlite3_db_config(
+            db,
+            ffi::SQLITE_DBCONFIG_ENABLE_LOAD_EXTENSION,
+            1 as c_int,
+            ptr::null_mut::<c_int>(),
+        );
+        let rc = ffi::sqlite3_load_extension(
+            db,
+            file.as_ptr(),
+            ptr::null(),
+            &mut message,
+        );
+        ffi::sqlite3_db_config(
+            db,
+            ffi::SQLITE_DBCONFIG_ENABLE_LOAD_EXTENSION,
+            0 as c_int,
+            ptr::null_mut::<c_int>(),
+        );
+        rc
+    };
+    if rc == ffi::SQLITE_OK {
+        return Ok(());
+    }
+    let debug = if message.is_null() {
+        format!("Error loading the extension {}", path.display())
+    } else {
+        let debug = unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { ffi::sqlite3_free(message as *mut c_void) };
+        debug
+    };
+    Err(RediSQLError::with_code(
+        77,
+        debug,
+        "Error loading the extension".to_string(),
+    ))
+}
@@ -0,0 +1,499 @@
+use std::ffi::CString;
+use std::sync::{Arc, Mutex};
+
+use fnv::FnvHashMap;
+
+use crate::community_statement::MultiStatement;
+use crate::redis::{do_execute, is_autocommit, rollback_savepoint};
+use crate::redis_type::{
+    ffi, Call, CallReply, Context, ContextLock, RMString,
+};
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::{
+    Argument, Bindings, Connection, Entity, QueryResult,
+    StatementTrait,
+};
+
+/// Where the entries of the stream are read from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StreamSource<'a> {
+    /// The entries starting from an id, included.
+    Range { from: &'a str },
+    /// The entries of a consumer group, they are acknowledged once
+    /// written in the database.
+    ///
+    /// The entries delivered to the consumer and never acknowledged, since
+    /// their insert failed, are read again before the new ones.
+    Group { group: &'a str, consumer: &'a str },
+}
+
+/// An entry of a stream, with the values converted to the type written in
+/// the prefix of their field.
+#[derive(Debug, Clone)]
+pub struct StreamEntry {
+    pub id: String,
+    pub fields: Vec<(String, Entity)>,
+}
+
+/// The entries read from the stream.
+#[derive(Debug, Clone, Default)]
+pub struct StreamRead {
+    pub entries: Vec<StreamEntry>,
+    /// If the entries are the pending ones of the consumer, which are
+    /// not contiguous in the stream.
+    pub redelivered: bool,
+}
+
+/// Read up to `count` entries from the stream.
+///
+/// The consumer group is read with `XREADGROUP`, which is replicated so
+/// that the replicas know which entries have been delivered.
+pub fn read_stream(
+    ctx: &Context,
+    stream: &str,
+    source: StreamSource,
+    count: usize,
+) -> Result<StreamRead, RediSQLError> {
+    let count = count.to_string();
+    let lock = ctx.lock();
+    let result =
+        match source {
+            StreamSource::Range { from } => {
+                let reply = Call(
+                    ctx,
+                    "XRANGE",
+                    &[
+                        stream.as_bytes(),
+                        from.as_bytes(),
+                        b"+",
+                        b"COUNT",
+                        count.as_bytes(),
+                    ],
+                    false,
+                    &lock,
+                );
+                read_reply(stream, &reply).map(|(entries, _)| {
+                    StreamRead {
+                        entries,
+                        redelivered: false,
+                    }
+                })
+            }
+            StreamSource::Group { group, consumer } => read_pending(
+                ctx, stream, group, consumer, &count, &lock,
+            )
+            .and_then(|entries| {
+                if !entries.is_empty() {
+                    return Ok(StreamRead {
+                        entries,
+                        redelivered: true,
+                    });
+                }
+                read_group(
+                    ctx, stream, group, consumer, ">", &count, &lock,
+                )
+                .map(|(entries, _)| StreamRead {
+                    entries,
+                    redelivered: false,
+                })
+            }),
+        };
+    ctx.release(lock);
+    result
+}
+
+/// The entries delivered to the consumer and not acknowledged.
+///
+/// The entries deleted from the stream in the meantime cannot be
+/// written anymore, so they are acknowledged right away.
+fn read_pending(
+    ctx: &Context,
+    stream: &str,
+    group: &str,
+    consumer: &str,
+    count: &str,
+    lock: &ContextLock,
+) -> Result<Vec<StreamEntry>, RediSQLError> {
+    let (entries, deleted) =
+        read_group(ctx, stream, group, consumer, "0", count, lock)?;
+    if !deleted.is_empty() {
+        let mut args = vec![stream.as_bytes(), group.as_bytes()];
+        args.extend(deleted.iter().map(|id| id.as_bytes()));
+        let reply = Call(ctx, "XACK", &args, true, lock);
+        if let CallReply::RError { .. } = reply {
+            return Err(ack_error(
+                stream,
+                group,
+                &reply.access_error().unwrap_or_default(),
+            ));
+        }
+    }
+    Ok(entries)
+}
+
+/// Read the group from `id`, `>` for the entries never delivered, along
+/// with the ids of the entries deleted from the stream.
+fn read_group(
+    ctx: &Context,
+    stream: &str,
+    group: &str,
+    consumer: &str,
+    id: &str,
+    count: &str,
+    lock: &ContextLock,
+) -> Result<(Vec<StreamEntry>, Vec<String>), RediSQLError> {
+    let reply = Call(
+        ctx,
+        "XREADGROUP",
+        &[
+            b"GROUP",
+            group.as_bytes(),
+            consumer.as_bytes(),
+            b"COUNT",
+            count.as_bytes(),
+            b"STREAMS",
+            stream.as_bytes(),
+            id.as_bytes(),
+        ],
+        true,
+        lock,
+    );
+    if let CallReply::RError { .. } = reply {
+        return read_reply(stream, &reply);
+    }
+    // the reply holds the name of the stream and its entries
+    match reply
+        .access_array_subelement(0)
+        .and_then(|s| s.access_array_subelement(1))
+    {
+        Some(entries) => read_reply(stream, &entries),
+        None => Ok((vec![], vec![])),
+    }
+}
+
+fn read_reply(
+    stream: &str,
+    reply: &CallReply,
+) -> Result<(Vec<StreamEntry>, Vec<String>), RediSQLError> {
+    match reply {
+        CallReply::RError { .. } => Err(read_error(
+            stream,
+            &reply.access_error().unwrap_or_default(),
+        )),
+        CallReply::RNull { .. } => Ok((vec![], vec![])),
+        _ => parse_entries(reply),
+    }
+}
+
+/// The arguments of `REDISQL.V2.STREAM.INGEST` that replicas execute
+/// once the entries are in the database, `args` are the ones before the
+/// range of the entries.
+///
+/// The replicas read the range from their copy of the stream, the
+/// redelivered entries are not contiguous so each one is its own range.
+pub fn replicate_args(
+    args: &[String],
+    read: &StreamRead,
+) -> Vec<Vec<String>> {
+    let range = |id: &str, count: usize| {
+        let mut range = args.to_vec();
+        range.push("FROM".to_string());
+        range.push(id.to_string());
+        range.push("COUNT".to_string());
+        range.push(count.to_string());
+        range
+    };
+    match read.entries.first() {
+        None => vec![],
+        Some(first) if !read.redelivered => {
+            vec![range(&first.id, read.entries.len())]
+        }
+        Some(_) => read
+            .entries
+            .iter()
+            .map(|entry| range(&entry.id, 1))
+            .collect(),
+    }
+}
+
+/// Replicate the ingestion of the entries, after they are written in the
+/// database.
+pub fn replicate_ingest(ctx: &Context, replicate: &[Vec<String>]) {
+    if replicate.is_empty() {
+        return;
+    }
+    let command = CString::new("REDISQL.V2.STREAM.INGEST").unwrap();
+    let format = CString::new("v").unwrap();
+    let lock = ctx.lock();
+    for args in replicate {
+        let strings: Vec<RMString> =
+            args.iter().map(|arg| RMString::new(ctx, arg)).collect();
+        let mut ptrs: Vec<*mut ffi::RedisModuleString> =
+            strings.iter().map(|s| s.as_ptr()).collect();
+        unsafe {
+            ffi::RedisModule_Replicate.unwrap()(
+                ctx.as_ptr(),
+                command.as_ptr(),
+                format.as_ptr(),
+                ptrs.as_mut_ptr(),
+                ptrs.len(),
+            );
+        }
+    }
+    ctx.release(lock);
+}
+
+/// Acknowledge to the consumer group the entries written in the database.
+pub fn acknowledge(
+    ctx: &Context,
+    stream: &str,
+    group: &str,
+    entries: &[StreamEntry],
+) -> Result<(), RediSQLError> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let mut args = vec![stream.as_bytes(), group.as_bytes()];
+    args.extend(entries.iter().map(|entry| entry.id.as_bytes()));
+    let lock = ctx.lock();
+    let reply = Call(ctx, "XACK", &args, true, &lock);
+    let result = match reply {
+        CallReply::RError { .. } => Err(ack_error(
+            stream,
+            group,
+            &reply.access_error().unwrap_or_default(),
+        )),
+        _ => Ok(()),
+    };
+    ctx.release(lock);
+    result
+}
+
+/// Insert each entry as a row of the table, in a single transaction.
+///
+/// The fields of the entry are the columns of the row, without their
+/// type prefix. If `id_column` is provided, the id of the entry is
+/// written in that column.
+pub fn ingest_entries(
+    db: &Arc<Mutex<Connection>>,
+    table: &str,
+    id_column: Option<&str>,
+    entries: &[StreamEntry],
+) -> Result<QueryResult, RediSQLError> {
+    let no_args = Bindings::default();
+    // outside a transaction the savepoint starts a new one
+    let in_transaction = !is_autocommit(db);
+    do_execute(db, "SAVEPOINT redisql_ingest", &no_args)?;
+    if let Err(e) = insert_entries(db, table, id_column, entries) {
+        rollback_savepoint(db, "redisql_ingest", in_transaction)?;
+        return Err(e);
+    }
+    do_execute(db, "RELEASE redisql_ingest", &no_args)?;
+    let last_id = match entries.last() {
+        Some(entry) => Entity::Text {
+            text: entry.id.clone(),
+        },
+        None => Entity::Null,
+    };
+    Ok(QueryResult::Array {
+        names: vec!["ingested".to_string(), "last_id".to_string()],
+        types: vec!["INT", "TEXT"],
+        array: vec![
+            Entity::Integer {
+                int: entries.len() as i64,
+            },
+            last_id,
+        ],
+    })
+}
+
+fn insert_entries(
+    db: &Arc<Mutex<Connection>>,
+    table: &str,
+    id_column: Option<&str>,
+    entries: &[StreamEntry],
+) -> Result<(), RediSQLError> {
+    // entries with the same fields share the same statement
+    let mut statements: FnvHashMap<String, MultiStatement> =
+        FnvHashMap::default();
+    for entry in entries {
+        let mut columns = Vec::with_capacity(entry.fields.len() + 1);
+        let mut values = Vec::with_capacity(entry.fields.len() + 1);
+        if let Some(id_column) = id_column {
+            columns.push(id_column);
+            values.push(Argument::Text { text: &entry.id });
+        }
+        for (column, value) in &entry.fields {
+            columns.push(column);
+            values.push(to_argument(value));
+        }
+        let sql = insert_sql(table, &columns);
+        if !statements.contains_key(&sql) {
+            let stmt = MultiStatement::new(db.clone(), &sql)?;
+            statements.insert(sql.clone(), stmt);
+        }
+        let stmt = &statements[&sql];
+        stmt.reset();
+        let result =
+            stmt.bind_arguments(&values).and_then(|_| stmt.execute());
+        stmt.reset();
+        result?;
+    }
+    Ok(())
+}
+
+pub(crate) fn insert_sql(table: &str, columns: &[&str]) -> String {
+    if columns.is_empty() {
+        return format!(
+            "INSERT INTO {} DEFAULT VALUES;",
+            quote_identifier(table)
+        );
+    }
+    let names: Vec<String> =
+        columns.iter().map(|c| quote_identifier(c)).collect();
+    let params: Vec<String> =
+        (1..=columns.len()).map(|i| format!("?{}", i)).collect();
+    format!(
+        "INSERT INTO {} ({}) VALUES ({});",
+        quote_identifier(table),
+        names.join(", "),
+        params.join(", ")
+    )
+}
+
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn to_argument(entity: &Entity) -> Argument<'_> {
+    match entity {
+        Entity::Integer { int } => Argument::Integer { int: *int },
+        Entity::Float { float } => Argument::Float { float: *float },
+        Entity::Text { text } => Argument::Text { text },
+        Entity::Blob { blob } => Argument::Blob { blob },
+        _ => Argument::Null,
+    }
+}
+
+/// The entries of the reply, and the ids of the entries without fields,
+/// which are the pending entries deleted from the stream.
+fn parse_entries(
+    reply: &CallReply,
+) -> Result<(Vec<StreamEntry>, Vec<String>), RediSQLError> {
+    let len = reply.length().unwrap_or(0);
+    let mut entries = Vec::with_capacity(len);
+    let mut deleted = Vec::new();
+    for i in 0..len {
+        let entry = match reply.access_array_subelement(i) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let id = match entry
+            .access_array_subelement(0)
+            .and_then(|id| id.access_string())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        let pairs = match entry.access_array_subelement(1) {
+            Some(pairs @ CallReply::RArray { .. }) => pairs,
+            _ => {
+                deleted.push(id);
+                continue;
+            }
+        };
+        let mut fields = Vec::new();
+        let pairs_len = pairs.length().unwrap_or(0);
+        for j in (0..pairs_len).step_by(2) {
+            let field = pairs
+                .access_array_subelement(j)
+                .and_then(|f| f.access_bytes());
+            let value = pairs
+                .access_array_subelement(j + 1)
+                .and_then(|v| v.access_bytes());
+            if let (Some(field), Some(value)) = (field, value) {
+                fields.push(typed_field(&field, value)?);
+            }
+        }
+        entries.push(StreamEntry { id, fields });
+    }
+    Ok((entries, deleted))
+}
+
+/// Split the type prefix from the name of the field, and convert the
+/// value to that type.
+///
+/// The prefixes are the ones used by `QUERY.INTO`: `int:`, `real:`,
+/// `text:`, `blob:` and `null:`. Fields without a prefix are TEXT, or
+/// BLOB if they are not valid UTF-8.
+fn typed_field(
+    field: &[u8],
+    value: Vec<u8>,
+) -> Result<(String, Entity), RediSQLError> {
+    let field = String::from_utf8_lossy(field);
+    let (prefix, name) = match field.split_once(':') {
+        Some((prefix, name)) => (prefix, name),
+        None => ("", field.as_ref()),
+    };
+    let invalid = |type_name: &str, value: &[u8]| {
+        RediSQLError::with_code(
+            54,
+            format!(
+                "The field `{}` has the value `{}` which is not {}",
+                field,
+                String::from_utf8_lossy(value),
+                type_name
+            ),
+            "Stream value of the wrong type".to_string(),
+        )
+    };
+    let entity = match prefix {
+        "int" => match std::str::from_utf8(&value)
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+        {
+            Some(int) => Entity::Integer { int },
+            None => return Err(invalid("an integer", &value)),
+        },
+        "real" => match std::str::from_utf8(&value)
+            .ok()
+            .and_then(|v| v.parse::<f64>().ok())
+        {
+            Some(float) => Entity::Float { float },
+            None => return Err(invalid("a number", &value)),
+        },
+        "null" => Entity::Null,
+        "blob" => Entity::Blob { blob: value },
+        "text" => text_or_blob(value),
+        _ => return Ok((field.to_string(), text_or_blob(value))),
+    };
+    Ok((name.to_string(), entity))
+}
+
+fn text_or_blob(value: Vec<u8>) -> Entity {
+    match String::from_utf8(value) {
+        Ok(text) => Entity::Text { text },
+        Err(e) => Entity::Blob {
+            blob: e.into_bytes(),
+        },
+    }
+}
+
+fn ack_error(stream: &str, group: &str, error: &str) -> RediSQLError {
+    RediSQLError::with_code(
+        55,
+        format!(
+            "Error acknowledging the entries of `{}` to the group `{}`: {}",
+            stream, group, error
+        ),
+        "Impossible to acknowledge the entries".to_string(),
+    )
+}
+
+fn read_error(stream: &str, error: &str) -> RediSQLError {
+    RediSQLError::with_code(
+        55,
+        format!("Error reading the stream `{}`: {}", stream, error),
+        "Impossible to read the stream".to_string(),
+    )
+}
@@ -0,0 +1,147 @@
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+use serde_json::{Number, Value};
+
+use crate::redis::RedisReply;
+use crate::redis_type::{Context, ReplyWithStringBuffer};
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::{Entity, QueryResult};
+
+/// The largest integer that a JSON parser can read into a double without
+/// losing precision, 2^53 - 1.
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+/// The reply of `FORMAT JSON` and `FORMAT JSON_TABLE`: a single string
+/// holding the rows as a JSON document.
+///
+/// `JSON` replies with an array of objects, one for each row, keyed by the
+/// names of the columns. `JSON_TABLE` replies with an object holding the
+/// `columns`, their `types` and the `rows`, each row as an array of values.
+///
+/// BLOBs are encoded in base64, and the columns declared as BOOLEAN are
+/// replied as booleans.
+pub struct JsonReply {
+    result: Result<QueryResult, RediSQLError>,
+    booleans: Vec<bool>,
+    table: bool,
+    bigint_as_string: bool,
+}
+
+impl JsonReply {
+    /// `booleans` tells which columns are declared as BOOLEAN, with
+    /// `bigint_as_string` the integers that JSON cannot represent exactly
+    /// are replied as strings.
+    pub fn new(
+        result: Result<QueryResult, RediSQLError>,
+        booleans: Vec<bool>,
+        table: bool,
+        bigint_as_string: bool,
+    ) -> Self {
+        JsonReply {
+            result,
+            booleans,
+            table,
+            bigint_as_string,
+        }
+    }
+
+    fn to_json(&self, i: usize, entity: &Entity) -> Value {
+        match entity {
+            Entity::Integer { int }
+                if self.booleans.get(i) == Some(&true) =>
+            {
+                Value::Bool(*int != 0)
+            }
+            Entity::Integer { int }
+                if self.bigint_as_string
+                    && !(-MAX_SAFE_INTEGER..=MAX_SAFE_INTEGER)
+                        .contains(int) =>
+            {
+                Value::String(int.to_string())
+            }
+            Entity::Integer { int } => Value::Number((*int).into()),
+            Entity::Float { float } => match Number::from_f64(*float)
+            {
+                Some(n) => Value::Number(n),
+                None => Value::Null,
+            },
+            Entity::Text { text } => Value::String(text.clone()),
+            Entity::Blob { blob } => {
+                Value::String(base64::encode(blob))
+            }
+            _ => Value::Null,
+        }
+    }
+
+    fn document(
+        &self,
+        names: &[String],
+        types: &[&'static str],
+        array: &[Entity],
+    ) -> String {
+        let rows = array.chunks(names.len().max(1)).map(|row| {
+            row.iter()
+                .enumerate()
+                .map(|(i, value)| self.to_json(i, value))
+                .collect::<Vec<Value>>()
+        });
+        if self.table {
+            let table = Table {
+                columns: names,
+                types,
+                rows: rows.collect(),
+            };
+            return serde_json::to_string(&table).unwrap();
+        }
+        let objects: Vec<Object> =
+            rows.map(|values| Object { names, values }).collect();
+        serde_json::to_string(&objects).unwrap()
+    }
+}
+
+/// The document of `JSON_TABLE`.
+#[derive(Serialize)]
+struct Table<'a> {
+    columns: &'a [String],
+    types: &'a [&'static str],
+    rows: Vec<Vec<Value>>,
+}
+
+/// A row of `JSON`, its keys follow the order of the columns, while a
+/// `serde_json::Map` would sort them.
+struct Object<'a> {
+    names: &'a [String],
+    values: Vec<Value>,
+}
+
+impl Serialize for Object<'_> {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map =
+            serializer.serialize_map(Some(self.values.len()))?;
+        for (name, value) in self.names.iter().zip(&self.values) {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+impl RedisReply for JsonReply {
+    fn reply(&mut self, ctx: &Context) -> i32 {
+        if let Ok(QueryResult::Array {
+            names,
+            types,
+            array,
+        }) = &self.result
+        {
+            let document = self.document(names, types, array);
+            return ReplyWithStringBuffer(ctx, document.as_bytes());
+        }
+        match &mut self.result {
+            Ok(result) => result.reply_v2(ctx),
+            Err(e) => e.reply_v2(ctx),
+        }
+    }
+}
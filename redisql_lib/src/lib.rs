@@ -3,9 +3,28 @@
 #[macro_use]
 extern crate log;
 
+pub mod aof;
+pub mod backup;
+pub mod cdc;
 pub mod community_statement;
+pub mod config;
+pub mod csv_io;
+pub mod cursor;
+pub mod digest;
+pub mod dump;
+pub mod extensions;
+pub mod ingest;
+pub mod json_reply;
+pub mod locks;
+pub mod map_reply;
+pub mod readers;
 pub mod redis;
+pub mod redis_functions;
 pub mod redis_type;
 pub mod redisql_error;
 pub mod sqlite;
 pub mod statistics;
+pub mod timeout;
+pub mod transaction;
+pub mod user_functions;
+pub mod virtual_tables;
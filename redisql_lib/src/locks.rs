@@ -0,0 +1,113 @@
+//! The order between the lock of the context of Redis and the mutex of
+//! the connections.
+//!
+//! Redis runs the commands holding the lock of the context, so the main
+//! thread can wait on the mutex of a connection while holding it. The
+//! threads of a database, the writer and the readers, instead hold their
+//! connection while SQLite runs the virtual tables and the functions that
+//! call Redis, and those need the lock of the context. To never wait on each other, the main thread
+//! announces the connection it is waiting on, and the thread holding it
+//! stops trying to lock the context and fails the statement, which
+//! releases the connection.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::thread::{self, ThreadId};
+use std::time::Duration;
+
+use crate::redis_type::{ffi, Context, ContextLock};
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::Connection;
+
+/// The thread where Redis runs the commands, it already holds the lock
+/// of the context.
+static MAIN_THREAD: Mutex<Option<ThreadId>> = Mutex::new(None);
+
+/// The connection the main thread is waiting on, 0 if none.
+static WAITED: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The connection of the database served by this thread, 0 if none.
+    static SERVED: Cell<usize> = Cell::new(0);
+}
+
+/// How long to wait before trying again to lock the context.
+const RETRY_INTERVAL: Duration = Duration::from_micros(100);
+
+/// Remember the thread of Redis, it must be called when the module is
+/// loaded.
+pub fn set_main_thread() {
+    *MAIN_THREAD.lock().unwrap() = Some(thread::current().id());
+}
+
+pub fn on_main_thread() -> bool {
+    *MAIN_THREAD.lock().unwrap() == Some(thread::current().id())
+}
+
+fn address(db: &Arc<Mutex<Connection>>) -> usize {
+    Arc::as_ptr(db) as usize
+}
+
+/// Mark the connection as the one of the database served by the current
+/// thread.
+pub fn serve(db: &Arc<Mutex<Connection>>) {
+    SERVED.with(|served| served.set(address(db)));
+}
+
+/// Lock the connection, from any thread. The main thread announces that
+/// it is waiting, if the connection is busy.
+pub fn lock_connection(
+    db: &Arc<Mutex<Connection>>,
+) -> MutexGuard<'_, Connection> {
+    if !on_main_thread() {
+        return db.lock().unwrap();
+    }
+    match db.try_lock() {
+        Ok(conn) => conn,
+        Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        Err(TryLockError::WouldBlock) => {
+            WAITED.store(address(db), Ordering::SeqCst);
+            let conn = db.lock().unwrap();
+            WAITED.store(0, Ordering::SeqCst);
+            conn
+        }
+    }
+}
+
+/// Run `f` with the context locked, unless the caller is Redis itself
+/// that is already holding the lock.
+///
+/// It fails if the main thread is waiting on the connection of the
+/// current thread. On Redis without `ThreadSafeContextTryLock` the lock
+/// is waited for, as the main thread cannot be noticed.
+pub fn with_context<T, F: FnOnce(&ContextLock) -> T>(
+    ctx: &Context,
+    f: F,
+) -> Result<T, RediSQLError> {
+    if on_main_thread() {
+        return Ok(f(&ContextLock {}));
+    }
+    let lock = loop {
+        if let Some(lock) = ctx.try_lock() {
+            break lock;
+        }
+        if unsafe { ffi::RedisModule_ThreadSafeContextTryLock }
+            .is_none()
+        {
+            break ctx.lock();
+        }
+        let served = SERVED.with(|served| served.get());
+        if served != 0 && WAITED.load(Ordering::SeqCst) == served {
+            return Err(RediSQLError::with_code(
+                79,
+                "Redis is waiting for the connection held by this statement, the statement stops to release it".to_string(),
+                "Redis is waiting for the database, it cannot be called from SQL now".to_string(),
+            ));
+        }
+        thread::sleep(RETRY_INTERVAL);
+    };
+    let result = f(&lock);
+    ctx.release(lock);
+    Ok(result)
+}
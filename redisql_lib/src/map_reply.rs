@@ -0,0 +1,78 @@
+use crate::redis::RedisReply;
+use crate::redis_type::ffi;
+use crate::redis_type::{
+    Context, ReplyWithAttribute, ReplyWithBool, ReplyWithMap,
+    ReplyWithStringBuffer,
+};
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::{Entity, QueryResult};
+
+/// The reply of `FORMAT MAP`: each row is a map from the names of the
+/// columns to their values.
+///
+/// RESP3 clients receive the values with their own types, the types of
+/// the columns as an attribute of the reply and the columns declared as
+/// BOOLEAN as booleans. RESP2 clients receive each map as an array of
+/// names and values.
+pub struct MapReply {
+    result: Result<QueryResult, RediSQLError>,
+    booleans: Vec<bool>,
+}
+
+impl MapReply {
+    /// `booleans` tells which columns are declared as BOOLEAN.
+    pub fn new(
+        result: Result<QueryResult, RediSQLError>,
+        booleans: Vec<bool>,
+    ) -> Self {
+        MapReply { result, booleans }
+    }
+}
+
+impl RedisReply for MapReply {
+    fn reply(&mut self, ctx: &Context) -> i32 {
+        let (names, types, array) = match &mut self.result {
+            Ok(QueryResult::Array {
+                names,
+                types,
+                array,
+            }) => (names, types, array),
+            Ok(result) => return result.reply_v2(ctx),
+            Err(e) => return e.reply_v2(ctx),
+        };
+        if ReplyWithAttribute(ctx, 1) == ffi::REDISMODULE_OK {
+            ReplyWithStringBuffer(ctx, b"types");
+            ReplyWithMap(ctx, names.len());
+            for (name, t) in names.iter().zip(types.iter()) {
+                ReplyWithStringBuffer(ctx, name.as_bytes());
+                ReplyWithStringBuffer(ctx, t.as_bytes());
+            }
+        }
+        let rows = array.chunks_mut(names.len().max(1));
+        unsafe {
+            ffi::RedisModule_ReplyWithArray.unwrap()(
+                ctx.as_ptr(),
+                rows.len() as std::os::raw::c_long,
+            );
+        }
+        for row in rows {
+            ReplyWithMap(ctx, row.len());
+            for (i, (name, value)) in
+                names.iter().zip(row.iter_mut()).enumerate()
+            {
+                ReplyWithStringBuffer(ctx, name.as_bytes());
+                match value {
+                    Entity::Integer { int }
+                        if self.booleans.get(i) == Some(&true) =>
+                    {
+                        ReplyWithBool(ctx, *int != 0);
+                    }
+                    value => {
+                        value.reply(ctx);
+                    }
+                }
+            }
+        }
+        ffi::REDISMODULE_OK
+    }
+}
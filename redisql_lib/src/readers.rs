@@ -0,0 +1,215 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use fnv::FnvHashMap;
+
+use crate::community_statement::MultiStatement;
+use crate::locks::{lock_connection, serve};
+use crate::redis::{
+    bind_statement, count_query, count_query_statement, do_query,
+    return_value, Command, ReplicationBook, StatementCache,
+};
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::{Bindings, Connection, Cursor, StatementTrait};
+use crate::timeout::InterruptAfter;
+use crate::user_functions::RegisteredFunctions;
+
+/// The reader threads of a database.
+///
+/// Each reader has its own connection to the database, so queries run
+/// concurrently among them and with the writer thread. In WAL mode they
+/// read the last committed state of the database. In-memory databases
+/// have no WAL, a shared cache would lock whole tables between readers
+/// and writer, so they do not start readers and their queries run on the
+/// writer thread.
+#[derive(Clone, Default)]
+pub struct Readers {
+    senders: Vec<Sender<Command>>,
+    connections: Vec<Arc<Mutex<Connection>>>,
+    next: Arc<AtomicUsize>,
+}
+
+unsafe impl Send for Readers {}
+
+impl Readers {
+    /// Start `n` reader threads on the database of the connection.
+    ///
+    /// File databases are moved to WAL mode, so that readers and writer
+    /// do not block each other. In-memory databases start no readers.
+    pub fn spawn(
+        db: &Arc<Mutex<Connection>>,
+        statements: &ReplicationBook,
+        n: usize,
+    ) -> Result<Readers, RediSQLError> {
+        if n == 0 {
+            return Ok(Readers::default());
+        }
+        let (path, in_memory) = {
+            let conn = lock_connection(&db);
+            (conn.path.clone(), conn.is_in_memory())
+        };
+        if in_memory {
+            debug!("In-memory database {}, the queries run on the writer", path);
+            return Ok(Readers::default());
+        }
+        MultiStatement::new(db.clone(), "PRAGMA journal_mode=WAL;")?
+            .execute()?;
+        let mut senders = Vec::with_capacity(n);
+        let mut connections = Vec::with_capacity(n);
+        for _ in 0..n {
+            let conn = Connection::open_connection(&path)?;
+            let conn = Arc::new(Mutex::new(conn));
+            MultiStatement::new(
+                conn.clone(),
+                "PRAGMA query_only = ON;",
+            )?
+            .execute()?;
+            connections.push(conn.clone());
+            let mut reader = Reader {
+                db: conn,
+                statements: FnvHashMap::default(),
+                writer_statements: statements.clone(),
+                functions: RegisteredFunctions::default(),
+                extensions: 0,
+            };
+            let (tx, rx) = channel();
+            thread::spawn(move || listen_and_read(&mut reader, &rx));
+            senders.push(tx);
+        }
+        Ok(Readers {
+            senders,
+            connections,
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+    /// Send the command to the readers, one after the other.
+    pub fn send(
+        &self,
+        command: Command,
+    ) -> Result<(), SendError<Command>> {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        self.senders[next % self.senders.len()].send(command)
+    }
+    /// The connections of the readers.
+    pub fn connections(&self) -> &[Arc<Mutex<Connection>>] {
+        &self.connections
+    }
+    pub fn stop(&self) {
+        for reader in &self.senders {
+            let _ = reader.send(Command::Stop);
+        }
+    }
+}
+
+struct Reader {
+    db: Arc<Mutex<Connection>>,
+    statements: FnvHashMap<String, MultiStatement>,
+    writer_statements: ReplicationBook,
+    /// The functions of the writer registered on this connection.
+    functions: RegisteredFunctions,
+    /// How many extensions of the writer are loaded on this connection.
+    extensions: usize,
+}
+
+unsafe impl Send for Reader {}
+
+impl Reader {
+    /// Run a statement of the database.
+    ///
+    /// Statements are created and updated by the writer thread, the
+    /// reader compiles again its own copy when the SQL of the writer
+    /// changes.
+    fn query_statement(
+        &mut self,
+        identifier: &str,
+        args: &Bindings,
+    ) -> Result<Cursor, RediSQLError> {
+        let sql =
+            match self.writer_statements.statement_sql(identifier) {
+                Ok(sql) => sql,
+                Err(e) => {
+                    self.statements.remove(identifier);
+                    return Err(e);
+                }
+            };
+        let up_to_date = match self.statements.get(identifier) {
+            Some(stmt) => stmt.sql() == sql,
+            None => false,
+        };
+        if !up_to_date {
+            let stmt = MultiStatement::new(self.db.clone(), &sql)?;
+            self.statements.insert(identifier.to_string(), stmt);
+        }
+        let stmt = &self.statements[identifier];
+        if !stmt.is_read_only() {
+            let debug = String::from("Not read only statement");
+            let description = String::from("Statement is not read only but it may modify the database, use `EXEC_STATEMENT` instead.",);
+            return Err(RediSQLError::new(debug, description));
+        }
+        stmt.reset();
+        let stmt = bind_statement(stmt, args)?;
+        Ok(stmt.execute()?)
+    }
+}
+
+fn listen_and_read(reader: &mut Reader, rx: &Receiver<Command>) {
+    debug!("Start reader thread execution");
+    serve(&reader.db);
+    while let Ok(command) = rx.recv() {
+        reader
+            .writer_statements
+            .extensions()
+            .sync(&reader.db, &mut reader.extensions);
+        reader
+            .writer_statements
+            .functions()
+            .sync(&reader.db, &mut reader.functions);
+        let _interrupt = command.deadline().map(InterruptAfter::new);
+        match command {
+            Command::Query {
+                query,
+                arguments,
+                timeout,
+                return_method,
+                client,
+            } => {
+                debug!("Reader Query | Query = {:?}", query);
+                let result = do_query(&reader.db, query, &arguments);
+                count_query(&return_method, result.is_ok());
+                return_value(
+                    &client,
+                    &return_method,
+                    result,
+                    timeout,
+                );
+            }
+            Command::QueryStatement {
+                identifier,
+                arguments,
+                timeout,
+                return_method,
+                client,
+            } => {
+                let result =
+                    reader.query_statement(identifier, &arguments);
+                count_query_statement(&return_method, result.is_ok());
+                return_value(
+                    &client,
+                    &return_method,
+                    result,
+                    timeout,
+                );
+            }
+            Command::Stop => {
+                debug!("Stop reader thread");
+                return;
+            }
+            _ => debug!("Reader received a command it cannot run"),
+        }
+    }
+}
@@ -4,16 +4,20 @@ use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::ffi::{CStr, CString};
-use std::fs::File;
-use std::io::{BufReader, Read, Write};
+use std::io::Write;
 use std::os::raw::{c_char, c_long};
+use std::path::Path;
 use std::slice;
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::channel;
-use std::sync::mpsc::{Receiver, RecvError, Sender};
+use std::sync::mpsc::{
+    Receiver, RecvError, RecvTimeoutError, SendError, Sender,
+};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 
+use crate::locks::{lock_connection, serve};
 pub use crate::redis_type as rm;
 use crate::redis_type::{
     BlockedClient, Context, OpenKey, ReplyWithError,
@@ -23,19 +27,43 @@ use crate::redisql_error as err;
 use crate::redisql_error::RediSQLError;
 
 use crate::sqlite::{
-    Connection, Cursor, Entity, QueryResult, SQLite3Error,
-    StatementTrait,
+    Argument, Bindings, Connection, Cursor, Entity, QueryResult,
+    SQLite3Error, SQLiteConnection, StatementTrait,
 };
 
 use crate::community_statement::MultiStatement;
 
 use crate::sqlite as sql;
 
+use crate::backup::{
+    backup_in_progress, read_image, replicate_load, BackupDirection,
+    BackupJob, BackupState, Backups, Finished,
+};
+use crate::cdc::ChangeCapture;
+use crate::config::config;
+use crate::csv_io::{
+    export_to_file, export_to_payload, import_csv, ColumnType,
+    CsvOptions,
+};
+use crate::cursor::{CursorQuery, OpenCursors};
+use crate::dump::{dump, DumpFormat};
+use crate::extensions::Extensions;
+use crate::ingest::{
+    acknowledge, ingest_entries, replicate_ingest, StreamEntry,
+};
+use crate::json_reply::JsonReply;
+use crate::map_reply::MapReply;
+use crate::readers::Readers;
 use crate::statistics::STATISTICS;
+use crate::timeout::{default_deadline, InterruptAfter};
+use crate::transaction::{Snapshot, TransactionState};
+use crate::user_functions::{FunctionDefinition, Functions};
 
 #[derive(Clone)]
 pub struct ReplicationBook {
     data: Arc<RwLock<FnvHashMap<String, (MultiStatement, bool)>>>,
+    functions: Functions,
+    extensions: Extensions,
     db: ConcurrentConnection,
 }
 
@@ -56,6 +84,73 @@ impl ReplicationBook {
         }
         new
     }
+    /// Forget all the statements and the functions, they are restored
+    /// from the metadata table after the database is loaded from a file.
+    fn clear(&self) {
+        self.data.write().unwrap().clear();
+        self.functions.clear(&self.db);
+    }
+    /// The functions defined on the database.
+    pub fn functions(&self) -> &Functions {
+        &self.functions
+    }
+    /// Define a new function, or replace the one with the same name
+    /// with `can_update`.
+    pub fn insert_function(
+        &self,
+        definition: FunctionDefinition,
+        can_update: bool,
+    ) -> Result<QueryResult, RediSQLError> {
+        let db = self.db.clone();
+        self.functions.insert(
+            &self.db,
+            definition,
+            can_update,
+            |definition| {
+                replace_metadata(
+                    db,
+                    "function",
+                    &definition.name,
+                    Some(&definition.to_metadata()),
+                )
+                .map_err(RediSQLError::from)
+            },
+        )
+    }
+    pub fn delete_function(
+        &self,
+        name: &str,
+    ) -> Result<QueryResult, RediSQLError> {
+        let db = self.db.clone();
+        self.functions.delete(&self.db, name, || {
+            replace_metadata(db, "function", name, None)
+                .map_err(RediSQLError::from)
+        })
+    }
+    pub fn list_functions(
+        &self,
+    ) -> Result<QueryResult, RediSQLError> {
+        Ok(self.functions.list())
+    }
+    /// The extensions loaded on the database.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+    pub fn load_extension(
+        &self,
+        name: &str,
+    ) -> Result<QueryResult, RediSQLError> {
+        let db = self.db.clone();
+        self.extensions.load(&self.db, name, |name| {
+            replace_metadata(db, "extension", name, Some(name))
+                .map_err(RediSQLError::from)
+        })
+    }
+    pub fn list_extensions(
+        &self,
+    ) -> Result<QueryResult, RediSQLError> {
+        Ok(self.extensions.list())
+    }
 }
 
 pub trait StatementCache<'a> {
@@ -80,13 +175,23 @@ pub trait StatementCache<'a> {
     fn exec_statement(
         &self,
         identifier: &str,
-        args: &[&str],
+        args: &Bindings,
     ) -> Result<Cursor, RediSQLError>;
+    fn exec_statement_batch(
+        &self,
+        identifier: &str,
+        batch: &[Bindings],
+        with_errors: bool,
+    ) -> Result<QueryResult, RediSQLError>;
     fn query_statement(
         &self,
         identifier: &str,
-        args: &[&str],
+        args: &Bindings,
     ) -> Result<Cursor, RediSQLError>;
+    fn statement_sql(
+        &self,
+        identifier: &str,
+    ) -> Result<String, RediSQLError>;
     fn show_statement(
         &self,
         identifier: &str,
@@ -98,6 +203,8 @@ impl<'a> StatementCache<'a> for ReplicationBook {
     fn new(db: &ConcurrentConnection) -> Self {
         ReplicationBook {
             data: Arc::new(RwLock::new(FnvHashMap::default())),
+            functions: Functions::default(),
+            extensions: Extensions::default(),
             db: Arc::clone(db),
         }
     }
@@ -200,7 +307,7 @@ impl<'a> StatementCache<'a> for ReplicationBook {
     fn query_statement(
         &self,
         identifier: &str,
-        args: &[&str],
+        args: &Bindings,
     ) -> Result<Cursor, RediSQLError> {
         let map = self.data.read().unwrap();
         match map.get(identifier) {
@@ -228,7 +335,7 @@ impl<'a> StatementCache<'a> for ReplicationBook {
     fn exec_statement(
         &self,
         identifier: &str,
-        args: &[&str],
+        args: &Bindings,
     ) -> Result<Cursor, RediSQLError> {
         let map = self.data.read().unwrap();
         match map.get(identifier) {
@@ -247,6 +354,99 @@ impl<'a> StatementCache<'a> for ReplicationBook {
             }
         }
     }
+    /// Execute the statement once for each element of the batch, all the
+    /// executions happen inside a single savepoint, so that it works also
+    /// inside an open transaction.
+    /// Without `with_errors` the first error rolls back the whole batch,
+    /// otherwise the failing executions are skipped and reported.
+    fn exec_statement_batch(
+        &self,
+        identifier: &str,
+        batch: &[Bindings],
+        with_errors: bool,
+    ) -> Result<QueryResult, RediSQLError> {
+        let map = self.data.read().unwrap();
+        let stmt = match map.get(identifier) {
+            None => {
+                let debug = String::from("No statement found");
+                let description = String::from(
+                    "The statement is not present in the database",
+                );
+                return Err(RediSQLError::new(debug, description));
+            }
+            Some(&(ref stmt, _)) => stmt,
+        };
+        let no_args = Bindings::default();
+        // outside a transaction the savepoint starts a new one
+        let in_transaction = !is_autocommit(&self.db);
+        do_execute(&self.db, "SAVEPOINT redisql_batch", &no_args)?;
+        let mut modified_rows = 0;
+        let mut errors = Vec::new();
+        for (i, args) in batch.iter().enumerate() {
+            stmt.reset();
+            let result = bind_statement(stmt, args)
+                .and_then(|stmt| stmt.execute());
+            match result {
+                Ok(cursor) => modified_rows += cursor.modified_rows(),
+                Err(e) if with_errors => {
+                    errors.push((i, e.error_message))
+                }
+                Err(e) => {
+                    stmt.reset();
+                    rollback_savepoint(
+                        &self.db,
+                        "redisql_batch",
+                        in_transaction,
+                    )?;
+                    return Err(e.into());
+                }
+            }
+        }
+        stmt.reset();
+        do_execute(&self.db, "RELEASE redisql_batch", &no_args)?;
+        if !with_errors {
+            return Ok(QueryResult::DONE { modified_rows });
+        }
+        // the first row holds the total of the modified rows, the
+        // others the index and the error of the failed executions
+        let mut array = vec![
+            Entity::Null,
+            Entity::Integer {
+                int: i64::from(modified_rows),
+            },
+            Entity::Null,
+        ];
+        for (i, error) in errors {
+            array.push(Entity::Integer { int: i as i64 });
+            array.push(Entity::Integer { int: 0 });
+            array.push(Entity::Text { text: error });
+        }
+        Ok(QueryResult::Array {
+            names: vec![
+                "tuple".to_string(),
+                "modified_rows".to_string(),
+                "error".to_string(),
+            ],
+            types: vec!["INT", "INT", "TEXT"],
+            array,
+        })
+    }
+    fn statement_sql(
+        &self,
+        identifier: &str,
+    ) -> Result<String, RediSQLError> {
+        let map = self.data.read().unwrap();
+        match map.get(identifier) {
+            None => {
+                let debug = String::from("No statement found");
+                let description = String::from(
+                    "The statement is not present in the database",
+                );
+                Err(RediSQLError::new(debug, description))
+            }
+            Some(&(ref stmt, _)) => Ok(stmt.sql()),
+        }
+    }
     fn show_statement(
         &self,
         identifier: &str,
@@ -266,8 +466,10 @@ impl<'a> StatementCache<'a> for ReplicationBook {
                     "SQL".to_string(),
                     "parameters_count".to_string(),
                     "read_only".to_string(),
+                    "parameters_names".to_string(),
                 ];
-                let types = vec!["TEXT", "TEXT", "INT", "INT"];
+                let types =
+                    vec!["TEXT", "TEXT", "INT", "INT", "TEXT"];
                 let array = vec![
                     Entity::Text {
                         text: identifier.to_string(),
@@ -279,6 +481,9 @@ impl<'a> StatementCache<'a> for ReplicationBook {
                     Entity::Integer {
                         int: if read_only { 1 } else { 0 },
                     },
+                    Entity::Text {
+                        text: stmt.parameters_names().join(","),
+                    },
                 ];
                 Ok(QueryResult::Array {
                     names,
@@ -295,9 +500,10 @@ impl<'a> StatementCache<'a> for ReplicationBook {
             "SQL".to_string(),
             "parameters_count".to_string(),
             "read_only".to_string(),
+            "parameters_names".to_string(),
         ];
-        let types = vec!["TEXT", "TEXT", "INT", "INT"];
-        let n = map.len() * 4;
+        let types = vec!["TEXT", "TEXT", "INT", "INT", "TEXT"];
+        let n = map.len() * 5;
         let mut array = Vec::with_capacity(n);
         for (key, (multi_stmt, read_only)) in map.iter() {
             array.push(Entity::Text {
@@ -312,6 +518,9 @@ impl<'a> StatementCache<'a> for ReplicationBook {
             array.push(Entity::Integer {
                 int: if *read_only { 1 } else { 0 },
             });
+            array.push(Entity::Text {
+                text: multi_stmt.parameters_names().join(","),
+            });
         }
         Ok(QueryResult::Array {
             names,
@@ -327,6 +536,10 @@ type ConcurrentConnection = Arc<Mutex<Connection>>;
 pub struct Loop {
     db: ConcurrentConnection,
     replication_book: ReplicationBook,
+    transaction: TransactionState,
+    backup: BackupState,
+    // the last field, so it is dropped after the connection that uses it
+    changes: Option<Arc<ChangeCapture>>,
 }
 
 impl Drop for Loop {
@@ -340,6 +553,9 @@ unsafe impl Send for Loop {}
 pub trait LoopData {
     fn get_replication_book(&self) -> ReplicationBook;
     fn get_db(&self) -> ConcurrentConnection;
+    fn get_transaction(&self) -> TransactionState;
+    fn get_change_capture(&self) -> Option<Arc<ChangeCapture>>;
+    fn get_backup_state(&self) -> BackupState;
 }
 
 impl LoopData for Loop {
@@ -349,6 +565,15 @@ impl LoopData for Loop {
     fn get_db(&self) -> ConcurrentConnection {
         Arc::clone(&self.db)
     }
+    fn get_transaction(&self) -> TransactionState {
+        self.transaction.clone()
+    }
+    fn get_change_capture(&self) -> Option<Arc<ChangeCapture>> {
+        self.changes.clone()
+    }
+    fn get_backup_state(&self) -> BackupState {
+        self.backup.clone()
+    }
 }
 
 impl Loop {
@@ -357,6 +582,9 @@ impl Loop {
         Loop {
             db,
             replication_book,
+            transaction: TransactionState::new(),
+            backup: BackupState::new(),
+            changes: None,
         }
     }
     fn new_from_db_and_replication_book(
@@ -366,6 +594,9 @@ impl Loop {
         Loop {
             db,
             replication_book,
+            transaction: TransactionState::new(),
+            backup: BackupState::new(),
+            changes: None,
         }
     }
 }
@@ -390,7 +621,7 @@ impl RedisReply for Entity {
                 rm::ReplyWithStringBuffer(ctx, text.as_bytes())
             }
             Entity::Blob { ref blob } => {
-                rm::ReplyWithStringBuffer(ctx, blob.as_bytes())
+                rm::ReplyWithStringBuffer(ctx, blob)
             }
             Entity::Null => rm::ReplyWithNull(ctx),
             Entity::OK { .. } => (QueryResult::OK {}).reply(ctx),
@@ -498,42 +729,71 @@ pub fn create_argument(
     argv: *mut *mut rm::ffi::RedisModuleString,
     argc: i32,
 ) -> Result<Vec<&'static str>, RediSQLError> {
-    match parse_args(argv, argc) {
-        Err(e) => Err(RediSQLError::new(
-            format!(
-                "String valid up to byte number {}",
-                e.valid_up_to()
-            ),
-            "Got a non-valid UTF8 string as input".to_string(),
-        )),
-        Ok(argvector) => Ok(argvector),
-    }
+    create_binary_argument(argv, argc)
+        .into_iter()
+        .map(argument_to_str)
+        .collect()
 }
 
-fn parse_args(
+/// Like `create_argument` but it does not require the arguments to be valid
+/// UTF-8, it is used where the arguments may carry binary data.
+pub fn create_binary_argument(
     argv: *mut *mut rm::ffi::RedisModuleString,
     argc: i32,
-) -> Result<Vec<&'static str>, std::str::Utf8Error> {
-    let mut args: Vec<&'static str> =
+) -> Vec<&'static [u8]> {
+    let mut args: Vec<&'static [u8]> =
         Vec::with_capacity(argc as usize);
     for i in 0..argc {
         let redis_str = unsafe { *argv.offset(i as isize) };
-        let arg = unsafe { string_ptr_len(redis_str)? };
+        let arg = unsafe { string_ptr_len(redis_str) };
         args.push(arg);
     }
-    Ok(args)
+    args
+}
+
+/// The arguments of the commands that bind values to a statement: the
+/// first `names` arguments (command, database, statement) must be valid
+/// UTF-8, the others are the values to bind and may carry binary data.
+pub fn create_statement_argument(
+    argv: *mut *mut rm::ffi::RedisModuleString,
+    argc: i32,
+    names: usize,
+) -> Result<(Vec<&'static str>, Vec<Argument<'static>>), RediSQLError>
+{
+    let args = create_binary_argument(argv, argc);
+    let names = names.min(args.len());
+    let values = args[names..]
+        .iter()
+        .map(|arg| Argument::untyped(arg))
+        .collect();
+    let names = args[..names]
+        .iter()
+        .map(|arg| argument_to_str(arg))
+        .collect::<Result<_, _>>()?;
+    Ok((names, values))
+}
+
+pub fn argument_to_str(arg: &[u8]) -> Result<&str, RediSQLError> {
+    match str::from_utf8(arg) {
+        Err(e) => Err(RediSQLError::new(
+            format!(
+                "String valid up to byte number {}",
+                e.valid_up_to()
+            ),
+            "Got a non-valid UTF8 string as input".to_string(),
+        )),
+        Ok(s) => Ok(s.trim_end_matches(char::from(0))),
+    }
 }
 
 unsafe fn string_ptr_len(
     str: *mut rm::ffi::RedisModuleString,
-) -> Result<&'static str, std::str::Utf8Error> {
+) -> &'static [u8] {
     let mut len = 0;
     let base =
         rm::ffi::RedisModule_StringPtrLen.unwrap()(str, &mut len)
             as *mut u8;
-    let slice = slice::from_raw_parts(base, len);
-    let s = str::from_utf8(slice)?;
-    Ok(s.trim_end_matches(char::from(0)))
+    slice::from_raw_parts(base, len)
 }
 
 pub enum KeyTypes {
@@ -602,12 +862,9 @@ impl RedisKey {
             _ => Err(RediSQLError::no_redisql_key()),
         }
     }
-    pub fn get_channel(
-        &self,
-    ) -> Result<Sender<Command>, RediSQLError> {
+    pub fn get_channel(&self) -> Result<DBChannel, RediSQLError> {
         let dbkey = self.get_dbkey()?;
-        let channel = dbkey.tx.clone();
-        Ok(channel)
+        Ok(dbkey.channel())
     }
     pub fn get_db(
         &self,
@@ -619,6 +876,72 @@ impl RedisKey {
         let dbkey = self.get_dbkey()?;
         Ok(dbkey.loop_data.clone())
     }
+    /// Check if a command, part or not of a transaction, can run on the
+    /// database, no command runs while the database is loaded from a
+    /// file.
+    /// Transactions that were idle for too long are closed here, if the
+    /// database thread did not close them already.
+    pub fn check_transaction(
+        &self,
+        ctx: &Context,
+        database: &str,
+        token: Option<&str>,
+    ) -> Result<TransactionState, RediSQLError> {
+        let dbkey = self.get_dbkey()?;
+        if dbkey.loop_data.get_backup_state().is_loading() {
+            return Err(backup_in_progress());
+        }
+        let transaction = dbkey.loop_data.get_transaction();
+        if let Some((expired, _)) = transaction.take_expired() {
+            let _ = dbkey.tx.send(Command::EndTransaction {
+                token: expired,
+                database: database.to_string(),
+                commit: false,
+                client: None,
+            });
+            replicate_transaction_statement(
+                ctx, database, "ROLLBACK",
+            );
+        }
+        transaction.check(token)?;
+        Ok(transaction)
+    }
+}
+
+/// Check that a command outside of any transaction can run on the
+/// database, for the commands that do not take a transaction token.
+pub fn check_no_transaction(
+    ctx: &Context,
+    database: &str,
+) -> Result<(), RediSQLError> {
+    RedisKey::new(database, ctx)
+        .check_transaction(ctx, database, None)
+        .map(|_| ())
+}
+
+/// The image of the committed content of the database: while a
+/// transaction is open, the image of the snapshot taken before its BEGIN.
+pub fn committed_image<L: LoopData>(
+    loopdata: &L,
+) -> Result<Vec<u8>, SQLite3Error> {
+    let db = loopdata.get_db();
+    // the worker keeps the snapshot before it starts the transaction
+    let conn = lock_connection(&db);
+    match loopdata.get_transaction().open_snapshot()? {
+        Some(snapshot) => sql::serialize(&snapshot),
+        None => sql::serialize(&conn),
+    }
+}
+
+/// A connection to the committed content of the database, to the
+/// snapshot taken before the BEGIN if a transaction is open.
+pub fn committed_connection<L: LoopData>(
+    loopdata: &L,
+) -> Result<ConcurrentConnection, SQLite3Error> {
+    match loopdata.get_transaction().open_snapshot()? {
+        Some(snapshot) => Ok(Arc::new(Mutex::new(snapshot))),
+        None => Ok(loopdata.get_db()),
+    }
 }
 
 impl Drop for RedisKey {
@@ -632,7 +955,19 @@ impl Drop for RedisKey {
 pub enum ReturnMethod {
     Reply,
     ReplyWithHeader,
-    Stream { name: &'static str },
+    /// Each row as a map from the names of the columns to the values.
+    ReplyWithMap,
+    /// The rows as a single JSON document, with `table` an object with
+    /// the columns, their types and the rows, otherwise an array of
+    /// objects. With `bigint_as_string` the integers beyond 2^53 are
+    /// replied as strings.
+    ReplyWithJson {
+        table: bool,
+        bigint_as_string: bool,
+    },
+    Stream {
+        name: &'static str,
+    },
 }
 
 pub enum Command {
@@ -640,14 +975,14 @@ pub enum Command {
     Stop,
     Exec {
         query: &'static str,
-        arguments: Vec<&'static str>,
+        arguments: Bindings<'static>,
         timeout: std::time::Instant,
         return_method: ReturnMethod,
         client: BlockedClient,
     },
     Query {
         query: &'static str,
-        arguments: Vec<&'static str>,
+        arguments: Bindings<'static>,
         timeout: std::time::Instant,
         return_method: ReturnMethod,
         client: BlockedClient,
@@ -660,7 +995,15 @@ pub enum Command {
     },
     ExecStatement {
         identifier: &'static str,
-        arguments: Vec<&'static str>,
+        arguments: Bindings<'static>,
+        timeout: std::time::Instant,
+        return_method: ReturnMethod,
+        client: BlockedClient,
+    },
+    ExecStatementBatch {
+        identifier: &'static str,
+        batch: Vec<Bindings<'static>>,
+        with_errors: bool,
         timeout: std::time::Instant,
         return_method: ReturnMethod,
         client: BlockedClient,
@@ -677,17 +1020,42 @@ pub enum Command {
     },
     QueryStatement {
         identifier: &'static str,
-        arguments: Vec<&'static str>,
+        arguments: Bindings<'static>,
         timeout: std::time::Instant,
         return_method: ReturnMethod,
         client: BlockedClient,
     },
     ShowStatement {
         identifier: &'static str,
+        timeout: std::time::Instant,
         return_method: ReturnMethod,
         client: BlockedClient,
     },
     ListStatements {
+        timeout: std::time::Instant,
+        return_method: ReturnMethod,
+        client: BlockedClient,
+    },
+    CreateFunction {
+        definition: FunctionDefinition,
+        can_update: bool,
+        client: BlockedClient,
+    },
+    DeleteFunction {
+        name: &'static str,
+        client: BlockedClient,
+    },
+    ListFunctions {
+        timeout: std::time::Instant,
+        return_method: ReturnMethod,
+        client: BlockedClient,
+    },
+    LoadExtension {
+        name: &'static str,
+        client: BlockedClient,
+    },
+    ListExtensions {
+        timeout: std::time::Instant,
         return_method: ReturnMethod,
         client: BlockedClient,
     },
@@ -695,6 +1063,145 @@ pub enum Command {
         destination: DBKey<'static>,
         client: BlockedClient,
     },
+    /// The content of the database, as replied by `REDISQL.DUMP`.
+    Dump {
+        format: DumpFormat,
+        client: BlockedClient,
+    },
+    /// Open the transaction, the BEGIN is replicated once it succeeds.
+    BeginTransaction {
+        token: String,
+        database: String,
+        client: BlockedClient,
+    },
+    /// Commit or roll back the transaction, without a client the
+    /// transaction is rolled back because the client disconnected or
+    /// it was idle for too long, and the rollback is already replicated.
+    EndTransaction {
+        token: String,
+        database: String,
+        commit: bool,
+        client: Option<BlockedClient>,
+    },
+    InTransaction {
+        token: &'static str,
+        command: Box<Command>,
+    },
+    OpenCursor {
+        query: CursorQuery,
+        arguments: Bindings<'static>,
+        count: usize,
+        timeout: std::time::Instant,
+        return_method: ReturnMethod,
+        client: BlockedClient,
+    },
+    FetchCursor {
+        id: u64,
+        count: Option<usize>,
+        timeout: std::time::Instant,
+        return_method: ReturnMethod,
+        client: BlockedClient,
+    },
+    CloseCursor {
+        id: u64,
+        client: BlockedClient,
+    },
+    /// Write the entries of a stream in a table, and acknowledge them to
+    /// the consumer group they were read from, if any.
+    IngestStream {
+        table: &'static str,
+        id_column: Option<&'static str>,
+        entries: Vec<StreamEntry>,
+        /// The arguments to replicate the ingestion, once the entries
+        /// are in the database.
+        replicate: Vec<Vec<String>>,
+        ack: Option<(&'static str, &'static str)>,
+        timeout: std::time::Instant,
+        return_method: ReturnMethod,
+        client: BlockedClient,
+    },
+    /// Insert the records of a CSV in a table, in a single transaction.
+    ImportCsv {
+        table: &'static str,
+        csv: Vec<u8>,
+        options: CsvOptions,
+        types: Option<Vec<ColumnType>>,
+        timeout: std::time::Instant,
+        return_method: ReturnMethod,
+        client: BlockedClient,
+    },
+    /// Write the rows returned by a query as CSV, in a file or in the
+    /// reply.
+    ExportCsv {
+        query: &'static str,
+        path: Option<&'static str>,
+        options: CsvOptions,
+        timeout: std::time::Instant,
+        client: BlockedClient,
+    },
+    /// Copy the database to a file, or a file to the database, a few
+    /// pages at the time while the other commands keep running.
+    Backup {
+        direction: BackupDirection,
+        path: String,
+        /// The content of the file to load when it comes with the
+        /// command, otherwise the thread of the database reads the file.
+        image: Option<Vec<u8>>,
+        /// The arguments to replicate the load, with the content of the
+        /// file, once the load starts.
+        replicate: Option<Vec<String>>,
+        pages: i32,
+        client: BlockedClient,
+    },
+}
+
+impl Command {
+    /// When the SQL executed by the command should be interrupted.
+    pub(crate) fn deadline(&self) -> Option<std::time::Instant> {
+        match self {
+            Command::Exec { timeout, .. }
+            | Command::Query { timeout, .. }
+            | Command::ExecStatement { timeout, .. }
+            | Command::ExecStatementBatch { timeout, .. }
+            | Command::QueryStatement { timeout, .. }
+            | Command::OpenCursor { timeout, .. }
+            | Command::FetchCursor { timeout, .. }
+            | Command::IngestStream { timeout, .. }
+            | Command::ImportCsv { timeout, .. }
+            | Command::ExportCsv { timeout, .. } => Some(*timeout),
+            _ => None,
+        }
+    }
+    /// If the command only reads the database, so that it can be
+    /// executed by a reader thread.
+    pub(crate) fn is_query(&self) -> bool {
+        matches!(
+            self,
+            Command::Query { .. } | Command::QueryStatement { .. }
+        )
+    }
+}
+
+/// Where the commands of a database are sent.
+/// Queries go to the reader threads of the database, if it has any,
+/// everything else goes to the writer thread.
+#[derive(Clone)]
+pub struct DBChannel {
+    writer: Sender<Command>,
+    readers: Readers,
+}
+
+impl DBChannel {
+    pub fn send(
+        &self,
+        command: Command,
+    ) -> Result<(), SendError<Command>> {
+        if command.is_query() && !self.readers.is_empty() {
+            self.readers.send(command)
+        } else {
+            self.writer.send(command)
+        }
+    }
 }
 
 struct SQLiteResultIterator<'s> {
@@ -832,6 +1339,33 @@ impl RowFiller for QueryResultRowFiller<'_> {
                 self.filled_rows += 1;
                 Some(len)
             }
+            QueryResult::Page {
+                cursor,
+                array,
+                names,
+                ..
+            } => {
+                if self.filled_rows == 0 {
+                    row.push(Entity::Text {
+                        text: "CURSOR".to_string(),
+                    });
+                    row.push(Entity::Integer {
+                        int: *cursor as i64,
+                    });
+                    self.filled_rows += 1;
+                    return Some(2);
+                }
+                let len = names.len();
+                let offset = (self.filled_rows as usize - 1) * len;
+                if len == 0 || array.len() <= offset {
+                    return None;
+                }
+                for i in 0..len {
+                    row.push(array[offset + i].clone())
+                }
+                self.filled_rows += 1;
+                Some(len)
+            }
             QueryResult::Stream {
                 stream,
                 first_id,
@@ -955,13 +1489,62 @@ impl Returner for QueryResult {
                     };
                     Box::new(Box::new(res))
                 }
+                QueryResult::Page {
+                    cursor,
+                    mut array,
+                    names,
+                    types,
+                } => {
+                    let mut new_array = Vec::with_capacity(
+                        names.len() + types.len() + array.len(),
+                    );
+                    for name in names.clone() {
+                        new_array.push(Entity::Text { text: name });
+                    }
+                    for t in &types {
+                        new_array.push(Entity::Text {
+                            text: t.to_string(),
+                        });
+                    }
+                    new_array.append(&mut array);
+                    let res = QueryResult::Page {
+                        cursor,
+                        names,
+                        types,
+                        array: new_array,
+                    };
+                    Box::new(Box::new(res))
+                }
                 _ => Box::new(Box::new(self)),
             },
+            ReturnMethod::ReplyWithMap => {
+                Box::new(Box::new(MapReply::new(Ok(self), vec![])))
+            }
+            ReturnMethod::ReplyWithJson {
+                table,
+                bigint_as_string,
+            } => Box::new(Box::new(JsonReply::new(
+                Ok(self),
+                vec![],
+                *table,
+                *bigint_as_string,
+            ))),
             _ => Box::new(Box::new(self)),
         }
     }
 }
 
+impl Returner for Entity {
+    fn create_data_to_return(
+        self,
+        _ctx: &Context,
+        _return_method: &ReturnMethod,
+        _timeout: std::time::Instant,
+    ) -> Box<Box<dyn RedisReply>> {
+        Box::new(Box::new(self))
+    }
+}
+
 impl Returner for RediSQLError {
     fn create_data_to_return(
         self,
@@ -1028,6 +1611,33 @@ impl<'s> Returner for Cursor {
                         );
                     Box::new(Box::new(query_result))
                 }
+                ReturnMethod::ReplyWithMap => {
+                    let booleans = self.boolean_columns();
+                    let query_result =
+                        QueryResult::from_cursor_before(
+                            self, timeout,
+                        );
+                    Box::new(Box::new(MapReply::new(
+                        query_result,
+                        booleans,
+                    )))
+                }
+                ReturnMethod::ReplyWithJson {
+                    table,
+                    bigint_as_string,
+                } => {
+                    let booleans = self.boolean_columns();
+                    let query_result =
+                        QueryResult::from_cursor_before(
+                            self, timeout,
+                        );
+                    Box::new(Box::new(JsonReply::new(
+                        query_result,
+                        booleans,
+                        *table,
+                        *bigint_as_string,
+                    )))
+                }
             },
             Cursor::OKCursor => {
                 Box::new(Box::new(QueryResult::OK {}))
@@ -1067,6 +1677,12 @@ impl RedisReply for QueryResult {
                 debug!("QueryResult::Array");
                 reply_with_array(ctx, array.chunks(names.len()))
             }
+            QueryResult::Page { array, names, .. } => {
+                reply_with_array(
+                    ctx,
+                    array.chunks(names.len().max(1)),
+                )
+            }
             QueryResult::Stream {
                 stream,
                 first_id,
@@ -1100,8 +1716,8 @@ impl RedisReply for QueryResult {
 pub fn do_execute(
     db: &ConcurrentConnection,
     query: &str,
-    args: &Vec<&str>,
-) -> Result<impl Returner, err::RediSQLError> {
+    args: &Bindings,
+) -> Result<Cursor, err::RediSQLError> {
     let mut stmt = MultiStatement::new(db.clone(), query)?;
     stmt.reset();
     let stmt = bind_statement(&mut stmt, args)?;
@@ -1114,8 +1730,8 @@ pub fn do_execute(
 pub fn do_query(
     db: &ConcurrentConnection,
     query: &str,
-    args: &Vec<&str>,
-) -> Result<impl Returner, err::RediSQLError> {
+    args: &Bindings,
+) -> Result<Cursor, err::RediSQLError> {
     let mut stmt = MultiStatement::new(db.clone(), query)?;
     if stmt.is_read_only() {
         stmt.reset();
@@ -1144,8 +1760,8 @@ pub fn do_copy(
     let backup_result = {
         let destination_db = destination_loopdata.get_db();
 
-        let destination_db = destination_db.lock().unwrap();
-        let source_db = source_db.lock().unwrap();
+        let destination_db = lock_connection(&destination_db);
+        let source_db = lock_connection(&source_db);
         match make_backup(&source_db, &destination_db) {
             Err(e) => Err(RediSQLError::from(e)),
             Ok(_) => Ok(QueryResult::OK {}),
@@ -1164,11 +1780,15 @@ pub fn do_copy(
     backup_result
 }
 
-fn bind_statement<'a>(
+pub(crate) fn bind_statement<'a>(
     stmt: &'a MultiStatement,
-    arguments: &[&str],
+    arguments: &Bindings,
 ) -> Result<&'a MultiStatement, SQLite3Error> {
-    match stmt.bind_texts(arguments) {
+    let bound = match arguments {
+        Bindings::Positional(values) => stmt.bind_arguments(values),
+        Bindings::Named(values) => stmt.bind_named_arguments(values),
+    };
+    match bound {
         Err(e) => Err(e),
         Ok(_) => Ok(stmt),
     }
@@ -1182,6 +1802,9 @@ fn bind_statement<'a>(
 /// I am not sure if it is a good idea or if I should upgrade the code to return an error, and
 /// maybe just ignore the error to keep the whole flow as it is now.
 fn restore_previous_statements<'a, L: 'a + LoopData>(loopdata: &L) {
+    // the extensions and the functions first, the statements may use them
+    restore_extensions(loopdata);
+    restore_functions(loopdata);
     let saved_statements = get_statement_metadata(loopdata.get_db());
     match saved_statements {
         Ok(QueryResult::Array { array, names, .. }) => {
@@ -1206,10 +1829,203 @@ fn restore_previous_statements<'a, L: 'a + LoopData>(loopdata: &L) {
     }
 }
 
-fn return_value(
-    client: &BlockedClient,
-    return_method: &ReturnMethod,
-    result: Result<impl Returner, err::RediSQLError>,
+/// Load again the extensions whose names are in the metadata table, the
+/// ones that cannot be loaded are skipped with a warning.
+fn restore_extensions<'a, L: 'a + LoopData>(loopdata: &L) {
+    let db = loopdata.get_db();
+    let book = loopdata.get_replication_book();
+    match get_extension_metadata(db.clone()) {
+        Ok(QueryResult::Array { array, .. }) => {
+            for value in array {
+                let name = match value {
+                    Entity::Text { ref text } => text,
+                    _ => continue,
+                };
+                if let Err(e) =
+                    book.extensions().load(&db, name, |_| Ok(()))
+                {
+                    warn!(
+                        "Error loading the extension {}: {}",
+                        name, e
+                    )
+                }
+            }
+        }
+        Err(e) => {
+            warn!("Error reading the extensions to load: {}", e)
+        }
+        _ => (),
+    }
+}
+
+/// Register again the functions whose definitions are in the metadata
+/// table, the ones that cannot be registered are skipped with a warning.
+fn restore_functions<'a, L: 'a + LoopData>(loopdata: &L) {
+    let db = loopdata.get_db();
+    let book = loopdata.get_replication_book();
+    match get_function_metadata(db.clone()) {
+        Ok(QueryResult::Array { array, .. }) => {
+            for value in array {
+                let definition = match value {
+                    Entity::Text { ref text } => {
+                        FunctionDefinition::from_metadata(text)
+                    }
+                    _ => continue,
+                };
+                let restored = definition.and_then(|definition| {
+                    book.functions().insert(
+                        &db,
+                        definition,
+                        true,
+                        |_| Ok(()),
+                    )
+                });
+                if let Err(e) = restored {
+                    warn!("Error restoring a function: {}", e)
+                }
+            }
+        }
+        Err(e) => {
+            warn!("Error reading the functions to restore: {}", e)
+        }
+        _ => (),
+    }
+}
+
+/// Publish the changes committed by a command to the change stream of
+/// the database, before replying to the client.
+fn publish_changes<L: LoopData>(
+    loopdata: &L,
+    client: &BlockedClient,
+) {
+    if let Some(changes) = loopdata.get_change_capture() {
+        let ctx = Context::thread_safe(client);
+        if let Err(e) = changes.publish(&ctx, &loopdata.get_db()) {
+            debug!("Error publishing the changes: {}", e);
+        }
+    }
+}
+
+/// The rows returned by a write inside a transaction, added to their
+/// stream once the transaction commits.
+struct PendingStream {
+    stream: &'static str,
+    names: Vec<String>,
+    array: Vec<Entity>,
+}
+
+/// Reply to a write that adds the rows it returns to a stream.
+///
+/// All the rows are read first, so that the statement is complete and its
+/// changes committed before they are added to the stream. Inside a
+/// transaction the rows wait for the commit in `pending`, meanwhile they
+/// are returned to the client.
+fn return_written_rows<L: LoopData>(
+    loopdata: &L,
+    client: &BlockedClient,
+    stream: &'static str,
+    result: Result<Cursor, RediSQLError>,
+    pending: Option<&mut Vec<PendingStream>>,
+    timeout: std::time::Instant,
+) {
+    let result = result.and_then(QueryResult::try_from);
+    publish_changes(loopdata, client);
+    match (pending, result) {
+        (
+            Some(pending),
+            Ok(QueryResult::Array {
+                names,
+                types,
+                array,
+            }),
+        ) => {
+            pending.push(PendingStream {
+                stream,
+                names: names.clone(),
+                array: array.clone(),
+            });
+            let rows = QueryResult::Array {
+                names,
+                types,
+                array,
+            };
+            return_value(
+                client,
+                &ReturnMethod::ReplyWithHeader,
+                Ok(rows),
+                timeout,
+            );
+        }
+        (_, result) => return_value(
+            client,
+            &ReturnMethod::Stream { name: stream },
+            result,
+            timeout,
+        ),
+    }
+}
+
+/// Add to their streams the rows written by a committed transaction.
+fn add_pending_streams(
+    client: &BlockedClient,
+    pending: Vec<PendingStream>,
+) {
+    if pending.is_empty() {
+        return;
+    }
+    let ctx = Context::thread_safe(client);
+    for rows in pending {
+        if let Err(e) = stream_query_result_array(
+            &ctx,
+            rows.stream,
+            &rows.names,
+            rows.array.chunks(rows.names.len()),
+            default_deadline(),
+        ) {
+            // the transaction is already committed, so it is not failed
+            warn!(
+                "The rows committed are not added to the stream {}: {}",
+                rows.stream, e
+            );
+        }
+    }
+}
+
+/// Update the statistics of the queries.
+pub(crate) fn count_query(return_method: &ReturnMethod, ok: bool) {
+    match (return_method, ok) {
+        (ReturnMethod::Stream { .. }, true) => {
+            STATISTICS.query_into_ok()
+        }
+        (ReturnMethod::Stream { .. }, false) => {
+            STATISTICS.query_into_err()
+        }
+        (_, true) => STATISTICS.query_ok(),
+        (_, false) => STATISTICS.query_err(),
+    }
+}
+
+/// Update the statistics of the queries of statements.
+pub(crate) fn count_query_statement(
+    return_method: &ReturnMethod,
+    ok: bool,
+) {
+    match (return_method, ok) {
+        (ReturnMethod::Stream { .. }, true) => {
+            STATISTICS.query_statement_into_ok()
+        }
+        (ReturnMethod::Stream { .. }, false) => {
+            STATISTICS.query_statement_into_err()
+        }
+        (_, true) => STATISTICS.query_statement_ok(),
+        (_, false) => STATISTICS.query_statement_err(),
+    }
+}
+
+pub(crate) fn return_value(
+    client: &BlockedClient,
+    return_method: &ReturnMethod,
+    result: Result<impl Returner, err::RediSQLError>,
     timeout: std::time::Instant,
 ) {
     let ctx = Context::thread_safe(client);
@@ -1229,6 +2045,41 @@ fn return_value(
     }
 }
 
+/// Add the value of a column to a stream entry, the name of the field is
+/// prefixed by the type of the value, like `int:column`.
+pub(crate) fn add_entity_to_xadd(
+    xadd: &mut rm::XADDCommand,
+    column: &str,
+    entity: &Entity,
+) {
+    match entity {
+        Entity::OK {} | Entity::DONE { .. } => {
+            // do nothing
+        }
+        Entity::Null => {
+            xadd.add_element(&format!("null:{}", column), "(null)");
+        }
+        Entity::Integer { int } => {
+            xadd.add_element(
+                &format!("int:{}", column),
+                &int.to_string(),
+            );
+        }
+        Entity::Float { float } => {
+            xadd.add_element(
+                &format!("real:{}", column),
+                &float.to_string(),
+            );
+        }
+        Entity::Text { text } => {
+            xadd.add_element(&format!("text:{}", column), &text);
+        }
+        Entity::Blob { blob } => {
+            xadd.add_blob_element(&format!("blob:{}", column), &blob);
+        }
+    }
+}
+
 pub fn stream_query_result_array<A>(
     context: &Context,
     stream_name: &str,
@@ -1273,41 +2124,7 @@ where
         let mut xadd = rm::XADDCommand::new(&context, stream_name);
 
         for (j, entity) in row.iter().enumerate() {
-            match entity {
-                Entity::OK {} | Entity::DONE { .. } => {
-                    // do nothing
-                }
-                Entity::Null => {
-                    xadd.add_element(
-                        &format!("null:{}", &columns_names[j]),
-                        "(null)",
-                    );
-                }
-                Entity::Integer { int } => {
-                    xadd.add_element(
-                        &format!("int:{}", &columns_names[j]),
-                        &int.to_string(),
-                    );
-                }
-                Entity::Float { float } => {
-                    xadd.add_element(
-                        &format!("real:{}", &columns_names[j]),
-                        &float.to_string(),
-                    );
-                }
-                Entity::Text { text } => {
-                    xadd.add_element(
-                        &format!("text:{}", &columns_names[j]),
-                        &text,
-                    );
-                }
-                Entity::Blob { blob } => {
-                    xadd.add_element(
-                        &format!("blob:{}", &columns_names[j]),
-                        &blob,
-                    );
-                }
-            }
+            add_entity_to_xadd(&mut xadd, &columns_names[j], entity);
         }
         debug!("XADD {:?}", xadd);
         let xadd_result = xadd.execute(&lock);
@@ -1373,11 +2190,42 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
     rx: &Receiver<Command>,
 ) {
     debug!("Start thread execution");
+    serve(&loopdata.get_db());
     restore_previous_statements(loopdata);
     debug!("Done restoring statements");
+    let mut open_transaction: Option<String> = None;
+    let mut pending_streams: Vec<PendingStream> = Vec::new();
+    let mut cursors = OpenCursors::new();
+    let mut backups = Backups::new(loopdata.get_backup_state());
     loop {
         debug!("Loop iteration");
-        match rx.recv() {
+        let received = match receive_command(
+            loopdata,
+            rx,
+            &mut open_transaction,
+            &mut cursors,
+            &mut backups,
+        ) {
+            Ok(command) => {
+                match admit_command(
+                    &open_transaction,
+                    &backups,
+                    command,
+                ) {
+                    Some(command) => Ok(command),
+                    None => continue,
+                }
+            }
+            Err(e) => Err(e),
+        };
+        let _interrupt = match received
+            .as_ref()
+            .map(Command::deadline)
+        {
+            Ok(Some(deadline)) => Some(InterruptAfter::new(deadline)),
+            _ => None,
+        };
+        match received {
             Ok(Command::Ping {}) => debug!("Received PING!"),
             Ok(Command::Exec {
                 query,
@@ -1393,12 +2241,29 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
                     Ok(_) => STATISTICS.exec_ok(),
                     Err(_) => STATISTICS.exec_err(),
                 }
-                return_value(
-                    &client,
-                    &return_method,
-                    result,
-                    timeout,
-                );
+                match return_method {
+                    ReturnMethod::Stream { name } => {
+                        return_written_rows(
+                            loopdata,
+                            &client,
+                            name,
+                            result,
+                            open_transaction
+                                .as_ref()
+                                .map(|_| &mut pending_streams),
+                            timeout,
+                        )
+                    }
+                    _ => {
+                        publish_changes(loopdata, &client);
+                        return_value(
+                            &client,
+                            &return_method,
+                            result,
+                            timeout,
+                        );
+                    }
+                }
                 debug!("Exec | DONE, returning result");
             }
             Ok(Command::Query {
@@ -1411,27 +2276,7 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
                 debug!("Query | Query = {:?}", query);
                 let result =
                     do_query(&loopdata.get_db(), query, &arguments);
-
-                match (&return_method, &result) {
-                    (ReturnMethod::Reply, Ok(_)) => {
-                        STATISTICS.query_ok()
-                    }
-                    (ReturnMethod::Reply, Err(_)) => {
-                        STATISTICS.query_err()
-                    }
-                    (ReturnMethod::ReplyWithHeader, Ok(_)) => {
-                        STATISTICS.query_ok()
-                    }
-                    (ReturnMethod::ReplyWithHeader, Err(_)) => {
-                        STATISTICS.query_err()
-                    }
-                    (ReturnMethod::Stream { .. }, Ok(_)) => {
-                        STATISTICS.query_into_ok()
-                    }
-                    (ReturnMethod::Stream { .. }, Err(_)) => {
-                        STATISTICS.query_into_err()
-                    }
-                };
+                count_query(&return_method, result.is_ok());
                 return_value(
                     &client,
                     &return_method,
@@ -1457,8 +2302,7 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
                     Ok(_) => STATISTICS.update_statement_ok(),
                     Err(_) => STATISTICS.update_statement_err(),
                 };
-                let t = std::time::Instant::now()
-                    + std::time::Duration::from_secs(10);
+                let t = default_deadline();
 
                 return_value(&client, &ReturnMethod::Reply, result, t)
             }
@@ -1474,8 +2318,7 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
                     Ok(_) => STATISTICS.delete_statement_ok(),
                     Err(_) => STATISTICS.delete_statement_err(),
                 }
-                let t = std::time::Instant::now()
-                    + std::time::Duration::from_secs(10);
+                let t = default_deadline();
 
                 return_value(
                     &client,
@@ -1503,8 +2346,7 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
                     Ok(_) => STATISTICS.create_statement_ok(),
                     Err(_) => STATISTICS.create_statement_err(),
                 }
-                let t = std::time::Instant::now()
-                    + std::time::Duration::from_secs(10);
+                let t = default_deadline();
 
                 return_value(
                     &client,
@@ -1532,7 +2374,55 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
                     Ok(_) => STATISTICS.exec_statement_ok(),
                     Err(_) => STATISTICS.exec_statement_err(),
                 }
-
+                match return_method {
+                    ReturnMethod::Stream { name } => {
+                        return_written_rows(
+                            loopdata,
+                            &client,
+                            name,
+                            result,
+                            open_transaction
+                                .as_ref()
+                                .map(|_| &mut pending_streams),
+                            timeout,
+                        )
+                    }
+                    _ => {
+                        publish_changes(loopdata, &client);
+                        return_value(
+                            &client,
+                            &return_method,
+                            result,
+                            timeout,
+                        );
+                    }
+                }
+            }
+            Ok(Command::ExecStatementBatch {
+                identifier,
+                batch,
+                with_errors,
+                timeout,
+                return_method,
+                client,
+            }) => {
+                debug!(
+                    "ExecStatementBatch | Identifier = {:?} Size = {:?}",
+                    identifier,
+                    batch.len()
+                );
+                let result = loopdata
+                    .get_replication_book()
+                    .exec_statement_batch(
+                        identifier,
+                        &batch,
+                        with_errors,
+                    );
+                match result {
+                    Ok(_) => STATISTICS.exec_statement_ok(),
+                    Err(_) => STATISTICS.exec_statement_err(),
+                }
+                publish_changes(loopdata, &client);
                 return_value(
                     &client,
                     &return_method,
@@ -1540,38 +2430,206 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
                     timeout,
                 );
             }
-            Ok(Command::QueryStatement {
-                identifier,
-                arguments,
-                return_method,
+            Ok(Command::IngestStream {
+                table,
+                id_column,
+                entries,
+                replicate,
+                ack,
                 timeout,
+                return_method,
                 client,
             }) => {
-                let result =
-                    loopdata.get_replication_book().query_statement(
-                        identifier,
-                        arguments.as_slice(),
+                debug!(
+                    "IngestStream | Table = {:?} Size = {:?}",
+                    table,
+                    entries.len()
+                );
+                let mut result = ingest_entries(
+                    &loopdata.get_db(),
+                    table,
+                    id_column,
+                    &entries,
+                );
+                if result.is_ok() {
+                    replicate_ingest(
+                        &Context::thread_safe(&client),
+                        &replicate,
                     );
-                match (&return_method, &result) {
-                    (ReturnMethod::Reply, Ok(_)) => {
-                        STATISTICS.query_statement_ok()
-                    }
-                    (ReturnMethod::Reply, Err(_)) => {
-                        STATISTICS.query_statement_err()
-                    }
-                    (ReturnMethod::ReplyWithHeader, Ok(_)) => {
-                        STATISTICS.query_statement_ok()
+                }
+                if let (Ok(_), Some((stream, group))) = (&result, ack)
+                {
+                    // the entries are already in the database, if the
+                    // acknowledgement fails they are delivered again
+                    let ctx = Context::thread_safe(&client);
+                    if let Err(e) =
+                        acknowledge(&ctx, stream, group, &entries)
+                    {
+                        result = Err(e);
                     }
-                    (ReturnMethod::ReplyWithHeader, Err(_)) => {
-                        STATISTICS.query_statement_err()
+                }
+                match result {
+                    Ok(_) => STATISTICS.exec_ok(),
+                    Err(_) => STATISTICS.exec_err(),
+                }
+                publish_changes(loopdata, &client);
+                return_value(
+                    &client,
+                    &return_method,
+                    result,
+                    timeout,
+                );
+            }
+            Ok(Command::ImportCsv {
+                table,
+                csv,
+                options,
+                types,
+                timeout,
+                return_method,
+                client,
+            }) => {
+                debug!("ImportCsv | Table = {:?}", table);
+                let result = import_csv(
+                    &loopdata.get_db(),
+                    table,
+                    &csv,
+                    &options,
+                    types.as_deref(),
+                );
+                match result {
+                    Ok(_) => STATISTICS.exec_ok(),
+                    Err(_) => STATISTICS.exec_err(),
+                }
+                publish_changes(loopdata, &client);
+                return_value(
+                    &client,
+                    &return_method,
+                    result,
+                    timeout,
+                );
+            }
+            Ok(Command::ExportCsv {
+                query,
+                path,
+                options,
+                timeout,
+                client,
+            }) => {
+                debug!("ExportCsv | Query = {:?}", query);
+                let db = loopdata.get_db();
+                match path {
+                    Some(path) => {
+                        let result = export_to_file(
+                            &db, query, path, &options, timeout,
+                        );
+                        count_query(
+                            &ReturnMethod::ReplyWithHeader,
+                            result.is_ok(),
+                        );
+                        return_value(
+                            &client,
+                            &ReturnMethod::ReplyWithHeader,
+                            result,
+                            timeout,
+                        );
                     }
-                    (ReturnMethod::Stream { .. }, Ok(_)) => {
-                        STATISTICS.query_statement_into_ok()
+                    None => {
+                        let result = export_to_payload(
+                            &db, query, &options, timeout,
+                        );
+                        count_query(
+                            &ReturnMethod::Reply,
+                            result.is_ok(),
+                        );
+                        return_value(
+                            &client,
+                            &ReturnMethod::Reply,
+                            result,
+                            timeout,
+                        );
                     }
-                    (ReturnMethod::Stream { .. }, Err(_)) => {
-                        STATISTICS.query_statement_into_err()
+                }
+            }
+            Ok(Command::OpenCursor {
+                query,
+                arguments,
+                count,
+                timeout,
+                return_method,
+                client,
+            }) => {
+                debug!("OpenCursor | Query = {:?}", query);
+                // statements are compiled again, so that the cursor is not
+                // reset by the next execution of the same statement
+                let sql = match query {
+                    CursorQuery::Command(query) => {
+                        Ok(query.to_string())
                     }
+                    CursorQuery::Statement(identifier) => loopdata
+                        .get_replication_book()
+                        .statement_sql(identifier),
                 };
+                let result = sql
+                    .and_then(|sql| {
+                        do_query(&loopdata.get_db(), &sql, &arguments)
+                    })
+                    .and_then(|cursor| {
+                        cursors.open(
+                            client.client_id(),
+                            cursor,
+                            count,
+                            timeout,
+                        )
+                    });
+                return_value(
+                    &client,
+                    &return_method,
+                    result,
+                    timeout,
+                );
+            }
+            Ok(Command::FetchCursor {
+                id,
+                count,
+                timeout,
+                return_method,
+                client,
+            }) => {
+                let result = cursors.next(
+                    client.client_id(),
+                    id,
+                    count,
+                    timeout,
+                );
+                return_value(
+                    &client,
+                    &return_method,
+                    result,
+                    timeout,
+                );
+            }
+            Ok(Command::CloseCursor { id, client }) => {
+                let result = cursors.close(client.client_id(), id);
+                let t = default_deadline();
+                return_value(
+                    &client,
+                    &ReturnMethod::Reply,
+                    result,
+                    t,
+                );
+            }
+            Ok(Command::QueryStatement {
+                identifier,
+                arguments,
+                return_method,
+                timeout,
+                client,
+            }) => {
+                let result = loopdata
+                    .get_replication_book()
+                    .query_statement(identifier, &arguments);
+                count_query_statement(&return_method, result.is_ok());
 
                 return_value(
                     &client,
@@ -1582,25 +2640,118 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
             }
             Ok(Command::ShowStatement {
                 identifier,
+                timeout,
                 return_method,
                 client,
             }) => {
                 let result = loopdata
                     .get_replication_book()
                     .show_statement(identifier);
-                let t = std::time::Instant::now()
-                    + std::time::Duration::from_secs(10);
-                return_value(&client, &return_method, result, t);
+                return_value(
+                    &client,
+                    &return_method,
+                    result,
+                    timeout,
+                );
             }
             Ok(Command::ListStatements {
+                timeout,
                 return_method,
                 client,
             }) => {
                 let result =
                     loopdata.get_replication_book().list_statements();
-                let t = std::time::Instant::now()
-                    + std::time::Duration::from_secs(10);
-                return_value(&client, &return_method, result, t);
+                return_value(
+                    &client,
+                    &return_method,
+                    result,
+                    timeout,
+                );
+            }
+            Ok(Command::CreateFunction {
+                definition,
+                can_update,
+                client,
+            }) => {
+                debug!(
+                    "CreateFunction | Name = {:?}",
+                    definition.name
+                );
+                let result = loopdata
+                    .get_replication_book()
+                    .insert_function(definition, can_update);
+                return_value(
+                    &client,
+                    &ReturnMethod::Reply,
+                    result,
+                    default_deadline(),
+                );
+            }
+            Ok(Command::DeleteFunction { name, client }) => {
+                debug!("DeleteFunction | Name = {:?}", name);
+                let result = loopdata
+                    .get_replication_book()
+                    .delete_function(name);
+                return_value(
+                    &client,
+                    &ReturnMethod::Reply,
+                    result,
+                    default_deadline(),
+                );
+            }
+            Ok(Command::ListFunctions {
+                timeout,
+                return_method,
+                client,
+            }) => {
+                let result =
+                    loopdata.get_replication_book().list_functions();
+                return_value(
+                    &client,
+                    &return_method,
+                    result,
+                    timeout,
+                );
+            }
+            Ok(Command::Dump { format, client }) => {
+                debug!("Dump | Format = {:?}", format);
+                let result = dump(
+                    &lock_connection(&loopdata.get_db()),
+                    format,
+                )
+                .map(|blob| Entity::Blob { blob });
+                return_value(
+                    &client,
+                    &ReturnMethod::Reply,
+                    result,
+                    default_deadline(),
+                );
+            }
+            Ok(Command::LoadExtension { name, client }) => {
+                debug!("LoadExtension | Name = {:?}", name);
+                let result = loopdata
+                    .get_replication_book()
+                    .load_extension(name);
+                return_value(
+                    &client,
+                    &ReturnMethod::Reply,
+                    result,
+                    default_deadline(),
+                );
+            }
+            Ok(Command::ListExtensions {
+                timeout,
+                return_method,
+                client,
+            }) => {
+                let result =
+                    loopdata.get_replication_book().list_extensions();
+                return_value(
+                    &client,
+                    &return_method,
+                    result,
+                    timeout,
+                );
             }
             Ok(Command::MakeCopy {
                 destination,
@@ -1614,8 +2765,7 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
                     Ok(_) => STATISTICS.copy_ok(),
                     Err(_) => STATISTICS.copy_err(),
                 };
-                let t = std::time::Instant::now()
-                    + std::time::Duration::from_secs(10);
+                let t = default_deadline();
 
                 return_value(
                     &client,
@@ -1625,8 +2775,208 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
                 );
                 std::mem::forget(destination);
             }
+            Ok(Command::BeginTransaction {
+                token,
+                database,
+                client,
+            }) => {
+                debug!("BeginTransaction | Token = {:?}", token);
+                let db = loopdata.get_db();
+                let transaction = loopdata.get_transaction();
+                // in WAL mode the content committed before the BEGIN
+                // stays readable by the other connections
+                let wal = if lock_connection(&db).is_in_memory() {
+                    Ok(())
+                } else {
+                    MultiStatement::new(
+                        db.clone(),
+                        "PRAGMA journal_mode=WAL;",
+                    )
+                    .and_then(|stmt| stmt.execute().map(|_| ()))
+                    .map_err(RediSQLError::from)
+                };
+                let begin = wal
+                    .and_then(|_| {
+                        Snapshot::take(&lock_connection(&db))
+                            .map_err(RediSQLError::from)
+                    })
+                    .and_then(|snapshot| {
+                        transaction.keep_snapshot(snapshot);
+                        do_execute(&db, "BEGIN", &Bindings::default())
+                    });
+                let result = match begin {
+                    Ok(_) => {
+                        replicate_transaction_outcome(
+                            &client, &database, "BEGIN",
+                        );
+                        open_transaction = Some(token.clone());
+                        pending_streams.clear();
+                        Ok(QueryResult::Array {
+                            names: vec![String::from("token")],
+                            types: vec!["TEXT"],
+                            array: vec![Entity::Text { text: token }],
+                        })
+                    }
+                    Err(e) => {
+                        transaction.drop_snapshot();
+                        let _ = transaction.end(&token);
+                        Err(e)
+                    }
+                };
+                let t = default_deadline();
+                return_value(
+                    &client,
+                    &ReturnMethod::Reply,
+                    result,
+                    t,
+                );
+            }
+            Ok(Command::EndTransaction {
+                token,
+                database,
+                commit,
+                client,
+            }) => {
+                debug!(
+                    "EndTransaction | Token = {:?} Commit = {:?}",
+                    token, commit
+                );
+                let result = match open_transaction {
+                    Some(ref open) if *open == token => {
+                        open_transaction = None;
+                        let result = end_transaction(
+                            &loopdata.get_db(),
+                            commit,
+                        );
+                        loopdata.get_transaction().drop_snapshot();
+                        // a failed COMMIT rolls back, so do the replicas
+                        if let Some(client) = &client {
+                            let statement =
+                                if commit && result.is_ok() {
+                                    "COMMIT"
+                                } else {
+                                    "ROLLBACK"
+                                };
+                            replicate_transaction_outcome(
+                                client, &database, statement,
+                            );
+                        }
+                        result
+                    }
+                    _ => Err(RediSQLError::transaction_not_found(
+                        &token,
+                    )),
+                };
+                let pending = std::mem::take(&mut pending_streams);
+                if let Some(client) = client {
+                    publish_changes(loopdata, &client);
+                    if commit && result.is_ok() {
+                        add_pending_streams(&client, pending);
+                    }
+                    let t = default_deadline();
+                    return_value(
+                        &client,
+                        &ReturnMethod::Reply,
+                        result,
+                        t,
+                    );
+                }
+            }
+            Ok(command @ Command::InTransaction { .. }) => {
+                reject_command(
+                    command,
+                    RediSQLError::transaction_in_progress(),
+                );
+            }
+            Ok(Command::Backup {
+                direction,
+                path,
+                image,
+                replicate,
+                pages,
+                client,
+            }) => {
+                debug!("Backup | Path = {:?}", path);
+                if backups.is_running() {
+                    backups.done(direction);
+                    reject_command(
+                        Command::Backup {
+                            direction,
+                            path,
+                            image,
+                            replicate,
+                            pages,
+                            client,
+                        },
+                        backup_in_progress(),
+                    );
+                    continue;
+                }
+                // the file is read here, not to stop Redis while it is
+                // read
+                let image = match (direction, image) {
+                    (BackupDirection::FromFile, None) => {
+                        read_image(Path::new(&path)).map(Some)
+                    }
+                    (_, image) => Ok(image),
+                };
+                let db = loopdata.get_db();
+                let job = image.and_then(|image| {
+                    let job = BackupJob::start(
+                        &lock_connection(&db),
+                        direction,
+                        &path,
+                        image.as_deref(),
+                        pages,
+                    )?;
+                    if let (Some(args), Some(image)) =
+                        (&replicate, &image)
+                    {
+                        replicate_load(
+                            &Context::thread_safe(&client),
+                            args,
+                            image,
+                        );
+                    }
+                    Ok(job)
+                });
+                match job {
+                    Ok(mut job) => {
+                        if direction == BackupDirection::FromFile {
+                            for (data_type, key) in &[
+                                ("readers", "readers"),
+                                ("cdc", "stream"),
+                            ] {
+                                let value = get_metadata(
+                                    db.clone(),
+                                    data_type,
+                                    key,
+                                );
+                                job.keep_metadata((
+                                    data_type, key, value,
+                                ));
+                            }
+                        }
+                        backups.run(job, &path, client);
+                    }
+                    Err(e) => {
+                        backups.done(direction);
+                        let result: Result<
+                            QueryResult,
+                            RediSQLError,
+                        > = Err(e);
+                        return_value(
+                            &client,
+                            &ReturnMethod::ReplyWithHeader,
+                            result,
+                            default_deadline(),
+                        )
+                    }
+                }
+            }
             Ok(Command::Stop) => {
                 dbg!("Stop, exiting from work loop");
+                abort_backup(loopdata, &mut backups);
                 return;
             }
             Err(RecvError) => {
@@ -1634,12 +2984,390 @@ pub fn listen_and_execute<'a, L: 'a + LoopData>(
                     "RecvError {}, exiting from work loop",
                     RecvError
                 );
+                abort_backup(loopdata, &mut backups);
                 return;
             }
         }
     }
 }
 
+/// Wait for the next command, while a transaction is open it is rolled
+/// back once it stays idle for too long, the same happens to the cursors
+/// that are not fetched anymore.
+fn receive_command<L: LoopData>(
+    loopdata: &L,
+    rx: &Receiver<Command>,
+    open_transaction: &mut Option<String>,
+    cursors: &mut OpenCursors,
+    backups: &mut Backups,
+) -> Result<Command, RecvError> {
+    loop {
+        cursors.close_expired();
+        step_backup(loopdata, backups);
+        let transaction = loopdata.get_transaction();
+        let transaction_wait = match open_transaction {
+            Some(_) => transaction.time_left(),
+            None => None,
+        };
+        // while a backup runs its pages are copied between commands
+        let cursors_wait = if backups.is_running() {
+            Some(std::time::Duration::from_millis(0))
+        } else {
+            cursors.time_left()
+        };
+        let wait = match (transaction_wait, cursors_wait) {
+            (Some(t), Some(c)) => t.min(c),
+            (Some(wait), None) | (None, Some(wait)) => wait,
+            (None, None) => return rx.recv(),
+        };
+        match rx.recv_timeout(wait) {
+            Ok(command) => return Ok(command),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(RecvError)
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if open_transaction.is_none() {
+                    continue;
+                }
+                if let Some((token, database)) =
+                    transaction.take_expired()
+                {
+                    debug!("Rolling back idle transaction {}", token);
+                    let _ =
+                        end_transaction(&loopdata.get_db(), false);
+                    transaction.drop_snapshot();
+                    *open_transaction = None;
+                    let ctx = Context::detached();
+                    let lock = ctx.lock();
+                    replicate_transaction_statement(
+                        &ctx, &database, "ROLLBACK",
+                    );
+                    ctx.release(lock);
+                }
+            }
+        }
+    }
+}
+
+/// Copy the next pages of the running backup, and answer its client once
+/// it is over.
+/// After a load the database keeps its own path and settings, and its
+/// statements are the ones stored in the file.
+fn step_backup<L: LoopData>(loopdata: &L, backups: &mut Backups) {
+    let db = loopdata.get_db();
+    let finished = match backups.step(&lock_connection(&db)) {
+        Some(finished) => finished,
+        None => return,
+    };
+    let Finished {
+        client,
+        direction,
+        metadata,
+        mut result,
+    } = finished;
+    if direction == BackupDirection::FromFile && result.is_ok() {
+        let path = lock_connection(&db).path.clone();
+        let restored = create_metadata_table(db.clone())
+            .and_then(|db| {
+                replace_metadata(db, "path", "path", Some(&path))
+            })
+            .and_then(|_| {
+                metadata.iter().try_for_each(
+                    |(data_type, key, value)| {
+                        replace_metadata(
+                            db.clone(),
+                            data_type,
+                            key,
+                            value.as_deref(),
+                        )
+                    },
+                )
+            });
+        if let Err(e) = restored {
+            result = Err(e.into());
+        }
+        loopdata.get_replication_book().clear();
+        restore_previous_statements(loopdata);
+    }
+    return_value(
+        &client,
+        &ReturnMethod::ReplyWithHeader,
+        result,
+        default_deadline(),
+    );
+}
+
+/// Stop the running backup when the database stops.
+fn abort_backup<L: LoopData>(loopdata: &L, backups: &mut Backups) {
+    let db = loopdata.get_db();
+    let client = backups.abort(&lock_connection(&db));
+    if let Some(client) = client {
+        let result: Result<QueryResult, RediSQLError> =
+            Err(RediSQLError::with_code(
+                67,
+                "The database stopped before the end of the backup"
+                    .to_string(),
+                "Backup interrupted".to_string(),
+            ));
+        return_value(
+            &client,
+            &ReturnMethod::ReplyWithHeader,
+            result,
+            default_deadline(),
+        );
+    }
+}
+
+/// While a transaction is open only its own commands are executed, all
+/// the others are rejected. The same happens while the database is
+/// loaded from a file.
+fn admit_command(
+    open_transaction: &Option<String>,
+    backups: &Backups,
+    command: Command,
+) -> Option<Command> {
+    if backups.is_loading()
+        && !matches!(command, Command::Ping | Command::Stop)
+    {
+        reject_command(command, backup_in_progress());
+        return None;
+    }
+    match command {
+        Command::InTransaction { token, command } => {
+            match open_transaction {
+                Some(open) if open == token => Some(*command),
+                _ => {
+                    reject_command(
+                        *command,
+                        RediSQLError::transaction_not_found(token),
+                    );
+                    None
+                }
+            }
+        }
+        Command::Ping
+        | Command::Stop
+        | Command::EndTransaction { .. } => Some(command),
+        command => match open_transaction {
+            None => Some(command),
+            Some(_) => {
+                reject_command(
+                    command,
+                    RediSQLError::transaction_in_progress(),
+                );
+                None
+            }
+        },
+    }
+}
+
+fn reject_command(command: Command, error: RediSQLError) {
+    let result: Result<QueryResult, RediSQLError> = Err(error);
+    let t = default_deadline();
+    match command {
+        Command::Exec {
+            client,
+            return_method,
+            timeout,
+            ..
+        }
+        | Command::Query {
+            client,
+            return_method,
+            timeout,
+            ..
+        }
+        | Command::ExecStatement {
+            client,
+            return_method,
+            timeout,
+            ..
+        }
+        | Command::ExecStatementBatch {
+            client,
+            return_method,
+            timeout,
+            ..
+        }
+        | Command::OpenCursor {
+            client,
+            return_method,
+            timeout,
+            ..
+        }
+        | Command::FetchCursor {
+            client,
+            return_method,
+            timeout,
+            ..
+        }
+        | Command::QueryStatement {
+            client,
+            return_method,
+            timeout,
+            ..
+        }
+        | Command::ShowStatement {
+            client,
+            return_method,
+            timeout,
+            ..
+        }
+        | Command::ListStatements {
+            client,
+            return_method,
+            timeout,
+        }
+        | Command::ListFunctions {
+            client,
+            return_method,
+            timeout,
+        }
+        | Command::ListExtensions {
+            client,
+            return_method,
+            timeout,
+        }
+        | Command::IngestStream {
+            client,
+            return_method,
+            timeout,
+            ..
+        }
+        | Command::ImportCsv {
+            client,
+            return_method,
+            timeout,
+            ..
+        } => return_value(&client, &return_method, result, timeout),
+        Command::ExportCsv {
+            client, timeout, ..
+        } => return_value(
+            &client,
+            &ReturnMethod::Reply,
+            result,
+            timeout,
+        ),
+        Command::CompileStatement { client, .. }
+        | Command::UpdateStatement { client, .. }
+        | Command::DeleteStatement { client, .. }
+        | Command::CreateFunction { client, .. }
+        | Command::DeleteFunction { client, .. }
+        | Command::LoadExtension { client, .. }
+        | Command::Dump { client, .. }
+        | Command::BeginTransaction { client, .. }
+        | Command::CloseCursor { client, .. }
+        | Command::EndTransaction {
+            client: Some(client),
+            ..
+        } => return_value(&client, &ReturnMethod::Reply, result, t),
+        Command::MakeCopy {
+            destination,
+            client,
+        } => {
+            return_value(&client, &ReturnMethod::Reply, result, t);
+            std::mem::forget(destination);
+        }
+        Command::Backup { client, .. } => return_value(
+            &client,
+            &ReturnMethod::ReplyWithHeader,
+            result,
+            t,
+        ),
+        Command::EndTransaction { client: None, .. }
+        | Command::InTransaction { .. }
+        | Command::Ping
+        | Command::Stop => {}
+    }
+}
+
+/// If the connection is not inside a transaction.
+pub(crate) fn is_autocommit(db: &ConcurrentConnection) -> bool {
+    let db = lock_connection(&db).get_db();
+    unsafe { sql::ffi::sqlite3_get_autocommit(db) != 0 }
+}
+
+/// Undo what was written since the savepoint.
+/// Outside a transaction the savepoint started a new one, which is rolled
+/// back completely so that the rollback hook is invoked.
+pub(crate) fn rollback_savepoint(
+    db: &ConcurrentConnection,
+    savepoint: &str,
+    in_transaction: bool,
+) -> Result<(), RediSQLError> {
+    let no_args = Bindings::default();
+    if in_transaction {
+        do_execute(
+            db,
+            &format!("ROLLBACK TO {}", savepoint),
+            &no_args,
+        )?;
+        do_execute(db, &format!("RELEASE {}", savepoint), &no_args)?;
+    } else {
+        do_execute(db, "ROLLBACK", &no_args)?;
+    }
+    Ok(())
+}
+
+fn end_transaction(
+    db: &ConcurrentConnection,
+    commit: bool,
+) -> Result<QueryResult, RediSQLError> {
+    if commit {
+        if let Err(e) = do_execute(db, "COMMIT", &Bindings::default())
+        {
+            // a failed COMMIT leaves the transaction open
+            let _ = do_execute(db, "ROLLBACK", &Bindings::default());
+            return Err(e);
+        }
+    } else {
+        do_execute(db, "ROLLBACK", &Bindings::default())?;
+    }
+    Ok(QueryResult::OK {})
+}
+
+/// Replicate the statements that open and close a transaction, replicas
+/// run them right away, like all the other replicated commands.
+pub fn replicate_transaction_statement(
+    ctx: &Context,
+    database: &str,
+    statement: &str,
+) {
+    let database = match CString::new(database) {
+        Ok(database) => database,
+        Err(_) => return,
+    };
+    let command = CString::new("REDISQL.V2.EXEC").unwrap();
+    let format = CString::new("cccc").unwrap();
+    let now = CString::new("NOW").unwrap();
+    let keyword = CString::new("COMMAND").unwrap();
+    let statement = CString::new(statement).unwrap();
+    unsafe {
+        rm::ffi::RedisModule_Replicate.unwrap()(
+            ctx.as_ptr(),
+            command.as_ptr(),
+            format.as_ptr(),
+            database.as_ptr(),
+            now.as_ptr(),
+            keyword.as_ptr(),
+            statement.as_ptr(),
+        );
+    }
+}
+
+/// Replicate the statement of a transaction from the thread of the
+/// database, once it is known how it ended.
+fn replicate_transaction_outcome(
+    client: &BlockedClient,
+    database: &str,
+    statement: &str,
+) {
+    let ctx = Context::thread_safe(client);
+    let lock = ctx.lock();
+    replicate_transaction_statement(&ctx, database, statement);
+    ctx.release(lock);
+}
+
 fn compile_and_insert_statement<'a, L: 'a + LoopData>(
     identifier: &str,
     statement: &str,
@@ -1683,6 +3411,29 @@ pub struct DBKey<'c> {
     pub loop_data: Loop,
     pub context: Option<&'c Context>,
     pub connections: HashMap<String, Sender<Command>>,
+    pub readers: Readers,
+}
+
+/// How many databases are open, used to enforce MAX_DATABASES.
+static OPEN_DATABASES: AtomicUsize = AtomicUsize::new(0);
+
+/// Check that a new database can be created without going over
+/// MAX_DATABASES.
+pub fn check_max_databases() -> Result<(), RediSQLError> {
+    let max = config().max_databases;
+    if max > 0 && OPEN_DATABASES.load(Ordering::SeqCst) >= max {
+        return Err(RediSQLError::with_code(
+            46,
+            format!("The module is configured to hold at most {} databases (MAX_DATABASES)", max),
+            "Too many databases".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Called when Redis frees a database.
+pub fn database_freed() {
+    OPEN_DATABASES.fetch_sub(1, Ordering::SeqCst);
 }
 
 impl<'c> DBKey<'c> {
@@ -1690,14 +3441,54 @@ impl<'c> DBKey<'c> {
         tx: Sender<Command>,
         db: ConcurrentConnection,
     ) -> Self {
+        OPEN_DATABASES.fetch_add(1, Ordering::SeqCst);
         let loop_data = Loop::new_from_arc(db);
         DBKey {
             tx,
             loop_data,
             connections: HashMap::new(),
             context: None,
+            readers: Readers::default(),
         }
     }
+    pub fn channel(&self) -> DBChannel {
+        DBChannel {
+            writer: self.tx.clone(),
+            readers: self.readers.clone(),
+        }
+    }
+    /// Publish the changes to the database to the stream, the stream is
+    /// saved in the metadata so that the changes are captured also after
+    /// the database is loaded.
+    /// It must be called before the loop of the database is started.
+    pub fn capture_changes(
+        &mut self,
+        stream: &str,
+    ) -> Result<(), RediSQLError> {
+        let db = self.loop_data.get_db();
+        replace_metadata(db.clone(), "cdc", "stream", Some(stream))?;
+        self.loop_data.changes =
+            Some(ChangeCapture::register(&db, stream));
+        Ok(())
+    }
+    /// Start the reader threads of the database, the readers are saved
+    /// in the metadata so that they are started again when the database
+    /// is loaded.
+    pub fn start_readers(
+        &mut self,
+        readers: usize,
+    ) -> Result<(), RediSQLError> {
+        let db = self.loop_data.get_db();
+        self.readers = Readers::spawn(
+            &db,
+            &self.loop_data.get_replication_book(),
+            readers,
+        )?;
+        let readers = readers.to_string();
+        let readers = Some(readers.as_str()).filter(|r| *r != "0");
+        replace_metadata(db, "readers", "readers", readers)?;
+        Ok(())
+    }
     pub fn add_connection(
         &mut self,
         clone_name: &str,
@@ -1709,7 +3500,7 @@ impl<'c> DBKey<'c> {
             let replication_book =
                 self.loop_data.get_replication_book();
             let db = self.loop_data.get_db();
-            let db = db.lock().unwrap();
+            let db = lock_connection(&db);
             if db.is_multithread() {
                 let serialized_db = match db.duplicate_connection() {
                     Ok(db) => Arc::new(Mutex::new(db)),
@@ -1735,7 +3526,7 @@ impl<'c> DBKey<'c> {
         }
 
         let db = self.loop_data.get_db();
-        let db = db.lock().unwrap();
+        let db = lock_connection(&db);
         let new_db = match db.duplicate_connection() {
             Ok(db) => Arc::new(Mutex::new(db)),
             Err(_) => return Err(()),
@@ -1851,6 +3642,26 @@ fn get_statement_metadata(
     QueryResult::try_from(cursor)
 }
 
+fn get_function_metadata(
+    db: ConcurrentConnection,
+) -> Result<QueryResult, err::RediSQLError> {
+    let statement = "SELECT value FROM RediSQLMetadata WHERE data_type = 'function';";
+
+    let stmt = MultiStatement::new(db, statement)?;
+    let cursor = stmt.execute()?;
+    QueryResult::try_from(cursor)
+}
+
+fn get_extension_metadata(
+    db: ConcurrentConnection,
+) -> Result<QueryResult, err::RediSQLError> {
+    let statement = "SELECT key FROM RediSQLMetadata WHERE data_type = 'extension' ORDER BY rowid;";
+
+    let stmt = MultiStatement::new(db, statement)?;
+    let cursor = stmt.execute()?;
+    QueryResult::try_from(cursor)
+}
+
 fn get_path_metadata(
     db: ConcurrentConnection,
 ) -> Result<QueryResult, err::RediSQLError> {
@@ -1920,6 +3731,46 @@ pub fn insert_path_metadata(
     insert_metadata(db, "path", "path", path)
 }
 
+/// Set the value of a setting of the database in the metadata, or remove
+/// it if the value is `None`.
+pub fn replace_metadata(
+    db: ConcurrentConnection,
+    data_type: &str,
+    key: &str,
+    value: Option<&str>,
+) -> Result<(), SQLite3Error> {
+    let statement =
+        "DELETE FROM RediSQLMetadata WHERE data_type = ?1 AND key = ?2;";
+    let stmt = MultiStatement::new(db.clone(), statement)?;
+    stmt.bind_index(1, data_type)?;
+    stmt.bind_index(2, key)?;
+    stmt.execute()?;
+    if let Some(value) = value {
+        insert_metadata(db, data_type, key, value)?;
+    }
+    Ok(())
+}
+
+/// Read the value of a setting of the database from the metadata.
+pub fn get_metadata(
+    db: ConcurrentConnection,
+    data_type: &str,
+    key: &str,
+) -> Option<String> {
+    let statement = "SELECT value FROM RediSQLMetadata WHERE data_type = ?1 AND key = ?2;";
+    let stmt = MultiStatement::new(db, statement).ok()?;
+    stmt.bind_index(1, data_type).ok()?;
+    stmt.bind_index(2, key).ok()?;
+    let cursor = stmt.execute().ok()?;
+    match QueryResult::try_from(cursor) {
+        Ok(QueryResult::Array { array, .. }) => match array.first() {
+            Some(Entity::Text { text }) => Some(text.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 fn update_path_metadata(
     db: ConcurrentConnection,
     value: &str,
@@ -1960,33 +3811,16 @@ pub fn create_backup(
     }
 }
 
-pub unsafe fn write_file_to_rdb(
-    f: File,
+/// Save the image of a database in the RDB, in blocks of 40KB that
+/// `write_rdb_to_file` reads back.
+pub unsafe fn write_image_to_rdb(
+    image: &[u8],
     rdb: *mut rm::ffi::RedisModuleIO,
-) -> Result<(), std::io::Error> {
-    let block_size = 1024 * 4 * 10;
-    let lenght = f.metadata().unwrap().len();
-    let blocks = lenght / block_size;
-    let blocks = match lenght % block_size {
-        0 => blocks,
-        _n => blocks + 1,
-    };
-
-    rm::SaveSigned(rdb, blocks as i64);
-    debug!(
-        "Saved {} blocks from a file of len {} and block of size {}",
-        blocks, lenght, block_size
-    );
-
-    let to_write: Vec<u8> = vec![0; block_size as usize];
-    let mut buffer = BufReader::with_capacity(block_size as usize, f);
-    loop {
-        let mut tw = to_write.clone();
-        match buffer.read(tw.as_mut_slice()) {
-            Ok(0) => return Ok(()),
-            Ok(n) => rm::SaveStringBuffer(rdb, tw.as_slice(), n),
-            Err(e) => return Err(e),
-        }
+) {
+    let blocks = image.chunks(1024 * 4 * 10);
+    rm::SaveSigned(rdb, blocks.len() as i64);
+    for block in blocks {
+        rm::SaveStringBuffer(rdb, block, block.len());
     }
 }
 
@@ -2004,8 +3838,8 @@ impl Drop for SafeRedisModuleString {
     }
 }
 
-pub unsafe fn write_rdb_to_file(
-    f: &mut File,
+pub unsafe fn write_rdb_to_file<W: Write>(
+    f: &mut W,
     rdb: *mut rm::ffi::RedisModuleIO,
 ) -> Result<(), std::io::Error> {
     let blocks = rm::LoadSigned(rdb);
@@ -2073,10 +3907,8 @@ pub fn get_dbkey_from_name(
     Ok(dbkey)
 }
 
-pub unsafe fn get_ch_from_dbkeyptr(
-    db: *mut DBKey,
-) -> Sender<Command> {
-    (*db).tx.clone()
+pub unsafe fn get_ch_from_dbkeyptr(db: *mut DBKey) -> DBChannel {
+    (*db).channel()
 }
 
 pub fn reply_with_error_from_key_type(
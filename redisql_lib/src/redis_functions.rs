@@ -0,0 +1,365 @@
+//! SQL functions that run Redis commands, like
+//! `SELECT redis_get('counter')`.
+//!
+//! The functions that may write to Redis make the statement not read
+//! only, so it cannot be used in a query and it is replicated as any
+//! other write. The commands themselves are not replicated, the replicas
+//! run the same statement.
+//!
+//! The statements do not run as the client that sent them, so
+//! `redis_call` is limited to the commands that read and write the keys,
+//! the ones in `ALLOWED_COMMANDS`.
+
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::slice;
+
+use serde_json::Value as Json;
+
+use crate::cdc;
+use crate::redis_type::CallReply;
+use crate::sqlite::{ffi, SQLITE_TRANSIENT};
+use crate::virtual_tables::{call, value_bytes, Redis};
+
+struct Function {
+    name: &'static str,
+    /// The number of arguments, -1 for any.
+    arity: c_int,
+    /// The command to run, `None` if it is the first argument.
+    command: Option<&'static str>,
+    writes: bool,
+}
+
+const FUNCTIONS: &[Function] = &[
+    Function {
+        name: "redis_get",
+        arity: 1,
+        command: Some("GET"),
+        writes: false,
+    },
+    Function {
+        name: "redis_hget",
+        arity: 2,
+        command: Some("HGET"),
+        writes: false,
+    },
+    Function {
+        name: "redis_incr",
+        arity: 1,
+        command: Some("INCR"),
+        writes: true,
+    },
+    Function {
+        name: "redis_call",
+        arity: -1,
+        command: None,
+        writes: true,
+    },
+];
+
+/// The commands `redis_call` can run: the commands on the keys and on
+/// their values, not the ones that administer the server, block, or
+/// call the module again.
+const ALLOWED_COMMANDS: &[&str] = &[
+    // keys
+    "DEL",
+    "EXISTS",
+    "EXPIRE",
+    "EXPIREAT",
+    "PERSIST",
+    "PEXPIRE",
+    "PEXPIREAT",
+    "PTTL",
+    "TTL",
+    "TYPE",
+    "UNLINK",
+    // strings
+    "APPEND",
+    "BITCOUNT",
+    "DECR",
+    "DECRBY",
+    "GET",
+    "GETBIT",
+    "GETRANGE",
+    "GETSET",
+    "INCR",
+    "INCRBY",
+    "INCRBYFLOAT",
+    "MGET",
+    "MSET",
+    "MSETNX",
+    "PSETEX",
+    "SET",
+    "SETBIT",
+    "SETEX",
+    "SETNX",
+    "SETRANGE",
+    "STRLEN",
+    // hashes
+    "HDEL",
+    "HEXISTS",
+    "HGET",
+    "HGETALL",
+    "HINCRBY",
+    "HINCRBYFLOAT",
+    "HKEYS",
+    "HLEN",
+    "HMGET",
+    "HMSET",
+    "HSET",
+    "HSETNX",
+    "HSTRLEN",
+    "HVALS",
+    // lists
+    "LINDEX",
+    "LINSERT",
+    "LLEN",
+    "LPOP",
+    "LPUSH",
+    "LPUSHX",
+    "LRANGE",
+    "LREM",
+    "LSET",
+    "LTRIM",
+    "RPOP",
+    "RPUSH",
+    "RPUSHX",
+    // sets
+    "SADD",
+    "SCARD",
+    "SISMEMBER",
+    "SMEMBERS",
+    "SPOP",
+    "SRANDMEMBER",
+    "SREM",
+    // sorted sets
+    "ZADD",
+    "ZCARD",
+    "ZCOUNT",
+    "ZINCRBY",
+    "ZLEXCOUNT",
+    "ZRANGE",
+    "ZRANGEBYLEX",
+    "ZRANGEBYSCORE",
+    "ZRANK",
+    "ZREM",
+    "ZREVRANGE",
+    "ZREVRANGEBYSCORE",
+    "ZREVRANK",
+    "ZSCORE",
+    // streams
+    "XADD",
+    "XDEL",
+    "XLEN",
+    "XRANGE",
+    "XREVRANGE",
+    "XTRIM",
+    // hyperloglogs
+    "PFADD",
+    "PFCOUNT",
+];
+
+/// What a function needs when it is called.
+struct FunctionData {
+    redis: Redis,
+    command: Option<&'static str>,
+}
+
+thread_local! {
+    /// Set by the authorizer when the statement being prepared on this
+    /// thread calls a function that writes to Redis.
+    static WRITES_REDIS: Cell<bool> = Cell::new(false);
+}
+
+/// Run `prepare`, and tell if the statement it prepares calls a function
+/// that writes to Redis.
+pub fn track_writes<T, F: FnOnce() -> T>(prepare: F) -> (T, bool) {
+    WRITES_REDIS.with(|writes| writes.set(false));
+    let prepared = prepare();
+    (prepared, WRITES_REDIS.with(|writes| writes.replace(false)))
+}
+
+/// Register the functions on the connection.
+pub fn register_functions(db: *mut ffi::sqlite3) -> c_int {
+    for function in FUNCTIONS {
+        let name = CString::new(function.name).unwrap();
+        let data = Box::new(FunctionData {
+            redis: Redis::new(),
+            command: function.command,
+        });
+        let rc = unsafe {
+            ffi::sqlite3_create_function_v2(
+                db,
+                name.as_ptr(),
+                function.arity,
+                ffi::SQLITE_UTF8,
+                Box::into_raw(data) as *mut c_void,
+                Some(call_function),
+                None,
+                None,
+                Some(free_function_data),
+            )
+        };
+        if rc != ffi::SQLITE_OK {
+            return rc;
+        }
+    }
+    unsafe {
+        ffi::sqlite3_set_authorizer(
+            db,
+            Some(authorize),
+            ptr::null_mut(),
+        )
+    }
+}
+
+/// It allows everything, it only records the calls to the functions
+/// that write to Redis, and the savepoints for the change capture.
+unsafe extern "C" fn authorize(
+    _data: *mut c_void,
+    action: c_int,
+    arg1: *const c_char,
+    arg2: *const c_char,
+    _database: *const c_char,
+    _trigger: *const c_char,
+) -> c_int {
+    if action == ffi::SQLITE_FUNCTION && !arg2.is_null() {
+        let name = CStr::from_ptr(arg2).to_string_lossy();
+        let writes = FUNCTIONS.iter().any(|function| {
+            function.writes
+                && function.name.eq_ignore_ascii_case(&name)
+        });
+        if writes {
+            WRITES_REDIS.with(|writes| writes.set(true));
+        }
+    }
+    if action == ffi::SQLITE_SAVEPOINT
+        && !arg1.is_null()
+        && !arg2.is_null()
+    {
+        cdc::authorize_savepoint(
+            &CStr::from_ptr(arg1).to_string_lossy(),
+            &CStr::from_ptr(arg2).to_string_lossy(),
+        );
+    }
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn free_function_data(data: *mut c_void) {
+    drop(Box::from_raw(data as *mut FunctionData));
+}
+
+unsafe extern "C" fn call_function(
+    ctx: *mut ffi::sqlite3_context,
+    argc: c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+) {
+    let data = &*(ffi::sqlite3_user_data(ctx) as *const FunctionData);
+    let mut args: Vec<Vec<u8>> = Vec::with_capacity(argc as usize);
+    for value in slice::from_raw_parts(argv, argc as usize) {
+        match value_bytes(*value) {
+            Some(bytes) => args.push(bytes),
+            // Redis has no NULL, nothing to look up
+            None => return ffi::sqlite3_result_null(ctx),
+        }
+    }
+    let command = match data.command {
+        Some(command) => command.to_string(),
+        None if !args.is_empty() => {
+            String::from_utf8_lossy(&args.remove(0)).into_owned()
+        }
+        None => {
+            return result_error(
+                ctx,
+                "redis_call needs the command to run",
+            )
+        }
+    };
+    let allowed = ALLOWED_COMMANDS
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(&command));
+    if !allowed {
+        return result_error(
+            ctx,
+            &format!("redis_call cannot run the command {}", command),
+        );
+    }
+    let args: Vec<&[u8]> = args.iter().map(Vec::as_slice).collect();
+    let reply = data.redis.with_lock(|redis, lock| {
+        call(redis, lock, &command, &args).map(|reply| {
+            result_reply(ctx, &reply);
+        })
+    });
+    if let Err(e) = reply {
+        result_error(ctx, &e);
+    }
+}
+
+/// Return the reply to SQLite: strings as text, integers as integers
+/// and arrays as JSON arrays.
+unsafe fn result_reply(
+    ctx: *mut ffi::sqlite3_context,
+    reply: &CallReply,
+) {
+    match reply {
+        CallReply::RInteger { .. } => ffi::sqlite3_result_int64(
+            ctx,
+            reply.access_integer().unwrap_or_default(),
+        ),
+        CallReply::RString { .. } => {
+            let text = reply.access_bytes().unwrap_or_default();
+            ffi::sqlite3_result_text(
+                ctx,
+                text.as_ptr() as *const c_char,
+                text.len() as c_int,
+                SQLITE_TRANSIENT(),
+            )
+        }
+        CallReply::RArray { .. } => {
+            let json = reply_json(reply).to_string();
+            ffi::sqlite3_result_text(
+                ctx,
+                json.as_ptr() as *const c_char,
+                json.len() as c_int,
+                SQLITE_TRANSIENT(),
+            )
+        }
+        CallReply::RError { .. } | CallReply::RNull { .. } => {
+            ffi::sqlite3_result_null(ctx)
+        }
+    }
+}
+
+fn reply_json(reply: &CallReply) -> Json {
+    match reply {
+        CallReply::RInteger { .. } => {
+            reply.access_integer().map_or(Json::Null, Json::from)
+        }
+        CallReply::RString { .. } => reply
+            .access_bytes()
+            .map(|b| String::from_utf8_lossy(&b).into_owned())
+            .map_or(Json::Null, Json::from),
+        CallReply::RArray { .. } => Json::Array(
+            (0..reply.length().unwrap_or(0))
+                .filter_map(|i| reply.access_array_subelement(i))
+                .map(|element| reply_json(&element))
+                .collect(),
+        ),
+        CallReply::RError { .. } | CallReply::RNull { .. } => {
+            Json::Null
+        }
+    }
+}
+
+unsafe fn result_error(
+    ctx: *mut ffi::sqlite3_context,
+    message: &str,
+) {
+    ffi::sqlite3_result_error(
+        ctx,
+        message.as_ptr() as *const c_char,
+        message.len() as c_int,
+    )
+}
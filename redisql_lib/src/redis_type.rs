@@ -1,6 +1,6 @@
 use std::ffi::CString;
 use std::mem;
-use std::os::raw::{c_char, c_int};
+use std::os::raw::{c_char, c_int, c_long};
 
 #[allow(dead_code)]
 #[allow(non_snake_case)]
@@ -43,6 +43,19 @@ impl Context {
             thread_safe: true,
         }
     }
+    /// A thread safe context not bound to any client, used to call
+    /// Redis from the threads of the databases.
+    pub fn detached() -> Context {
+        let ctx = unsafe {
+            ffi::RedisModule_GetThreadSafeContext.unwrap()(
+                std::ptr::null_mut(),
+            )
+        };
+        Context {
+            ctx,
+            thread_safe: true,
+        }
+    }
     pub fn lock(&self) -> ContextLock {
         if self.thread_safe {
             unsafe {
@@ -53,6 +66,19 @@ impl Context {
         }
         ContextLock {}
     }
+    /// Lock the context only if it is free, `None` if Redis is holding
+    /// it or if this version of Redis cannot try the lock.
+    pub fn try_lock(&self) -> Option<ContextLock> {
+        if !self.thread_safe {
+            return Some(ContextLock {});
+        }
+        let try_lock =
+            unsafe { ffi::RedisModule_ThreadSafeContextTryLock }?;
+        match unsafe { try_lock(self.as_ptr()) } {
+            ffi::REDISMODULE_OK => Some(ContextLock {}),
+            _ => None,
+        }
+    }
     pub fn release(&self, _lock: ContextLock) {
         if self.thread_safe {
             unsafe {
@@ -82,6 +108,7 @@ unsafe impl Sync for Context {}
 
 pub struct BlockedClient {
     pub client: *mut ffi::RedisModuleBlockedClient,
+    client_id: u64,
 }
 
 unsafe impl Send for BlockedClient {}
@@ -110,11 +137,18 @@ impl BlockedClient {
                 timeout,
             )
         };
-        Self { client }
+        Self {
+            client,
+            client_id: GetClientId(ctx),
+        }
     }
     pub fn as_ptr(&self) -> *mut ffi::RedisModuleBlockedClient {
         self.client
     }
+    /// The id of the client that sent the command.
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
 }
 
 #[derive(Debug)]
@@ -125,6 +159,9 @@ pub struct RMString<'a> {
 
 impl<'a> RMString<'a> {
     pub fn new(ctx: &'a Context, s: &str) -> RMString<'a> {
+        RMString::from_bytes(ctx, s.as_bytes())
+    }
+    pub fn from_bytes(ctx: &'a Context, s: &[u8]) -> RMString<'a> {
         let ptr = unsafe {
             ffi::RedisModule_CreateString.unwrap()(
                 ctx.as_ptr(),
@@ -166,7 +203,7 @@ impl<'a> LeakyArrayOfRMString<'a> {
             ctx,
         }
     }
-    fn push(&mut self, s: &str) {
+    fn push(&mut self, s: &[u8]) {
         let ptr = unsafe {
             ffi::RedisModule_CreateString.unwrap()(
                 self.ctx.as_ptr(),
@@ -200,12 +237,15 @@ impl<'a> XADDCommand<'a> {
         id: &str,
     ) -> XADDCommand<'a> {
         let mut array = LeakyArrayOfRMString::new(ctx);
-        array.push(key);
-        array.push(id);
+        array.push(key.as_bytes());
+        array.push(id.as_bytes());
         XADDCommand { ctx, array }
     }
     pub fn add_element(&mut self, field: &str, value: &str) {
-        self.array.push(field);
+        self.add_blob_element(field, value.as_bytes());
+    }
+    pub fn add_blob_element(&mut self, field: &str, value: &[u8]) {
+        self.array.push(field.as_bytes());
         self.array.push(value);
     }
     // this command must be called inside a transaction with the context locked, hence we use as
@@ -226,6 +266,35 @@ impl<'a> XADDCommand<'a> {
     }
 }
 
+/// Run a Redis command with the context locked. With `replicate` the
+/// command is also propagated to the replicas and to the AOF.
+#[allow(non_snake_case)]
+pub fn Call(
+    ctx: &Context,
+    command: &str,
+    args: &[&[u8]],
+    replicate: bool,
+    _lock: &ContextLock,
+) -> CallReply {
+    let mut array = LeakyArrayOfRMString::new(ctx);
+    for arg in args {
+        array.push(arg);
+    }
+    let command = CString::new(command).unwrap();
+    let call_specifiers =
+        CString::new(if replicate { "!v" } else { "v" }).unwrap();
+    let reply = unsafe {
+        ffi::RedisModule_Call.unwrap()(
+            ctx.as_ptr(),
+            command.as_ptr(),
+            call_specifiers.as_ptr(),
+            array.as_ptr(),
+            array.len(),
+        )
+    };
+    unsafe { CallReply::new(reply) }
+}
+
 #[allow(non_snake_case)]
 pub fn CreateCommand(
     ctx: &Context,
@@ -265,6 +334,69 @@ pub fn CreateCommandWithKeys(
     }
 }
 
+#[allow(non_snake_case)]
+pub fn GetClientId(ctx: &Context) -> u64 {
+    unsafe {
+        ffi::RedisModule_GetClientId.unwrap()(ctx.as_ptr()) as u64
+    }
+}
+
+#[allow(non_snake_case)]
+pub fn Log(ctx: &Context, level: &str, message: &str) {
+    let level = CString::new(level).unwrap();
+    let format = CString::new("%s").unwrap();
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    unsafe {
+        ffi::RedisModule_Log.unwrap()(
+            ctx.as_ptr(),
+            level.as_ptr(),
+            format.as_ptr(),
+            message.as_ptr(),
+        )
+    }
+}
+
+/// Log from the callbacks that save and load the keys, where there is no
+/// context.
+#[allow(non_snake_case, clippy::missing_safety_doc)]
+pub unsafe fn LogIOError(
+    io: *mut ffi::RedisModuleIO,
+    level: &str,
+    message: &str,
+) {
+    let level = CString::new(level).unwrap();
+    let format = CString::new("%s").unwrap();
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    ffi::RedisModule_LogIOError.unwrap()(
+        io,
+        level.as_ptr(),
+        format.as_ptr(),
+        message.as_ptr(),
+    )
+}
+
+/// The flags of the context, none if Redis cannot provide them.
+fn context_flags(ctx: &Context) -> c_int {
+    match unsafe { ffi::RedisModule_GetContextFlags } {
+        Some(get_flags) => unsafe { get_flags(ctx.as_ptr()) },
+        None => 0,
+    }
+}
+
+/// If the commands executed with the context come from the master or
+/// from the AOF being loaded, and not from a client.
+pub fn is_replica_or_loading(ctx: &Context) -> bool {
+    context_flags(ctx)
+        & (ffi::REDISMODULE_CTX_FLAGS_SLAVE
+            | ffi::REDISMODULE_CTX_FLAGS_LOADING)
+        != 0
+}
+
+/// If the client of the context speaks RESP3.
+pub fn is_resp3(ctx: &Context) -> bool {
+    context_flags(ctx) & ffi::REDISMODULE_CTX_FLAGS_RESP3 != 0
+}
+
 #[allow(non_snake_case)]
 pub fn ReplicateVerbatim(ctx: &Context) -> i32 {
     unsafe {
@@ -350,6 +482,54 @@ pub fn ReplyWithDouble(ctx: &Context, dd: f64) -> i32 {
     }
 }
 
+/// Reply with a map of `len` pairs of key and value.
+/// Where Redis does not support maps in modules the reply is an array of
+/// `2 * len` elements, the same RESP2 clients receive.
+#[allow(non_snake_case)]
+pub fn ReplyWithMap(ctx: &Context, len: usize) -> i32 {
+    unsafe {
+        match ffi::RedisModule_ReplyWithMap {
+            Some(reply_with_map) => {
+                reply_with_map(ctx.as_ptr(), len as c_long)
+            }
+            None => ffi::RedisModule_ReplyWithArray.unwrap()(
+                ctx.as_ptr(),
+                2 * len as c_long,
+            ),
+        }
+    }
+}
+
+/// Attach to the next reply an attribute of `len` pairs of key and
+/// value. Only RESP3 clients receive attributes, for the others nothing
+/// is sent and the function returns `REDISMODULE_ERR`.
+#[allow(non_snake_case)]
+pub fn ReplyWithAttribute(ctx: &Context, len: usize) -> i32 {
+    match unsafe { ffi::RedisModule_ReplyWithAttribute } {
+        Some(reply_with_attribute) if is_resp3(ctx) => unsafe {
+            reply_with_attribute(ctx.as_ptr(), len as c_long)
+        },
+        _ => ffi::REDISMODULE_ERR,
+    }
+}
+
+/// Reply with a boolean, the integer 1 or 0 where Redis does not support
+/// booleans in modules.
+#[allow(non_snake_case)]
+pub fn ReplyWithBool(ctx: &Context, b: bool) -> i32 {
+    unsafe {
+        match ffi::RedisModule_ReplyWithBool {
+            Some(reply_with_bool) => {
+                reply_with_bool(ctx.as_ptr(), b as c_int)
+            }
+            None => ffi::RedisModule_ReplyWithLongLong.unwrap()(
+                ctx.as_ptr(),
+                b as i64,
+            ),
+        }
+    }
+}
+
 #[allow(non_snake_case)]
 pub fn ReplyWithStringBuffer(ctx: &Context, buffer: &[u8]) -> i32 {
     let ptr = buffer.as_ptr() as *const c_char;
@@ -408,6 +588,48 @@ pub unsafe fn EmitAOF(
     )
 }
 
+/// Emit `command key index total checksum chunk`, the chunk is binary
+/// safe. The command must be terminated by a NUL byte.
+#[allow(non_snake_case)]
+pub unsafe fn EmitAOFChunk(
+    aof: &AOF,
+    command: &str,
+    key: *mut ffi::RedisModuleString,
+    index: i64,
+    total: i64,
+    checksum: i64,
+    chunk: &[u8],
+) {
+    ffi::RedisModule_EmitAOF.unwrap()(
+        aof.as_ptr(),
+        command.as_ptr() as *const c_char,
+        "slllb\0".as_ptr() as *const c_char,
+        key,
+        index as ::std::os::raw::c_longlong,
+        total as ::std::os::raw::c_longlong,
+        checksum as ::std::os::raw::c_longlong,
+        chunk.as_ptr() as *const c_char,
+        chunk.len(),
+    )
+}
+
+/// Add each element of the sequence to the digest, then close the
+/// sequence.
+#[allow(non_snake_case)]
+pub unsafe fn DigestAddSequence(
+    digest: *mut ffi::RedisModuleDigest,
+    sequence: &[Vec<u8>],
+) {
+    for element in sequence {
+        ffi::RedisModule_DigestAddStringBuffer.unwrap()(
+            digest,
+            element.as_ptr() as *mut ::std::os::raw::c_uchar,
+            element.len(),
+        );
+    }
+    ffi::RedisModule_DigestEndSequence.unwrap()(digest);
+}
+
 #[derive(Debug)]
 pub enum CallReply {
     RString { ptr: *mut ffi::RedisModuleCallReply },
@@ -521,6 +743,33 @@ impl CallReply {
         }
     }
 
+    /// The content of a string reply, which may not be valid UTF-8.
+    pub fn access_bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            CallReply::RInteger { .. }
+            | CallReply::RError { .. }
+            | CallReply::RArray { .. }
+            | CallReply::RNull { .. } => None,
+
+            CallReply::RString { ptr } => {
+                let mut size = 0;
+                unsafe {
+                    let ptr = ffi::RedisModule_CallReplyStringPtr
+                        .unwrap()(
+                        *ptr, &mut size
+                    );
+                    Some(
+                        std::slice::from_raw_parts(
+                            ptr as *const u8,
+                            size,
+                        )
+                        .to_vec(),
+                    )
+                }
+            }
+        }
+    }
+
     pub fn access_error(&self) -> Option<String> {
         match self {
             CallReply::RString { .. }
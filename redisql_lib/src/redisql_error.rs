@@ -31,7 +31,7 @@ pub struct RediSQLError {
  * 14  - Provided STATEMENT twice
  * 15  - Provided key does not exists
  * 16  - Ask stream without heading, which does not make sense
- * 17  - Ask stream for not read_only query, not supported
+ * 17  - Ask stream for not read_only query, not supported (no longer used)
  * 18  - Statement command without Action (DELETE, UPDATE, NEW, SHOW)
  * 19  - Statement command without name of the statement to operate with
  * 20  - Statement command without necessary query, for UPDATE or NEW
@@ -40,6 +40,61 @@ pub struct RediSQLError {
  * 23  - Command for Statement unknown
  * 24  - Didn't provide neither COMMAND nor STATEMENT
  * 25  - Provide name of the statement to STATEMENT LIST
+ * 26  - Argument declared as INT is not an integer
+ * 27  - Argument declared as REAL is not a number
+ * 28  - TYPES and ARGS have different lengths
+ * 29  - Provided TYPES keyword without any type
+ * 30  - NAMED_ARGS without a value for each name
+ * 31  - Another transaction is open on the database
+ * 32  - Transaction token unknown, committed or rolled back
+ * 33  - Transaction command without a valid action
+ * 34  - Transaction token not provided
+ * 35  - IDLE_TIMEOUT is not a positive integer
+ * 36  - TX and NOW used together
+ * 37  - BATCH arity is not a positive integer
+ * 38  - BATCH values are not a multiple of the arity
+ * 39  - BATCH used without STATEMENT, with READ_ONLY or WITH_ERRORS used without BATCH
+ * 40  - CURSOR count is not a positive integer
+ * 41  - CURSOR used together with NOW, INTO, BATCH or TX
+ * 42  - Cursor unknown, closed or expired
+ * 43  - Too many cursors open on the database
+ * 44  - Cursor id not provided or not valid
+ * 45  - TIMEOUT is not a positive integer
+ * 46  - Reached MAX_DATABASES, impossible to create a new database
+ * 47  - CONFIG command without GET and the option to read
+ * 48  - READERS is not a non negative integer
+ * 49  - Not used anymore
+ * 50  - Provide the CDC_STREAM option but not the stream to use
+ * 51  - STREAM.INGEST without the stream, the TABLE or the ID_COLUMN to use
+ * 52  - STREAM.INGEST COUNT is not a positive integer
+ * 53  - STREAM.INGEST with both FROM and GROUP, or GROUP without the consumer
+ * 54  - Stream field with the int: or real: prefix but a value of another type
+ * 55  - Error reading the stream or acknowledging its entries
+ * 56  - FORMAT is not one of the formats supported
+ * 57  - FORMAT other than ARRAY used with INTO or CURSOR
+ * 58  - BIGINT_AS_STRING used without FORMAT JSON or JSON_TABLE
+ * 59  - IMPORT or EXPORT with missing or invalid options
+ * 60  - Error reading or writing the CSV or its file
+ * 61  - CSV value not of its declared type, or record not matching TYPES
+ * 62  - DUMP with a format other than SQL or SQLITE, or RESTORE or RESTORE.CHUNK with missing arguments
+ * 63  - RESTORE payload that is not a SQLite image nor a valid SQL dump
+ * 64  - RESTORE into a key that already exists
+ * 65  - BACKUP or LOAD without the file, or PAGES not a positive integer
+ * 66  - BACKUP or LOAD in progress on the database
+ * 67  - Error opening the file of a BACKUP or LOAD, or copying its pages
+ * 68  - RESTORE.CHUNK out of order, or not matching the previous chunks
+ * 69  - RESTORE.CHUNK image not matching its checksum
+ * 70  - FUNCTION command without a valid action (NEW, DELETE, LIST)
+ * 71  - FUNCTION without the name, ARITY or BODY, or with options not supported by the action
+ * 72  - FUNCTION LANGUAGE not supported
+ * 73  - FUNCTION definition not valid, its name, arity or body
+ * 74  - FUNCTION NEW of a function already present, or DELETE of one not present
+ * 75  - EXTENSION command without a valid action (LOAD, LIST) or without the name to LOAD
+ * 76  - EXTENSION not in EXTENSIONS_DIR, or EXTENSIONS_DIR not set
+ * 77  - Error loading the EXTENSION
+ * 78  - File of IMPORT, EXPORT, BACKUP or LOAD outside of DATA_DIR
+ * 79  - Redis commands from SQL while Redis waits on the same database
+ * 80  - File to LOAD larger than MAX_LOAD_SIZE
  */
 impl RediSQLError {
     pub fn new(debug: String, error_description: String) -> Self {
@@ -87,6 +142,48 @@ impl RediSQLError {
                 "Key does not belong to us".to_string(),
             )
     }
+    pub fn transaction_in_progress() -> Self {
+        RediSQLError::with_code(
+            31,
+            "A transaction is open on the database, only commands with its `TX` token are accepted until it is committed or rolled back".to_string(),
+            "Database busy in a transaction".to_string(),
+        )
+    }
+    pub fn transaction_not_found(token: &str) -> Self {
+        RediSQLError::with_code(
+            32,
+            format!("The transaction `{}` does not exists, it may be already committed or rolled back", token),
+            "Transaction not found".to_string(),
+        )
+    }
+    pub fn batch_not_supported() -> Self {
+        RediSQLError::with_code(
+            39,
+            "BATCH can only execute a STATEMENT that is not READ_ONLY, and WITH_ERRORS is only valid together with BATCH".to_string(),
+            "BATCH not supported".to_string(),
+        )
+    }
+    pub fn invalid_cursor_count() -> Self {
+        RediSQLError::with_code(
+            40,
+            "The number of rows of each page of the cursor must be a positive integer".to_string(),
+            "Invalid cursor count".to_string(),
+        )
+    }
+    pub fn cursor_not_found(id: u64) -> Self {
+        RediSQLError::with_code(
+            42,
+            format!("The cursor `{}` does not exists, it may be already closed or expired", id),
+            "Cursor not found".to_string(),
+        )
+    }
+    pub fn too_many_cursors(max: usize) -> Self {
+        RediSQLError::with_code(
+            43,
+            format!("There are already {} cursors open on the database, close some of them before opening a new one", max),
+            "Too many cursors open".to_string(),
+        )
+    }
     pub fn empty_key() -> Self {
         RediSQLError::with_code(
                 15,
@@ -7,7 +7,10 @@ use std::os::raw::c_char;
 use std::ptr;
 use std::sync::{Arc, Mutex};
 
+use crate::redis_functions;
 use crate::redisql_error as err;
+use crate::timeout;
+use crate::virtual_tables;
 
 use crate::community_statement::Statement;
 
@@ -83,8 +86,21 @@ impl RawConnection {
             )
         };
         let conn = unsafe { conn.assume_init() };
+        let r = match r {
+            ffi::SQLITE_OK => virtual_tables::register_modules(conn),
+            r => r,
+        };
+        let r = match r {
+            ffi::SQLITE_OK => {
+                redis_functions::register_functions(conn)
+            }
+            r => r,
+        };
         match r {
-            ffi::SQLITE_OK => Ok(RawConnection { conn, flags }),
+            ffi::SQLITE_OK => {
+                timeout::install_progress_handler(conn);
+                Ok(RawConnection { conn, flags })
+            }
             _ => Err(unsafe {
                 get_last_error_from_db_connection(conn)
             }),
@@ -180,6 +196,12 @@ impl Connection {
     pub fn is_serialized(&self) -> bool {
         self.db.is_serialized()
     }
+    /// If the database lives only in memory, other connections cannot
+    /// open it.
+    pub fn is_in_memory(&self) -> bool {
+        self.path.starts_with(":memory")
+            || self.path.contains("mode=memory")
+    }
     pub fn is_multithread(&self) -> bool {
         self.db.is_multithread()
     }
@@ -199,19 +221,34 @@ pub trait StatementTrait<'a>: Sized {
     ) -> Result<Self, SQLite3Error>;
     fn reset(&self);
     fn execute(&self) -> Result<Cursor, SQLite3Error>;
-    fn bind_texts(
+    fn bind_arguments(
         &self,
-        values: &[&str],
+        values: &[Argument],
     ) -> Result<SQLiteOK, SQLite3Error>;
     fn bind_index(
         &self,
         index: i32,
         value: &str,
+    ) -> Result<SQLiteOK, SQLite3Error> {
+        self.bind_argument_index(
+            index,
+            &Argument::Text { text: value },
+        )
+    }
+    fn bind_argument_index(
+        &self,
+        index: i32,
+        value: &Argument,
+    ) -> Result<SQLiteOK, SQLite3Error>;
+    fn bind_named_arguments(
+        &self,
+        values: &[(&str, Argument)],
     ) -> Result<SQLiteOK, SQLite3Error>;
     fn is_read_only(&self) -> bool {
         false
     }
     fn parameters_count(&self) -> u32;
+    fn parameters_names(&self) -> Vec<String>;
     fn sql(&self) -> String;
 }
 
@@ -230,13 +267,49 @@ pub enum Entity {
     Integer { int: i64 },
     Float { float: f64 },
     Text { text: String },
-    Blob { blob: String },
+    Blob { blob: Vec<u8> },
 
     Null,
     OK {},
     DONE { modified_rows: i32 },
 }
 
+/// A value to bind to a statement parameter, it decides which
+/// `sqlite3_bind_*` function is used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Argument<'a> {
+    Integer { int: i64 },
+    Float { float: f64 },
+    Text { text: &'a str },
+    Blob { blob: &'a [u8] },
+    Null,
+}
+
+impl<'a> Argument<'a> {
+    /// Arguments without an explicit type are bound as TEXT when they
+    /// are valid UTF-8 and as BLOB otherwise.
+    pub fn untyped(value: &'a [u8]) -> Self {
+        match std::str::from_utf8(value) {
+            Ok(text) => Argument::Text { text },
+            Err(_) => Argument::Blob { blob: value },
+        }
+    }
+}
+
+/// The values to bind to a statement, either by position or by the
+/// name of the parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bindings<'a> {
+    Positional(Vec<Argument<'a>>),
+    Named(Vec<(&'a str, Argument<'a>)>),
+}
+
+impl<'a> Default for Bindings<'a> {
+    fn default() -> Self {
+        Bindings::Positional(Vec::new())
+    }
+}
+
 impl Entity {
     pub fn new(stmt: &Statement, i: i32) -> Entity {
         match get_entity_type(stmt.as_ptr(), i) {
@@ -266,18 +339,62 @@ impl Entity {
             }
 
             EntityType::Blob => {
+                // the pointer must be read before asking for the size, see
+                // https://www.sqlite.org/c3ref/column_blob.html
                 let value = unsafe {
-                    CStr::from_ptr(ffi::sqlite3_column_blob(
-                        stmt.as_ptr(),
-                        i,
-                    )
+                    let ptr =
+                        ffi::sqlite3_column_blob(stmt.as_ptr(), i);
+                    let len =
+                        ffi::sqlite3_column_bytes(stmt.as_ptr(), i);
+                    match len {
+                        0 => Vec::new(),
+                        len => std::slice::from_raw_parts(
+                            ptr as *const u8,
+                            len as usize,
+                        )
+                        .to_vec(),
+                    }
+                };
+                Entity::Blob { blob: value }
+            }
+            EntityType::Null => Entity::Null {},
+        }
+    }
+    /// Read a protected or unprotected `sqlite3_value`, like the ones
+    /// passed to hooks and functions.
+    pub fn from_value(value: *mut ffi::sqlite3_value) -> Entity {
+        match unsafe { ffi::sqlite3_value_type(value) } {
+            ffi::SQLITE_INTEGER => Entity::Integer {
+                int: unsafe { ffi::sqlite3_value_int64(value) },
+            },
+            ffi::SQLITE_FLOAT => Entity::Float {
+                float: unsafe { ffi::sqlite3_value_double(value) },
+            },
+            ffi::SQLITE_TEXT => {
+                let text = unsafe {
+                    CStr::from_ptr(ffi::sqlite3_value_text(value)
                         as *const c_char)
                     .to_string_lossy()
                     .into_owned()
                 };
-                Entity::Blob { blob: value }
+                Entity::Text { text }
             }
-            EntityType::Null => Entity::Null {},
+            ffi::SQLITE_BLOB => {
+                let blob = unsafe {
+                    let ptr = ffi::sqlite3_value_blob(value);
+                    let len = ffi::sqlite3_value_bytes(value);
+                    match len {
+                        0 => Vec::new(),
+                        len => std::slice::from_raw_parts(
+                            ptr as *const u8,
+                            len as usize,
+                        )
+                        .to_vec(),
+                    }
+                };
+                Entity::Blob { blob }
+            }
+            _ => Entity::Null,
         }
     }
 }
@@ -297,6 +414,44 @@ pub enum Cursor {
      * cleaner?) way to manage empty return statements */
 }
 
+impl Cursor {
+    pub fn modified_rows(&self) -> i32 {
+        match self {
+            Cursor::OKCursor {} => 0,
+            Cursor::DONECursor { modified_rows }
+            | Cursor::RowsCursor { modified_rows, .. } => {
+                *modified_rows
+            }
+        }
+    }
+    /// Which columns of the rows are declared as BOOLEAN.
+    pub fn boolean_columns(&self) -> Vec<bool> {
+        match self {
+            Cursor::RowsCursor {
+                stmt, num_columns, ..
+            } => (0..*num_columns)
+                .map(|i| {
+                    let decltype = unsafe {
+                        ffi::sqlite3_column_decltype(stmt.as_ptr(), i)
+                    };
+                    if decltype.is_null() {
+                        return false;
+                    }
+                    let decltype =
+                        unsafe { CStr::from_ptr(decltype) };
+                    decltype
+                        .to_bytes()
+                        .eq_ignore_ascii_case(b"BOOLEAN")
+                        || decltype
+                            .to_bytes()
+                            .eq_ignore_ascii_case(b"BOOL")
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
 impl<'a> FromIterator<Cursor> for Cursor {
     fn from_iter<I: IntoIterator<Item = Cursor>>(
         cursors: I,
@@ -358,9 +513,17 @@ pub enum QueryResult {
         last_id: String,
         size: u32,
     },
+    /// A page of rows read from a cursor, the cursor is 0 once all the
+    /// rows have been returned.
+    Page {
+        cursor: u64,
+        names: Vec<String>,
+        types: Vec<&'static str>,
+        array: Vec<Entity>,
+    },
 }
 
-fn type_to_string(t: i32) -> &'static str {
+pub(crate) fn type_to_string(t: i32) -> &'static str {
     match t {
         ffi::SQLITE_INTEGER => "INT",
         ffi::SQLITE_FLOAT => "FLOAT",
@@ -624,6 +787,17 @@ pub unsafe fn BackupFinish(bk: &Backup) -> i32 {
     ffi::sqlite3_backup_finish(bk.as_ptr())
 }
 
+/// How many pages are left to copy, and the pages of the source, as of
+/// the last step of the backup.
+pub fn backup_progress(bk: &Backup) -> (i32, i32) {
+    unsafe {
+        (
+            ffi::sqlite3_backup_remaining(bk.as_ptr()),
+            ffi::sqlite3_backup_pagecount(bk.as_ptr()),
+        )
+    }
+}
+
 pub fn backup_step_is_ok(result: i32) -> bool {
     result == ffi::SQLITE_OK
 }
@@ -640,6 +814,123 @@ pub fn backup_complete_with_done(result: i32) -> bool {
     result == ffi::SQLITE_DONE
 }
 
+/// The content of the main database of the connection, as the bytes of a
+/// SQLite file.
+pub fn serialize(conn: &Connection) -> Result<Vec<u8>, SQLite3Error> {
+    let schema = CString::new("main").unwrap();
+    let mut size: ffi::sqlite3_int64 = 0;
+    let image = unsafe {
+        ffi::sqlite3_serialize(
+            conn.get_db(),
+            schema.as_ptr(),
+            &mut size,
+            0,
+        )
+    };
+    if image.is_null() {
+        return Err(conn.get_last_error());
+    }
+    let bytes =
+        unsafe { std::slice::from_raw_parts(image, size as usize) }
+            .to_vec();
+    unsafe { ffi::sqlite3_free(image as *mut std::os::raw::c_void) };
+    Ok(bytes)
+}
+
+/// Replace the main database of the connection with a copy of the image
+/// produced by `serialize`, the copy is kept in memory.
+pub fn deserialize(
+    conn: &Connection,
+    image: &[u8],
+) -> Result<(), SQLite3Error> {
+    let schema = CString::new("main").unwrap();
+    let size = image.len() as ffi::sqlite3_int64;
+    // SQLite takes the ownership of the buffer, even if it fails
+    let buffer = unsafe { ffi::sqlite3_malloc64(image.len() as u64) }
+        as *mut u8;
+    if buffer.is_null() {
+        return Err(conn.get_last_error());
+    }
+    unsafe {
+        ptr::copy_nonoverlapping(image.as_ptr(), buffer, image.len())
+    };
+    let flags = ffi::SQLITE_DESERIALIZE_FREEONCLOSE
+        | ffi::SQLITE_DESERIALIZE_RESIZEABLE;
+    let rc = unsafe {
+        ffi::sqlite3_deserialize(
+            conn.get_db(),
+            schema.as_ptr(),
+            buffer,
+            size,
+            size,
+            flags as u32,
+        )
+    };
+    match rc {
+        ffi::SQLITE_OK => Ok(()),
+        _ => Err(conn.get_last_error()),
+    }
+}
+
+/// Execute the statements one after the other, each one is compiled only
+/// after the previous ones are executed, so that it can use the tables
+/// they create.
+pub fn execute_script(
+    conn: &Connection,
+    script: &str,
+) -> Result<(), SQLite3Error> {
+    let script = match CString::new(script) {
+        Ok(script) => script,
+        Err(_) => {
+            return Err(SQLite3Error {
+                code: ffi::SQLITE_MISUSE as u32,
+                error_message: "The script contains a NUL character"
+                    .to_string(),
+                error_string: "Invalid script".to_string(),
+            })
+        }
+    };
+    let rc = unsafe {
+        ffi::sqlite3_exec(
+            conn.get_db(),
+            script.as_ptr(),
+            None,
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+    };
+    match rc {
+        ffi::SQLITE_OK => Ok(()),
+        _ => Err(conn.get_last_error()),
+    }
+}
+
+/// The memory, in bytes, used by the connection for its page cache, its
+/// schema and its prepared statements.
+pub fn memory_used(conn: &Connection) -> usize {
+    [
+        ffi::SQLITE_DBSTATUS_CACHE_USED,
+        ffi::SQLITE_DBSTATUS_SCHEMA_USED,
+        ffi::SQLITE_DBSTATUS_STMT_USED,
+    ]
+    .iter()
+    .map(|op| {
+        let mut current = 0;
+        let mut highwater = 0;
+        unsafe {
+            ffi::sqlite3_db_status(
+                conn.get_db(),
+                *op,
+                &mut current,
+                &mut highwater,
+                0,
+            )
+        };
+        current.max(0) as usize
+    })
+    .sum()
+}
+
 pub fn disable_global_memory_statistics() {
     unsafe {
         ffi::sqlite3_config(ffi::SQLITE_CONFIG_MEMSTATUS, 0);
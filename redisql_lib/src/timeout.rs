@@ -0,0 +1,85 @@
+use std::cell::Cell;
+use std::os::raw::{c_int, c_void};
+use std::time::{Duration, Instant};
+
+use crate::config::config;
+use crate::sqlite::ffi;
+
+/// How many SQLite virtual machine instructions are executed between two
+/// checks of the deadline.
+const PROGRESS_HANDLER_STEPS: c_int = 1_000;
+
+/// How long a command can run when it does not provide its own `TIMEOUT`.
+pub fn default_timeout() -> Duration {
+    config().default_timeout
+}
+
+/// The deadline of a command that starts now and uses the default
+/// timeout.
+pub fn default_deadline() -> Instant {
+    Instant::now() + default_timeout()
+}
+
+/// How many milliseconds Redis should keep blocked a client that waits
+/// for a command with the given timeout.
+/// It is a little longer than the timeout itself, so that the client
+/// receives the error of the interrupted query and not a null reply.
+pub fn blocked_client_timeout(timeout: Duration) -> i64 {
+    timeout.as_millis() as i64 + 1_000
+}
+
+thread_local! {
+    /// The deadline of the command that the thread is running.
+    static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+}
+
+/// Interrupt the SQL run by the current thread once the deadline passes,
+/// until it is dropped.
+///
+/// The deadline is checked by the SQLite progress handler installed on
+/// every connection when it is opened, so also a single long running step
+/// is interrupted and fails with `SQLITE_INTERRUPT`. The deadline belongs
+/// to the thread and not to the connection: the connection is never
+/// touched outside its lock, and the commands that Redis runs on its own
+/// thread do not change the deadline of the worker, or the other way
+/// around.
+pub struct InterruptAfter {
+    previous: Option<Instant>,
+}
+
+impl InterruptAfter {
+    pub fn new(deadline: Instant) -> Self {
+        let previous = DEADLINE.with(|d| d.replace(Some(deadline)));
+        InterruptAfter { previous }
+    }
+}
+
+impl Drop for InterruptAfter {
+    fn drop(&mut self) {
+        DEADLINE.with(|d| d.set(self.previous));
+    }
+}
+
+/// Install the progress handler on a connection that is being opened.
+pub fn install_progress_handler(db: *mut ffi::sqlite3) {
+    unsafe {
+        ffi::sqlite3_progress_handler(
+            db,
+            PROGRESS_HANDLER_STEPS,
+            Some(interrupt_after_deadline),
+            std::ptr::null_mut(),
+        );
+    }
+}
+
+extern "C" fn interrupt_after_deadline(_: *mut c_void) -> c_int {
+    let expired = DEADLINE.with(|d| match d.get() {
+        Some(deadline) => Instant::now() > deadline,
+        None => false,
+    });
+    if expired {
+        1
+    } else {
+        0
+    }
+}
@@ -0,0 +1,312 @@
+use std::os::raw::c_void;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::config;
+use crate::redis::{
+    create_backup, replicate_transaction_statement, Command,
+    DBChannel,
+};
+use crate::redis_type::ffi;
+use crate::redis_type::Context;
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::{Connection, SQLite3Error};
+
+/// How long a transaction can stay open without receiving any command
+/// before being rolled back.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct Transaction {
+    token: String,
+    client_id: u64,
+    database: String,
+    idle_timeout: Duration,
+    last_activity: Instant,
+}
+
+impl Transaction {
+    fn deadline(&self) -> Instant {
+        self.last_activity + self.idle_timeout
+    }
+}
+
+/// The transaction open on a database.
+///
+/// It is shared between the Redis main thread, that decides which
+/// commands are allowed to reach the database, and the worker thread,
+/// that rolls back the transaction when it stays idle for too long.
+/// Whoever closes a transaction without a COMMIT or a ROLLBACK also
+/// replicates its rollback.
+///
+/// While the transaction is open the connection holds its uncommitted
+/// writes, so the worker keeps a snapshot of the database taken right
+/// before the BEGIN, and the RDB and the AOF save that one instead.
+#[derive(Clone, Debug, Default)]
+pub struct TransactionState {
+    transaction: Arc<Mutex<Option<Transaction>>>,
+    snapshot: Arc<Mutex<Option<Snapshot>>>,
+}
+
+/// The file with the content of the database committed before the BEGIN.
+///
+/// A file database in WAL mode is its own snapshot: a new connection
+/// does not see the writes of the open transaction. In-memory databases
+/// are copied in a temporary file in DATA_DIR, removed once the
+/// transaction is over.
+#[derive(Debug)]
+pub struct Snapshot {
+    path: String,
+    temporary: bool,
+}
+
+impl Snapshot {
+    /// Take the snapshot of the database, before its BEGIN.
+    pub fn take(conn: &Connection) -> Result<Snapshot, SQLite3Error> {
+        if !conn.is_in_memory() {
+            return Ok(Snapshot {
+                path: conn.path.clone(),
+                temporary: false,
+            });
+        }
+        static TAKEN: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "redisql-snapshot-{}-{}.sqlite",
+            std::process::id(),
+            TAKEN.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = config()
+            .data_dir
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join(name)
+            .to_string_lossy()
+            .into_owned();
+        let snapshot = Snapshot {
+            path,
+            temporary: true,
+        };
+        create_backup(conn, &snapshot.path)?;
+        Ok(snapshot)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl TransactionState {
+    pub fn new() -> Self {
+        TransactionState::default()
+    }
+    /// Open a new transaction, it fails if another transaction is
+    /// already open.
+    pub fn begin(
+        &self,
+        token: &str,
+        client_id: u64,
+        database: &str,
+        idle_timeout: Duration,
+    ) -> Result<(), RediSQLError> {
+        let mut transaction = self.transaction.lock().unwrap();
+        if transaction.is_some() {
+            return Err(RediSQLError::transaction_in_progress());
+        }
+        *transaction = Some(Transaction {
+            token: token.to_string(),
+            client_id,
+            database: database.to_string(),
+            idle_timeout,
+            last_activity: Instant::now(),
+        });
+        Ok(())
+    }
+    /// Check that a command can be executed given the transaction open
+    /// on the database.
+    /// Commands without a token are allowed only if no transaction is
+    /// open, commands with a token only if they belong to the open
+    /// transaction.
+    pub fn check(
+        &self,
+        token: Option<&str>,
+    ) -> Result<(), RediSQLError> {
+        let mut transaction = self.transaction.lock().unwrap();
+        match (transaction.as_mut(), token) {
+            (None, None) => Ok(()),
+            (Some(tx), Some(token)) if tx.token == token => {
+                tx.last_activity = Instant::now();
+                Ok(())
+            }
+            (Some(_), None) => {
+                Err(RediSQLError::transaction_in_progress())
+            }
+            (_, Some(token)) => {
+                Err(RediSQLError::transaction_not_found(token))
+            }
+        }
+    }
+    /// Close the transaction identified by the token.
+    pub fn end(&self, token: &str) -> Result<(), RediSQLError> {
+        self.close_if(|tx| tx.token == token)
+            .map(|_| ())
+            .ok_or_else(|| RediSQLError::transaction_not_found(token))
+    }
+    /// Close the transaction if it has been idle for too long, it
+    /// returns the token of the closed transaction and its database.
+    pub fn take_expired(&self) -> Option<(String, String)> {
+        self.close_if(|tx| tx.deadline() <= Instant::now())
+    }
+    /// Close the transaction if it was open by the client.
+    fn take_owned_by(
+        &self,
+        client_id: u64,
+    ) -> Option<(String, String)> {
+        self.close_if(|tx| tx.client_id == client_id)
+    }
+    fn close_if<F: FnOnce(&Transaction) -> bool>(
+        &self,
+        to_close: F,
+    ) -> Option<(String, String)> {
+        let closed = {
+            let mut transaction = self.transaction.lock().unwrap();
+            match transaction.take() {
+                Some(tx) if to_close(&tx) => {
+                    Some((tx.token, tx.database))
+                }
+                tx => {
+                    *transaction = tx;
+                    None
+                }
+            }
+        };
+        if closed.is_some() {
+            forget_client_transaction(self);
+        }
+        closed
+    }
+    /// Keep the snapshot of the database as it was before the BEGIN.
+    pub fn keep_snapshot(&self, snapshot: Snapshot) {
+        *self.snapshot.lock().unwrap() = Some(snapshot);
+    }
+    /// Forget the snapshot, the transaction is over on the connection.
+    pub fn drop_snapshot(&self) {
+        *self.snapshot.lock().unwrap() = None;
+    }
+    /// A new connection to the database as it was before the open
+    /// transaction, if any. The snapshot is not removed while it is
+    /// opened.
+    pub fn open_snapshot(
+        &self,
+    ) -> Result<Option<Connection>, SQLite3Error> {
+        match self.snapshot.lock().unwrap().as_ref() {
+            Some(snapshot) => {
+                Connection::open_connection(&snapshot.path).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+    /// How long the worker thread can wait for the next command before
+    /// checking if the transaction is idle.
+    pub fn time_left(&self) -> Option<Duration> {
+        let transaction = self.transaction.lock().unwrap();
+        match transaction.as_ref() {
+            Some(tx) => Some(
+                tx.deadline()
+                    .saturating_duration_since(Instant::now()),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// The transactions opened by each client, used to roll them back when
+/// the client disconnects.
+static OPEN_TRANSACTIONS: Mutex<
+    Vec<(u64, TransactionState, DBChannel)>,
+> = Mutex::new(Vec::new());
+
+pub fn register_client_transaction(
+    client_id: u64,
+    state: &TransactionState,
+    ch: &DBChannel,
+) {
+    let mut open = OPEN_TRANSACTIONS.lock().unwrap();
+    open.retain(|(id, s, _)| {
+        !(*id == client_id
+            && Arc::ptr_eq(&s.transaction, &state.transaction))
+    });
+    open.push((client_id, state.clone(), ch.clone()));
+}
+
+fn forget_client_transaction(state: &TransactionState) {
+    let mut open = OPEN_TRANSACTIONS.lock().unwrap();
+    open.retain(|(_, s, _)| {
+        !Arc::ptr_eq(&s.transaction, &state.transaction)
+    });
+}
+
+/// Roll back all the transactions opened by the client.
+pub fn client_disconnected(ctx: &Context, client_id: u64) {
+    let disconnected = {
+        let mut open = OPEN_TRANSACTIONS.lock().unwrap();
+        let (disconnected, rest) = open
+            .drain(..)
+            .partition::<Vec<_>, _>(|(id, _, _)| *id == client_id);
+        *open = rest;
+        disconnected
+    };
+    for (_, state, ch) in disconnected {
+        if let Some((token, database)) =
+            state.take_owned_by(client_id)
+        {
+            debug!("Rolling back transaction {} of a disconnected client", token);
+            let _ = ch.send(Command::EndTransaction {
+                token,
+                database: database.clone(),
+                commit: false,
+                client: None,
+            });
+            replicate_transaction_statement(
+                ctx, &database, "ROLLBACK",
+            );
+        }
+    }
+}
+
+extern "C" fn on_client_change(
+    ctx: *mut ffi::RedisModuleCtx,
+    _eid: ffi::RedisModuleEvent,
+    subevent: u64,
+    data: *mut c_void,
+) {
+    if subevent
+        != ffi::REDISMODULE_SUBEVENT_CLIENT_CHANGE_DISCONNECTED as u64
+        || data.is_null()
+    {
+        return;
+    }
+    let client =
+        unsafe { &*(data as *const ffi::RedisModuleClientInfo) };
+    client_disconnected(&Context::new(ctx), client.id);
+}
+
+/// Roll back the transactions of the clients that disconnect.
+/// Server events are available only since Redis 6, on older versions
+/// the transactions are rolled back only when they are idle for too long.
+pub fn subscribe_to_disconnections(ctx: &Context) {
+    let event = ffi::RedisModuleEvent {
+        id: ffi::REDISMODULE_EVENT_CLIENT_CHANGE as u64,
+        dataver: 1,
+    };
+    match unsafe { ffi::RedisModule_SubscribeToServerEvent } {
+        Some(subscribe) => unsafe {
+            subscribe(ctx.as_ptr(), event, Some(on_client_change));
+        },
+        None => debug!("Server events not supported by this Redis"),
+    }
+}
@@ -0,0 +1,823 @@
+//! The `EXPR` language, the bodies of the functions are expressions
+//! written like in SQL:
+//!
+//! * the arguments are `$1`, `$2`, ... and `acc` is the value computed
+//!   so far by an aggregate, `NULL` before the first row;
+//! * literals are numbers, strings between single quotes, `NULL`,
+//!   `TRUE` and `FALSE`;
+//! * the operators, by precedence, are `||`, `* / %`, `+ -`, the
+//!   comparisons `= == != <> < <= > >=`, `NOT`, `AND` and `OR`;
+//! * the functions are `abs`, `coalesce`, `if`, `ifnull`, `length`,
+//!   `lower`, `max`, `min`, `nullif`, `round` and `upper`.
+//!
+//! `NULL` works as in SQL, most operations with a `NULL` are `NULL`.
+//!
+//! An expression can nest at most `MAX_DEPTH` operators, parentheses
+//! and function calls.
+
+use std::cmp::Ordering;
+
+/// How deep an expression can be, the deeper ones would overflow the
+/// stack when they are parsed or evaluated.
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Number {
+    Integer(i64),
+    Real(f64),
+}
+
+impl Number {
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Integer(i) => i as f64,
+            Number::Real(f) => f,
+        }
+    }
+    fn as_i64(self) -> i64 {
+        match self {
+            Number::Integer(i) => i,
+            Number::Real(f) => f as i64,
+        }
+    }
+}
+
+impl Value {
+    fn is_null(&self) -> bool {
+        *self == Value::Null
+    }
+    /// The value as a number, text that is not a number is 0.
+    fn number(&self) -> Option<Number> {
+        match self {
+            Value::Null => None,
+            Value::Integer(i) => Some(Number::Integer(*i)),
+            Value::Real(f) => Some(Number::Real(*f)),
+            Value::Text(text) => {
+                let text = text.trim();
+                if let Ok(i) = text.parse() {
+                    Some(Number::Integer(i))
+                } else {
+                    Some(Number::Real(text.parse().unwrap_or(0.0)))
+                }
+            }
+            Value::Blob(_) => Some(Number::Integer(0)),
+        }
+    }
+    fn text(&self) -> Option<String> {
+        match self {
+            Value::Null => None,
+            Value::Integer(i) => Some(i.to_string()),
+            Value::Real(f) if f.fract() == 0.0 && f.abs() < 1e15 => {
+                Some(format!("{:.1}", f))
+            }
+            Value::Real(f) => Some(f.to_string()),
+            Value::Text(text) => Some(text.clone()),
+            Value::Blob(blob) => {
+                Some(String::from_utf8_lossy(blob).into_owned())
+            }
+        }
+    }
+    fn truth(&self) -> Option<bool> {
+        self.number().map(|n| n.as_f64() != 0.0)
+    }
+    fn from_bool(b: Option<bool>) -> Value {
+        match b {
+            Some(b) => Value::Integer(b as i64),
+            None => Value::Null,
+        }
+    }
+    /// The order of SQLite: numbers, then text, then blobs.
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        fn class(v: &Value) -> u8 {
+            match v {
+                Value::Null => 0,
+                Value::Integer(_) | Value::Real(_) => 1,
+                Value::Text(_) => 2,
+                Value::Blob(_) => 3,
+            }
+        }
+        match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => None,
+            (Value::Text(a), Value::Text(b)) => Some(a.cmp(b)),
+            (Value::Blob(a), Value::Blob(b)) => Some(a.cmp(b)),
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (a, b) if class(a) == 1 && class(b) == 1 => {
+                let (a, b) = (a.number()?, b.number()?);
+                a.as_f64().partial_cmp(&b.as_f64())
+            }
+            (a, b) => Some(class(a).cmp(&class(b))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Concat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    Abs,
+    Coalesce,
+    If,
+    IfNull,
+    Length,
+    Lower,
+    Max,
+    Min,
+    NullIf,
+    Round,
+    Upper,
+}
+
+impl Builtin {
+    fn from_name(name: &str) -> Option<Builtin> {
+        let builtin = match name.to_ascii_lowercase().as_str() {
+            "abs" => Builtin::Abs,
+            "coalesce" => Builtin::Coalesce,
+            "if" => Builtin::If,
+            "ifnull" => Builtin::IfNull,
+            "length" => Builtin::Length,
+            "lower" => Builtin::Lower,
+            "max" => Builtin::Max,
+            "min" => Builtin::Min,
+            "nullif" => Builtin::NullIf,
+            "round" => Builtin::Round,
+            "upper" => Builtin::Upper,
+            _ => return None,
+        };
+        Some(builtin)
+    }
+    /// The minimum and maximum number of arguments.
+    fn arity(self) -> (usize, usize) {
+        match self {
+            Builtin::Abs
+            | Builtin::Length
+            | Builtin::Lower
+            | Builtin::Upper => (1, 1),
+            Builtin::IfNull | Builtin::NullIf => (2, 2),
+            Builtin::If => (3, 3),
+            Builtin::Round => (1, 2),
+            Builtin::Coalesce | Builtin::Max | Builtin::Min => {
+                (1, usize::MAX)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Literal(Value),
+    /// The argument of the function, from 0.
+    Argument(usize),
+    Accumulator,
+    Negate(Box<Expression>),
+    Not(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+    Call(Builtin, Vec<Expression>),
+}
+
+impl Expression {
+    /// Parse the body of a function.
+    pub fn parse(body: &str) -> Result<Expression, String> {
+        let mut parser = Parser {
+            tokens: tokenize(body)?,
+            position: 0,
+            depth: 0,
+        };
+        let expression = parser.or()?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(token) => Err(format!(
+                "Unexpected `{}` after the expression",
+                token
+            )),
+        }
+    }
+
+    /// The number of arguments used, the highest `$n`.
+    pub fn arguments_used(&self) -> usize {
+        match self {
+            Expression::Literal(_) | Expression::Accumulator => 0,
+            Expression::Argument(i) => i + 1,
+            Expression::Negate(e) | Expression::Not(e) => {
+                e.arguments_used()
+            }
+            Expression::Binary(_, a, b) => {
+                a.arguments_used().max(b.arguments_used())
+            }
+            Expression::Call(_, args) => args
+                .iter()
+                .map(Expression::arguments_used)
+                .max()
+                .unwrap_or(0),
+        }
+    }
+
+    pub fn uses_accumulator(&self) -> bool {
+        match self {
+            Expression::Accumulator => true,
+            Expression::Literal(_) | Expression::Argument(_) => false,
+            Expression::Negate(e) | Expression::Not(e) => {
+                e.uses_accumulator()
+            }
+            Expression::Binary(_, a, b) => {
+                a.uses_accumulator() || b.uses_accumulator()
+            }
+            Expression::Call(_, args) => {
+                args.iter().any(Expression::uses_accumulator)
+            }
+        }
+    }
+
+    pub fn evaluate(
+        &self,
+        args: &[Value],
+        acc: &Value,
+    ) -> Result<Value, String> {
+        match self {
+            Expression::Literal(value) => Ok(value.clone()),
+            Expression::Argument(i) => {
+                Ok(args.get(*i).cloned().unwrap_or(Value::Null))
+            }
+            Expression::Accumulator => Ok(acc.clone()),
+            Expression::Negate(e) => {
+                let value = e.evaluate(args, acc)?;
+                Ok(match value.number() {
+                    None => Value::Null,
+                    Some(Number::Integer(i)) => match i.checked_neg()
+                    {
+                        Some(i) => Value::Integer(i),
+                        None => Value::Real(-(i as f64)),
+                    },
+                    Some(Number::Real(f)) => Value::Real(-f),
+                })
+            }
+            Expression::Not(e) => {
+                let value = e.evaluate(args, acc)?;
+                Ok(Value::from_bool(value.truth().map(|b| !b)))
+            }
+            Expression::Binary(Operator::And, a, b) => {
+                let a = a.evaluate(args, acc)?.truth();
+                if a == Some(false) {
+                    return Ok(Value::Integer(0));
+                }
+                let b = b.evaluate(args, acc)?.truth();
+                Ok(Value::from_bool(match (a, b) {
+                    (_, Some(false)) => Some(false),
+                    (Some(true), Some(true)) => Some(true),
+                    _ => None,
+                }))
+            }
+            Expression::Binary(Operator::Or, a, b) => {
+                let a = a.evaluate(args, acc)?.truth();
+                if a == Some(true) {
+                    return Ok(Value::Integer(1));
+                }
+                let b = b.evaluate(args, acc)?.truth();
+                Ok(Value::from_bool(match (a, b) {
+                    (_, Some(true)) => Some(true),
+                    (Some(false), Some(false)) => Some(false),
+                    _ => None,
+                }))
+            }
+            Expression::Binary(operator, a, b) => {
+                let a = a.evaluate(args, acc)?;
+                let b = b.evaluate(args, acc)?;
+                Ok(binary(*operator, &a, &b))
+            }
+            Expression::Call(Builtin::If, call_args) => {
+                let condition = call_args[0].evaluate(args, acc)?;
+                if condition.truth() == Some(true) {
+                    call_args[1].evaluate(args, acc)
+                } else {
+                    call_args[2].evaluate(args, acc)
+                }
+            }
+            Expression::Call(builtin, call_args) => {
+                let values = call_args
+                    .iter()
+                    .map(|arg| arg.evaluate(args, acc))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(call(*builtin, values))
+            }
+        }
+    }
+}
+
+fn binary(operator: Operator, a: &Value, b: &Value) -> Value {
+    use Operator::*;
+    match operator {
+        Concat => match (a.text(), b.text()) {
+            (Some(a), Some(b)) => Value::Text(a + &b),
+            _ => Value::Null,
+        },
+        Equal | NotEqual | Less | LessEqual | Greater
+        | GreaterEqual => {
+            Value::from_bool(a.compare(b).map(|ordering| {
+                match operator {
+                    Equal => ordering == Ordering::Equal,
+                    NotEqual => ordering != Ordering::Equal,
+                    Less => ordering == Ordering::Less,
+                    LessEqual => ordering != Ordering::Greater,
+                    Greater => ordering == Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                }
+            }))
+        }
+        _ => match (a.number(), b.number()) {
+            (Some(a), Some(b)) => arithmetic(operator, a, b),
+            _ => Value::Null,
+        },
+    }
+}
+
+/// Integers stay integers unless they overflow, a division by zero is
+/// `NULL`.
+fn arithmetic(operator: Operator, a: Number, b: Number) -> Value {
+    if operator == Operator::Remainder {
+        return match a.as_i64().checked_rem(b.as_i64()) {
+            Some(r) => Value::Integer(r),
+            None => Value::Null,
+        };
+    }
+    if let (Number::Integer(a), Number::Integer(b)) = (a, b) {
+        let result = match operator {
+            Operator::Add => a.checked_add(b),
+            Operator::Subtract => a.checked_sub(b),
+            Operator::Multiply => a.checked_mul(b),
+            _ if b == 0 => return Value::Null,
+            _ => a.checked_div(b),
+        };
+        if let Some(result) = result {
+            return Value::Integer(result);
+        }
+    }
+    let (a, b) = (a.as_f64(), b.as_f64());
+    match operator {
+        Operator::Add => Value::Real(a + b),
+        Operator::Subtract => Value::Real(a - b),
+        Operator::Multiply => Value::Real(a * b),
+        _ if b == 0.0 => Value::Null,
+        _ => Value::Real(a / b),
+    }
+}
+
+fn call(builtin: Builtin, args: Vec<Value>) -> Value {
+    let mut args = args.into_iter();
+    let first = args.next().unwrap_or(Value::Null);
+    match builtin {
+        Builtin::Abs => match first.number() {
+            None => Value::Null,
+            Some(Number::Integer(i)) => match i.checked_abs() {
+                Some(i) => Value::Integer(i),
+                None => Value::Real((i as f64).abs()),
+            },
+            Some(Number::Real(f)) => Value::Real(f.abs()),
+        },
+        Builtin::Coalesce | Builtin::IfNull => std::iter::once(first)
+            .chain(args)
+            .find(|v| !v.is_null())
+            .unwrap_or(Value::Null),
+        // only the branch chosen is evaluated, by `Expression::evaluate`
+        Builtin::If => first,
+        Builtin::Length => match &first {
+            Value::Null => Value::Null,
+            Value::Blob(blob) => Value::Integer(blob.len() as i64),
+            v => Value::Integer(
+                v.text().unwrap_or_default().chars().count() as i64,
+            ),
+        },
+        Builtin::Lower => match first.text() {
+            Some(text) => Value::Text(text.to_lowercase()),
+            None => Value::Null,
+        },
+        Builtin::Upper => match first.text() {
+            Some(text) => Value::Text(text.to_uppercase()),
+            None => Value::Null,
+        },
+        Builtin::Max | Builtin::Min => {
+            let wanted = if builtin == Builtin::Max {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+            let mut best = first;
+            for value in args {
+                match value.compare(&best) {
+                    None => return Value::Null,
+                    Some(ordering) if ordering == wanted => {
+                        best = value
+                    }
+                    Some(_) => {}
+                }
+            }
+            best
+        }
+        Builtin::NullIf => {
+            let second = args.next().unwrap_or(Value::Null);
+            if first.compare(&second) == Some(Ordering::Equal) {
+                Value::Null
+            } else {
+                first
+            }
+        }
+        Builtin::Round => {
+            let digits = args
+                .next()
+                .and_then(|d| d.number())
+                .map_or(0, |d| d.as_i64().clamp(0, 30));
+            match first.number() {
+                None => Value::Null,
+                Some(n) => {
+                    let scale = 10f64.powi(digits as i32);
+                    Value::Real((n.as_f64() * scale).round() / scale)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Value),
+    Text(String),
+    Argument(usize),
+    Identifier(String),
+    Operator(&'static str),
+    Open,
+    Close,
+    Comma,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Number(Value::Integer(i)) => write!(f, "{}", i),
+            Token::Number(Value::Real(r)) => write!(f, "{}", r),
+            Token::Number(_) => write!(f, "number"),
+            Token::Text(text) => write!(f, "'{}'", text),
+            Token::Argument(i) => write!(f, "${}", i + 1),
+            Token::Identifier(name) => write!(f, "{}", name),
+            Token::Operator(op) => write!(f, "{}", op),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::Comma => write!(f, ","),
+        }
+    }
+}
+
+const OPERATORS: &[&str] = &[
+    "||", "<=", ">=", "<>", "!=", "==", "=", "<", ">", "+", "-", "*",
+    "/", "%",
+];
+
+fn tokenize(body: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = body.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..].iter().take(2).collect();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            i += 1;
+        } else if c == ',' {
+            tokens.push(Token::Comma);
+            i += 1;
+        } else if c == '\'' {
+            // a quote inside the string is written twice
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => {
+                        return Err(
+                            "String not terminated".to_string()
+                        )
+                    }
+                    Some('\'') if chars.get(i + 1) == Some(&'\'') => {
+                        text.push('\'');
+                        i += 2;
+                    }
+                    Some('\'') => {
+                        i += 1;
+                        break;
+                    }
+                    Some(c) => {
+                        text.push(*c);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Text(text));
+        } else if c == '$' {
+            let start = i + 1;
+            i = start;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let index: String = chars[start..i].iter().collect();
+            match index.parse::<usize>() {
+                Ok(index) if index > 0 => {
+                    tokens.push(Token::Argument(index - 1))
+                }
+                _ => {
+                    return Err(
+                        "Arguments are written as $1, $2, ..."
+                            .to_string(),
+                    )
+                }
+            }
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_ascii_digit() || chars[i] == '.')
+            {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            let value = match number.parse::<i64>() {
+                Ok(int) => Value::Integer(int),
+                Err(_) => match number.parse::<f64>() {
+                    Ok(real) => Value::Real(real),
+                    Err(_) => {
+                        return Err(format!(
+                            "Invalid number `{}`",
+                            number
+                        ))
+                    }
+                },
+            };
+            tokens.push(Token::Number(value));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_')
+            {
+                i += 1;
+            }
+            tokens.push(Token::Identifier(
+                chars[start..i].iter().collect(),
+            ));
+        } else if let Some(op) =
+            OPERATORS.iter().find(|op| rest.starts_with(**op))
+        {
+            tokens.push(Token::Operator(op));
+            i += op.len();
+        } else {
+            return Err(format!("Unexpected character `{}`", c));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// How deep the expression being parsed is.
+    depth: usize,
+}
+
+impl Parser {
+    /// Go one level deeper in the expression.
+    fn enter(&mut self) -> Result<(), String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(format!(
+                "The expression is nested more than {} levels",
+                MAX_DEPTH
+            ));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+    /// Parse with `parse` one level deeper.
+    fn nested(
+        &mut self,
+        parse: fn(&mut Parser) -> Result<Expression, String>,
+    ) -> Result<Expression, String> {
+        self.enter()?;
+        let expression = parse(self);
+        self.depth -= 1;
+        expression
+    }
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+    fn is_keyword(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Identifier(name)) => {
+                name.eq_ignore_ascii_case(keyword)
+            }
+            _ => false,
+        }
+    }
+    /// The operator among `operators` that comes next, if any.
+    fn operator(
+        &mut self,
+        operators: &[(&str, Operator)],
+    ) -> Option<Operator> {
+        for (symbol, operator) in operators {
+            let found = match self.peek() {
+                Some(Token::Operator(op)) => op == symbol,
+                _ => self.is_keyword(symbol),
+            };
+            if found {
+                self.position += 1;
+                return Some(*operator);
+            }
+        }
+        None
+    }
+    fn binary(
+        &mut self,
+        operators: &[(&str, Operator)],
+        operand: fn(&mut Parser) -> Result<Expression, String>,
+    ) -> Result<Expression, String> {
+        let mut left = operand(self)?;
+        // each operator makes the expression one level deeper
+        let depth = self.depth;
+        while let Some(operator) = self.operator(operators) {
+            self.enter()?;
+            let right = operand(self)?;
+            left = Expression::Binary(
+                operator,
+                Box::new(left),
+                Box::new(right),
+            );
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+    fn or(&mut self) -> Result<Expression, String> {
+        self.binary(&[("OR", Operator::Or)], Parser::and)
+    }
+    fn and(&mut self) -> Result<Expression, String> {
+        self.binary(&[("AND", Operator::And)], Parser::not)
+    }
+    fn not(&mut self) -> Result<Expression, String> {
+        if self.is_keyword("NOT") {
+            self.position += 1;
+            return Ok(Expression::Not(Box::new(
+                self.nested(Parser::not)?,
+            )));
+        }
+        self.comparison()
+    }
+    fn comparison(&mut self) -> Result<Expression, String> {
+        self.binary(
+            &[
+                ("=", Operator::Equal),
+                ("==", Operator::Equal),
+                ("!=", Operator::NotEqual),
+                ("<>", Operator::NotEqual),
+                ("<", Operator::Less),
+                ("<=", Operator::LessEqual),
+                (">", Operator::Greater),
+                (">=", Operator::GreaterEqual),
+            ],
+            Parser::additive,
+        )
+    }
+    fn additive(&mut self) -> Result<Expression, String> {
+        self.binary(
+            &[("+", Operator::Add), ("-", Operator::Subtract)],
+            Parser::multiplicative,
+        )
+    }
+    fn multiplicative(&mut self) -> Result<Expression, String> {
+        self.binary(
+            &[
+                ("*", Operator::Multiply),
+                ("/", Operator::Divide),
+                ("%", Operator::Remainder),
+            ],
+            Parser::concat,
+        )
+    }
+    fn concat(&mut self) -> Result<Expression, String> {
+        self.binary(&[("||", Operator::Concat)], Parser::unary)
+    }
+    fn unary(&mut self) -> Result<Expression, String> {
+        match self.peek() {
+            Some(Token::Operator("-")) => {
+                self.position += 1;
+                Ok(Expression::Negate(Box::new(
+                    self.nested(Parser::unary)?,
+                )))
+            }
+            Some(Token::Operator("+")) => {
+                self.position += 1;
+                self.nested(Parser::unary)
+            }
+            _ => self.primary(),
+        }
+    }
+    fn primary(&mut self) -> Result<Expression, String> {
+        let token = match self.next() {
+            Some(token) => token,
+            None => {
+                return Err(
+                    "The expression ends too early".to_string()
+                )
+            }
+        };
+        match token {
+            Token::Number(value) => Ok(Expression::Literal(value)),
+            Token::Text(text) => {
+                Ok(Expression::Literal(Value::Text(text)))
+            }
+            Token::Argument(i) => Ok(Expression::Argument(i)),
+            Token::Open => {
+                let expression = self.nested(Parser::or)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expression),
+                    _ => Err("Missing `)`".to_string()),
+                }
+            }
+            Token::Identifier(name) => self.identifier(name),
+            token => Err(format!("Unexpected `{}`", token)),
+        }
+    }
+    fn identifier(
+        &mut self,
+        name: String,
+    ) -> Result<Expression, String> {
+        match name.to_ascii_uppercase().as_str() {
+            "NULL" => return Ok(Expression::Literal(Value::Null)),
+            "TRUE" => {
+                return Ok(Expression::Literal(Value::Integer(1)))
+            }
+            "FALSE" => {
+                return Ok(Expression::Literal(Value::Integer(0)))
+            }
+            "ACC" => return Ok(Expression::Accumulator),
+            _ => {}
+        }
+        let builtin = match Builtin::from_name(&name) {
+            Some(builtin) => builtin,
+            None => return Err(format!("Unknown name `{}`", name)),
+        };
+        if self.next() != Some(Token::Open) {
+            return Err(format!("Missing `(` after `{}`", name));
+        }
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::Close) {
+            self.position += 1;
+        } else {
+            loop {
+                args.push(self.nested(Parser::or)?);
+                match self.next() {
+                    Some(Token::Comma) => continue,
+                    Some(Token::Close) => break,
+                    _ => {
+                        return Err(format!(
+                            "Missing `)` after the arguments of `{}`",
+                            name
+                        ))
+                    }
+                }
+            }
+        }
+        let (min, max) = builtin.arity();
+        if args.len() < min || args.len() > max {
+            return Err(format!(
+                "Wrong number of arguments for `{}`",
+                name
+            ));
+        }
+        Ok(Expression::Call(builtin, args))
+    }
+}
@@ -0,0 +1,507 @@
+//! The SQL functions defined with `REDISQL.FUNCTION`.
+//!
+//! A function is registered on the connection of the writer and, before
+//! their next query, on the connections of the readers. Its definition
+//! is stored in the metadata table, so it is registered again when the
+//! database is loaded.
+
+use std::collections::hash_map::Entry;
+use std::ffi::CString;
+use std::mem;
+use std::os::raw::{c_char, c_int, c_void};
+use std::slice;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::locks::lock_connection;
+use crate::redisql_error::RediSQLError;
+use crate::sqlite::{
+    ffi, Connection, Entity, QueryResult, SQLiteConnection,
+    SQLITE_TRANSIENT,
+};
+
+use self::expression::{Expression, Value};
+
+mod expression;
+
+/// The languages of the bodies of the functions.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    /// The SQL expressions of `expression`.
+    Expr,
+}
+
+impl FromStr for Language {
+    type Err = RediSQLError;
+    fn from_str(language: &str) -> Result<Self, Self::Err> {
+        match language.to_ascii_uppercase().as_str() {
+            "EXPR" => Ok(Language::Expr),
+            _ => Err(RediSQLError::with_code(
+                72,
+                format!(
+                    "The language `{}` is not supported, the body of the function must be written in EXPR",
+                    language
+                ),
+                "Language not supported".to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    /// The number of arguments, -1 for any number.
+    pub arity: i32,
+    /// An aggregate computes `acc` for each row, its result is the last
+    /// value of `acc`.
+    pub aggregate: bool,
+    pub language: Language,
+    pub body: String,
+}
+
+impl FunctionDefinition {
+    /// The definition as it is stored in the metadata table.
+    pub fn to_metadata(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn from_metadata(value: &str) -> Result<Self, RediSQLError> {
+        serde_json::from_str(value).map_err(|e| {
+            invalid_function(format!(
+                "The definition of the function in the metadata is not valid: {}",
+                e
+            ))
+        })
+    }
+    fn compile(&self) -> Result<Expression, RediSQLError> {
+        let valid_name =
+            self.name.chars().next().map_or(false, |c| {
+                c.is_ascii_alphabetic() || c == '_'
+            }) && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(invalid_function(format!(
+                "The name `{}` is not valid, it must be made of letters, digits and `_`",
+                self.name
+            )));
+        }
+        if self.arity < -1 || self.arity > 127 {
+            return Err(invalid_function(format!(
+                "The ARITY must be between -1 and 127, not {}",
+                self.arity
+            )));
+        }
+        let expression =
+            Expression::parse(&self.body).map_err(|e| {
+                invalid_function(format!(
+                    "The body of `{}` is not valid: {}",
+                    self.name, e
+                ))
+            })?;
+        if self.arity >= 0
+            && expression.arguments_used() > self.arity as usize
+        {
+            return Err(invalid_function(format!(
+                "The body of `{}` uses ${}, but the function has only {} arguments",
+                self.name,
+                expression.arguments_used(),
+                self.arity
+            )));
+        }
+        if !self.aggregate && expression.uses_accumulator() {
+            return Err(invalid_function(format!(
+                "The body of `{}` uses `acc`, but the function is not an AGGREGATE",
+                self.name
+            )));
+        }
+        Ok(expression)
+    }
+}
+
+fn invalid_function(debug: String) -> RediSQLError {
+    RediSQLError::with_code(
+        73,
+        debug,
+        "Function definition not valid".to_string(),
+    )
+}
+
+/// The functions of a database, shared by the writer and the readers.
+#[derive(Clone, Default)]
+pub struct Functions {
+    definitions: Arc<RwLock<FnvHashMap<String, FunctionDefinition>>>,
+    /// Incremented at each change of the definitions.
+    version: Arc<AtomicUsize>,
+}
+
+/// The functions registered on the connection of a reader.
+#[derive(Default)]
+pub struct RegisteredFunctions {
+    version: usize,
+    functions: Vec<(String, i32)>,
+}
+
+impl Functions {
+    /// Register the function on the connection and add it, `persist`
+    /// stores its definition.
+    pub fn insert<F>(
+        &self,
+        db: &Arc<Mutex<Connection>>,
+        definition: FunctionDefinition,
+        can_update: bool,
+        persist: F,
+    ) -> Result<QueryResult, RediSQLError>
+    where
+        F: FnOnce(&FunctionDefinition) -> Result<(), RediSQLError>,
+    {
+        definition.compile()?;
+        let mut definitions = self.definitions.write().unwrap();
+        let previous = match definitions.entry(definition.name.clone())
+        {
+            Entry::Occupied(_) if !can_update => {
+                return Err(RediSQLError::with_code(
+                    74,
+                    format!("The function `{}` is already present, use CAN_UPDATE to replace it", definition.name),
+                    "Function already present".to_string(),
+                ))
+            }
+            Entry::Occupied(o) => Some(o.get().clone()),
+            Entry::Vacant(_) => None,
+        };
+        {
+            let conn = lock_connection(&db);
+            if let Some(previous) = &previous {
+                unregister(&conn, &previous.name, previous.arity)?;
+            }
+            register(&conn, &definition)?;
+        }
+        if let Err(e) = persist(&definition) {
+            let conn = lock_connection(&db);
+            let _ =
+                unregister(&conn, &definition.name, definition.arity);
+            if let Some(previous) = &previous {
+                let _ = register(&conn, previous);
+            }
+            return Err(e);
+        }
+        definitions.insert(definition.name.clone(), definition);
+        self.version.fetch_add(1, Ordering::SeqCst);
+        Ok(QueryResult::OK {})
+    }
+    /// Remove the function from the connection, `forget` removes its
+    /// definition.
+    pub fn delete<F>(
+        &self,
+        db: &Arc<Mutex<Connection>>,
+        name: &str,
+        forget: F,
+    ) -> Result<QueryResult, RediSQLError>
+    where
+        F: FnOnce() -> Result<(), RediSQLError>,
+    {
+        let mut definitions = self.definitions.write().unwrap();
+        let definition = match definitions.get(name) {
+            Some(definition) => definition,
+            None => {
+                return Err(RediSQLError::with_code(
+                    74,
+                    format!("The function `{}` is not present", name),
+                    "Function not present".to_string(),
+                ))
+            }
+        };
+        unregister(&lock_connection(&db), name, definition.arity)?;
+        if let Err(e) = forget() {
+            let _ = register(&lock_connection(&db), definition);
+            return Err(e);
+        }
+        definitions.remove(name);
+        self.version.fetch_add(1, Ordering::SeqCst);
+        Ok(QueryResult::OK {})
+    }
+    pub fn list(&self) -> QueryResult {
+        let definitions = self.definitions.read().unwrap();
+        let mut sorted: Vec<_> = definitions.values().collect();
+        sorted.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut array = Vec::with_capacity(sorted.len() * 5);
+        for definition in sorted {
+            array.push(Entity::Text {
+                text: definition.name.clone(),
+            });
+            array.push(Entity::Integer {
+                int: definition.arity as i64,
+            });
+            array.push(Entity::Integer {
+                int: definition.aggregate as i64,
+            });
+            array.push(Entity::Text {
+                text: format!("{:?}", definition.language)
+                    .to_uppercase(),
+            });
+            array.push(Entity::Text {
+                text: definition.body.clone(),
+            });
+        }
+        QueryResult::Array {
+            names: vec![
+                "name".to_string(),
+                "arity".to_string(),
+                "aggregate".to_string(),
+                "language".to_string(),
+                "body".to_string(),
+            ],
+            types: vec!["TEXT", "INT", "INT", "TEXT", "TEXT"],
+            array,
+        }
+    }
+    /// Remove all the functions from the connection, they are restored
+    /// from the metadata table after the database is loaded.
+    pub fn clear(&self, db: &Arc<Mutex<Connection>>) {
+        let mut definitions = self.definitions.write().unwrap();
+        let conn = lock_connection(&db);
+        for definition in definitions.values() {
+            let _ =
+                unregister(&conn, &definition.name, definition.arity);
+        }
+        definitions.clear();
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+    /// Register on the connection of a reader the functions that changed
+    /// since the last time.
+    pub fn sync(
+        &self,
+        db: &Arc<Mutex<Connection>>,
+        registered: &mut RegisteredFunctions,
+    ) {
+        let version = self.version.load(Ordering::SeqCst);
+        if version == registered.version {
+            return;
+        }
+        let definitions = self.definitions.read().unwrap();
+        let conn = lock_connection(&db);
+        for (name, arity) in registered.functions.drain(..) {
+            let _ = unregister(&conn, &name, arity);
+        }
+        for definition in definitions.values() {
+            match register(&conn, definition) {
+                Ok(()) => registered
+                    .functions
+                    .push((definition.name.clone(), definition.arity)),
+                Err(e) => debug!(
+                    "Error registering the function {} on a reader: {}",
+                    definition.name, e
+                ),
+            }
+        }
+        registered.version = version;
+    }
+}
+
+/// What a function needs when it is called.
+struct Compiled {
+    expression: Expression,
+}
+
+fn register(
+    conn: &Connection,
+    definition: &FunctionDefinition,
+) -> Result<(), RediSQLError> {
+    let expression = definition.compile()?;
+    let name = CString::new(definition.name.as_str()).unwrap();
+    let compiled = Box::into_raw(Box::new(Compiled { expression }));
+    let flags = ffi::SQLITE_UTF8 | ffi::SQLITE_DETERMINISTIC;
+    // if the registration fails SQLite frees `compiled` itself
+    let rc = unsafe {
+        if definition.aggregate {
+            ffi::sqlite3_create_function_v2(
+                conn.get_db(),
+                name.as_ptr(),
+                definition.arity,
+                flags,
+                compiled as *mut c_void,
+                None,
+                Some(step),
+                Some(finalize),
+                Some(free_compiled),
+            )
+        } else {
+            ffi::sqlite3_create_function_v2(
+                conn.get_db(),
+                name.as_ptr(),
+                definition.arity,
+                flags,
+                compiled as *mut c_void,
+                Some(scalar),
+                None,
+                None,
+                Some(free_compiled),
+            )
+        }
+    };
+    match rc {
+        ffi::SQLITE_OK => Ok(()),
+        _ => Err(conn.get_last_error().into()),
+    }
+}
+
+fn unregister(
+    conn: &Connection,
+    name: &str,
+    arity: i32,
+) -> Result<(), RediSQLError> {
+    let name = CString::new(name).unwrap();
+    let rc = unsafe {
+        ffi::sqlite3_create_function_v2(
+            conn.get_db(),
+            name.as_ptr(),
+            arity,
+            ffi::SQLITE_UTF8,
+            std::ptr::null_mut(),
+            None,
+            None,
+            None,
+            None,
+        )
+    };
+    match rc {
+        ffi::SQLITE_OK => Ok(()),
+        _ => Err(conn.get_last_error().into()),
+    }
+}
+
+unsafe extern "C" fn free_compiled(compiled: *mut c_void) {
+    drop(Box::from_raw(compiled as *mut Compiled));
+}
+
+unsafe fn arguments(
+    argc: c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+) -> Vec<Value> {
+    slice::from_raw_parts(argv, argc as usize)
+        .iter()
+        .map(|value| to_value(*value))
+        .collect()
+}
+
+unsafe extern "C" fn scalar(
+    ctx: *mut ffi::sqlite3_context,
+    argc: c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+) {
+    let compiled = &*(ffi::sqlite3_user_data(ctx) as *const Compiled);
+    let args = arguments(argc, argv);
+    match compiled.expression.evaluate(&args, &Value::Null) {
+        Ok(value) => result_value(ctx, &value),
+        Err(e) => result_error(ctx, &e),
+    }
+}
+
+/// The accumulator of the group, it lives in the memory that SQLite
+/// provides for the group, `finalize` frees it.
+unsafe fn accumulator(
+    ctx: *mut ffi::sqlite3_context,
+    size: c_int,
+) -> *mut *mut Value {
+    ffi::sqlite3_aggregate_context(ctx, size) as *mut *mut Value
+}
+
+unsafe extern "C" fn step(
+    ctx: *mut ffi::sqlite3_context,
+    argc: c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+) {
+    let compiled = &*(ffi::sqlite3_user_data(ctx) as *const Compiled);
+    let acc = accumulator(ctx, mem::size_of::<*mut Value>() as c_int);
+    if acc.is_null() {
+        return ffi::sqlite3_result_error_nomem(ctx);
+    }
+    if (*acc).is_null() {
+        *acc = Box::into_raw(Box::new(Value::Null));
+    }
+    let args = arguments(argc, argv);
+    match compiled.expression.evaluate(&args, &**acc) {
+        Ok(value) => **acc = value,
+        Err(e) => result_error(ctx, &e),
+    }
+}
+
+unsafe extern "C" fn finalize(ctx: *mut ffi::sqlite3_context) {
+    // without rows there is no accumulator
+    let acc = accumulator(ctx, 0);
+    if acc.is_null() || (*acc).is_null() {
+        return ffi::sqlite3_result_null(ctx);
+    }
+    let value = Box::from_raw(*acc);
+    *acc = std::ptr::null_mut();
+    result_value(ctx, &value);
+}
+
+unsafe fn to_value(value: *mut ffi::sqlite3_value) -> Value {
+    match ffi::sqlite3_value_type(value) {
+        ffi::SQLITE_INTEGER => {
+            Value::Integer(ffi::sqlite3_value_int64(value))
+        }
+        ffi::SQLITE_FLOAT => {
+            Value::Real(ffi::sqlite3_value_double(value))
+        }
+        ffi::SQLITE_TEXT => {
+            let text = ffi::sqlite3_value_text(value);
+            let size = ffi::sqlite3_value_bytes(value) as usize;
+            if text.is_null() {
+                return Value::Text(String::new());
+            }
+            let bytes = slice::from_raw_parts(text, size);
+            Value::Text(String::from_utf8_lossy(bytes).into_owned())
+        }
+        ffi::SQLITE_BLOB => {
+            let blob = ffi::sqlite3_value_blob(value) as *const u8;
+            let size = ffi::sqlite3_value_bytes(value) as usize;
+            if blob.is_null() {
+                return Value::Blob(Vec::new());
+            }
+            Value::Blob(slice::from_raw_parts(blob, size).to_vec())
+        }
+        _ => Value::Null,
+    }
+}
+
+unsafe fn result_value(
+    ctx: *mut ffi::sqlite3_context,
+    value: &Value,
+) {
+    match value {
+        Value::Null => ffi::sqlite3_result_null(ctx),
+        Value::Integer(i) => ffi::sqlite3_result_int64(ctx, *i),
+        Value::Real(f) => ffi::sqlite3_result_double(ctx, *f),
+        Value::Text(text) => ffi::sqlite3_result_text(
+            ctx,
+            text.as_ptr() as *const c_char,
+            text.len() as c_int,
+            SQLITE_TRANSIENT(),
+        ),
+        Value::Blob(blob) => ffi::sqlite3_result_blob(
+            ctx,
+            blob.as_ptr() as *const c_void,
+            blob.len() as c_int,
+            SQLITE_TRANSIENT(),
+        ),
+    }
+}
+
+unsafe fn result_error(
+    ctx: *mut ffi::sqlite3_context,
+    message: &str,
+) {
+    ffi::sqlite3_result_error(
+        ctx,
+        message.as_ptr() as *const c_char,
+        message.len() as c_int,
+    )
+}
@@ -0,0 +1,705 @@
+//! `REDISQL_TABLES_BRUTE_HASH`, a table over the Redis hashes whose keys
+//! match a pattern:
+//!
+//! `CREATE VIRTUAL TABLE users USING REDISQL_TABLES_BRUTE_HASH('user:*', name, age INT);`
+//!
+//! Each hash is a row, the `$id` column is the part of the key matched
+//! by `*` and the other columns are the fields of the hash. A name
+//! without `*`, like `user`, is the same as `user:*`.
+//!
+//! The queries on `$id` read the hash directly, the others `SCAN` the
+//! keys. INSERT and UPDATE set the fields with `HSET`, the NULL values
+//! remove them, and DELETE removes the fields of the columns with `HDEL`.
+//!
+//! The changes are kept by the table until the transaction commits, and
+//! then written all together with the context locked, so a statement
+//! that fails or a ROLLBACK leave the hashes untouched. Until then the
+//! queries of the same transaction see the changes over the hashes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::slice;
+
+use crate::redis_type::{Context, ContextLock};
+use crate::sqlite::ffi;
+
+use super::{
+    call, module_arguments, quote_name, reply_strings, result_value,
+    set_error, sqlite_message, unquote, value_bytes, Affinity, Redis,
+};
+
+const MODULE_NAME: &str = "REDISQL_TABLES_BRUTE_HASH";
+
+/// How many keys `SCAN` looks at each call.
+const SCAN_COUNT: &[u8] = b"1000";
+
+/// `idxNum` of the plans of `xBestIndex`.
+const FULL_SCAN: c_int = 0;
+const BY_ID: c_int = 1;
+
+static MODULE: ffi::sqlite3_module = ffi::sqlite3_module {
+    iVersion: 2,
+    xCreate: Some(connect),
+    xConnect: Some(connect),
+    xBestIndex: Some(best_index),
+    xDisconnect: Some(disconnect),
+    xDestroy: Some(disconnect),
+    xOpen: Some(open),
+    xClose: Some(close),
+    xFilter: Some(filter),
+    xNext: Some(next),
+    xEof: Some(eof),
+    xColumn: Some(column),
+    xRowid: Some(rowid),
+    xUpdate: Some(update),
+    xBegin: Some(begin),
+    xSync: Some(sync),
+    xCommit: Some(commit),
+    xRollback: Some(rollback),
+    xFindFunction: None,
+    xRename: None,
+    xSavepoint: Some(savepoint),
+    xRelease: Some(release),
+    xRollbackTo: Some(rollback_to),
+    xShadowName: None,
+};
+
+pub fn register(db: *mut ffi::sqlite3) -> c_int {
+    let name = CString::new(MODULE_NAME).unwrap();
+    unsafe {
+        ffi::sqlite3_create_module_v2(
+            db,
+            name.as_ptr(),
+            &MODULE,
+            ptr::null_mut(),
+            None,
+        )
+    }
+}
+
+struct Column {
+    field: Vec<u8>,
+    affinity: Affinity,
+}
+
+/// A change to a row not yet written to Redis.
+enum Change {
+    /// The values of the columns, NULL removes the field.
+    Write {
+        id: Vec<u8>,
+        values: Vec<Option<Vec<u8>>>,
+    },
+    /// Remove the fields of the columns.
+    Delete { id: Vec<u8> },
+}
+
+impl Change {
+    fn id(&self) -> &[u8] {
+        match self {
+            Change::Write { id, .. } | Change::Delete { id } => id,
+        }
+    }
+}
+
+#[repr(C)]
+struct HashTable {
+    // must be the first field, SQLite sees only this part
+    base: ffi::sqlite3_vtab,
+    redis: Redis,
+    /// The part of the pattern before and after the `*`.
+    prefix: String,
+    suffix: String,
+    columns: Vec<Column>,
+    /// The changes of the transaction, in order.
+    changes: Vec<Change>,
+    /// How many changes there were at each savepoint, by its index.
+    savepoints: Vec<usize>,
+}
+
+impl HashTable {
+    fn key(&self, id: &[u8]) -> Vec<u8> {
+        let mut key = self.prefix.as_bytes().to_vec();
+        key.extend_from_slice(id);
+        key.extend_from_slice(self.suffix.as_bytes());
+        key
+    }
+    fn id<'k>(&self, key: &'k [u8]) -> Option<&'k [u8]> {
+        let (prefix, suffix) =
+            (self.prefix.as_bytes(), self.suffix.as_bytes());
+        if key.len() < prefix.len() + suffix.len()
+            || !key.starts_with(prefix)
+            || !key.ends_with(suffix)
+        {
+            return None;
+        }
+        Some(&key[prefix.len()..key.len() - suffix.len()])
+    }
+    /// The rows of the hashes with the ids, the ids that are not hashes
+    /// are skipped.
+    fn read_rows(
+        &self,
+        ctx: &Context,
+        lock: &ContextLock,
+        ids: Vec<Vec<u8>>,
+    ) -> Result<Vec<Row>, String> {
+        let mut rows = Vec::with_capacity(ids.len());
+        for id in ids {
+            let key = self.key(&id);
+            let reply = match call(ctx, lock, "HGETALL", &[&key]) {
+                Ok(reply) => reply,
+                // the key holds another type
+                Err(_) => continue,
+            };
+            let mut fields = reply_strings(&reply).into_iter();
+            let mut values = HashMap::new();
+            while let (Some(field), Some(value)) =
+                (fields.next(), fields.next())
+            {
+                values.insert(field, value);
+            }
+            if !values.is_empty() {
+                rows.push(Row { id, values });
+            }
+        }
+        Ok(rows)
+    }
+    /// The ids of all the keys that match the pattern.
+    fn scan_ids(
+        &self,
+        ctx: &Context,
+        lock: &ContextLock,
+    ) -> Result<Vec<Vec<u8>>, String> {
+        let pattern = format!("{}*{}", self.prefix, self.suffix);
+        let mut cursor = b"0".to_vec();
+        let mut ids = Vec::new();
+        loop {
+            let reply = call(
+                ctx,
+                lock,
+                "SCAN",
+                &[
+                    &cursor,
+                    b"MATCH",
+                    pattern.as_bytes(),
+                    b"COUNT",
+                    SCAN_COUNT,
+                ],
+            )?;
+            let next = reply
+                .access_array_subelement(0)
+                .and_then(|c| c.access_bytes());
+            let keys = reply.access_array_subelement(1);
+            let (next, keys) = match (next, keys) {
+                (Some(next), Some(keys)) => (next, keys),
+                _ => {
+                    return Err("Unexpected reply to SCAN".to_string())
+                }
+            };
+            ids.extend(
+                reply_strings(&keys)
+                    .iter()
+                    .filter_map(|key| self.id(key))
+                    .map(|id| id.to_vec()),
+            );
+            if next == b"0" {
+                break;
+            }
+            cursor = next;
+        }
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
+    }
+    /// Show the changes of the transaction over the rows read from
+    /// Redis, only the ones of the row `only` if given.
+    fn apply_changes(
+        &self,
+        rows: Vec<Row>,
+        only: Option<&[u8]>,
+    ) -> Vec<Row> {
+        let mut rows: BTreeMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>> =
+            rows.into_iter()
+                .map(|row| (row.id, row.values))
+                .collect();
+        let changes = self.changes.iter().filter(|change| {
+            only.map_or(true, |id| id == change.id())
+        });
+        for change in changes {
+            let values =
+                rows.entry(change.id().to_vec()).or_default();
+            match change {
+                Change::Write {
+                    values: changed, ..
+                } => {
+                    for (column, value) in
+                        self.columns.iter().zip(changed)
+                    {
+                        match value {
+                            Some(value) => values.insert(
+                                column.field.clone(),
+                                value.clone(),
+                            ),
+                            None => values.remove(&column.field),
+                        };
+                    }
+                }
+                Change::Delete { .. } => {
+                    for column in &self.columns {
+                        values.remove(&column.field);
+                    }
+                }
+            }
+        }
+        rows.into_iter()
+            .filter(|(_, values)| !values.is_empty())
+            .map(|(id, values)| Row { id, values })
+            .collect()
+    }
+    /// Write the changes of the transaction, none if any of their keys
+    /// holds another type.
+    fn write_changes(
+        &self,
+        ctx: &Context,
+        lock: &ContextLock,
+    ) -> Result<(), String> {
+        let mut keys: Vec<Vec<u8>> = self
+            .changes
+            .iter()
+            .map(|change| self.key(change.id()))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        for key in &keys {
+            let reply = call(ctx, lock, "TYPE", &[key])?;
+            match reply.access_bytes().as_deref() {
+                Some(b"hash") | Some(b"none") => {}
+                _ => {
+                    return Err(format!(
+                        "The key `{}` is not a hash",
+                        String::from_utf8_lossy(key)
+                    ))
+                }
+            }
+        }
+        for change in &self.changes {
+            match change {
+                Change::Write { id, values } => {
+                    self.write_row(ctx, lock, id, values)?
+                }
+                Change::Delete { id } => {
+                    self.delete_row(ctx, lock, id)?
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Set the fields of the columns with a value, remove the ones that
+    /// are NULL.
+    fn write_row(
+        &self,
+        ctx: &Context,
+        lock: &ContextLock,
+        id: &[u8],
+        values: &[Option<Vec<u8>>],
+    ) -> Result<(), String> {
+        let key = self.key(id);
+        let mut set: Vec<&[u8]> = vec![&key];
+        let mut removed: Vec<&[u8]> = vec![&key];
+        for (column, value) in self.columns.iter().zip(values) {
+            match value {
+                Some(value) => {
+                    set.push(&column.field);
+                    set.push(value);
+                }
+                None => removed.push(&column.field),
+            }
+        }
+        if removed.len() > 1 {
+            call(ctx, lock, "HDEL", &removed)?;
+        }
+        if set.len() > 1 {
+            call(ctx, lock, "HSET", &set)?;
+        }
+        Ok(())
+    }
+    /// Remove the fields of the columns, Redis deletes the hash once
+    /// it is empty.
+    fn delete_row(
+        &self,
+        ctx: &Context,
+        lock: &ContextLock,
+        id: &[u8],
+    ) -> Result<(), String> {
+        let key = self.key(id);
+        let mut args: Vec<&[u8]> = vec![&key];
+        args.extend(self.columns.iter().map(|c| c.field.as_slice()));
+        call(ctx, lock, "HDEL", &args).map(|_| ())
+    }
+}
+
+struct Row {
+    id: Vec<u8>,
+    values: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+#[repr(C)]
+struct HashCursor {
+    // must be the first field, SQLite sees only this part
+    base: ffi::sqlite3_vtab_cursor,
+    rows: Vec<Row>,
+    current: usize,
+}
+
+/// Parse the arguments of the table, the pattern of the keys followed
+/// by the columns, and build its schema.
+fn parse_arguments(
+    args: &[String],
+) -> Result<(String, String, Vec<Column>, String), String> {
+    let mut args = args.iter();
+    let pattern = match args.next() {
+        Some(pattern) => unquote(pattern).to_string(),
+        None => return Err(format!(
+            "{} needs the pattern of the keys, like 'user:*', followed by the columns",
+            MODULE_NAME
+        )),
+    };
+    let pattern = if pattern.contains('*') {
+        pattern
+    } else {
+        format!("{}:*", pattern)
+    };
+    let (prefix, suffix) = match pattern.find('*') {
+        Some(star) if pattern.matches('*').count() == 1 => (
+            pattern[..star].to_string(),
+            pattern[star + 1..].to_string(),
+        ),
+        _ => {
+            return Err(format!(
+                "The pattern `{}` must contain a single `*`, in place of the id of the row",
+                pattern
+            ))
+        }
+    };
+    let mut columns = Vec::new();
+    let mut schema = String::from(
+        "CREATE TABLE x(\"$id\" TEXT PRIMARY KEY NOT NULL",
+    );
+    for arg in args {
+        let mut parts = arg.splitn(2, char::is_whitespace);
+        let name = unquote(parts.next().unwrap_or(""));
+        let declared = parts.next().unwrap_or("").trim();
+        if name.is_empty() || name == "$id" {
+            return Err(format!("Invalid column `{}`", arg));
+        }
+        schema.push_str(&format!(
+            ", {} {}",
+            quote_name(name),
+            declared
+        ));
+        columns.push(Column {
+            field: name.as_bytes().to_vec(),
+            affinity: Affinity::from_declared_type(declared),
+        });
+    }
+    if columns.is_empty() {
+        return Err(format!(
+            "{} needs at least one column, the fields of the hashes",
+            MODULE_NAME
+        ));
+    }
+    schema.push_str(") WITHOUT ROWID;");
+    Ok((prefix, suffix, columns, schema))
+}
+
+unsafe extern "C" fn connect(
+    db: *mut ffi::sqlite3,
+    _aux: *mut c_void,
+    argc: c_int,
+    argv: *const *const c_char,
+    vtab: *mut *mut ffi::sqlite3_vtab,
+    err: *mut *mut c_char,
+) -> c_int {
+    let args = module_arguments(argc, argv);
+    let (prefix, suffix, columns, schema) =
+        match parse_arguments(&args) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                *err = sqlite_message(&e);
+                return ffi::SQLITE_ERROR;
+            }
+        };
+    let schema = CString::new(schema).unwrap();
+    let rc = ffi::sqlite3_declare_vtab(db, schema.as_ptr());
+    if rc != ffi::SQLITE_OK {
+        return rc;
+    }
+    let table = Box::new(HashTable {
+        base: std::mem::zeroed(),
+        redis: Redis::new(),
+        prefix,
+        suffix,
+        columns,
+        changes: Vec::new(),
+        savepoints: Vec::new(),
+    });
+    *vtab = Box::into_raw(table) as *mut ffi::sqlite3_vtab;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn disconnect(
+    vtab: *mut ffi::sqlite3_vtab,
+) -> c_int {
+    let table = Box::from_raw(vtab as *mut HashTable);
+    if !table.base.zErrMsg.is_null() {
+        ffi::sqlite3_free(table.base.zErrMsg as *mut c_void);
+    }
+    ffi::SQLITE_OK
+}
+
+/// An equality on `$id` reads a single hash, anything else scans the
+/// keys.
+unsafe extern "C" fn best_index(
+    _vtab: *mut ffi::sqlite3_vtab,
+    info: *mut ffi::sqlite3_index_info,
+) -> c_int {
+    let info = &mut *info;
+    let constraints = slice::from_raw_parts(
+        info.aConstraint,
+        info.nConstraint as usize,
+    );
+    let usage = slice::from_raw_parts_mut(
+        info.aConstraintUsage,
+        info.nConstraint as usize,
+    );
+    let by_id = constraints.iter().position(|c| {
+        c.iColumn == 0
+            && c.usable != 0
+            && c.op as i32 == ffi::SQLITE_INDEX_CONSTRAINT_EQ
+    });
+    match by_id {
+        Some(i) => {
+            usage[i].argvIndex = 1;
+            usage[i].omit = 1;
+            info.idxNum = BY_ID;
+            info.estimatedCost = 1.0;
+            info.estimatedRows = 1;
+            info.idxFlags = ffi::SQLITE_INDEX_SCAN_UNIQUE;
+        }
+        None => {
+            info.idxNum = FULL_SCAN;
+            info.estimatedCost = 1_000_000.0;
+            info.estimatedRows = 100_000;
+        }
+    }
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn open(
+    _vtab: *mut ffi::sqlite3_vtab,
+    cursor: *mut *mut ffi::sqlite3_vtab_cursor,
+) -> c_int {
+    let hash_cursor = Box::new(HashCursor {
+        base: std::mem::zeroed(),
+        rows: Vec::new(),
+        current: 0,
+    });
+    *cursor =
+        Box::into_raw(hash_cursor) as *mut ffi::sqlite3_vtab_cursor;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn close(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+) -> c_int {
+    drop(Box::from_raw(cursor as *mut HashCursor));
+    ffi::SQLITE_OK
+}
+
+/// Read all the rows of the query at once, with the context locked a
+/// single time.
+unsafe extern "C" fn filter(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+    idx_num: c_int,
+    _idx_str: *const c_char,
+    argc: c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+) -> c_int {
+    let vtab = (*cursor).pVtab;
+    let table = &*(vtab as *const HashTable);
+    let cursor = &mut *(cursor as *mut HashCursor);
+    let id = if idx_num == BY_ID && argc == 1 {
+        match value_bytes(*argv) {
+            Some(id) => Some(id),
+            // nothing is equal to NULL
+            None => {
+                cursor.rows = Vec::new();
+                cursor.current = 0;
+                return ffi::SQLITE_OK;
+            }
+        }
+    } else {
+        None
+    };
+    let rows = table.redis.with_lock(|ctx, lock| {
+        let ids = match &id {
+            Some(id) => vec![id.clone()],
+            None => table.scan_ids(ctx, lock)?,
+        };
+        table.read_rows(ctx, lock, ids)
+    });
+    match rows {
+        Ok(rows) => {
+            cursor.rows = table.apply_changes(rows, id.as_deref());
+            cursor.current = 0;
+            ffi::SQLITE_OK
+        }
+        Err(e) => set_error(vtab, &e),
+    }
+}
+
+unsafe extern "C" fn next(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+) -> c_int {
+    (*(cursor as *mut HashCursor)).current += 1;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn eof(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+) -> c_int {
+    let cursor = &*(cursor as *const HashCursor);
+    (cursor.current >= cursor.rows.len()) as c_int
+}
+
+unsafe extern "C" fn column(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+    ctx: *mut ffi::sqlite3_context,
+    i: c_int,
+) -> c_int {
+    let table = &*((*cursor).pVtab as *const HashTable);
+    let cursor = &*(cursor as *const HashCursor);
+    let row = &cursor.rows[cursor.current];
+    if i == 0 {
+        result_value(ctx, Some(&row.id), Affinity::Text);
+    } else {
+        let column = &table.columns[i as usize - 1];
+        result_value(
+            ctx,
+            row.values.get(&column.field).map(Vec::as_slice),
+            column.affinity,
+        );
+    }
+    ffi::SQLITE_OK
+}
+
+/// Never called, the table is WITHOUT ROWID.
+unsafe extern "C" fn rowid(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+    rowid: *mut ffi::sqlite3_int64,
+) -> c_int {
+    *rowid = (*(cursor as *const HashCursor)).current as i64;
+    ffi::SQLITE_OK
+}
+
+/// `argv[0]` is the `$id` of the row to change, NULL for INSERT, and
+/// `argv[2..]` are the new values of the columns, starting from `$id`.
+unsafe extern "C" fn update(
+    vtab: *mut ffi::sqlite3_vtab,
+    argc: c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+    _rowid: *mut ffi::sqlite3_int64,
+) -> c_int {
+    let table = &mut *(vtab as *mut HashTable);
+    let argv = slice::from_raw_parts(argv, argc as usize);
+    let old_id = value_bytes(argv[0]);
+    if argc == 1 {
+        if let Some(id) = old_id {
+            table.changes.push(Change::Delete { id });
+        }
+        return ffi::SQLITE_OK;
+    }
+    let new_id = match value_bytes(argv[2]) {
+        Some(id) if !id.is_empty() => id,
+        _ => {
+            set_error(
+                vtab,
+                "The $id of the row cannot be NULL nor empty",
+            );
+            return ffi::SQLITE_CONSTRAINT;
+        }
+    };
+    let values: Vec<Option<Vec<u8>>> =
+        argv[3..].iter().map(|v| value_bytes(*v)).collect();
+    if let Some(old_id) = old_id {
+        if old_id != new_id {
+            table.changes.push(Change::Delete { id: old_id });
+        }
+    }
+    table.changes.push(Change::Write { id: new_id, values });
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn begin(vtab: *mut ffi::sqlite3_vtab) -> c_int {
+    let table = &mut *(vtab as *mut HashTable);
+    table.changes.clear();
+    table.savepoints.clear();
+    ffi::SQLITE_OK
+}
+
+/// Write the changes before the transaction commits, if Redis refuses
+/// them the transaction fails.
+unsafe extern "C" fn sync(vtab: *mut ffi::sqlite3_vtab) -> c_int {
+    let table = &*(vtab as *const HashTable);
+    if table.changes.is_empty() {
+        return ffi::SQLITE_OK;
+    }
+    match table
+        .redis
+        .with_lock(|ctx, lock| table.write_changes(ctx, lock))
+    {
+        Ok(()) => ffi::SQLITE_OK,
+        Err(e) => set_error(vtab, &e),
+    }
+}
+
+unsafe extern "C" fn commit(vtab: *mut ffi::sqlite3_vtab) -> c_int {
+    begin(vtab)
+}
+
+unsafe extern "C" fn rollback(vtab: *mut ffi::sqlite3_vtab) -> c_int {
+    begin(vtab)
+}
+
+unsafe extern "C" fn savepoint(
+    vtab: *mut ffi::sqlite3_vtab,
+    i: c_int,
+) -> c_int {
+    let table = &mut *(vtab as *mut HashTable);
+    let changes = table.changes.len();
+    table.savepoints.resize(i as usize + 1, changes);
+    table.savepoints[i as usize] = changes;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn release(
+    vtab: *mut ffi::sqlite3_vtab,
+    i: c_int,
+) -> c_int {
+    let table = &mut *(vtab as *mut HashTable);
+    table.savepoints.truncate(i as usize);
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn rollback_to(
+    vtab: *mut ffi::sqlite3_vtab,
+    i: c_int,
+) -> c_int {
+    let table = &mut *(vtab as *mut HashTable);
+    if let Some(&changes) = table.savepoints.get(i as usize) {
+        table.changes.truncate(changes);
+        table.savepoints.truncate(i as usize + 1);
+    }
+    ffi::SQLITE_OK
+}
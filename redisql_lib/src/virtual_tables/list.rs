@@ -0,0 +1,68 @@
+//! `redis_list(key)`, the elements of a list with their index, from the
+//! head. A range on `idx` reads only those elements with `LRANGE`.
+
+use std::os::raw::c_int;
+
+use crate::redis_type::{Context, ContextLock};
+
+use super::table_function::{Range, TableFunction, Value};
+use super::{call, reply_strings};
+
+pub(super) struct List;
+
+impl TableFunction for List {
+    const NAME: &'static str = "redis_list";
+    const SCHEMA: &'static str =
+        "CREATE TABLE x(idx INTEGER, value TEXT, key HIDDEN);";
+    const KEY_COLUMN: c_int = 2;
+    const RANGE_COLUMN: Option<c_int> = Some(0);
+
+    unsafe fn rows(
+        ctx: &Context,
+        lock: &ContextLock,
+        key: &[u8],
+        range: &Range,
+    ) -> Result<Vec<Vec<Value>>, String> {
+        // the first and the last index in the range, both included
+        let lower = range
+            .lower
+            .as_ref()
+            .and_then(|b| b.as_f64().map(|idx| (b.exclusive, idx)));
+        let upper = range
+            .upper
+            .as_ref()
+            .and_then(|b| b.as_f64().map(|idx| (b.exclusive, idx)));
+        let start = match lower {
+            Some((true, idx)) => idx.floor() + 1.0,
+            Some((false, idx)) => idx.ceil(),
+            None => 0.0,
+        }
+        .max(0.0);
+        let stop = match upper {
+            Some((true, idx)) => idx.ceil() - 1.0,
+            Some((false, idx)) => idx.floor(),
+            None => -1.0,
+        };
+        if upper.is_some() && stop < start {
+            return Ok(Vec::new());
+        }
+        let (start, stop) = (start as i64, stop as i64);
+        let reply = call(
+            ctx,
+            lock,
+            "LRANGE",
+            &[
+                key,
+                start.to_string().as_bytes(),
+                stop.to_string().as_bytes(),
+            ],
+        )?;
+        Ok(reply_strings(&reply)
+            .into_iter()
+            .zip(start..)
+            .map(|(value, idx)| {
+                vec![Value::Integer(idx), Value::Text(value)]
+            })
+            .collect())
+    }
+}
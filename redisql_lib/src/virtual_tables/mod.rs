@@ -0,0 +1,233 @@
+//! SQLite virtual tables over the native types of Redis.
+//!
+//! The modules are registered on every connection, so any database can
+//! create its tables with `CREATE VIRTUAL TABLE ... USING module(...)`.
+//! The tables run Redis commands from the thread of the database, with
+//! the thread safe context locked.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::slice;
+
+use crate::locks::with_context;
+use crate::redis_type::{Call, CallReply, Context, ContextLock};
+use crate::sqlite::{ffi, SQLITE_TRANSIENT};
+
+use list::List;
+use stream::Stream;
+use zset::Zset;
+
+mod hash;
+mod list;
+mod stream;
+mod table_function;
+mod zset;
+
+static ZSET: ffi::sqlite3_module = table_function::module::<Zset>();
+static LIST: ffi::sqlite3_module = table_function::module::<List>();
+static STREAM: ffi::sqlite3_module =
+    table_function::module::<Stream>();
+
+/// Register the modules of the virtual tables on the connection.
+pub fn register_modules(db: *mut ffi::sqlite3) -> c_int {
+    let registered = [
+        hash::register(db),
+        table_function::register::<Zset>(db, &ZSET),
+        table_function::register::<List>(db, &LIST),
+        table_function::register::<Stream>(db, &STREAM),
+    ];
+    registered
+        .iter()
+        .copied()
+        .find(|rc| *rc != ffi::SQLITE_OK)
+        .unwrap_or(ffi::SQLITE_OK)
+}
+
+/// The access of a virtual table, or of a function, to Redis.
+pub(crate) struct Redis {
+    ctx: Context,
+}
+
+impl Redis {
+    pub(crate) fn new() -> Self {
+        Redis {
+            ctx: Context::detached(),
+        }
+    }
+    /// Run the commands with the context locked, unless the caller is
+    /// Redis itself that is already holding the lock.
+    pub(crate) fn with_lock<
+        T,
+        F: FnOnce(&Context, &ContextLock) -> Result<T, String>,
+    >(
+        &self,
+        f: F,
+    ) -> Result<T, String> {
+        with_context(&self.ctx, |lock| f(&self.ctx, lock))
+            .map_err(|e| e.to_string())?
+    }
+}
+
+/// Run a command, its error as an `Err`. The commands are not
+/// replicated, the replicas run the same SQL statement.
+pub(crate) fn call(
+    ctx: &Context,
+    lock: &ContextLock,
+    command: &str,
+    args: &[&[u8]],
+) -> Result<CallReply, String> {
+    let reply = Call(ctx, command, args, false, lock);
+    match reply.access_error() {
+        Some(e) => Err(format!("{} failed: {}", command, e)),
+        None => Ok(reply),
+    }
+}
+
+/// The strings of an array reply.
+fn reply_strings(reply: &CallReply) -> Vec<Vec<u8>> {
+    (0..reply.length().unwrap_or(0))
+        .filter_map(|i| reply.access_array_subelement(i))
+        .filter_map(|element| element.access_bytes())
+        .collect()
+}
+
+/// How the values read from Redis, always strings, are returned to
+/// SQLite, following the affinity of the type declared for the column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Affinity {
+    Integer,
+    Real,
+    Text,
+}
+
+impl Affinity {
+    fn from_declared_type(declared: &str) -> Self {
+        let declared = declared.to_ascii_uppercase();
+        if declared.contains("INT") {
+            Affinity::Integer
+        } else if declared.contains("REAL")
+            || declared.contains("FLOA")
+            || declared.contains("DOUB")
+        {
+            Affinity::Real
+        } else {
+            Affinity::Text
+        }
+    }
+}
+
+/// Return the value to SQLite, as a number if it is declared as such
+/// and it can be parsed, as text otherwise.
+unsafe fn result_value(
+    ctx: *mut ffi::sqlite3_context,
+    value: Option<&[u8]>,
+    affinity: Affinity,
+) {
+    let value = match value {
+        Some(value) => value,
+        None => return ffi::sqlite3_result_null(ctx),
+    };
+    let number = std::str::from_utf8(value).ok();
+    match affinity {
+        Affinity::Integer => {
+            if let Some(int) = number.and_then(|n| n.parse().ok()) {
+                return ffi::sqlite3_result_int64(ctx, int);
+            }
+        }
+        Affinity::Real => {
+            if let Some(float) = number.and_then(|n| n.parse().ok()) {
+                return ffi::sqlite3_result_double(ctx, float);
+            }
+        }
+        Affinity::Text => {}
+    }
+    ffi::sqlite3_result_text(
+        ctx,
+        value.as_ptr() as *const c_char,
+        value.len() as c_int,
+        SQLITE_TRANSIENT(),
+    );
+}
+
+/// The bytes stored in Redis for a SQLite value, `None` for NULL.
+pub(crate) unsafe fn value_bytes(
+    value: *mut ffi::sqlite3_value,
+) -> Option<Vec<u8>> {
+    match ffi::sqlite3_value_type(value) {
+        ffi::SQLITE_NULL => None,
+        ffi::SQLITE_BLOB => {
+            let size = ffi::sqlite3_value_bytes(value) as usize;
+            let blob = ffi::sqlite3_value_blob(value) as *const u8;
+            if size == 0 || blob.is_null() {
+                return Some(Vec::new());
+            }
+            Some(slice::from_raw_parts(blob, size).to_vec())
+        }
+        _ => {
+            let text = ffi::sqlite3_value_text(value);
+            let size = ffi::sqlite3_value_bytes(value) as usize;
+            if text.is_null() {
+                return Some(Vec::new());
+            }
+            Some(slice::from_raw_parts(text, size).to_vec())
+        }
+    }
+}
+
+/// A copy of the message allocated by SQLite, which frees it.
+fn sqlite_message(message: &str) -> *mut c_char {
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    let format = CString::new("%s").unwrap();
+    unsafe { ffi::sqlite3_mprintf(format.as_ptr(), message.as_ptr()) }
+}
+
+/// Report the error of a method of the virtual table.
+unsafe fn set_error(
+    vtab: *mut ffi::sqlite3_vtab,
+    message: &str,
+) -> c_int {
+    if !(*vtab).zErrMsg.is_null() {
+        ffi::sqlite3_free(
+            (*vtab).zErrMsg as *mut std::os::raw::c_void,
+        );
+    }
+    (*vtab).zErrMsg = sqlite_message(message);
+    ffi::SQLITE_ERROR
+}
+
+/// The arguments of `CREATE VIRTUAL TABLE`, after the name of the
+/// module, the database and the table.
+unsafe fn module_arguments(
+    argc: c_int,
+    argv: *const *const c_char,
+) -> Vec<String> {
+    slice::from_raw_parts(argv, argc as usize)
+        .iter()
+        .skip(3)
+        .map(|arg| {
+            CStr::from_ptr(*arg).to_string_lossy().trim().to_string()
+        })
+        .collect()
+}
+
+/// Remove the quotes around a name or a string literal.
+fn unquote(s: &str) -> &str {
+    let s = s.trim();
+    for quote in &['\'', '"', '`'] {
+        if s.len() >= 2
+            && s.starts_with(*quote)
+            && s.ends_with(*quote)
+        {
+            return &s[1..s.len() - 1];
+        }
+    }
+    if s.len() >= 2 && s.starts_with('[') && s.ends_with(']') {
+        return &s[1..s.len() - 1];
+    }
+    s
+}
+
+/// Quote the name for the schema of the table.
+fn quote_name(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
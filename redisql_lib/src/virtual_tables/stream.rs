@@ -0,0 +1,70 @@
+//! `redis_stream(key)`, the entries of a stream, from the oldest. The
+//! fields of each entry are a JSON object, to read with `json_extract`.
+//!
+//! The whole stream is read with `XRANGE`: SQLite compares the ids as
+//! text, in an order that is not the one of the stream, so a range on
+//! `id` is left to SQLite.
+
+use std::os::raw::c_int;
+
+use serde_json::{Map, Value as Json};
+
+use crate::redis_type::{Context, ContextLock};
+
+use super::table_function::{Range, TableFunction, Value};
+use super::{call, reply_strings};
+
+pub(super) struct Stream;
+
+impl TableFunction for Stream {
+    const NAME: &'static str = "redis_stream";
+    const SCHEMA: &'static str =
+        "CREATE TABLE x(id TEXT, fields TEXT, key HIDDEN);";
+    const KEY_COLUMN: c_int = 2;
+    const RANGE_COLUMN: Option<c_int> = None;
+
+    unsafe fn rows(
+        ctx: &Context,
+        lock: &ContextLock,
+        key: &[u8],
+        _range: &Range,
+    ) -> Result<Vec<Vec<Value>>, String> {
+        let reply = call(ctx, lock, "XRANGE", &[key, b"-", b"+"])?;
+        let mut rows = Vec::new();
+        for i in 0..reply.length().unwrap_or(0) {
+            let entry = match reply.access_array_subelement(i) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let id = match entry
+                .access_array_subelement(0)
+                .and_then(|id| id.access_bytes())
+            {
+                Some(id) => id,
+                None => continue,
+            };
+            let mut fields = Map::new();
+            if let Some(values) = entry.access_array_subelement(1) {
+                let mut values = reply_strings(&values).into_iter();
+                while let (Some(field), Some(value)) =
+                    (values.next(), values.next())
+                {
+                    fields.insert(
+                        String::from_utf8_lossy(&field).into_owned(),
+                        Json::String(
+                            String::from_utf8_lossy(&value)
+                                .into_owned(),
+                        ),
+                    );
+                }
+            }
+            rows.push(vec![
+                Value::Text(id),
+                Value::Text(
+                    Json::Object(fields).to_string().into_bytes(),
+                ),
+            ]);
+        }
+        Ok(rows)
+    }
+}
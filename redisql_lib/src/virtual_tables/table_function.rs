@@ -0,0 +1,377 @@
+//! Table-valued functions over a single Redis key, like
+//! `SELECT * FROM redis_zset('leaderboard')`.
+//!
+//! They are eponymous virtual tables with a hidden `key` column, the
+//! argument of the function. A numeric range on one of the columns is
+//! pushed down to the Redis command that reads the key, SQLite still
+//! checks it on the rows returned.
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::slice;
+
+use crate::redis_type::{Context, ContextLock};
+use crate::sqlite::{ffi, SQLITE_TRANSIENT};
+
+use super::{set_error, value_bytes, Redis};
+
+/// A value of a row, as it is returned to SQLite.
+pub(super) enum Value {
+    Integer(i64),
+    Real(f64),
+    Text(Vec<u8>),
+}
+
+/// A bound of the range pushed down by SQLite.
+pub(super) struct Bound {
+    pub value: *mut ffi::sqlite3_value,
+    pub exclusive: bool,
+}
+
+impl Bound {
+    /// The bound as a number, `None` if it is NULL or a text that is not
+    /// a number: SQLite does not compare those as numbers, so they cannot
+    /// limit what is read.
+    pub unsafe fn as_f64(&self) -> Option<f64> {
+        match ffi::sqlite3_value_numeric_type(self.value) {
+            ffi::SQLITE_INTEGER | ffi::SQLITE_FLOAT => {
+                Some(ffi::sqlite3_value_double(self.value))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The range on the column of `RANGE_COLUMN`, it limits what is read
+/// from Redis, SQLite checks it again on the rows.
+#[derive(Default)]
+pub(super) struct Range {
+    pub lower: Option<Bound>,
+    pub upper: Option<Bound>,
+}
+
+/// A table-valued function over one type of Redis key.
+pub(super) trait TableFunction {
+    const NAME: &'static str;
+    /// The schema of the table, the hidden `key` column is the last.
+    const SCHEMA: &'static str;
+    const KEY_COLUMN: c_int;
+    /// The numeric column whose ranges are pushed down, if any.
+    const RANGE_COLUMN: Option<c_int>;
+    /// The rows of the key in the range, in the order of Redis.
+    unsafe fn rows(
+        ctx: &Context,
+        lock: &ContextLock,
+        key: &[u8],
+        range: &Range,
+    ) -> Result<Vec<Vec<Value>>, String>;
+}
+
+// the bits of `idxNum`, which constraints are passed to `xFilter`
+const HAS_KEY: c_int = 1;
+const HAS_LOWER: c_int = 2;
+const LOWER_EXCLUSIVE: c_int = 4;
+const HAS_UPPER: c_int = 8;
+const UPPER_EXCLUSIVE: c_int = 16;
+
+pub(super) const fn module<T: TableFunction>() -> ffi::sqlite3_module
+{
+    ffi::sqlite3_module {
+        iVersion: 1,
+        // eponymous only, it cannot be used in CREATE VIRTUAL TABLE
+        xCreate: None,
+        xConnect: Some(connect::<T>),
+        xBestIndex: Some(best_index::<T>),
+        xDisconnect: Some(disconnect),
+        xDestroy: Some(disconnect),
+        xOpen: Some(open),
+        xClose: Some(close),
+        xFilter: Some(filter::<T>),
+        xNext: Some(next),
+        xEof: Some(eof),
+        xColumn: Some(column::<T>),
+        xRowid: Some(rowid),
+        xUpdate: None,
+        xBegin: None,
+        xSync: None,
+        xCommit: None,
+        xRollback: None,
+        xFindFunction: None,
+        xRename: None,
+        xSavepoint: None,
+        xRelease: None,
+        xRollbackTo: None,
+        xShadowName: None,
+    }
+}
+
+pub(super) fn register<T: TableFunction>(
+    db: *mut ffi::sqlite3,
+    module: &'static ffi::sqlite3_module,
+) -> c_int {
+    let name = CString::new(T::NAME).unwrap();
+    unsafe {
+        ffi::sqlite3_create_module_v2(
+            db,
+            name.as_ptr(),
+            module,
+            ptr::null_mut(),
+            None,
+        )
+    }
+}
+
+#[repr(C)]
+struct FunctionTable {
+    // must be the first field, SQLite sees only this part
+    base: ffi::sqlite3_vtab,
+    redis: Redis,
+}
+
+#[repr(C)]
+struct FunctionCursor {
+    // must be the first field, SQLite sees only this part
+    base: ffi::sqlite3_vtab_cursor,
+    key: Vec<u8>,
+    rows: Vec<Vec<Value>>,
+    current: usize,
+}
+
+unsafe extern "C" fn connect<T: TableFunction>(
+    db: *mut ffi::sqlite3,
+    _aux: *mut c_void,
+    _argc: c_int,
+    _argv: *const *const c_char,
+    vtab: *mut *mut ffi::sqlite3_vtab,
+    _err: *mut *mut c_char,
+) -> c_int {
+    let schema = CString::new(T::SCHEMA).unwrap();
+    let rc = ffi::sqlite3_declare_vtab(db, schema.as_ptr());
+    if rc != ffi::SQLITE_OK {
+        return rc;
+    }
+    let table = Box::new(FunctionTable {
+        base: std::mem::zeroed(),
+        redis: Redis::new(),
+    });
+    *vtab = Box::into_raw(table) as *mut ffi::sqlite3_vtab;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn disconnect(
+    vtab: *mut ffi::sqlite3_vtab,
+) -> c_int {
+    let table = Box::from_raw(vtab as *mut FunctionTable);
+    if !table.base.zErrMsg.is_null() {
+        ffi::sqlite3_free(table.base.zErrMsg as *mut c_void);
+    }
+    ffi::SQLITE_OK
+}
+
+/// The key must be provided, a lower and an upper bound on the range
+/// column are used if present. The constraints are passed to `xFilter`
+/// in this order: key, lower bound, upper bound. SQLite checks the
+/// bounds again, as the ones that are not numbers are not pushed down.
+unsafe extern "C" fn best_index<T: TableFunction>(
+    _vtab: *mut ffi::sqlite3_vtab,
+    info: *mut ffi::sqlite3_index_info,
+) -> c_int {
+    let info = &mut *info;
+    let constraints = slice::from_raw_parts(
+        info.aConstraint,
+        info.nConstraint as usize,
+    );
+    let usage = slice::from_raw_parts_mut(
+        info.aConstraintUsage,
+        info.nConstraint as usize,
+    );
+    let (mut key, mut lower, mut upper) = (None, None, None);
+    for (i, c) in constraints.iter().enumerate() {
+        if c.usable == 0 {
+            continue;
+        }
+        let op = c.op as i32;
+        if c.iColumn == T::KEY_COLUMN
+            && op == ffi::SQLITE_INDEX_CONSTRAINT_EQ
+        {
+            key = key.or(Some(i));
+        } else if Some(c.iColumn) == T::RANGE_COLUMN {
+            match op {
+                ffi::SQLITE_INDEX_CONSTRAINT_GT
+                | ffi::SQLITE_INDEX_CONSTRAINT_GE => {
+                    lower = lower.or(Some((i, op)))
+                }
+                ffi::SQLITE_INDEX_CONSTRAINT_LT
+                | ffi::SQLITE_INDEX_CONSTRAINT_LE => {
+                    upper = upper.or(Some((i, op)))
+                }
+                _ => {}
+            }
+        }
+    }
+    let key = match key {
+        Some(key) => key,
+        None => {
+            // without the key there is nothing to read, the plan is
+            // made so expensive that SQLite picks it only if it has no
+            // alternative, xFilter reports the error
+            info.idxNum = 0;
+            info.estimatedCost = 1e300;
+            return ffi::SQLITE_OK;
+        }
+    };
+    let mut idx_num = HAS_KEY;
+    let mut argv_index = 1;
+    usage[key].argvIndex = argv_index;
+    usage[key].omit = 1;
+    if let Some((i, op)) = lower {
+        argv_index += 1;
+        usage[i].argvIndex = argv_index;
+        idx_num |= HAS_LOWER;
+        if op == ffi::SQLITE_INDEX_CONSTRAINT_GT {
+            idx_num |= LOWER_EXCLUSIVE;
+        }
+    }
+    if let Some((i, op)) = upper {
+        argv_index += 1;
+        usage[i].argvIndex = argv_index;
+        idx_num |= HAS_UPPER;
+        if op == ffi::SQLITE_INDEX_CONSTRAINT_LT {
+            idx_num |= UPPER_EXCLUSIVE;
+        }
+    }
+    info.idxNum = idx_num;
+    info.estimatedCost = if argv_index > 1 { 10.0 } else { 100.0 };
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn open(
+    _vtab: *mut ffi::sqlite3_vtab,
+    cursor: *mut *mut ffi::sqlite3_vtab_cursor,
+) -> c_int {
+    let function_cursor = Box::new(FunctionCursor {
+        base: std::mem::zeroed(),
+        key: Vec::new(),
+        rows: Vec::new(),
+        current: 0,
+    });
+    *cursor = Box::into_raw(function_cursor)
+        as *mut ffi::sqlite3_vtab_cursor;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn close(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+) -> c_int {
+    drop(Box::from_raw(cursor as *mut FunctionCursor));
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn filter<T: TableFunction>(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+    idx_num: c_int,
+    _idx_str: *const c_char,
+    argc: c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+) -> c_int {
+    let vtab = (*cursor).pVtab;
+    let table = &*(vtab as *const FunctionTable);
+    let cursor = &mut *(cursor as *mut FunctionCursor);
+    cursor.rows = Vec::new();
+    cursor.current = 0;
+    if idx_num & HAS_KEY == 0 {
+        return set_error(
+            vtab,
+            &format!(
+                "{} needs the key to read, like {}('key')",
+                T::NAME,
+                T::NAME
+            ),
+        );
+    }
+    let mut argv = slice::from_raw_parts(argv, argc as usize).iter();
+    let key = match argv.next().and_then(|k| value_bytes(*k)) {
+        Some(key) => key,
+        // nothing is equal to NULL
+        None => return ffi::SQLITE_OK,
+    };
+    let mut range = Range::default();
+    if idx_num & HAS_LOWER != 0 {
+        range.lower = argv.next().map(|v| Bound {
+            value: *v,
+            exclusive: idx_num & LOWER_EXCLUSIVE != 0,
+        });
+    }
+    if idx_num & HAS_UPPER != 0 {
+        range.upper = argv.next().map(|v| Bound {
+            value: *v,
+            exclusive: idx_num & UPPER_EXCLUSIVE != 0,
+        });
+    }
+    let rows = table
+        .redis
+        .with_lock(|ctx, lock| T::rows(ctx, lock, &key, &range));
+    match rows {
+        Ok(rows) => {
+            cursor.key = key;
+            cursor.rows = rows;
+            ffi::SQLITE_OK
+        }
+        Err(e) => set_error(vtab, &e),
+    }
+}
+
+unsafe extern "C" fn next(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+) -> c_int {
+    (*(cursor as *mut FunctionCursor)).current += 1;
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn eof(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+) -> c_int {
+    let cursor = &*(cursor as *const FunctionCursor);
+    (cursor.current >= cursor.rows.len()) as c_int
+}
+
+unsafe extern "C" fn column<T: TableFunction>(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+    ctx: *mut ffi::sqlite3_context,
+    i: c_int,
+) -> c_int {
+    let cursor = &*(cursor as *const FunctionCursor);
+    if i == T::KEY_COLUMN {
+        result_text(ctx, &cursor.key);
+        return ffi::SQLITE_OK;
+    }
+    match cursor.rows[cursor.current].get(i as usize) {
+        Some(Value::Integer(int)) => {
+            ffi::sqlite3_result_int64(ctx, *int)
+        }
+        Some(Value::Real(float)) => {
+            ffi::sqlite3_result_double(ctx, *float)
+        }
+        Some(Value::Text(text)) => result_text(ctx, text),
+        None => ffi::sqlite3_result_null(ctx),
+    }
+    ffi::SQLITE_OK
+}
+
+unsafe fn result_text(ctx: *mut ffi::sqlite3_context, text: &[u8]) {
+    ffi::sqlite3_result_text(
+        ctx,
+        text.as_ptr() as *const c_char,
+        text.len() as c_int,
+        SQLITE_TRANSIENT(),
+    )
+}
+
+unsafe extern "C" fn rowid(
+    cursor: *mut ffi::sqlite3_vtab_cursor,
+    rowid: *mut ffi::sqlite3_int64,
+) -> c_int {
+    *rowid = (*(cursor as *const FunctionCursor)).current as i64;
+    ffi::SQLITE_OK
+}
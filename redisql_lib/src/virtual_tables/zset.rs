@@ -0,0 +1,60 @@
+//! `redis_zset(key)`, the members of a sorted set with their score, from
+//! the lowest score. A range on `score` is read with `ZRANGEBYSCORE`.
+
+use std::os::raw::c_int;
+
+use crate::redis_type::{Context, ContextLock};
+
+use super::call;
+use super::table_function::{Bound, Range, TableFunction, Value};
+
+pub(super) struct Zset;
+
+impl TableFunction for Zset {
+    const NAME: &'static str = "redis_zset";
+    const SCHEMA: &'static str =
+        "CREATE TABLE x(member TEXT, score REAL, key HIDDEN);";
+    const KEY_COLUMN: c_int = 2;
+    const RANGE_COLUMN: Option<c_int> = Some(1);
+
+    unsafe fn rows(
+        ctx: &Context,
+        lock: &ContextLock,
+        key: &[u8],
+        range: &Range,
+    ) -> Result<Vec<Vec<Value>>, String> {
+        let min = score(range.lower.as_ref(), "-inf");
+        let max = score(range.upper.as_ref(), "+inf");
+        let reply = call(
+            ctx,
+            lock,
+            "ZRANGEBYSCORE",
+            &[key, min.as_bytes(), max.as_bytes(), b"WITHSCORES"],
+        )?;
+        let mut elements = super::reply_strings(&reply).into_iter();
+        let mut rows = Vec::new();
+        while let (Some(member), Some(score)) =
+            (elements.next(), elements.next())
+        {
+            let score =
+                String::from_utf8_lossy(&score).parse().map_err(
+                    |_| "Invalid score in ZRANGEBYSCORE reply",
+                )?;
+            rows.push(vec![Value::Text(member), Value::Real(score)]);
+        }
+        Ok(rows)
+    }
+}
+
+/// The bound in the syntax of `ZRANGEBYSCORE`, `(` marks the exclusive
+/// ones.
+unsafe fn score(bound: Option<&Bound>, unbounded: &str) -> String {
+    match bound.and_then(|b| b.as_f64().map(|score| (b, score))) {
+        Some((bound, score)) => format!(
+            "{}{}",
+            if bound.exclusive { "(" } else { "" },
+            score
+        ),
+        None => unbounded.to_string(),
+    }
+}
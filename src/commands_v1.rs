@@ -9,17 +9,22 @@ use redisql_lib::redis::{
     RedisReply,
 };
 use redisql_lib::redis_type::ReplicateVerbatim;
-use redisql_lib::sqlite::{get_arc_connection, QueryResult};
+use redisql_lib::sqlite::{
+    get_arc_connection, Bindings, QueryResult,
+};
 
 use redisql_lib::redis as r;
 
 use sync_engine::Replicate;
 
+use redisql_lib::config::resolve_path;
 use redisql_lib::statistics::STATISTICS;
-
-use uuid::Uuid;
+use redisql_lib::timeout::{
+    blocked_client_timeout, default_deadline, default_timeout,
+};
 
 use crate::common::{free_privdata, reply, timeout};
+use crate::v2::create_db::in_memory_name;
 
 const REDISQL_VERSION: Option<&'static str> =
     option_env!("CARGO_PKG_VERSION");
@@ -33,13 +38,14 @@ pub extern "C" fn ExecStatement(
     STATISTICS.exec_statement();
 
     let context = r::rm::Context::new(ctx);
-    let argvector = match r::create_argument(argv, argc) {
-        Ok(argvector) => argvector,
-        Err(mut error) => {
-            STATISTICS.exec_statement_err();
-            return error.reply(&context);
-        }
-    };
+    let (argvector, values) =
+        match r::create_statement_argument(argv, argc, 3) {
+            Ok(args) => args,
+            Err(mut error) => {
+                STATISTICS.exec_statement_err();
+                return error.reply(&context);
+            }
+        };
 
     match argvector.len() {
         0..=2 => {
@@ -72,20 +78,25 @@ pub extern "C" fn ExecStatement(
             };
 
             let ch = unsafe { get_ch_from_dbkeyptr(db) };
+            if let Err(mut e) =
+                r::check_no_transaction(&context, argvector[1])
+            {
+                STATISTICS.exec_err();
+                return e.reply(&context);
+            }
 
             let blocked_client = r::rm::BlockedClient::new(
                 &context,
                 reply,
                 timeout,
                 free_privdata,
-                10000,
+                blocked_client_timeout(default_timeout()),
             );
-            let t = std::time::Instant::now()
-                + std::time::Duration::from_secs(10);
+            let t = default_deadline();
 
             let cmd = r::Command::ExecStatement {
                 identifier: argvector[2],
-                arguments: argvector[3..].to_vec(),
+                arguments: Bindings::Positional(values),
                 client: blocked_client,
                 return_method: r::ReturnMethod::Reply {},
                 timeout: t,
@@ -117,13 +128,14 @@ pub extern "C" fn QueryStatement(
 ) -> i32 {
     STATISTICS.query_statement();
     let context = r::rm::Context::new(ctx);
-    let argvector = match r::create_argument(argv, argc) {
-        Ok(argvector) => argvector,
-        Err(mut error) => {
-            STATISTICS.query_statement_err();
-            return error.reply(&context);
-        }
-    };
+    let (argvector, values) =
+        match r::create_statement_argument(argv, argc, 3) {
+            Ok(args) => args,
+            Err(mut error) => {
+                STATISTICS.query_statement_err();
+                return error.reply(&context);
+            }
+        };
 
     match argvector.len() {
         0..=2 => {
@@ -155,21 +167,26 @@ pub extern "C" fn QueryStatement(
                 }
             };
             let ch = unsafe { get_ch_from_dbkeyptr(db) };
+            if let Err(mut e) =
+                r::check_no_transaction(&context, argvector[1])
+            {
+                STATISTICS.exec_err();
+                return e.reply(&context);
+            }
 
             let blocked_client = r::rm::BlockedClient::new(
                 &context,
                 reply,
                 timeout,
                 free_privdata,
-                10000,
+                blocked_client_timeout(default_timeout()),
             );
 
-            let t = std::time::Instant::now()
-                + std::time::Duration::from_secs(10);
+            let t = default_deadline();
 
             let cmd = r::Command::QueryStatement {
                 identifier: argvector[2],
-                arguments: argvector[3..].to_vec(),
+                arguments: Bindings::Positional(values),
                 return_method: r::ReturnMethod::Reply,
                 client: blocked_client,
                 timeout: t,
@@ -191,13 +208,14 @@ pub extern "C" fn QueryStatementInto(
 ) -> i32 {
     STATISTICS.query_statement_into();
     let context = r::rm::Context::new(ctx);
-    let argvector = match r::create_argument(argv, argc) {
-        Ok(argvector) => argvector,
-        Err(mut error) => {
-            STATISTICS.query_statement_into_err();
-            return error.reply(&context);
-        }
-    };
+    let (argvector, values) =
+        match r::create_statement_argument(argv, argc, 4) {
+            Ok(args) => args,
+            Err(mut error) => {
+                STATISTICS.query_statement_into_err();
+                return error.reply(&context);
+            }
+        };
 
     match argvector.len() {
         0..=3 => {
@@ -231,21 +249,26 @@ pub extern "C" fn QueryStatementInto(
                 }
             };
             let ch = unsafe { get_ch_from_dbkeyptr(db) };
+            if let Err(mut e) =
+                r::check_no_transaction(&context, argvector[2])
+            {
+                STATISTICS.exec_err();
+                return e.reply(&context);
+            }
 
             let blocked_client = r::rm::BlockedClient::new(
                 &context,
                 reply,
                 timeout,
                 free_privdata,
-                10000,
+                blocked_client_timeout(default_timeout()),
             );
 
-            let t = std::time::Instant::now()
-                + std::time::Duration::from_secs(10);
+            let t = default_deadline();
 
             let cmd = r::Command::QueryStatement {
                 identifier: argvector[3],
-                arguments: argvector[4..].to_vec(),
+                arguments: Bindings::Positional(values),
                 return_method: r::ReturnMethod::Stream {
                     name: stream_name,
                 },
@@ -287,21 +310,26 @@ pub extern "C" fn Exec(
                     return e.reply(&context);
                 }
             };
+            if let Err(mut e) =
+                db.check_transaction(&context, argvector[1], None)
+            {
+                STATISTICS.exec_err();
+                return e.reply(&context);
+            }
 
             let blocked_client = r::rm::BlockedClient::new(
                 &context,
                 reply,
                 timeout,
                 free_privdata,
-                10000,
+                blocked_client_timeout(default_timeout()),
             );
 
-            let t = std::time::Instant::now()
-                + std::time::Duration::from_secs(10);
+            let t = default_deadline();
 
             let cmd = r::Command::Exec {
                 query: argvector[2],
-                arguments: Vec::new(),
+                arguments: Bindings::default(),
                 client: blocked_client,
                 timeout: t,
                 return_method: r::ReturnMethod::Reply,
@@ -370,20 +398,25 @@ pub extern "C" fn Query(
                     return e.reply(&context);
                 }
             };
+            if let Err(mut e) =
+                db.check_transaction(&context, argvector[1], None)
+            {
+                STATISTICS.query_err();
+                return e.reply(&context);
+            }
             let blocked_client = r::rm::BlockedClient::new(
                 &context,
                 reply,
                 timeout,
                 free_privdata,
-                10000,
+                blocked_client_timeout(default_timeout()),
             );
 
-            let t = std::time::Instant::now()
-                + std::time::Duration::from_secs(10);
+            let t = default_deadline();
 
             let cmd = r::Command::Query {
                 query: argvector[2],
-                arguments: Vec::new(),
+                arguments: Bindings::default(),
                 return_method: r::ReturnMethod::Reply,
                 client: blocked_client,
                 timeout: t,
@@ -438,21 +471,26 @@ pub extern "C" fn QueryInto(
                     return e.reply(&context);
                 }
             };
+            if let Err(mut e) =
+                db.check_transaction(&context, argvector[2], None)
+            {
+                STATISTICS.query_err();
+                return e.reply(&context);
+            }
 
             let blocked_client = r::rm::BlockedClient::new(
                 &context,
                 reply,
                 timeout,
                 free_privdata,
-                10000,
+                blocked_client_timeout(default_timeout()),
             );
 
-            let t = std::time::Instant::now()
-                + std::time::Duration::from_secs(10);
+            let t = default_deadline();
 
             let cmd = r::Command::Query {
                 query: argvector[3],
-                arguments: Vec::new(),
+                arguments: Bindings::default(),
                 return_method: r::ReturnMethod::Stream {
                     name: stream_name,
                 },
@@ -509,13 +547,19 @@ pub extern "C" fn CreateStatement(
                     return e.reply(&context);
                 }
             };
+            if let Err(mut e) =
+                db.check_transaction(&context, argvector[1], None)
+            {
+                STATISTICS.create_statement_err();
+                return e.reply(&context);
+            }
 
             let blocked_client = r::rm::BlockedClient::new(
                 &context,
                 reply,
                 timeout,
                 free_privdata,
-                10000,
+                blocked_client_timeout(default_timeout()),
             );
 
             let cmd = r::Command::CompileStatement {
@@ -584,12 +628,18 @@ pub extern "C" fn UpdateStatement(
                     return e.reply(&context);
                 }
             };
+            if let Err(mut e) =
+                db.check_transaction(&context, argvector[1], None)
+            {
+                STATISTICS.update_statement_err();
+                return e.reply(&context);
+            }
             let blocked_client = r::rm::BlockedClient::new(
                 &context,
                 reply,
                 timeout,
                 free_privdata,
-                10000,
+                blocked_client_timeout(default_timeout()),
             );
 
             let cmd = r::Command::UpdateStatement {
@@ -659,12 +709,18 @@ pub extern "C" fn DeleteStatement(
                     return e.reply(&context);
                 }
             };
+            if let Err(mut e) =
+                db.check_transaction(&context, argvector[1], None)
+            {
+                STATISTICS.delete_statement_err();
+                return e.reply(&context);
+            }
             let blocked_client = r::rm::BlockedClient::new(
                 &context,
                 reply,
                 timeout,
                 free_privdata,
-                10000,
+                blocked_client_timeout(default_timeout()),
             );
 
             let cmd = r::Command::DeleteStatement {
@@ -735,14 +791,15 @@ pub extern "C" fn CreateDB(
                 r::rm::ffi::RedisModule_KeyType.unwrap()(safe_key.key)
             } {
                 r::rm::ffi::REDISMODULE_KEYTYPE_EMPTY => {
-                    let db_name = format!(
-                        "file:{}?mode=memory&cache=shared",
-                        Uuid::new_v4().to_simple()
-                    );
-                    let path: &str = match argvector.len() {
-                        3 => argvector[2],
-                        _ => &db_name,
+                    if let Err(mut e) = r::check_max_databases() {
+                        STATISTICS.create_db_err();
+                        return e.reply(&context);
+                    }
+                    let path = match argvector.len() {
+                        3 => resolve_path(argvector[2]),
+                        _ => in_memory_name(),
                     };
+                    let path = path.as_str();
                     match get_arc_connection(path) {
                         Ok(rc) => {
                             match r::create_metadata_table(rc)
@@ -893,6 +950,18 @@ pub extern "C" fn MakeCopy(
             };
 
             let ch = unsafe { get_ch_from_dbkeyptr(db) };
+            if let Err(mut e) =
+                r::check_no_transaction(&context, argvector[1])
+                    .and_then(|_| {
+                        r::check_no_transaction(
+                            &context,
+                            argvector[2],
+                        )
+                    })
+            {
+                STATISTICS.copy_err();
+                return e.reply(&context);
+            }
             let dest_db =
                 get_dbkey_from_name(context.as_ptr(), argvector[2]);
             if dest_db.is_err() {
@@ -915,7 +984,7 @@ pub extern "C" fn MakeCopy(
                 reply,
                 timeout,
                 free_privdata,
-                10000,
+                blocked_client_timeout(default_timeout()),
             );
             let cmd = r::Command::MakeCopy {
                 destination: dest_db,
@@ -8,20 +8,26 @@ mod v2;
 extern crate log;
 
 use env_logger::{Builder as logBuilder, Target as logTarget};
+use redisql_lib::aof::subscribe_to_loading;
+use redisql_lib::config::{set_config, Config};
+use redisql_lib::digest::digest_sequences;
+use redisql_lib::dump::{dump, DumpFormat};
+use redisql_lib::locks::{lock_connection, set_main_thread};
 use redisql_lib::redis as r;
 use redisql_lib::redis::{
     get_path_from_db, is_redisql_database, register_function,
     register_function_with_keys, register_write_function, LoopData,
 };
-use redisql_lib::redis_type::Context;
+use redisql_lib::redis_type::{
+    Context, DigestAddSequence, Log, LogIOError,
+};
 use redisql_lib::sqlite as sql;
+use redisql_lib::transaction::subscribe_to_disconnections;
 use std::ffi::CString;
-use std::fs::{remove_file, File};
 use std::ptr;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use uuid::Uuid;
 
 use sync_engine::{register, WriteAOF};
 
@@ -30,50 +36,115 @@ use commands_v1::{
     GetStatistics, MakeCopy, Query, QueryInto, QueryStatement,
     QueryStatementInto, RediSQLVersion, UpdateStatement,
 };
+use v2::backup::{BackupStatus_v2, Backup_v2};
+use v2::config::Config_v2;
 use v2::create_db::CreateDB_v2;
+use v2::csv::{Export_v2, Import_v2};
+use v2::cursor::Cursor_v2;
+use v2::dump::{Dump_v2, RestoreChunk_v2, Restore_v2, SQLITE_HEADER};
 use v2::exec::Exec_v2;
 use v2::exec::Query_v2;
+use v2::extension::Extension_v2;
+use v2::function::Function_v2;
+use v2::ingest::Ingest_v2;
 use v2::statement::Statement_v2;
+use v2::transaction::Transaction_v2;
 
 #[cfg(not(feature = "pro"))]
 extern crate telemetrics;
 
+/// How the databases are encoded in the RDB, the version is saved by
+/// Redis with each key and passed back to `rdb_load`.
+/// 1: the blocks of the SQLite file of the database.
+/// 2: the size of the image of the database, then the blocks of the
+///    image. If the database cannot be serialized, its SQL dump takes the
+///    place of the image.
+const RDB_ENCODING_VERSION: i32 = 2;
+
+// the image of the database is serialized in memory, without files
 unsafe extern "C" fn rdb_save(
     rdb: *mut r::rm::ffi::RedisModuleIO,
     value: *mut std::os::raw::c_void,
 ) {
-    let db: *mut r::DBKey =
-        Box::into_raw(Box::from_raw(value as *mut r::DBKey));
-
-    let path = format!("rediSQL_rdb_write_{}.sqlite", Uuid::new_v4());
-
-    let db = (*db).loop_data.get_db();
-    let conn = &db.lock().unwrap();
-    match r::create_backup(conn, &path) {
-        Err(e) => println!("{}", e),
-        Ok(not_done) if !sql::backup_complete_with_done(not_done) => {
-            println!("Return NOT DONE: {}", not_done)
-        }
-        Ok(_) => match File::open(path.clone()) {
-            Err(e) => println!("{}", e),
-            Ok(f) => match r::write_file_to_rdb(f, rdb) {
-                Ok(()) => match remove_file(path) {
-                    _ => (),
-                },
-                Err(_) => {
-                    println!(
-                        "Impossible to write the file \
-                         in the rdb file"
+    let db = &*(value as *mut r::DBKey);
+    let image = match r::committed_image(&db.loop_data) {
+        Ok(image) => image,
+        Err(e) => {
+            LogIOError(
+                rdb,
+                "warning",
+                &format!(
+                    "rediSQL: impossible to serialize the database, saving its SQL dump: {}",
+                    e
+                ),
+            );
+            let sql = r::committed_connection(&db.loop_data)
+                .map_err(|e| e.into())
+                .and_then(|conn| {
+                    dump(&lock_connection(&conn), DumpFormat::Sql)
+                });
+            match sql {
+                Ok(sql) => sql,
+                Err(e) => {
+                    // nothing else is left to save
+                    LogIOError(
+                        rdb,
+                        "warning",
+                        &format!(
+                            "rediSQL: impossible to dump the database, saving it empty: {}",
+                            e
+                        ),
                     );
+                    Vec::new()
                 }
-            },
-        },
+            }
+        }
+    };
+    r::rm::ffi::RedisModule_SaveUnsigned.unwrap()(
+        rdb,
+        image.len() as u64,
+    );
+    r::write_image_to_rdb(&image, rdb);
+}
+
+/// Read the image of the database saved in the RDB.
+unsafe fn read_image_from_rdb(
+    rdb: *mut r::rm::ffi::RedisModuleIO,
+    encoding_version: i32,
+) -> Result<Vec<u8>, String> {
+    let expected = match encoding_version {
+        1 => None,
+        2 => Some(r::rm::ffi::RedisModule_LoadUnsigned.unwrap()(rdb)),
+        v => {
+            return Err(format!(
+                "unknown encoding {} of the database, it was saved by a newer version of the module",
+                v
+            ))
+        }
+    };
+    let mut image = Vec::new();
+    if let Some(size) = expected {
+        image.reserve(size as usize);
+    }
+    if let Err(e) = r::write_rdb_to_file(&mut image, rdb) {
+        return Err(format!(
+            "impossible to read the database: {}",
+            e
+        ));
+    }
+    match expected {
+        Some(size) if size != image.len() as u64 => Err(format!(
+            "the database is truncated, read {} bytes of {}",
+            image.len(),
+            size
+        )),
+        _ => Ok(image),
     }
 }
 
-// in the RDB file we store a serialized database, nothing else.
-// the first step of loading is to write the content of the RDB into a random file
-// then we open a connection to the random file, called on_disk
+// in the RDB we store the image of a database, nothing else.
+// the first step of loading is to deserialize the image into an in-memory
+// connection, called on_disk
 // then we read what should be the path of the database to read.
 // It could be either:
 // 1) :memory:
@@ -86,46 +157,79 @@ unsafe extern "C" fn rdb_save(
 // Finally we start the whole threads and bell and whistles!
 unsafe extern "C" fn rdb_load(
     rdb: *mut r::rm::ffi::RedisModuleIO,
-    _encoding_version: i32,
+    encoding_version: i32,
 ) -> *mut std::os::raw::c_void {
-    let path = format!("rediSQL_rdb_read_{}.sqlite", Uuid::new_v4());
-
-    let mut file = match File::create(path.clone()) {
-        Err(_) => {
-            println!("Was impossible to create a file!");
+    let image = match read_image_from_rdb(rdb, encoding_version) {
+        Ok(image) => image,
+        Err(e) => {
+            LogIOError(rdb, "warning", &format!("rediSQL: {}", e));
             return ptr::null_mut();
         }
-        Ok(f) => f,
     };
 
-    if r::write_rdb_to_file(&mut file, rdb).is_err() {
-        println!("Was impossible to write the rdb file!");
-        return ptr::null_mut();
-    }
-
-    let on_disk = match sql::Connection::open_connection(&path) {
-        Err(_) => {
-            println!("Error in opening the rdb database");
+    let on_disk = match sql::Connection::open_connection(":memory:") {
+        Err(e) => {
+            LogIOError(
+                rdb,
+                "warning",
+                &format!(
+                    "rediSQL: error in opening the rdb database: {}",
+                    e
+                ),
+            );
             return ptr::null_mut();
         }
         Ok(on_disk) => on_disk,
     };
+    if !image.is_empty() && !image.starts_with(SQLITE_HEADER) {
+        let script = String::from_utf8_lossy(&image);
+        if let Err(e) = sql::execute_script(&on_disk, &script) {
+            LogIOError(
+                rdb,
+                "warning",
+                &format!(
+                    "rediSQL: the SQL dump in the RDB is not valid: {}",
+                    e
+                ),
+            );
+            return ptr::null_mut();
+        }
+    } else if !image.is_empty() {
+        if let Err(e) = sql::deserialize(&on_disk, &image) {
+            LogIOError(
+                rdb,
+                "warning",
+                &format!(
+                    "rediSQL: the database in the RDB is not valid: {}",
+                    e
+                ),
+            );
+            return ptr::null_mut();
+        }
+    }
 
     let on_disk = Arc::new(Mutex::new(on_disk));
     let previous_path = match get_path_from_db(on_disk.clone()) {
         Ok(path) => path,
         Err(e) => {
-            println!("Warning trying to load from RDB: {}", e);
+            LogIOError(
+                rdb,
+                "notice",
+                &format!(
+                    "rediSQL: path of the database not found, using an in-memory database: {}",
+                    e
+                ),
+            );
             ":memory:".to_string()
         }
     };
 
     let db = match sql::Connection::open_connection(&previous_path) {
         Err(_) => {
-            println!("WARN: Was impossible to open the database {}, using an in-memory database!", previous_path);
+            LogIOError(rdb, "warning", &format!("rediSQL: was impossible to open the database {}, using an in-memory database!", previous_path));
             match sql::Connection::open_connection(":memory:") {
                 Err(_) => {
-                    println!("ERROR: Was impossible to open also an in-memory database, fail!");
+                    LogIOError(rdb, "warning", "rediSQL: was impossible to open also an in-memory database, fail!");
                     return ptr::null_mut();
                 }
                 Ok(in_mem) => in_mem,
@@ -137,30 +241,88 @@ unsafe extern "C" fn rdb_load(
     let conn = Arc::new(Mutex::new(db));
     if !is_redisql_database(conn.clone()) {
         if let Err(e) = r::make_backup(
-            &on_disk.lock().unwrap(),
-            &conn.lock().unwrap(),
+            &lock_connection(&on_disk),
+            &lock_connection(&conn),
         ) {
-            println!("ERROR: Was impossible to copy the content of the RDB file into a database {}", e);
+            LogIOError(rdb, "warning", &format!("rediSQL: was impossible to copy the content of the RDB into a database: {}", e));
             return ptr::null_mut();
         }
     }
 
+    let readers = r::get_metadata(conn.clone(), "readers", "readers")
+        .and_then(|readers| readers.parse().ok())
+        .unwrap_or(0);
+    let cdc_stream = r::get_metadata(conn.clone(), "cdc", "stream");
     let (tx, rx) = channel();
-    let db = r::DBKey::new_from_arc(tx, conn);
+    let mut db = r::DBKey::new_from_arc(tx, conn);
+    if let Some(stream) = cdc_stream {
+        if let Err(e) = db.capture_changes(&stream) {
+            LogIOError(
+                rdb,
+                "warning",
+                &format!(
+                    "rediSQL: was impossible to capture the changes: {}",
+                    e
+                ),
+            );
+        }
+    }
+    if let Err(e) = db.start_readers(readers) {
+        LogIOError(
+            rdb,
+            "warning",
+            &format!(
+                "rediSQL: was impossible to start the readers: {}",
+                e
+            ),
+        );
+    }
     let mut loop_data = db.loop_data.clone();
 
     thread::spawn(move || r::listen_and_execute(&mut loop_data, &rx));
 
-    match remove_file(path) {
-        _ => (),
-    };
-
     Box::into_raw(Box::new(db)) as *mut std::os::raw::c_void
 }
 
+/// The memory of the SQLite connections of the database, the writer and
+/// the readers, for `MEMORY USAGE`.
+unsafe extern "C" fn mem_usage(
+    value: *const std::os::raw::c_void,
+) -> usize {
+    let db = &*(value as *const r::DBKey);
+    let writer = db.loop_data.get_db();
+    std::iter::once(&writer)
+        .chain(db.readers.connections())
+        // a connection busy with a query may be waiting for Redis
+        .filter_map(|conn| {
+            conn.try_lock().ok().map(|c| sql::memory_used(&c))
+        })
+        .sum::<usize>()
+        + std::mem::size_of::<r::DBKey>()
+}
+
+/// The hash of the content of the database for `DEBUG DIGEST`, used to
+/// check that the replicas have the same data of the primary.
+unsafe extern "C" fn digest(
+    md: *mut r::rm::ffi::RedisModuleDigest,
+    value: *mut std::os::raw::c_void,
+) {
+    let db = &*(value as *mut r::DBKey);
+    match digest_sequences(&db.loop_data.get_db()) {
+        Ok(sequences) => {
+            for sequence in sequences {
+                DigestAddSequence(md, &sequence);
+            }
+        }
+        Err(e) => warn!("Impossible to compute the digest: {}", e),
+    }
+}
+
 unsafe extern "C" fn free_db(db_ptr: *mut ::std::os::raw::c_void) {
     let db: Box<r::DBKey> = Box::from_raw(db_ptr as *mut r::DBKey);
     let tx = &db.tx;
+    r::database_freed();
+    db.readers.stop();
 
     match tx.send(r::Command::Stop) {
         _ => (),
@@ -171,20 +333,13 @@ unsafe extern "C" fn free_db(db_ptr: *mut ::std::os::raw::c_void) {
 #[no_mangle]
 pub extern "C" fn RedisModule_OnLoad(
     ctx: *mut r::rm::ffi::RedisModuleCtx,
-    _argv: *mut *mut r::rm::ffi::RedisModuleString,
-    _argc: i32,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: i32,
 ) -> i32 {
     let ctx = Context::new(ctx);
 
     sql::disable_global_memory_statistics();
-
-    logBuilder::new()
-        .filter_level(log::LevelFilter::Debug)
-        .target(logTarget::Stdout)
-        .init();
-
-    #[cfg(not(feature = "pro"))]
-    thread::spawn(telemetrics::start_telemetrics);
+    set_main_thread();
 
     let c_data_type_name = CString::new("rediSQLDB").unwrap();
     let ptr_data_type_name = c_data_type_name.as_ptr();
@@ -194,8 +349,8 @@ pub extern "C" fn RedisModule_OnLoad(
         rdb_load: Some(rdb_load),
         rdb_save: Some(rdb_save),
         aof_rewrite: Some(WriteAOF),
-        mem_usage: None,
-        digest: None,
+        mem_usage: Some(mem_usage),
+        digest: Some(digest),
         free: Some(free_db),
     };
 
@@ -213,12 +368,37 @@ pub extern "C" fn RedisModule_OnLoad(
         return r::rm::ffi::REDISMODULE_ERR;
     }
 
+    let config = match r::create_argument(argv, argc)
+        .map_err(|e| e.to_string())
+        .and_then(|args| Config::parse(&args))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            Log(&ctx, "warning", &format!("rediSQL: {}", e));
+            return r::rm::ffi::REDISMODULE_ERR;
+        }
+    };
+
+    logBuilder::new()
+        .filter_level(config.log_level)
+        .target(logTarget::Stdout)
+        .init();
+
+    #[cfg(not(feature = "pro"))]
+    {
+        if config.telemetry {
+            thread::spawn(telemetrics::start_telemetrics);
+        }
+    }
+
+    set_config(config);
+
     unsafe {
         r::rm::ffi::DBType = r::rm::ffi::RedisModule_CreateDataType
             .unwrap()(
             ctx.as_ptr(),
             ptr_data_type_name,
-            1,
+            RDB_ENCODING_VERSION,
             &mut types,
         );
     }
@@ -422,6 +602,354 @@ pub extern "C" fn RedisModule_OnLoad(
         Err(e) => return e,
     }
 
+    match register_write_function(
+        &ctx,
+        "REDISQL.V2.FUNCTION",
+        Function_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_write_function(
+        &ctx,
+        "REDISQL.FUNCTION",
+        Function_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_write_function(
+        &ctx,
+        "REDISQL.V2.EXTENSION",
+        Extension_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_write_function(
+        &ctx,
+        "REDISQL.EXTENSION",
+        Extension_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_write_function(
+        &ctx,
+        "REDISQL.V2.TX",
+        Transaction_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_write_function(&ctx, "REDISQL.TX", Transaction_v2)
+    {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function(
+        &ctx,
+        "REDISQL.V2.CURSOR.NEXT",
+        "readonly",
+        Cursor_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function(
+        &ctx,
+        "REDISQL.CURSOR.NEXT",
+        "readonly",
+        Cursor_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function(
+        &ctx,
+        "REDISQL.V2.CURSOR.CLOSE",
+        "readonly",
+        Cursor_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function(
+        &ctx,
+        "REDISQL.CURSOR.CLOSE",
+        "readonly",
+        Cursor_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function(
+        &ctx,
+        "REDISQL.V2.CONFIG",
+        "readonly",
+        Config_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function(
+        &ctx,
+        "REDISQL.CONFIG",
+        "readonly",
+        Config_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.V2.STREAM.INGEST",
+        "write",
+        1,
+        2,
+        1,
+        Ingest_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.STREAM.INGEST",
+        "write",
+        1,
+        2,
+        1,
+        Ingest_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.V2.IMPORT",
+        "write",
+        1,
+        1,
+        1,
+        Import_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.IMPORT",
+        "write",
+        1,
+        1,
+        1,
+        Import_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.V2.EXPORT",
+        "readonly",
+        1,
+        1,
+        1,
+        Export_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.EXPORT",
+        "readonly",
+        1,
+        1,
+        1,
+        Export_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.V2.DUMP",
+        "readonly",
+        1,
+        1,
+        1,
+        Dump_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.DUMP",
+        "readonly",
+        1,
+        1,
+        1,
+        Dump_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.V2.RESTORE",
+        "write",
+        1,
+        1,
+        1,
+        Restore_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.RESTORE",
+        "write",
+        1,
+        1,
+        1,
+        Restore_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.V2.RESTORE.CHUNK",
+        "write",
+        1,
+        1,
+        1,
+        RestoreChunk_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.RESTORE.CHUNK",
+        "write",
+        1,
+        1,
+        1,
+        RestoreChunk_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.V2.BACKUP",
+        "readonly",
+        1,
+        1,
+        1,
+        Backup_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.BACKUP",
+        "readonly",
+        1,
+        1,
+        1,
+        Backup_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.V2.LOAD",
+        "write",
+        1,
+        1,
+        1,
+        Backup_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.LOAD",
+        "write",
+        1,
+        1,
+        1,
+        Backup_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.V2.BACKUP.STATUS",
+        "readonly",
+        1,
+        1,
+        1,
+        BackupStatus_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    match register_function_with_keys(
+        &ctx,
+        "REDISQL.BACKUP.STATUS",
+        "readonly",
+        1,
+        1,
+        1,
+        BackupStatus_v2,
+    ) {
+        Ok(()) => (),
+        Err(e) => return e,
+    }
+
+    subscribe_to_disconnections(&ctx);
+    subscribe_to_loading(&ctx);
+
     match register(ctx) {
         Ok(()) => (),
         Err(e) => return e,
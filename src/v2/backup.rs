@@ -0,0 +1,89 @@
+use parser::backup::{Backup, BackupStatus};
+use parser::common::CommandV2;
+
+use redisql_lib::backup::check_image_size;
+use redisql_lib::redis as r;
+use redisql_lib::redis::{LoopData, RedisReply};
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::timeout::blocked_client_timeout;
+
+use crate::common::{free_privdata, reply_v2, timeout};
+
+#[allow(non_snake_case)]
+pub extern "C" fn Backup_v2(
+    ctx: *mut r::rm::ffi::RedisModuleCtx,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: ::std::os::raw::c_int,
+) -> i32 {
+    let context = r::rm::Context::new(ctx);
+    let argvector = r::create_binary_argument(argv, argc);
+    let command: Backup = match CommandV2::parse(argvector) {
+        Ok(comm) => comm,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let key = command.key(&context);
+    if let Err(mut e) =
+        key.check_transaction(&context, command.database(), None)
+    {
+        return e.reply_v2(&context);
+    }
+    let loop_data = match key.get_loop_data() {
+        Ok(loop_data) => loop_data,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let ch = match key.get_channel() {
+        Ok(ch) => ch,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let path = match command.file() {
+        Ok(path) => path,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    // LOAD changes the database, replicas do not read the file, they get
+    // its content from the thread of the database
+    if command.is_load() {
+        if !command.has_payload() {
+            if let Err(mut e) = check_image_size(&path) {
+                return e.reply_v2(&context);
+            }
+        }
+        // the commands after the LOAD are rejected until it is over,
+        // before running and before being replicated
+        loop_data.get_backup_state().start_loading();
+    }
+    let blocked_client = BlockedClient::new(
+        &context,
+        reply_v2,
+        timeout,
+        free_privdata,
+        blocked_client_timeout(command.timeout()),
+    );
+    match ch.send(command.get_command(path, blocked_client)) {
+        Err(e) => {
+            dbg!("Error in sending the command!", e.to_string());
+            r::rm::ffi::REDISMODULE_OK
+        }
+        Ok(_) => r::rm::ffi::REDISMODULE_OK,
+    }
+}
+
+#[allow(non_snake_case)]
+pub extern "C" fn BackupStatus_v2(
+    ctx: *mut r::rm::ffi::RedisModuleCtx,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: ::std::os::raw::c_int,
+) -> i32 {
+    let context = r::rm::Context::new(ctx);
+    let argvector = r::create_binary_argument(argv, argc);
+    let command: BackupStatus = match CommandV2::parse(argvector) {
+        Ok(comm) => comm,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let key = command.key(&context);
+    match key.get_loop_data() {
+        Ok(loop_data) => {
+            loop_data.get_backup_state().status().reply_v2(&context)
+        }
+        Err(mut e) => e.reply_v2(&context),
+    }
+}
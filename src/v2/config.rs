@@ -0,0 +1,48 @@
+use redisql_lib::config::config;
+use redisql_lib::redis as r;
+use redisql_lib::redis::RedisReply;
+use redisql_lib::redisql_error::RediSQLError;
+use redisql_lib::sqlite::{Entity, QueryResult};
+
+/// `REDISQL.CONFIG GET <option>` returns the value of the option,
+/// `REDISQL.CONFIG GET *` the value of all of them.
+#[allow(non_snake_case)]
+pub extern "C" fn Config_v2(
+    ctx: *mut r::rm::ffi::RedisModuleCtx,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: ::std::os::raw::c_int,
+) -> i32 {
+    let context = r::rm::Context::new(ctx);
+    let argvector = match r::create_argument(argv, argc) {
+        Ok(argvector) => argvector,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let option = match argvector.as_slice() {
+        [_, action, option] if action.eq_ignore_ascii_case("GET") => {
+            option.to_ascii_uppercase()
+        }
+        _ => {
+            let mut e = RediSQLError::with_code(
+                47,
+                "The config command should be used as: REDISQL.CONFIG GET <option>".to_string(),
+                "Wrong usage of the config command".to_string(),
+            );
+            return e.reply_v2(&context);
+        }
+    };
+    let mut array = vec![];
+    for (name, value) in config().entries() {
+        if option == "*" || option == name {
+            array.push(Entity::Text {
+                text: name.to_string(),
+            });
+            array.push(Entity::Text { text: value });
+        }
+    }
+    let mut result = QueryResult::Array {
+        names: vec!["option".to_string(), "value".to_string()],
+        types: vec!["TEXT", "TEXT"],
+        array,
+    };
+    result.reply_v2(&context)
+}
@@ -1,14 +1,18 @@
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use uuid::Uuid;
 
 use parser::common::CommandV2;
 use parser::create_db::CreateDB;
+use redisql_lib::config::resolve_path;
 use redisql_lib::redis as r;
 use redisql_lib::redis::{KeyTypes, RedisKey, RedisReply};
 use redisql_lib::redis_type::ReplicateVerbatim;
 use redisql_lib::redisql_error::RediSQLError;
-use redisql_lib::sqlite::{get_arc_connection, QueryResult};
+use redisql_lib::sqlite::{
+    get_arc_connection, Connection, QueryResult,
+};
 
 #[allow(non_snake_case)]
 pub extern "C" fn CreateDB_v2(
@@ -17,12 +21,7 @@ pub extern "C" fn CreateDB_v2(
     argc: ::std::os::raw::c_int,
 ) -> i32 {
     let context = r::rm::Context::new(ctx);
-    let argvector = match r::create_argument(argv, argc) {
-        Ok(argvector) => argvector,
-        Err(mut error) => {
-            return error.reply_v2(&context);
-        }
-    };
+    let argvector = r::create_binary_argument(argv, argc);
     let command: CreateDB = match CommandV2::parse(argvector) {
         Ok(comm) => comm,
         Err(mut e) => return e.reply_v2(&context),
@@ -30,7 +29,12 @@ pub extern "C" fn CreateDB_v2(
     let key = command.key(&context);
     match key.key_type() {
         KeyTypes::Empty => {
-            match create_db_from_path(key, command.path) {
+            match create_db_from_path(
+                key,
+                command.path,
+                command.readers,
+                command.cdc_stream,
+            ) {
                 Ok(mut ok) => {
                     ReplicateVerbatim(&context);
                     ok.reply_v2(&context)
@@ -60,23 +64,18 @@ pub extern "C" fn CreateDB_v2(
 fn create_db_from_path(
     key: RedisKey,
     path: Option<&str>,
+    readers: usize,
+    cdc_stream: Option<&str>,
 ) -> Result<QueryResult, RediSQLError> {
-    let possible_name = format!(
-        "file:{}?mode=memory&cache=shared",
-        Uuid::new_v4().to_simple()
-    );
+    r::check_max_databases()?;
     let name = match path {
-        None | Some(":memory") => &possible_name,
-        Some(name) => name,
+        None | Some(":memory") => in_memory_name(),
+        Some(name) => resolve_path(name),
     };
+    let name = name.as_str();
     let connection = get_arc_connection(name);
     if connection.is_err() {
-        let err = RediSQLError::with_code(
-            6,
-            "Error in opening database connection".to_string(),
-            "It was impossible to open a new database connection, maybe we are running out of space, memory, or you request to open a file that we cannot write.".to_string(),
-        );
-        return Err(err);
+        return Err(open_connection_error());
     }
     match connection
         .and_then(r::create_metadata_table)
@@ -84,45 +83,72 @@ fn create_db_from_path(
         .and_then(|rc| r::insert_path_metadata(rc, name))
     {
         Err(e) => Err(e.into()),
-        Ok(rc) => {
-            let (tx, rx) = channel();
-            let db = r::DBKey::new_from_arc(tx, rc);
-            let mut loop_data = db.loop_data.clone();
-            thread::spawn(move || {
-                r::listen_and_execute(&mut loop_data, &rx)
-            });
-            let ptr = Box::into_raw(Box::new(db));
-            let type_set = unsafe {
-                r::rm::ffi::RedisModule_ModuleTypeSetValue.unwrap()(
-                    key.key,
-                    r::rm::ffi::DBType,
-                    ptr as *mut std::os::raw::c_void,
-                )
-            };
+        Ok(rc) => start_database(key, rc, readers, cdc_stream),
+    }
+}
 
-            match type_set {
-                r::rm::ffi::REDISMODULE_OK => Ok(QueryResult::OK {}),
-                r::rm::ffi::REDISMODULE_ERR => {
-                    let err = RediSQLError::with_code(
-                        7,
-                        "Error in storing the key into redis"
-                            .to_string(),
-                        "Error in storing the key into redis"
-                            .to_string(),
-                    );
-                    Err(err)
-                }
-                _ => {
-                    let err = RediSQLError::with_code(
-                        8,
-                        "Unknow error in saving the key into redis"
-                            .to_string(),
-                        "Unknow error in saving the key into redis"
-                            .to_string(),
-                    );
-                    Err(err)
-                }
-            }
+/// The name of a new in-memory database, private to its connection.
+pub fn in_memory_name() -> String {
+    format!("file:{}?mode=memory", Uuid::new_v4().to_simple())
+}
+
+pub fn open_connection_error() -> RediSQLError {
+    RediSQLError::with_code(
+        6,
+        "Error in opening database connection".to_string(),
+        "It was impossible to open a new database connection, maybe we are running out of space, memory, or you request to open a file that we cannot write.".to_string(),
+    )
+}
+
+/// Start the threads of the database and store it in the key.
+pub fn start_database(
+    key: RedisKey,
+    rc: Arc<Mutex<Connection>>,
+    readers: usize,
+    cdc_stream: Option<&str>,
+) -> Result<QueryResult, RediSQLError> {
+    let (tx, rx) = channel();
+    let mut db = r::DBKey::new_from_arc(tx, rc);
+    let started = match cdc_stream {
+        Some(stream) => db.capture_changes(stream),
+        None => Ok(()),
+    }
+    .and_then(|_| db.start_readers(readers));
+    if let Err(e) = started {
+        let _ = db.tx.send(r::Command::Stop);
+        r::database_freed();
+        return Err(e);
+    }
+    let mut loop_data = db.loop_data.clone();
+    thread::spawn(move || r::listen_and_execute(&mut loop_data, &rx));
+    let ptr = Box::into_raw(Box::new(db));
+    let type_set = unsafe {
+        r::rm::ffi::RedisModule_ModuleTypeSetValue.unwrap()(
+            key.key,
+            r::rm::ffi::DBType,
+            ptr as *mut std::os::raw::c_void,
+        )
+    };
+
+    match type_set {
+        r::rm::ffi::REDISMODULE_OK => Ok(QueryResult::OK {}),
+        r::rm::ffi::REDISMODULE_ERR => {
+            let err = RediSQLError::with_code(
+                7,
+                "Error in storing the key into redis".to_string(),
+                "Error in storing the key into redis".to_string(),
+            );
+            Err(err)
+        }
+        _ => {
+            let err = RediSQLError::with_code(
+                8,
+                "Unknow error in saving the key into redis"
+                    .to_string(),
+                "Unknow error in saving the key into redis"
+                    .to_string(),
+            );
+            Err(err)
         }
     }
 }
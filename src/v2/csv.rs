@@ -0,0 +1,99 @@
+use parser::common::CommandV2;
+use parser::csv::{Export, Import};
+
+use redisql_lib::csv_io::replicate_import;
+use redisql_lib::redis as r;
+use redisql_lib::redis::RedisReply;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::redis_type::ReplicateVerbatim;
+use redisql_lib::timeout::blocked_client_timeout;
+
+use crate::common::{free_privdata, reply_v2, timeout};
+
+#[allow(non_snake_case)]
+pub extern "C" fn Import_v2(
+    ctx: *mut r::rm::ffi::RedisModuleCtx,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: ::std::os::raw::c_int,
+) -> i32 {
+    let context = r::rm::Context::new(ctx);
+    let argvector = r::create_binary_argument(argv, argc);
+    let command: Import = match CommandV2::parse(argvector) {
+        Ok(comm) => comm,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let t = std::time::Instant::now() + command.timeout();
+    let key = command.key(&context);
+    if let Err(mut e) =
+        key.check_transaction(&context, command.database(), None)
+    {
+        return e.reply_v2(&context);
+    }
+    let ch = match key.get_channel() {
+        Ok(ch) => ch,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let csv = match command.source().read() {
+        Ok(csv) => csv,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    // replicas do not read the file, they get its content
+    if command.is_payload() {
+        ReplicateVerbatim(&context);
+    } else {
+        replicate_import(&context, &command.replicate_args(), &csv);
+    }
+    let blocked_client = BlockedClient::new(
+        &context,
+        reply_v2,
+        timeout,
+        free_privdata,
+        blocked_client_timeout(command.timeout()),
+    );
+    match ch.send(command.get_command(csv, t, blocked_client)) {
+        Err(e) => {
+            dbg!("Error in sending the command!", e.to_string());
+            r::rm::ffi::REDISMODULE_OK
+        }
+        Ok(_) => r::rm::ffi::REDISMODULE_OK,
+    }
+}
+
+#[allow(non_snake_case)]
+pub extern "C" fn Export_v2(
+    ctx: *mut r::rm::ffi::RedisModuleCtx,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: ::std::os::raw::c_int,
+) -> i32 {
+    let context = r::rm::Context::new(ctx);
+    let argvector = r::create_binary_argument(argv, argc);
+    let command: Export = match CommandV2::parse(argvector) {
+        Ok(comm) => comm,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let t = std::time::Instant::now() + command.timeout();
+    let key = command.key(&context);
+    if let Err(mut e) =
+        key.check_transaction(&context, command.database(), None)
+    {
+        return e.reply_v2(&context);
+    }
+    let ch = match key.get_channel() {
+        Ok(ch) => ch,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let blocked_client = BlockedClient::new(
+        &context,
+        reply_v2,
+        timeout,
+        free_privdata,
+        blocked_client_timeout(command.timeout()),
+    );
+    match ch.send(command.get_command(t, blocked_client)) {
+        Err(e) => {
+            dbg!("Error in sending the command!", e.to_string());
+            r::rm::ffi::REDISMODULE_OK
+        }
+        Ok(_) => r::rm::ffi::REDISMODULE_OK,
+    }
+}
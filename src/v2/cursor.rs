@@ -0,0 +1,52 @@
+use parser::common::CommandV2;
+use parser::cursor::Cursor;
+
+use redisql_lib::redis as r;
+use redisql_lib::redis::RedisReply;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::timeout::blocked_client_timeout;
+
+use crate::common::{free_privdata, reply_v2, timeout};
+
+#[allow(non_snake_case)]
+pub extern "C" fn Cursor_v2(
+    ctx: *mut r::rm::ffi::RedisModuleCtx,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: ::std::os::raw::c_int,
+) -> i32 {
+    let context = r::rm::Context::new(ctx);
+    let argvector = r::create_binary_argument(argv, argc);
+    let command: Cursor = match CommandV2::parse(argvector) {
+        Ok(comm) => comm,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let t = std::time::Instant::now() + command.timeout();
+    let key = command.key(&context);
+    if let Err(mut e) =
+        key.check_transaction(&context, command.database(), None)
+    {
+        return e.reply_v2(&context);
+    }
+    match key.get_channel() {
+        Err(mut e) => e.reply_v2(&context),
+        Ok(ch) => {
+            let blocked_client = BlockedClient::new(
+                &context,
+                reply_v2,
+                timeout,
+                free_privdata,
+                blocked_client_timeout(command.timeout()),
+            );
+            match ch.send(command.get_command(t, blocked_client)) {
+                Err(e) => {
+                    dbg!(
+                        "Error in sending the command!",
+                        e.to_string()
+                    );
+                    r::rm::ffi::REDISMODULE_OK
+                }
+                Ok(_) => r::rm::ffi::REDISMODULE_OK,
+            }
+        }
+    }
+}
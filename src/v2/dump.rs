@@ -0,0 +1,218 @@
+use std::str;
+
+use parser::common::CommandV2;
+use parser::dump::{Dump, Restore, RestoreChunk};
+
+use redisql_lib::aof;
+use redisql_lib::locks::lock_connection;
+use redisql_lib::redis as r;
+use redisql_lib::redis::{KeyTypes, RedisKey, RedisReply};
+use redisql_lib::redis_type::{
+    BlockedClient, Context, ReplicateVerbatim,
+};
+use redisql_lib::redisql_error::RediSQLError;
+use redisql_lib::sqlite as sql;
+use redisql_lib::sqlite::{
+    get_arc_connection, Connection, QueryResult,
+};
+use redisql_lib::timeout::{blocked_client_timeout, default_timeout};
+
+use crate::common::{free_privdata, reply_v2, timeout};
+
+use crate::v2::create_db::{
+    in_memory_name, open_connection_error, start_database,
+};
+
+/// The first bytes of every SQLite file.
+pub const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+#[allow(non_snake_case)]
+pub extern "C" fn Dump_v2(
+    ctx: *mut r::rm::ffi::RedisModuleCtx,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: ::std::os::raw::c_int,
+) -> i32 {
+    let context = r::rm::Context::new(ctx);
+    let argvector = r::create_binary_argument(argv, argc);
+    let command: Dump = match CommandV2::parse(argvector) {
+        Ok(comm) => comm,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let key = command.key(&context);
+    if let Err(mut e) =
+        key.check_transaction(&context, command.database(), None)
+    {
+        return e.reply_v2(&context);
+    }
+    // the database is read by its thread, without blocking Redis
+    let ch = match key.get_channel() {
+        Ok(ch) => ch,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let blocked_client = BlockedClient::new(
+        &context,
+        reply_v2,
+        timeout,
+        free_privdata,
+        blocked_client_timeout(default_timeout()),
+    );
+    match ch.send(command.get_command(blocked_client)) {
+        Err(e) => {
+            dbg!("Error in sending the command!", e.to_string());
+            r::rm::ffi::REDISMODULE_OK
+        }
+        Ok(_) => r::rm::ffi::REDISMODULE_OK,
+    }
+}
+
+#[allow(non_snake_case)]
+pub extern "C" fn Restore_v2(
+    ctx: *mut r::rm::ffi::RedisModuleCtx,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: ::std::os::raw::c_int,
+) -> i32 {
+    let context = r::rm::Context::new(ctx);
+    let argvector = r::create_binary_argument(argv, argc);
+    let command: Restore = match CommandV2::parse(argvector) {
+        Ok(comm) => comm,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let key = command.key(&context);
+    if let Err(mut e) = check_key_is_empty(&key, command.database()) {
+        return e.reply_v2(&context);
+    }
+    restore(&context, key, command.payload)
+}
+
+#[allow(non_snake_case)]
+pub extern "C" fn RestoreChunk_v2(
+    ctx: *mut r::rm::ffi::RedisModuleCtx,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: ::std::os::raw::c_int,
+) -> i32 {
+    let context = r::rm::Context::new(ctx);
+    let argvector = r::create_binary_argument(argv, argc);
+    let command: RestoreChunk = match CommandV2::parse(argvector) {
+        Ok(comm) => comm,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let key = command.key(&context);
+    if let Err(mut e) = check_key_is_empty(&key, command.database()) {
+        aof::discard_image(command.database());
+        return e.reply_v2(&context);
+    }
+    let image = aof::add_chunk(
+        command.database(),
+        command.index,
+        command.total,
+        command.checksum,
+        command.chunk,
+    );
+    match image {
+        Ok(Some(image)) => restore(&context, key, &image),
+        Ok(None) => {
+            ReplicateVerbatim(&context);
+            (QueryResult::OK {}).reply_v2(&context)
+        }
+        Err(mut e) => e.reply_v2(&context),
+    }
+}
+
+fn check_key_is_empty(
+    key: &RedisKey,
+    database: &str,
+) -> Result<(), RediSQLError> {
+    match key.key_type() {
+        KeyTypes::Empty => Ok(()),
+        _ => Err(RediSQLError::with_code(
+            64,
+            format!("The key `{}` already exists", database),
+            "RESTORE needs a key that does not exist".to_string(),
+        )),
+    }
+}
+
+/// Create the database in the empty key from the payload.
+fn restore(context: &Context, key: RedisKey, payload: &[u8]) -> i32 {
+    if let Err(mut e) = r::check_max_databases() {
+        return e.reply_v2(context);
+    }
+    let name = in_memory_name();
+    let connection = match get_arc_connection(&name) {
+        Ok(connection) => connection,
+        Err(_) => return open_connection_error().reply_v2(context),
+    };
+    if let Err(mut e) =
+        load_payload(&lock_connection(&connection), payload)
+    {
+        return e.reply_v2(context);
+    }
+    // the changes of the copy are not published in the stream of the
+    // source database
+    let restored = r::create_metadata_table(connection)
+        .and_then(r::enable_foreign_key)
+        .and_then(|rc| {
+            r::replace_metadata(
+                rc.clone(),
+                "path",
+                "path",
+                Some(&name),
+            )
+            .and_then(|_| {
+                r::replace_metadata(rc.clone(), "cdc", "stream", None)
+            })
+            .map(|_| rc)
+        });
+    let rc = match restored {
+        Ok(rc) => rc,
+        Err(e) => return RediSQLError::from(e).reply_v2(context),
+    };
+    // the statements are read back from the metadata by the thread of
+    // the database once it starts
+    let readers = r::get_metadata(rc.clone(), "readers", "readers")
+        .and_then(|readers| readers.parse().ok())
+        .unwrap_or(0);
+    match start_database(key, rc, readers, None) {
+        Ok(mut ok) => {
+            ReplicateVerbatim(context);
+            ok.reply_v2(context)
+        }
+        Err(mut e) => e.reply_v2(context),
+    }
+}
+
+/// Fill the empty database with the content of the payload of RESTORE,
+/// either an image of the database or the SQL statements to execute.
+fn load_payload(
+    conn: &Connection,
+    payload: &[u8],
+) -> Result<(), RediSQLError> {
+    if payload.starts_with(SQLITE_HEADER) {
+        let image = Connection::open_connection(":memory:")?;
+        sql::deserialize(&image, payload)
+            .map_err(|e| invalid_payload(&e.to_string()))?;
+        let result = r::make_backup(&image, conn)
+            .map_err(|e| invalid_payload(&e.to_string()))?;
+        if !sql::backup_complete_with_done(result) {
+            return Err(invalid_payload(
+                "The image of the database is corrupted",
+            ));
+        }
+        return Ok(());
+    }
+    let script = str::from_utf8(payload).map_err(|_| {
+        invalid_payload(
+            "The payload is not a SQLite image nor SQL text",
+        )
+    })?;
+    sql::execute_script(conn, script)
+        .map_err(|e| invalid_payload(&e.to_string()))
+}
+
+fn invalid_payload(debug: &str) -> RediSQLError {
+    RediSQLError::with_code(
+        63,
+        debug.to_string(),
+        "Invalid RESTORE payload".to_string(),
+    )
+}
@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::ffi::CString;
 
 use parser::common::CommandV2;
@@ -8,12 +9,16 @@ use redisql_lib::redis as r;
 use redisql_lib::redis::do_execute;
 use redisql_lib::redis::do_query;
 use redisql_lib::redis::LoopData;
+use redisql_lib::redis::RedisKey;
 use redisql_lib::redis::RedisReply;
+use redisql_lib::redis::ReturnMethod;
 use redisql_lib::redis::Returner;
 use redisql_lib::redis::StatementCache;
 use redisql_lib::redis_type::BlockedClient;
 use redisql_lib::redis_type::Context;
 use redisql_lib::redis_type::ReplicateVerbatim;
+use redisql_lib::sqlite::{Cursor, QueryResult};
+use redisql_lib::timeout::{blocked_client_timeout, InterruptAfter};
 
 use crate::common::{free_privdata, reply_v2, timeout};
 
@@ -24,12 +29,7 @@ pub extern "C" fn Exec_v2(
     argc: ::std::os::raw::c_int,
 ) -> i32 {
     let context = r::rm::Context::new(ctx);
-    let argvector = match r::create_argument(argv, argc) {
-        Ok(argvector) => argvector,
-        Err(mut error) => {
-            return error.reply_v2(&context);
-        }
-    };
+    let argvector = r::create_binary_argument(argv, argc);
     let command: Exec = match CommandV2::parse(argvector) {
         Ok(comm) => comm,
         Err(mut e) => return e.reply_v2(&context),
@@ -44,24 +44,27 @@ pub extern "C" fn Query_v2(
     argc: ::std::os::raw::c_int,
 ) -> i32 {
     let context = r::rm::Context::new(ctx);
-    let argvector = match r::create_argument(argv, argc) {
-        Ok(argvector) => argvector,
-        Err(mut error) => {
-            return error.reply_v2(&context);
-        }
-    };
+    let argvector = r::create_binary_argument(argv, argc);
     let mut command: Exec = match CommandV2::parse(argvector) {
         Ok(comm) => comm,
         Err(mut e) => return e.reply_v2(&context),
     };
-    command.make_into_query();
+    if let Err(mut e) = command.make_into_query() {
+        return e.reply_v2(&context);
+    }
     do_exec_v2(command, context)
 }
 
 fn do_exec_v2(command: Exec<'static>, context: Context) -> i32 {
-    let t = std::time::Instant::now()
-        + std::time::Duration::from_secs(10);
+    let t = std::time::Instant::now() + command.timeout();
     let key = command.key(&context);
+    if let Err(mut e) = key.check_transaction(
+        &context,
+        command.database(),
+        command.transaction(),
+    ) {
+        return e.reply_v2(&context);
+    }
     if !command.is_now() {
         match key.get_channel() {
             Err(mut e) => e.reply_v2(&context),
@@ -71,7 +74,7 @@ fn do_exec_v2(command: Exec<'static>, context: Context) -> i32 {
                     reply_v2,
                     timeout,
                     free_privdata,
-                    10_000,
+                    blocked_client_timeout(command.timeout()),
                 );
                 let repl_args = command.replicate_args(&context);
                 let comm = command.get_command(t, blocked_client);
@@ -85,23 +88,7 @@ fn do_exec_v2(command: Exec<'static>, context: Context) -> i32 {
                     }
                     Ok(_) => {
                         if let Some(repl_args) = repl_args {
-                            let command =
-                                CString::new("REDISQL.V2.EXEC")
-                                    .unwrap();
-                            let format = CString::new("v").unwrap();
-                            unsafe {
-                                r::rm::ffi::RedisModule_Replicate
-                                    .unwrap()(
-                                    context.as_ptr(),
-                                    command.as_ptr(),
-                                    format.as_ptr(),
-                                    repl_args.as_ptr(),
-                                    repl_args.len(),
-                                );
-                                for ptr in repl_args {
-                                    r::rm::ffi::RedisModule_FreeString.unwrap()(context.as_ptr(), ptr);
-                                }
-                            }
+                            replicate_exec(&context, repl_args);
                         }
                         r::rm::ffi::REDISMODULE_OK
                     }
@@ -113,6 +100,7 @@ fn do_exec_v2(command: Exec<'static>, context: Context) -> i32 {
             Ok(k) => k,
             Err(mut e) => return e.reply_v2(&context),
         };
+        let _interrupt = InterruptAfter::new(t);
         let read_only = command.is_read_only();
         let return_method = command.get_return_method();
         let to_execute = command.get_to_execute();
@@ -133,9 +121,25 @@ fn do_exec_v2(command: Exec<'static>, context: Context) -> i32 {
                     },
                     false => match do_execute(&db, query, args) {
                         Ok(r) => {
-                            ReplicateVerbatim(&context);
-                            r.create_data_to_return(
+                            // the rows added to a stream are replicated
+                            // by their own XADDs
+                            match command.get_into() {
+                                Some(_) => {
+                                    if let Some(repl_args) = command
+                                        .replicate_args(&context)
+                                    {
+                                        replicate_exec(
+                                            &context, repl_args,
+                                        );
+                                    }
+                                }
+                                None => {
+                                    ReplicateVerbatim(&context);
+                                }
+                            }
+                            reply_to_write(
                                 &context,
+                                r,
                                 &return_method,
                                 t,
                             )
@@ -147,8 +151,41 @@ fn do_exec_v2(command: Exec<'static>, context: Context) -> i32 {
                         ),
                     },
                 };
+                publish_changes(&key, &context);
                 res.reply_v2(&context)
             }
+            ToExecute::Statement { stmt, .. }
+                if command.get_batch().is_some() =>
+            {
+                let loop_data = match key.get_loop_data() {
+                    Ok(k) => k,
+                    Err(mut e) => return e.reply_v2(&context),
+                };
+                let batch = command.get_batch().unwrap();
+                let mut result = match loop_data
+                    .get_replication_book()
+                    .exec_statement_batch(
+                        stmt,
+                        batch,
+                        command.with_errors(),
+                    ) {
+                    Ok(r) => {
+                        ReplicateVerbatim(&context);
+                        r.create_data_to_return(
+                            &context,
+                            &return_method,
+                            t,
+                        )
+                    }
+                    Err(e) => e.create_data_to_return(
+                        &context,
+                        &return_method,
+                        t,
+                    ),
+                };
+                publish_changes(&key, &context);
+                result.reply_v2(&context)
+            }
             ToExecute::Statement { stmt, args } => {
                 let loop_data = match key.get_loop_data() {
                     Ok(k) => k,
@@ -178,8 +215,9 @@ fn do_exec_v2(command: Exec<'static>, context: Context) -> i32 {
                             .get_replication_book()
                             .exec_statement(stmt, args)
                         {
-                            Ok(r) => r.create_data_to_return(
+                            Ok(r) => reply_to_write(
                                 &context,
+                                r,
                                 &return_method,
                                 t,
                             ),
@@ -191,8 +229,73 @@ fn do_exec_v2(command: Exec<'static>, context: Context) -> i32 {
                         }
                     }
                 };
+                publish_changes(&key, &context);
                 result.reply_v2(&context)
             }
         }
     }
 }
+
+/// Replicate the command as `REDISQL.V2.EXEC` with the arguments provided.
+fn replicate_exec(
+    context: &Context,
+    repl_args: Vec<*mut r::rm::ffi::RedisModuleString>,
+) {
+    let command = CString::new("REDISQL.V2.EXEC").unwrap();
+    let format = CString::new("v").unwrap();
+    unsafe {
+        r::rm::ffi::RedisModule_Replicate.unwrap()(
+            context.as_ptr(),
+            command.as_ptr(),
+            format.as_ptr(),
+            repl_args.as_ptr(),
+            repl_args.len(),
+        );
+        for ptr in repl_args {
+            r::rm::ffi::RedisModule_FreeString.unwrap()(
+                context.as_ptr(),
+                ptr,
+            );
+        }
+    }
+}
+
+/// Writes that add the rows they return to a stream read all of them
+/// first, so that the statement is committed before the XADDs.
+fn reply_to_write(
+    context: &Context,
+    cursor: Cursor,
+    return_method: &ReturnMethod,
+    t: std::time::Instant,
+) -> Box<Box<dyn RedisReply>> {
+    match return_method {
+        ReturnMethod::Stream { .. } => {
+            match QueryResult::try_from(cursor) {
+                Ok(rows) => rows.create_data_to_return(
+                    context,
+                    return_method,
+                    t,
+                ),
+                Err(e) => {
+                    e.create_data_to_return(context, return_method, t)
+                }
+            }
+        }
+        _ => cursor.create_data_to_return(context, return_method, t),
+    }
+}
+
+/// Publish to the change stream of the database the changes committed by
+/// a command executed `NOW`.
+fn publish_changes(key: &RedisKey, context: &Context) {
+    let loop_data = match key.get_loop_data() {
+        Ok(loop_data) => loop_data,
+        Err(_) => return,
+    };
+    if let Some(changes) = loop_data.get_change_capture() {
+        if let Err(e) = changes.publish(context, &loop_data.get_db())
+        {
+            debug!("Error publishing the changes: {}", e);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use parser::common::CommandV2;
+use parser::function::{Action, Function};
+
+use redisql_lib::redis as r;
+use redisql_lib::redis::LoopData;
+use redisql_lib::redis::RedisReply;
+use redisql_lib::redis::ReturnMethod;
+use redisql_lib::redis::Returner;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::redis_type::ReplicateVerbatim;
+use redisql_lib::timeout::blocked_client_timeout;
+
+use crate::common::{free_privdata, reply_v2, timeout};
+
+#[allow(non_snake_case)]
+pub extern "C" fn Function_v2(
+    ctx: *mut r::rm::ffi::RedisModuleCtx,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: ::std::os::raw::c_int,
+) -> i32 {
+    let context = r::rm::Context::new(ctx);
+    let argvector = r::create_binary_argument(argv, argc);
+    let command: Function = match CommandV2::parse(argvector) {
+        Ok(comm) => comm,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let t = std::time::Instant::now() + command.timeout();
+    let key = command.key(&context);
+    if let Err(mut e) =
+        key.check_transaction(&context, command.database(), None)
+    {
+        return e.reply_v2(&context);
+    }
+    // the replicas need the same functions to run the same statements
+    let writes = command.get_action() != Action::List;
+    if !command.is_now() {
+        match key.get_channel() {
+            Err(mut e) => e.reply_v2(&context),
+            Ok(ch) => {
+                let blocked_client = BlockedClient::new(
+                    &context,
+                    reply_v2,
+                    timeout,
+                    free_privdata,
+                    blocked_client_timeout(command.timeout()),
+                );
+                let command = command.get_command(t, blocked_client);
+                match ch.send(command) {
+                    Err(e) => {
+                        dbg!(
+                            "Error in sending the command!",
+                            e.to_string()
+                        );
+                        r::rm::ffi::REDISMODULE_OK
+                    }
+                    Ok(_) => {
+                        if writes {
+                            ReplicateVerbatim(&context);
+                        }
+                        r::rm::ffi::REDISMODULE_OK
+                    }
+                }
+            }
+        }
+    } else {
+        let loop_data = match key.get_loop_data() {
+            Ok(k) => k,
+            Err(mut e) => return e.reply_v2(&context),
+        };
+        let book = loop_data.get_replication_book();
+        let result = match command.get_action() {
+            Action::New => book.insert_function(
+                command.definition(),
+                command.can_update(),
+            ),
+            Action::Delete => book.delete_function(command.name()),
+            Action::List => book.list_functions(),
+        };
+        match result {
+            Err(mut e) => e.reply_v2(&context),
+            Ok(q) => {
+                if writes {
+                    ReplicateVerbatim(&context);
+                }
+                let mut to_return = q.create_data_to_return(
+                    &context,
+                    &ReturnMethod::ReplyWithHeader,
+                    t,
+                );
+                to_return.reply_v2(&context)
+            }
+        }
+    }
+}
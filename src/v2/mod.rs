@@ -1,3 +1,12 @@
+pub mod backup;
+pub mod config;
 pub mod create_db;
+pub mod csv;
+pub mod cursor;
+pub mod dump;
 pub mod exec;
+pub mod extension;
+pub mod function;
+pub mod ingest;
 pub mod statement;
+pub mod transaction;
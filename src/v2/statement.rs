@@ -11,6 +11,7 @@ use redisql_lib::redis::StatementCache;
 use redisql_lib::redis_type::BlockedClient;
 use redisql_lib::redis_type::ReplicateVerbatim;
 use redisql_lib::sqlite::QueryResult;
+use redisql_lib::timeout::blocked_client_timeout;
 
 use crate::common::{free_privdata, reply_v2, timeout};
 
@@ -21,17 +22,18 @@ pub extern "C" fn Statement_v2(
     argc: ::std::os::raw::c_int,
 ) -> i32 {
     let context = r::rm::Context::new(ctx);
-    let argvector = match r::create_argument(argv, argc) {
-        Ok(argvector) => argvector,
-        Err(mut error) => {
-            return error.reply_v2(&context);
-        }
-    };
+    let argvector = r::create_binary_argument(argv, argc);
     let command: Statement = match CommandV2::parse(argvector) {
         Ok(comm) => comm,
         Err(mut e) => return e.reply_v2(&context),
     };
+    let t = std::time::Instant::now() + command.timeout();
     let key = command.key(&context);
+    if let Err(mut e) =
+        key.check_transaction(&context, command.database(), None)
+    {
+        return e.reply_v2(&context);
+    }
     if !command.is_now() {
         match key.get_channel() {
             Err(mut e) => e.reply_v2(&context),
@@ -41,9 +43,9 @@ pub extern "C" fn Statement_v2(
                     reply_v2,
                     timeout,
                     free_privdata,
-                    10_000,
+                    blocked_client_timeout(command.timeout()),
                 );
-                let command = command.get_command(blocked_client);
+                let command = command.get_command(t, blocked_client);
                 match ch.send(command) {
                     Err(e) => {
                         dbg!(
@@ -117,8 +119,7 @@ pub extern "C" fn Statement_v2(
                         let mut to_return = q.create_data_to_return(
                             &context,
                             &ReturnMethod::ReplyWithHeader,
-                            std::time::Instant::now()
-                                + std::time::Duration::from_secs(10),
+                            t,
                         );
                         to_return.reply_v2(&context)
                     }
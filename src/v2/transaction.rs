@@ -0,0 +1,79 @@
+use parser::common::CommandV2;
+use parser::transaction::Action;
+use parser::transaction::Transaction;
+
+use redisql_lib::redis as r;
+use redisql_lib::redis::RedisReply;
+use redisql_lib::redis_type::BlockedClient;
+use redisql_lib::redis_type::GetClientId;
+use redisql_lib::timeout::{blocked_client_timeout, default_timeout};
+use redisql_lib::transaction::register_client_transaction;
+
+use uuid::Uuid;
+
+use crate::common::{free_privdata, reply_v2, timeout};
+
+#[allow(non_snake_case)]
+pub extern "C" fn Transaction_v2(
+    ctx: *mut r::rm::ffi::RedisModuleCtx,
+    argv: *mut *mut r::rm::ffi::RedisModuleString,
+    argc: ::std::os::raw::c_int,
+) -> i32 {
+    let context = r::rm::Context::new(ctx);
+    let argvector = r::create_binary_argument(argv, argc);
+    let mut command: Transaction = match CommandV2::parse(argvector) {
+        Ok(comm) => comm,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let key = command.key(&context);
+    let database = command.database().to_string();
+    let ch = match key.get_channel() {
+        Ok(ch) => ch,
+        Err(mut e) => return e.reply_v2(&context),
+    };
+    let result = match command.get_action() {
+        Action::Begin => key
+            .check_transaction(&context, &database, None)
+            .and_then(|transaction| {
+                let token = Uuid::new_v4().to_string();
+                let client_id = GetClientId(&context);
+                transaction.begin(
+                    &token,
+                    client_id,
+                    &database,
+                    command.idle_timeout(),
+                )?;
+                register_client_transaction(
+                    client_id,
+                    &transaction,
+                    &ch,
+                );
+                command.set_token(token);
+                Ok(())
+            }),
+        Action::Commit | Action::Rollback => {
+            let token = command.token().unwrap_or_default();
+            key.check_transaction(&context, &database, Some(token))
+                .and_then(|transaction| transaction.end(token))
+        }
+    };
+    if let Err(mut e) = result {
+        return e.reply_v2(&context);
+    }
+    let blocked_client = BlockedClient::new(
+        &context,
+        reply_v2,
+        timeout,
+        free_privdata,
+        blocked_client_timeout(default_timeout()),
+    );
+    match ch.send(command.get_command(blocked_client)) {
+        Err(e) => {
+            dbg!("Error in sending the command!", e.to_string());
+            r::rm::ffi::REDISMODULE_OK
+        }
+        // the thread of the database replicates the statement once it
+        // knows how it ended
+        Ok(_) => r::rm::ffi::REDISMODULE_OK,
+    }
+}
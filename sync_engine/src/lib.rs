@@ -1,3 +1,5 @@
+#[macro_use]
+extern crate log;
 extern crate redisql_lib;
 
 use std::collections::vec_deque::VecDeque;
@@ -7,9 +9,14 @@ use std::mem::ManuallyDrop;
 use std::os::raw;
 use std::sync::{Arc, Mutex};
 
+use redisql_lib::aof;
+use redisql_lib::aof::AofRewrite;
+use redisql_lib::config::config;
+use redisql_lib::locks::lock_connection;
 use redisql_lib::sqlite::ffi;
 use redisql_lib::sqlite::Connection;
 use redisql_lib::sqlite::SQLiteConnection;
+use redisql_lib::sqlite::{Argument, Bindings};
 
 use redisql_lib::redis as r;
 use redisql_lib::redis::{
@@ -22,21 +29,25 @@ use redisql_lib::redis_type::ffi::{
     RedisModuleIO, RedisModuleString,
 };
 use redisql_lib::redis_type::{Context, ReplicateVerbatim};
+use redisql_lib::timeout::default_deadline;
 
+/// The lines of the SQL dump of the database, read from the pipe where
+/// the dump is written.
 struct DumpIterator {
     fd: raw::c_int,
     buffer: [u8; 4096],
     iterator: VecDeque<String>,
-    first_chunk: String,
+    /// The start of the next line, its end is not read yet.
+    first_chunk: Vec<u8>,
 }
 
 impl<'b> DumpIterator {
     fn new(conn: &Arc<Mutex<Connection>>) -> DumpIterator {
-        let db = conn.lock().unwrap();
+        let db = lock_connection(&conn);
         let buffer: [u8; 4096] = unsafe { zeroed() };
         let fd = unsafe { ffi::start((*db).get_db()) };
         let iterator = VecDeque::new();
-        let first_chunk = String::from("");
+        let first_chunk = Vec::new();
         DumpIterator {
             fd,
             buffer,
@@ -58,49 +69,85 @@ impl Iterator for DumpIterator {
     type Item = String;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.iterator.len() {
-            n if n > 0 => {
-                Some(self.iterator.pop_front().unwrap_or_default())
+        loop {
+            if let Some(line) = self.iterator.pop_front() {
+                return Some(line);
             }
-            _ => {
-                let read_bytes = unsafe {
-                    ffi::read_from_pipe(
-                        self.fd,
-                        self.buffer.as_mut_ptr() as *mut raw::c_void,
-                        4096,
-                    )
-                };
-                match read_bytes {
-                    n if n > 0 => {
-                        let data = self
-                            .buffer
-                            .split_at(n as usize)
-                            .0
-                            .to_vec();
-                        let whole_string = unsafe {
-                            String::from_utf8_unchecked(data)
-                        };
-                        self.iterator = whole_string
-                            .split('\n')
-                            .map(String::from)
-                            .collect();
-
-                        let result = match self.iterator.pop_front() {
-                            None => None,
-                            Some(s) => {
-                                Some(self.first_chunk.clone() + &s)
-                            }
-                        };
-                        self.first_chunk = self
-                            .iterator
-                            .pop_back()
-                            .unwrap_or_else(|| String::from(""));
-                        result
-                    }
-                    _ => None,
+            let read_bytes = unsafe {
+                ffi::read_from_pipe(
+                    self.fd,
+                    self.buffer.as_mut_ptr() as *mut raw::c_void,
+                    4096,
+                )
+            };
+            if read_bytes <= 0 {
+                // the last line, if the dump does not end with a newline
+                if self.first_chunk.is_empty() {
+                    return None;
                 }
+                let last = std::mem::take(&mut self.first_chunk);
+                return Some(
+                    String::from_utf8_lossy(&last).into_owned(),
+                );
+            }
+            // a line can be longer than the buffer, it is returned only
+            // once its newline is read
+            self.first_chunk.extend_from_slice(
+                &self.buffer[..read_bytes as usize],
+            );
+            let mut lines: Vec<&[u8]> =
+                self.first_chunk.split(|b| *b == b'\n').collect();
+            let first_chunk =
+                lines.pop().unwrap_or_default().to_vec();
+            self.iterator = lines
+                .into_iter()
+                .map(|line| {
+                    String::from_utf8_lossy(line).into_owned()
+                })
+                .collect();
+            self.first_chunk = first_chunk;
+        }
+    }
+}
+
+/// The statements of the SQL dump, one for each item. The string literals
+/// of a statement can hold newlines, so a statement spans all the lines
+/// up to the one that completes it.
+struct Statements {
+    lines: DumpIterator,
+}
+
+impl Iterator for Statements {
+    type Item = String;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut statement = String::new();
+        for line in &mut self.lines {
+            if statement.is_empty() && line.is_empty() {
+                continue;
+            }
+            if !statement.is_empty() {
+                statement.push('\n');
+            }
+            statement.push_str(&line);
+            if is_complete(&statement) {
+                return Some(statement);
             }
         }
+        if statement.is_empty() {
+            None
+        } else {
+            Some(statement)
+        }
+    }
+}
+
+fn is_complete(statement: &str) -> bool {
+    match CString::new(statement) {
+        Ok(statement) => unsafe {
+            ffi::sqlite3_complete(statement.as_ptr()) != 0
+        },
+        Err(_) => true,
     }
 }
 
@@ -113,21 +160,65 @@ pub unsafe extern "C" fn WriteAOF(
     let aof = r::rm::AOF::new(aof);
     let dbkey: Box<r::DBKey> = Box::from_raw(value as *mut r::DBKey);
 
-    let db = dbkey.loop_data.get_db();
-
-    r::rm::EmitAOF(&aof, "REDISQL.V1.CREATE_DB", "s", key, "");
+    if config().aof_rewrite == AofRewrite::Image {
+        let image = r::committed_image(&dbkey.loop_data);
+        match image {
+            Ok(image) => return write_image_to_aof(&aof, key, &image),
+            Err(e) => warn!(
+                "Impossible to serialize the database for the AOF, writing its SQL dump instead: {}",
+                e
+            ),
+        }
+    }
 
-    let iter = DumpIterator::new(&db);
-    for s in iter {
-        for line in s.split('\n').filter(|l| !l.is_empty()) {
-            r::rm::EmitAOF(
-                &aof,
-                "REDISQL.V1.EXEC.NOW",
-                "sc",
-                key,
-                line,
+    let db = match r::committed_connection(&dbkey.loop_data) {
+        Ok(db) => db,
+        Err(e) => {
+            warn!(
+                "Impossible to read the database for the AOF: {}",
+                e
             );
+            return;
         }
+    };
+
+    r::rm::EmitAOF(&aof, "REDISQL.V1.CREATE_DB", "s", key, "");
+
+    let statements = Statements {
+        lines: DumpIterator::new(&db),
+    };
+    for statement in statements {
+        r::rm::EmitAOF(
+            &aof,
+            "REDISQL.V1.EXEC.NOW",
+            "sc",
+            key,
+            &statement,
+        );
+    }
+}
+
+/// Write the image of the database, that carries also its statements,
+/// as a sequence of `RESTORE.CHUNK`, the last one restores the database
+/// once the checksum of the whole image is verified.
+unsafe fn write_image_to_aof(
+    aof: &r::rm::AOF,
+    key: *mut RedisModuleString,
+    image: &[u8],
+) {
+    let checksum = aof::checksum(image);
+    let chunks = aof::chunks(image);
+    let total = chunks.len() as i64;
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        r::rm::EmitAOFChunk(
+            aof,
+            "REDISQL.V2.RESTORE.CHUNK\0",
+            key,
+            index as i64,
+            total,
+            checksum,
+            chunk,
+        );
     }
 }
 
@@ -175,11 +266,18 @@ pub extern "C" fn ExecNow(
                 ),
                 Ok(dbkey) => {
                     let dbkey = ManuallyDrop::new(dbkey);
+                    if let Err(mut e) =
+                        r::check_no_transaction(&context, args[1])
+                    {
+                        return e.reply(&context);
+                    }
                     let db = dbkey.loop_data.get_db();
-                    let result =
-                        do_execute(&db, args[2], &Vec::new());
-                    let t = std::time::Instant::now()
-                        + std::time::Duration::from_secs(10);
+                    let result = do_execute(
+                        &db,
+                        args[2],
+                        &Bindings::default(),
+                    );
+                    let t = default_deadline();
                     let mut result = match result {
                         Ok(r) => {
                             ReplicateVerbatim(&context);
@@ -232,10 +330,15 @@ pub extern "C" fn QueryNow(
                 ),
                 Ok(dbkey) => {
                     let dbkey = ManuallyDrop::new(dbkey);
+                    if let Err(mut e) =
+                        r::check_no_transaction(&context, args[1])
+                    {
+                        return e.reply(&context);
+                    }
                     let db = dbkey.loop_data.get_db();
-                    let result = do_query(&db, args[2], &Vec::new());
-                    let t = std::time::Instant::now()
-                        + std::time::Duration::from_secs(10);
+                    let result =
+                        do_query(&db, args[2], &Bindings::default());
+                    let t = default_deadline();
                     let mut result = match result {
                         Ok(r) => r.create_data_to_return(
                             &context,
@@ -284,12 +387,17 @@ pub extern "C" fn QueryNowInto(
                 ),
                 Ok(dbkey) => {
                     let dbkey = ManuallyDrop::new(dbkey);
+                    if let Err(mut e) =
+                        r::check_no_transaction(&context, args[2])
+                    {
+                        return e.reply(&context);
+                    }
                     let db = dbkey.loop_data.get_db();
-                    let result = do_query(&db, args[3], &Vec::new());
+                    let result =
+                        do_query(&db, args[3], &Bindings::default());
                     let return_method =
                         ReturnMethod::Stream { name: args[1] };
-                    let t = std::time::Instant::now()
-                        + std::time::Duration::from_secs(10);
+                    let t = default_deadline();
                     let mut result = match result {
                         Ok(r) => r.create_data_to_return(
                             &context,
@@ -316,12 +424,13 @@ pub extern "C" fn ExecStatementNow(
     argc: ::std::os::raw::c_int,
 ) -> i32 {
     let context = r::rm::Context::new(ctx);
-    let argvector = match r::create_argument(argv, argc) {
-        Ok(argvector) => argvector,
-        Err(mut error) => {
-            return error.reply(&context);
-        }
-    };
+    let (argvector, values) =
+        match r::create_statement_argument(argv, argc, 3) {
+            Ok(args) => args,
+            Err(mut error) => {
+                return error.reply(&context);
+            }
+        };
 
     match argvector.len() {
         0..=2 => {
@@ -338,17 +447,22 @@ pub extern "C" fn ExecStatementNow(
                 ),
                 Ok(dbkey) => {
                     let dbkey = ManuallyDrop::new(dbkey);
+                    if let Err(mut e) = r::check_no_transaction(
+                        &context,
+                        argvector[1],
+                    ) {
+                        return e.reply(&context);
+                    }
                     // _rc must be
                     // 1. Define befor the call to exec_statement() and .reply(&context)
                     // 2. Dropped before we forget the `dbkey`
-                    let t = std::time::Instant::now()
-                        + std::time::Duration::from_secs(10);
+                    let t = default_deadline();
                     let result = dbkey
                         .loop_data
                         .get_replication_book()
                         .exec_statement(
                             argvector[2],
-                            &argvector[3..],
+                            &Bindings::Positional(values),
                         );
                     match result {
                         Ok(res) => {
@@ -397,6 +511,11 @@ pub extern "C" fn CreateStatementNow(
                 ),
                 Ok(dbkey) => {
                     let dbkey = ManuallyDrop::new(dbkey);
+                    if let Err(mut e) =
+                        r::check_no_transaction(&context, args[1])
+                    {
+                        return e.reply(&context);
+                    }
                     let result = dbkey
                         .loop_data
                         .get_replication_book()
@@ -446,6 +565,11 @@ pub extern "C" fn UpdateStatementNow(
                 ),
                 Ok(dbkey) => {
                     let dbkey = ManuallyDrop::new(dbkey);
+                    if let Err(mut e) =
+                        r::check_no_transaction(&context, args[1])
+                    {
+                        return e.reply(&context);
+                    }
                     let result = dbkey
                         .loop_data
                         .get_replication_book()
@@ -493,6 +617,11 @@ pub extern "C" fn DeleteStatementNow(
                 ),
                 Ok(dbkey) => {
                     let dbkey = ManuallyDrop::new(dbkey);
+                    if let Err(mut e) =
+                        r::check_no_transaction(&context, args[1])
+                    {
+                        return e.reply(&context);
+                    }
                     let result = dbkey
                         .loop_data
                         .get_replication_book()
@@ -517,12 +646,13 @@ pub extern "C" fn QueryStatementNow(
     argc: ::std::os::raw::c_int,
 ) -> i32 {
     let context = r::rm::Context::new(ctx);
-    let argvector = match r::create_argument(argv, argc) {
-        Ok(argvector) => argvector,
-        Err(mut error) => {
-            return error.reply(&context);
-        }
-    };
+    let (argvector, values) =
+        match r::create_statement_argument(argv, argc, 3) {
+            Ok(args) => args,
+            Err(mut error) => {
+                return error.reply(&context);
+            }
+        };
 
     match argvector.len() {
         0..=2 => {
@@ -539,14 +669,19 @@ pub extern "C" fn QueryStatementNow(
                 ),
                 Ok(dbkey) => {
                     let dbkey = ManuallyDrop::new(dbkey);
-                    let t = std::time::Instant::now()
-                        + std::time::Duration::from_secs(10);
+                    if let Err(mut e) = r::check_no_transaction(
+                        &context,
+                        argvector[1],
+                    ) {
+                        return e.reply(&context);
+                    }
+                    let t = default_deadline();
                     let result = dbkey
                         .loop_data
                         .get_replication_book()
                         .query_statement(
                             argvector[2],
-                            &argvector[3..],
+                            &Bindings::Positional(values),
                         );
                     match result {
                         Ok(res) => {
@@ -595,12 +730,26 @@ pub extern "C" fn QueryStatementNowInto(
                 ),
                 Ok(dbkey) => {
                     let dbkey = ManuallyDrop::new(dbkey);
+                    if let Err(mut e) =
+                        r::check_no_transaction(&context, args[2])
+                    {
+                        return e.reply(&context);
+                    }
                     let result = dbkey
                         .loop_data
                         .get_replication_book()
-                        .query_statement(args[3], &args[4..]);
-                    let t = std::time::Instant::now()
-                        + std::time::Duration::from_secs(10);
+                        .query_statement(
+                            args[3],
+                            &Bindings::Positional(
+                                args[4..]
+                                    .iter()
+                                    .map(|arg| Argument::Text {
+                                        text: arg,
+                                    })
+                                    .collect::<Vec<_>>(),
+                            ),
+                        );
+                    let t = default_deadline();
                     match result {
                         Ok(result) => {
                             let mut to_return = result
@@ -677,11 +826,21 @@ pub extern "C" fn MakeCopyNow(
 
             let dest_db = dest_db.unwrap();
             let dest_db = ManuallyDrop::new(dest_db);
+            if let Err(mut e) =
+                r::check_no_transaction(&context, argvector[1])
+                    .and_then(|_| {
+                        r::check_no_transaction(
+                            &context,
+                            argvector[2],
+                        )
+                    })
+            {
+                return e.reply(&context);
+            }
 
             let dest_loopdata = &dest_db.loop_data;
             let source_loopdata = &source_db.loop_data;
-            let t = std::time::Instant::now()
-                + std::time::Duration::from_secs(10);
+            let t = default_deadline();
             let mut result = match r::do_copy(
                 &source_loopdata.get_db(),
                 dest_loopdata,
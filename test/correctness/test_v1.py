@@ -252,6 +252,17 @@ class TestStatements(TestRediSQLWithExec):
         result = self.exec_cmd("A", "SELECT * FROM t1 ORDER BY A;")
         self.assertEqual(result, [[3], [4]])
 
+  def test_statement_binary_argument(self):
+    with DB(self, "A"):
+      with Table(self, "t1", "(A BLOB)", key = "A"):
+        ok = self.exec_naked("REDISQL.V1.CREATE_STATEMENT", "A", "insert", "insert into t1 values(?1);")
+        self.assertEqual(ok, 'OK')
+        payload = '\x08\x96\x01\x00\xff\xfe'
+        done = self.exec_naked("REDISQL.V1.EXEC_STATEMENT", "A", "insert", payload)
+        self.assertEqual(done, ['DONE', 1])
+        result = self.exec_cmd("A", "SELECT A, typeof(A), length(A) FROM t1;")
+        self.assertEqual(result, [[payload, 'blob', 6]])
+
   def test_multi_statement_single_bind(self):
     with DB(self, "A"):
       with Table(self, "t1", "(A INTEGER)", key = "A"):
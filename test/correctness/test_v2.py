@@ -1,9 +1,11 @@
 #!/usr/bin/python -tt
 # -*- coding: utf-8 -*-
 
+import json
 import os
 import tempfile
 import shutil
+import threading
 import time
 
 import redis
@@ -623,10 +625,12 @@ class TestStatementsSynchronous(TestRediSQLWithExec):
 
 class TestStatementsIntrospection(TestRediSQLWithExec):
   def compare_results(self, a, b):
+    # the columns added after the expected ones are not compared
+    n = len(b[1])
     self.assertEquals(a[0], b[0]) # 'RESULT'
-    self.assertEquals(a[1], b[1]) # names
-    self.assertEquals(a[2], b[2]) # types
-    a_rows = set([tuple(x) for x in a[3:]])
+    self.assertEquals(a[1][:n], b[1]) # names
+    self.assertEquals(a[2][:n], b[2]) # types
+    a_rows = set([tuple(x[:n]) for x in a[3:]])
     b_rows = set([tuple(x) for x in b[3:]])
     self.assertEquals(a_rows, b_rows)
 
@@ -719,6 +723,1347 @@ class TestExecWithArguments(TestRediSQLWithExec):
                     "SELECT * FROM foo WHERE a >= ?1 ORDER BY a ASC", "NO_HEADER", "ARGS", 4)
             self.assertEqual(done, [['RESULT'], [4, 400], [5, 105]])
 
+class TestBinaryBlob(TestRediSQLWithExec):
+    def test_blob_with_null_bytes(self):
+        with DB(self, "D"):
+            result = self.exec_naked("REDISQL.V2.EXEC", "D", "COMMAND",
+                    "SELECT X'610062FF63';", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], ['a\x00b\xffc']])
+
+    def test_blob_from_binary_args(self):
+        with DB(self, "D"):
+            done = self.exec_query("D", "CREATE TABLE foo(a BLOB);")
+            self.assertEqual(done, [['DONE'], [0]])
+            payload = '\x08\x96\x01\x00\xff\xfe'
+            done = self.exec_naked("REDISQL.V2.EXEC", "D", "COMMAND",
+                    "INSERT INTO foo VALUES(?1)", "ARGS", payload)
+            self.assertEqual(done, [['DONE'], [1]])
+            result = self.exec_naked("REDISQL.V2.EXEC", "D", "COMMAND",
+                    "SELECT a, typeof(a), length(a) FROM foo;", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], [payload, 'blob', 6]])
+
+    def test_blob_statement_and_stream(self):
+        with DB(self, "D"):
+            done = self.exec_query("D", "CREATE TABLE foo(a BLOB);")
+            self.assertEqual(done, [['DONE'], [0]])
+            ok = self.exec_naked("REDISQL.V2.STATEMENT", "D", "NEW", "insert", "INSERT INTO foo VALUES(?1);")
+            self.assertEqual(ok, [['OK']])
+            payload = '\x00\x01\x02\xc3\x28'
+            done = self.exec_naked("REDISQL.V2.EXEC", "D", "STATEMENT", "insert", "ARGS", payload)
+            self.assertEqual(done, [['DONE'], [1]])
+            self.exec_naked("REDISQL.V2.EXEC", "D", "COMMAND",
+                    "SELECT a FROM foo;", "READ_ONLY", "INTO", "{D}:blob")
+            stream = self.exec_naked("XRANGE", "{D}:blob", "-", "+")
+            self.assertEqual(len(stream), 1)
+            self.assertEqual(stream[0][1], ['blob:a', payload])
+
+class TestTypedArguments(TestRediSQLWithExec):
+    def test_prefixed_args(self):
+        with DB(self, "E"):
+            result = self.exec_naked("REDISQL.V2.EXEC", "E", "COMMAND",
+                    "SELECT typeof(?1), typeof(?2), typeof(?3), typeof(?4), typeof(?5), typeof(?6);",
+                    "NO_HEADER", "TYPED_ARGS", "ARGS", "INT:42", "REAL:1.5", "TEXT:42", "BLOB:abc", "NULL", "42")
+            self.assertEqual(result, [['RESULT'], ['integer', 'real', 'text', 'blob', 'null', 'text']])
+
+    def test_plain_args_are_not_typed(self):
+        with DB(self, "E"):
+            result = self.exec_naked("REDISQL.V2.EXEC", "E", "COMMAND",
+                    "SELECT typeof(?1), ?1, typeof(?2), ?2;",
+                    "NO_HEADER", "ARGS", "NULL", "TEXT:foo")
+            self.assertEqual(result, [['RESULT'], ['text', 'NULL', 'text', 'TEXT:foo']])
+
+    def test_typed_comparison_on_untyped_column(self):
+        with DB(self, "E"):
+            done = self.exec_query("E", "CREATE TABLE foo(a);")
+            self.assertEqual(done, [['DONE'], [0]])
+            done = self.exec_naked("REDISQL.V2.EXEC", "E", "COMMAND",
+                    "INSERT INTO foo VALUES(?1), (?2)", "TYPED_ARGS", "ARGS", "INT:10", "INT:9")
+            self.assertEqual(done, [['DONE'], [2]])
+            result = self.exec_naked("REDISQL.V2.EXEC", "E", "COMMAND",
+                    "SELECT a FROM foo WHERE a > ?1 ORDER BY a;", "NO_HEADER", "TYPED_ARGS", "ARGS", "INT:9")
+            self.assertEqual(result, [['RESULT'], [10]])
+
+    def test_types_list(self):
+        with DB(self, "E"):
+            result = self.exec_naked("REDISQL.V2.EXEC", "E", "COMMAND",
+                    "SELECT typeof(?1), typeof(?2), typeof(?3), ?3;", "NO_HEADER",
+                    "TYPES", "INT", "NULL", "TEXT", "ARGS", "3", "whatever", "INT:3")
+            self.assertEqual(result, [['RESULT'], ['integer', 'null', 'text', 'INT:3']])
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "E", "COMMAND",
+                        "SELECT ?1, ?2;", "TYPES", "INT", "ARGS", "1", "2")
+
+    def test_invalid_typed_args(self):
+        with DB(self, "E"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "E", "COMMAND",
+                        "SELECT ?1;", "TYPED_ARGS", "ARGS", "INT:foo")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "E", "COMMAND",
+                        "SELECT ?1;", "TYPED_ARGS", "ARGS", "REAL:bar")
+
+    def test_typed_statement(self):
+        with DB(self, "E"):
+            done = self.exec_query("E", "CREATE TABLE foo(a, b);")
+            self.assertEqual(done, [['DONE'], [0]])
+            ok = self.exec_naked("REDISQL.V2.STATEMENT", "E", "NEW", "insert", "INSERT INTO foo VALUES(?1, ?2);")
+            self.assertEqual(ok, [['OK']])
+            done = self.exec_naked("REDISQL.V2.EXEC", "E", "STATEMENT", "insert", "TYPED_ARGS", "ARGS", "REAL:2.5", "NULL")
+            self.assertEqual(done, [['DONE'], [1]])
+            result = self.exec_naked("REDISQL.V2.EXEC", "E", "COMMAND",
+                    "SELECT typeof(a), typeof(b) FROM foo;", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], ['real', 'null']])
+
+class TestNamedArguments(TestRediSQLWithExec):
+    def test_named_args_command(self):
+        with DB(self, "F"):
+            done = self.exec_query("F", "CREATE TABLE foo(a, b);")
+            self.assertEqual(done, [['DONE'], [0]])
+            done = self.exec_naked("REDISQL.V2.EXEC", "F", "COMMAND",
+                    "INSERT INTO foo VALUES(:a, @b)", "TYPED_ARGS", "NAMED_ARGS", "b", "INT:2", ":a", "1")
+            self.assertEqual(done, [['DONE'], [1]])
+            result = self.exec_naked("REDISQL.V2.EXEC", "F", "COMMAND",
+                    "SELECT a, b FROM foo;", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], ['1', 2]])
+
+    def test_named_statement(self):
+        with DB(self, "F"):
+            done = self.exec_query("F", "CREATE TABLE foo(a, b);")
+            self.assertEqual(done, [['DONE'], [0]])
+            ok = self.exec_naked("REDISQL.V2.STATEMENT", "F", "NEW", "insert",
+                    "INSERT INTO foo VALUES($second, :first); SELECT :first;")
+            self.assertEqual(ok, [['OK']])
+            result = self.exec_naked("REDISQL.V2.STATEMENT", "F", "SHOW", "insert")
+            self.assertEqual(result[1], ["identifier", 'SQL', 'parameters_count', 'read_only', 'parameters_names'])
+            self.assertEqual(result[2], ['TEXT', 'TEXT', 'INT', 'INT', 'TEXT'])
+            self.assertEqual(result[3][2], 2)
+            self.assertEqual(result[3][4], '$second,:first')
+            done = self.exec_naked("REDISQL.V2.EXEC", "F", "STATEMENT", "insert",
+                    "TYPED_ARGS", "NAMED_ARGS", "first", "INT:1", "second", "INT:2")
+            self.assertEqual(done, [['DONE'], [1]])
+            done = self.exec_naked("REDISQL.V2.EXEC", "F", "STATEMENT", "insert",
+                    "TYPED_ARGS", "ARGS", "INT:3", "INT:4")
+            self.assertEqual(done, [['DONE'], [1]])
+            result = self.exec_naked("REDISQL.V2.EXEC", "F", "COMMAND",
+                    "SELECT a, b FROM foo ORDER BY a;", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], [2, 1], [3, 4]])
+
+    def test_named_args_errors(self):
+        with DB(self, "F"):
+            ok = self.exec_naked("REDISQL.V2.STATEMENT", "F", "NEW", "select", "SELECT :a, :b;")
+            self.assertEqual(ok, [['OK']])
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "F", "STATEMENT", "select",
+                        "NAMED_ARGS", "a", "1", "b")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "F", "STATEMENT", "select",
+                        "NAMED_ARGS", "a", "1", "c", "2")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "F", "STATEMENT", "select",
+                        "NAMED_ARGS", "a", "1")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.STATEMENT", "F", "NEW", "mixed", "SELECT ?1, :a;")
+
+class TestTransactions(TestRediSQLWithExec):
+    def begin(self, db, *options):
+        result = self.exec_naked("REDISQL.V2.TX", db, "BEGIN", *options)
+        self.assertEqual(result[0], ['RESULT'])
+        return result[1][0]
+
+    def test_commit(self):
+        with DB(self, "G"):
+            done = self.exec_query("G", "CREATE TABLE foo(a INT);")
+            self.assertEqual(done, [['DONE'], [0]])
+            token = self.begin("G")
+            done = self.exec_naked("REDISQL.V2.EXEC", "G", "TX", token, "COMMAND",
+                    "INSERT INTO foo VALUES(?1)", "TYPED_ARGS", "ARGS", "INT:1")
+            self.assertEqual(done, [['DONE'], [1]])
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("G", "INSERT INTO foo VALUES(2);")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.TX", "G", "BEGIN")
+            ok = self.exec_naked("REDISQL.V2.TX", "G", "COMMIT", token)
+            self.assertEqual(ok, [['OK']])
+            result = self.exec_query("G", "SELECT a FROM foo;", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], [1]])
+
+    def test_rollback(self):
+        with DB(self, "G"):
+            done = self.exec_query("G", "CREATE TABLE foo(a INT);")
+            self.assertEqual(done, [['DONE'], [0]])
+            token = self.begin("G")
+            done = self.exec_naked("REDISQL.V2.EXEC", "G", "TX", token, "COMMAND",
+                    "INSERT INTO foo VALUES(1)")
+            self.assertEqual(done, [['DONE'], [1]])
+            ok = self.exec_naked("REDISQL.V2.TX", "G", "ROLLBACK", token)
+            self.assertEqual(ok, [['OK']])
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.TX", "G", "COMMIT", token)
+            result = self.exec_query("G", "SELECT count(*) FROM foo;", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], [0]])
+
+    def test_wrong_token(self):
+        with DB(self, "G"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "G", "TX", "not-a-token", "COMMAND", "SELECT 1;")
+            token = self.begin("G")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.TX", "G", "COMMIT", "not-a-token")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "G", "TX", token, "NOW", "COMMAND", "SELECT 1;")
+            ok = self.exec_naked("REDISQL.V2.TX", "G", "COMMIT", token)
+            self.assertEqual(ok, [['OK']])
+
+    def test_idle_timeout(self):
+        with DB(self, "G"):
+            done = self.exec_query("G", "CREATE TABLE foo(a INT);")
+            self.assertEqual(done, [['DONE'], [0]])
+            token = self.begin("G", "IDLE_TIMEOUT", "100")
+            done = self.exec_naked("REDISQL.V2.EXEC", "G", "TX", token, "COMMAND",
+                    "INSERT INTO foo VALUES(1)")
+            self.assertEqual(done, [['DONE'], [1]])
+            time.sleep(0.5)
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "G", "TX", token, "COMMAND",
+                        "INSERT INTO foo VALUES(2)")
+            result = self.exec_query("G", "SELECT count(*) FROM foo;", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], [0]])
+
+    def test_save_during_a_transaction(self):
+        with DB(self, "G"):
+            done = self.exec_query("G", "CREATE TABLE foo(a INT);")
+            self.assertEqual(done, [['DONE'], [0]])
+            token = self.begin("G")
+            done = self.exec_naked("REDISQL.V2.EXEC", "G", "TX", token, "COMMAND",
+                    "INSERT INTO foo VALUES(1)")
+            self.assertEqual(done, [['DONE'], [1]])
+            self.client.execute_command("SAVE")
+            directory = self.client.config_get("dir")["dir"]
+            snapshots = [f for f in os.listdir(directory) if f.startswith("redisql-snapshot-")]
+            self.assertEqual(len(snapshots), 1)
+            ok = self.exec_naked("REDISQL.V2.TX", "G", "COMMIT", token)
+            self.assertEqual(ok, [['OK']])
+            snapshots = [f for f in os.listdir(directory) if f.startswith("redisql-snapshot-")]
+            self.assertEqual(snapshots, [])
+
+    def test_v1_commands_wait_for_the_transaction(self):
+        with DB(self, "G"):
+            done = self.exec_query("G", "CREATE TABLE foo(a INT);")
+            self.assertEqual(done, [['DONE'], [0]])
+            token = self.begin("G")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V1.EXEC.NOW", "G", "INSERT INTO foo VALUES(1);")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V1.EXEC", "G", "INSERT INTO foo VALUES(1);")
+            ok = self.exec_naked("REDISQL.V2.TX", "G", "ROLLBACK", token)
+            self.assertEqual(ok, [['OK']])
+            done = self.exec_naked("REDISQL.V1.EXEC.NOW", "G", "INSERT INTO foo VALUES(1);")
+            self.assertEqual(done, ['DONE', 1])
+
+class TestBatch(TestRediSQLWithExec):
+    def test_batch(self):
+        with DB(self, "H"):
+            done = self.exec_query("H", "CREATE TABLE foo(a INT, b TEXT);")
+            self.assertEqual(done, [['DONE'], [0]])
+            ok = self.exec_naked("REDISQL.V2.STATEMENT", "H", "NEW", "insert", "INSERT INTO foo VALUES(?1, ?2);")
+            self.assertEqual(ok, [['OK']])
+            done = self.exec_naked("REDISQL.V2.EXEC", "H", "STATEMENT", "insert",
+                    "TYPES", "INT", "TEXT", "BATCH", "2", "1", "a", "2", "b", "3", "c")
+            self.assertEqual(done, [['DONE'], [3]])
+            result = self.exec_naked("REDISQL.V2.EXEC", "H", "COMMAND",
+                    "SELECT a, b FROM foo ORDER BY a;", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], [1, 'a'], [2, 'b'], [3, 'c']])
+
+    def test_batch_rolled_back_on_error(self):
+        with DB(self, "H"):
+            done = self.exec_query("H", "CREATE TABLE foo(a INT UNIQUE);")
+            self.assertEqual(done, [['DONE'], [0]])
+            ok = self.exec_naked("REDISQL.V2.STATEMENT", "H", "NEW", "insert", "INSERT INTO foo VALUES(?1);")
+            self.assertEqual(ok, [['OK']])
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "H", "STATEMENT", "insert",
+                        "TYPED_ARGS", "BATCH", "1", "INT:1", "INT:2", "INT:1")
+            result = self.exec_naked("REDISQL.V2.EXEC", "H", "COMMAND",
+                    "SELECT count(*) FROM foo;", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], [0]])
+
+    def test_batch_with_errors(self):
+        with DB(self, "H"):
+            done = self.exec_query("H", "CREATE TABLE foo(a INT UNIQUE);")
+            self.assertEqual(done, [['DONE'], [0]])
+            ok = self.exec_naked("REDISQL.V2.STATEMENT", "H", "NEW", "insert", "INSERT INTO foo VALUES(?1);")
+            self.assertEqual(ok, [['OK']])
+            result = self.exec_naked("REDISQL.V2.EXEC", "H", "STATEMENT", "insert",
+                    "WITH_ERRORS", "NO_HEADER", "TYPED_ARGS", "BATCH", "1", "INT:1", "INT:2", "INT:1")
+            self.assertEqual(result[0:2], [['RESULT'], [None, 2, None]])
+            self.assertEqual(result[2][0:2], [2, 0])
+            result = self.exec_naked("REDISQL.V2.EXEC", "H", "COMMAND",
+                    "SELECT count(*) FROM foo;", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], [2]])
+
+    def test_batch_errors(self):
+        with DB(self, "H"):
+            ok = self.exec_naked("REDISQL.V2.STATEMENT", "H", "NEW", "select", "SELECT ?1, ?2;")
+            self.assertEqual(ok, [['OK']])
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "H", "STATEMENT", "select",
+                        "BATCH", "2", "1", "2", "3")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "H", "STATEMENT", "select",
+                        "BATCH", "0", "1", "2")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "H", "COMMAND", "SELECT 1;",
+                        "BATCH", "1", "1")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.QUERY", "H", "STATEMENT", "select",
+                        "BATCH", "2", "1", "2")
+
+class TestCursors(TestRediSQLWithExec):
+    def test_cursor_pages(self):
+        with DB(self, "I"):
+            done = self.exec_query("I", "CREATE TABLE foo(a INT);")
+            self.assertEqual(done, [['DONE'], [0]])
+            for i in range(5):
+                self.exec_query("I", "INSERT INTO foo VALUES({});".format(i))
+            page = self.exec_naked("REDISQL.V2.QUERY", "I", "CURSOR", "2",
+                    "COMMAND", "SELECT a FROM foo ORDER BY a;")
+            self.assertEqual(page[0][0], 'CURSOR')
+            cursor = page[0][1]
+            self.assertNotEqual(cursor, 0)
+            self.assertEqual(page[1:], [['a'], ['INT'], [0], [1]])
+            page = self.exec_naked("REDISQL.V2.CURSOR.NEXT", "I", cursor, "NO_HEADER")
+            self.assertEqual(page, [['CURSOR', cursor], [2], [3]])
+            page = self.exec_naked("REDISQL.V2.CURSOR.NEXT", "I", cursor, "COUNT", "10")
+            self.assertEqual(page, [['CURSOR', 0], ['a'], ['INT'], [4]])
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.CURSOR.NEXT", "I", cursor)
+
+    def test_cursor_statement(self):
+        with DB(self, "I"):
+            done = self.exec_query("I", "CREATE TABLE foo(a INT);")
+            self.assertEqual(done, [['DONE'], [0]])
+            for i in range(3):
+                self.exec_query("I", "INSERT INTO foo VALUES({});".format(i))
+            ok = self.exec_naked("REDISQL.V2.STATEMENT", "I", "NEW", "select",
+                    "SELECT a FROM foo WHERE a >= ?1 ORDER BY a;")
+            self.assertEqual(ok, [['OK']])
+            page = self.exec_naked("REDISQL.V2.QUERY", "I", "STATEMENT", "select",
+                    "NO_HEADER", "CURSOR", "1", "TYPED_ARGS", "ARGS", "INT:1")
+            cursor = page[0][1]
+            self.assertEqual(page[1:], [[1]])
+            # executing the statement does not reset the cursor
+            result = self.exec_naked("REDISQL.V2.QUERY", "I", "STATEMENT", "select",
+                    "NO_HEADER", "TYPED_ARGS", "ARGS", "INT:0")
+            self.assertEqual(result, [['RESULT'], [0], [1], [2]])
+            page = self.exec_naked("REDISQL.V2.CURSOR.NEXT", "I", cursor, "NO_HEADER")
+            self.assertEqual(page, [['CURSOR', 0], [2]])
+
+    def test_cursor_close(self):
+        with DB(self, "I"):
+            page = self.exec_naked("REDISQL.V2.QUERY", "I", "CURSOR", "1",
+                    "COMMAND", "SELECT 1 UNION ALL SELECT 2;")
+            cursor = page[0][1]
+            ok = self.exec_naked("REDISQL.V2.CURSOR.CLOSE", "I", cursor)
+            self.assertEqual(ok, [['OK']])
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.CURSOR.CLOSE", "I", cursor)
+
+    def test_cursor_of_another_client(self):
+        with DB(self, "I"):
+            page = self.exec_naked("REDISQL.V2.QUERY", "I", "CURSOR", "1",
+                    "COMMAND", "SELECT 1 UNION ALL SELECT 2;")
+            cursor = page[0][1]
+            other = redis.Redis(**self.client.connection_pool.connection_kwargs)
+            with self.assertRaises(redis.exceptions.ResponseError):
+                other.execute_command("REDISQL.V2.CURSOR.NEXT", "I", cursor)
+            with self.assertRaises(redis.exceptions.ResponseError):
+                other.execute_command("REDISQL.V2.CURSOR.CLOSE", "I", cursor)
+            ok = self.exec_naked("REDISQL.V2.CURSOR.CLOSE", "I", cursor)
+            self.assertEqual(ok, [['OK']])
+
+    def test_cursor_errors(self):
+        with DB(self, "I"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.QUERY", "I", "CURSOR", "0",
+                        "COMMAND", "SELECT 1;")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.QUERY", "I", "CURSOR", "1", "NOW",
+                        "COMMAND", "SELECT 1;")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "I", "CURSOR", "1",
+                        "COMMAND", "CREATE TABLE foo(a);")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.CURSOR.NEXT", "I", "not_a_cursor")
+
+class TestTimeout(TestRediSQLWithExec):
+    def test_long_query_is_interrupted(self):
+        with DB(self, "J"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.QUERY", "J", "TIMEOUT", "100", "COMMAND",
+                        "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT count(*) FROM c;")
+            result = self.exec_naked("REDISQL.V2.QUERY", "J", "NO_HEADER", "COMMAND", "SELECT 1;")
+            self.assertEqual(result, [['RESULT'], [1]])
+
+    def test_long_query_is_interrupted_now(self):
+        with DB(self, "J"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.QUERY", "J", "NOW", "TIMEOUT", "100", "COMMAND",
+                        "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT count(*) FROM c;")
+
+    def test_invalid_timeout(self):
+        with DB(self, "J"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.QUERY", "J", "TIMEOUT", "0", "COMMAND", "SELECT 1;")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.STATEMENT", "J", "LIST", "TIMEOUT", "soon")
+
+class TestConfig(TestRediSQLWithExec):
+    def test_get_default_timeout(self):
+        result = self.exec_naked("REDISQL.CONFIG", "GET", "default_timeout")
+        self.assertEqual(result, [['RESULT'], ['option', 'value'], ['TEXT', 'TEXT'], ['DEFAULT_TIMEOUT', '10000']])
+
+    def test_get_all(self):
+        result = self.exec_naked("REDISQL.V2.CONFIG", "GET", "*")
+        options = [row[0] for row in result[3:]]
+        self.assertEqual(options, ['LOG_LEVEL', 'DEFAULT_TIMEOUT', 'DATA_DIR', 'MAX_DATABASES', 'TELEMETRY', 'AOF_REWRITE', 'EXTENSIONS_DIR', 'CURSOR_IDLE_TIMEOUT', 'MAX_OPEN_CURSORS', 'MAX_LOAD_SIZE'])
+
+    def test_wrong_usage(self):
+        with self.assertRaises(redis.exceptions.ResponseError):
+            self.exec_naked("REDISQL.CONFIG", "SET", "LOG_LEVEL", "warn")
+
+class TestReaders(TestRediSQLWithExec):
+    def test_queries_on_readers(self):
+        self.exec_naked("REDISQL.V2.CREATE_DB", "K", "READERS", "2")
+        try:
+            self.exec_query("K", "CREATE TABLE t(a INT);")
+            self.exec_query("K", "INSERT INTO t VALUES(1), (2);")
+            for _ in range(4):
+                result = self.exec_naked("REDISQL.V2.QUERY", "K", "NO_HEADER", "COMMAND", "SELECT count(*) FROM t;")
+                self.assertEqual(result, [['RESULT'], [2]])
+        finally:
+            self.exec_naked("DEL", "K")
+
+    def test_statements_on_readers(self):
+        self.exec_naked("REDISQL.V2.CREATE_DB", "K", "READERS", "2")
+        try:
+            self.exec_query("K", "CREATE TABLE t(a INT);")
+            self.exec_query("K", "INSERT INTO t VALUES(1), (2);")
+            self.exec_naked("REDISQL.V2.STATEMENT", "K", "NEW", "s", "SELECT count(*) FROM t;")
+            for _ in range(2):
+                result = self.exec_naked("REDISQL.V2.QUERY", "K", "NO_HEADER", "STATEMENT", "s")
+                self.assertEqual(result, [['RESULT'], [2]])
+            self.exec_naked("REDISQL.V2.STATEMENT", "K", "UPDATE", "s", "SELECT sum(a) FROM t;")
+            for _ in range(2):
+                result = self.exec_naked("REDISQL.V2.QUERY", "K", "NO_HEADER", "STATEMENT", "s")
+                self.assertEqual(result, [['RESULT'], [3]])
+        finally:
+            self.exec_naked("DEL", "K")
+
+    def test_read_during_a_write(self):
+        self.exec_naked("REDISQL.V2.CREATE_DB", "K", "READERS", "1")
+        try:
+            self.exec_query("K", "CREATE TABLE t(a INT);")
+            self.exec_query("K", "INSERT INTO t VALUES(1), (2);")
+            writer = redis.Redis(**self.client.connection_pool.connection_kwargs)
+            write = threading.Thread(target=writer.execute_command, args=(
+                "REDISQL.V2.EXEC", "K", "COMMAND",
+                "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c LIMIT 2000000) INSERT INTO t SELECT x FROM c;"))
+            write.start()
+            time.sleep(0.1)
+            result = self.exec_naked("REDISQL.V2.QUERY", "K", "NO_HEADER", "COMMAND", "SELECT count(*) FROM t;")
+            # the query never sees the insert half done
+            self.assertIn(result, [[['RESULT'], [2]], [['RESULT'], [2000002]]])
+            write.join()
+            result = self.exec_naked("REDISQL.V2.QUERY", "K", "NO_HEADER", "COMMAND", "SELECT count(*) FROM t;")
+            self.assertEqual(result, [['RESULT'], [2000002]])
+        finally:
+            self.exec_naked("DEL", "K")
+
+    def test_invalid_readers(self):
+        with self.assertRaises(redis.exceptions.ResponseError):
+            self.exec_naked("REDISQL.V2.CREATE_DB", "K", "READERS", "many")
+
+class TestChangeCapture(TestRediSQLWithExec):
+    def test_changes_are_published(self):
+        self.exec_naked("REDISQL.V2.CREATE_DB", "L", "CDC_STREAM", "{L}:changes")
+        try:
+            self.exec_query("L", "CREATE TABLE t(a INT, b TEXT);")
+            self.exec_query("L", "INSERT INTO t VALUES(1, 'foo');")
+            self.exec_query("L", "UPDATE t SET b = 'bar' WHERE a = 1;")
+            self.exec_query("L", "DELETE FROM t;")
+            entries = self.exec_naked("XRANGE", "{L}:changes", "-", "+")
+            self.assertEqual(len(entries), 3)
+            self.assertEqual(entries[0][1], ['op', 'insert', 'table', 't', 'rowid', '1', 'int:a', '1', 'text:b', 'foo'])
+            self.assertEqual(entries[1][1][:2], ['op', 'update'])
+            self.assertEqual(entries[2][1], ['op', 'delete', 'table', 't', 'rowid', '1', 'int:a', '1', 'text:b', 'bar'])
+        finally:
+            self.exec_naked("DEL", "L", "{L}:changes")
+
+    def test_rolled_back_changes_are_not_published(self):
+        self.exec_naked("REDISQL.V2.CREATE_DB", "L", "CDC_STREAM", "{L}:changes")
+        try:
+            self.exec_query("L", "CREATE TABLE t(a INT UNIQUE);")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("L", "INSERT INTO t VALUES(1), (1);")
+            self.assertEqual(self.exec_naked("XLEN", "{L}:changes"), 0)
+        finally:
+            self.exec_naked("DEL", "L", "{L}:changes")
+
+    def test_failed_statement_in_a_transaction_is_not_published(self):
+        self.exec_naked("REDISQL.V2.CREATE_DB", "L", "CDC_STREAM", "{L}:changes")
+        try:
+            self.exec_query("L", "CREATE TABLE t(a INT UNIQUE);")
+            token = self.exec_naked("REDISQL.V2.TX", "L", "BEGIN")[1][0]
+            self.exec_naked("REDISQL.V2.EXEC", "L", "TX", token, "COMMAND", "INSERT INTO t VALUES(1);")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXEC", "L", "TX", token, "COMMAND", "INSERT INTO t VALUES(2), (1);")
+            self.exec_naked("REDISQL.V2.TX", "L", "COMMIT", token)
+            entries = self.exec_naked("XRANGE", "{L}:changes", "-", "+")
+            self.assertEqual([e[1][:6] for e in entries], [['op', 'insert', 'table', 't', 'rowid', '1']])
+        finally:
+            self.exec_naked("DEL", "L", "{L}:changes")
+
+    def test_rolled_back_savepoint_is_not_published(self):
+        self.exec_naked("REDISQL.V2.CREATE_DB", "L", "CDC_STREAM", "{L}:changes")
+        try:
+            self.exec_query("L", "CREATE TABLE t(a INT);")
+            self.exec_query("L", "SAVEPOINT a; INSERT INTO t VALUES(1); SAVEPOINT b; INSERT INTO t VALUES(2); ROLLBACK TO b; RELEASE a;")
+            entries = self.exec_naked("XRANGE", "{L}:changes", "-", "+")
+            self.assertEqual(len(entries), 1)
+            self.assertEqual(entries[0][1], ['op', 'insert', 'table', 't', 'rowid', '1', 'int:a', '1'])
+        finally:
+            self.exec_naked("DEL", "L", "{L}:changes")
+
+class TestStreamIngest(TestRediSQLWithExec):
+    def test_entries_are_inserted(self):
+        with DB(self, "{I}"):
+            self.exec_query("{I}", "CREATE TABLE events(a INT, b REAL, c TEXT, d);")
+            self.exec_naked("XADD", "{I}:events", "1-1", "int:a", "1", "real:b", "1.5", "text:c", "foo", "d", "bar")
+            self.exec_naked("XADD", "{I}:events", "1-2", "int:a", "2", "null:b", "(null)")
+            result = self.exec_naked("REDISQL.V2.STREAM.INGEST", "{I}", "{I}:events", "TABLE", "events", "NO_HEADER")
+            self.assertEqual(result, [["RESULT"], [2, "1-2"]])
+            result = self.exec_query("{I}", "SELECT a, typeof(b), c, d FROM events ORDER BY a;", "NO_HEADER")
+            self.assertEqual(result, [["RESULT"], [1, "real", "foo", "bar"], [2, "null", None, None]])
+            self.exec_naked("DEL", "{I}:events")
+
+    def test_from_count_and_id_column(self):
+        with DB(self, "{I}"):
+            self.exec_query("{I}", "CREATE TABLE events(id TEXT, a INT);")
+            for i in range(1, 6):
+                self.exec_naked("XADD", "{I}:events", "1-%d" % i, "int:a", str(i))
+            result = self.exec_naked("REDISQL.V2.STREAM.INGEST", "{I}", "{I}:events", "TABLE", "events", "ID_COLUMN", "id", "FROM", "1-2", "COUNT", "2", "NO_HEADER")
+            self.assertEqual(result, [["RESULT"], [2, "1-3"]])
+            result = self.exec_query("{I}", "SELECT id, a FROM events ORDER BY a;", "NO_HEADER")
+            self.assertEqual(result, [["RESULT"], ["1-2", 2], ["1-3", 3]])
+            self.exec_naked("DEL", "{I}:events")
+
+    def test_consumer_group(self):
+        with DB(self, "{I}"):
+            self.exec_query("{I}", "CREATE TABLE events(a INT);")
+            self.exec_naked("XADD", "{I}:events", "1-1", "int:a", "1")
+            self.exec_naked("XGROUP", "CREATE", "{I}:events", "sink", "0")
+            result = self.exec_naked("REDISQL.V2.STREAM.INGEST", "{I}", "{I}:events", "TABLE", "events", "GROUP", "sink", "db", "NO_HEADER")
+            self.assertEqual(result, [["RESULT"], [1, "1-1"]])
+            self.assertEqual(self.exec_naked("XPENDING", "{I}:events", "sink")[0], 0)
+            # entries already delivered to the group are not read again
+            result = self.exec_naked("REDISQL.V2.STREAM.INGEST", "{I}", "{I}:events", "TABLE", "events", "GROUP", "sink", "db", "NO_HEADER")
+            self.assertEqual(result, [["RESULT"], [0, None]])
+            result = self.exec_query("{I}", "SELECT count(*) FROM events;", "NO_HEADER")
+            self.assertEqual(result, [["RESULT"], [1]])
+            self.exec_naked("DEL", "{I}:events")
+
+    def test_failed_entries_are_delivered_again(self):
+        with DB(self, "{I}"):
+            self.exec_query("{I}", "CREATE TABLE events(a INT NOT NULL);")
+            self.exec_naked("XADD", "{I}:events", "1-1", "text:b", "1")
+            self.exec_naked("XGROUP", "CREATE", "{I}:events", "sink", "0")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.STREAM.INGEST", "{I}", "{I}:events", "TABLE", "events", "GROUP", "sink", "db")
+            self.assertEqual(self.exec_naked("XPENDING", "{I}:events", "sink")[0], 1)
+            self.exec_query("{I}", "CREATE TABLE fixed(a INT, b TEXT);")
+            self.exec_naked("XADD", "{I}:events", "1-2", "int:a", "2")
+            # the pending entry comes before the new one
+            result = self.exec_naked("REDISQL.V2.STREAM.INGEST", "{I}", "{I}:events", "TABLE", "fixed", "GROUP", "sink", "db", "NO_HEADER")
+            self.assertEqual(result, [["RESULT"], [1, "1-1"]])
+            result = self.exec_naked("REDISQL.V2.STREAM.INGEST", "{I}", "{I}:events", "TABLE", "fixed", "GROUP", "sink", "db", "NO_HEADER")
+            self.assertEqual(result, [["RESULT"], [1, "1-2"]])
+            self.assertEqual(self.exec_naked("XPENDING", "{I}:events", "sink")[0], 0)
+            self.exec_naked("DEL", "{I}:events")
+
+    def test_failed_ingestion_inserts_nothing(self):
+        with DB(self, "{I}"):
+            self.exec_query("{I}", "CREATE TABLE events(a INT UNIQUE);")
+            self.exec_naked("XADD", "{I}:events", "1-1", "int:a", "1")
+            self.exec_naked("XADD", "{I}:events", "1-2", "int:a", "1")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.STREAM.INGEST", "{I}", "{I}:events", "TABLE", "events")
+            result = self.exec_query("{I}", "SELECT count(*) FROM events;", "NO_HEADER")
+            self.assertEqual(result, [["RESULT"], [0]])
+            self.exec_naked("DEL", "{I}:events")
+
+    def test_wrong_typed_value(self):
+        with DB(self, "{I}"):
+            self.exec_query("{I}", "CREATE TABLE events(a INT);")
+            self.exec_naked("XADD", "{I}:events", "1-1", "int:a", "one")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.STREAM.INGEST", "{I}", "{I}:events", "TABLE", "events")
+            self.exec_naked("DEL", "{I}:events")
+
+class TestWriteInto(TestRediSQLWithExec):
+    def test_write_without_rows_into_stream(self):
+        with DB(self, "{W}"):
+            self.exec_query("{W}", "CREATE TABLE foo(a INT);")
+            done = self.exec_naked("REDISQL.V2.EXEC", "{W}", "COMMAND",
+                    "INSERT INTO foo VALUES(1);", "INTO", "{W}:rows")
+            self.assertEqual(done, [['DONE'], [1]])
+            self.assertEqual(self.exec_naked("EXISTS", "{W}:rows"), 0)
+
+    def test_returning_into_stream(self):
+        with DB(self, "{W}"):
+            self.exec_query("{W}", "CREATE TABLE foo(a INT, b TEXT);")
+            self.exec_naked("REDISQL.V2.EXEC", "{W}", "COMMAND",
+                    "INSERT INTO foo VALUES(1, 'x'), (2, 'y') RETURNING a, b;", "INTO", "{W}:rows")
+            entries = self.exec_naked("XRANGE", "{W}:rows", "-", "+")
+            self.assertEqual([e[1] for e in entries], [['int:a', '1', 'text:b', 'x'], ['int:a', '2', 'text:b', 'y']])
+            result = self.exec_query("{W}", "SELECT count(*) FROM foo;", "NO_HEADER")
+            self.assertEqual(result, [['RESULT'], [2]])
+            self.exec_naked("DEL", "{W}:rows")
+
+    def test_returning_into_stream_waits_for_commit(self):
+        with DB(self, "{W}"):
+            self.exec_query("{W}", "CREATE TABLE foo(a INT);")
+            token = self.exec_naked("REDISQL.V2.TX", "{W}", "BEGIN")[1][0]
+            result = self.exec_naked("REDISQL.V2.EXEC", "{W}", "TX", token, "COMMAND",
+                    "INSERT INTO foo VALUES(1) RETURNING a;", "INTO", "{W}:rows")
+            self.assertEqual(result, [['RESULT'], ['a'], ['INT'], [1]])
+            self.assertEqual(self.exec_naked("EXISTS", "{W}:rows"), 0)
+            self.exec_naked("REDISQL.V2.TX", "{W}", "COMMIT", token)
+            entries = self.exec_naked("XRANGE", "{W}:rows", "-", "+")
+            self.assertEqual([e[1] for e in entries], [['int:a', '1']])
+
+            token = self.exec_naked("REDISQL.V2.TX", "{W}", "BEGIN")[1][0]
+            self.exec_naked("REDISQL.V2.EXEC", "{W}", "TX", token, "COMMAND",
+                    "INSERT INTO foo VALUES(2) RETURNING a;", "INTO", "{W}:rows")
+            self.exec_naked("REDISQL.V2.TX", "{W}", "ROLLBACK", token)
+            self.assertEqual(self.exec_naked("XLEN", "{W}:rows"), 1)
+            self.exec_naked("DEL", "{W}:rows")
+
+class TestFormatMap(TestRediSQLWithExec):
+    def test_rows_as_maps(self):
+        with DB(self, "M"):
+            self.exec_query("M", "CREATE TABLE foo(a INT, b TEXT, c BOOLEAN, d REAL);")
+            self.exec_query("M", "INSERT INTO foo VALUES(1, 'x', 1, 1.5), (2, NULL, 0, NULL);")
+            result = self.exec_query("M", "SELECT * FROM foo ORDER BY a;", "FORMAT", "MAP")
+            # RESP2 clients receive each map as a flat array
+            self.assertEqual(result, [['a', 1, 'b', 'x', 'c', 1, 'd', '1.5'],
+                                      ['a', 2, 'b', None, 'c', 0, 'd', None]])
+            result = self.exec_naked("REDISQL.V2.QUERY", "M", "COMMAND", "SELECT a FROM foo WHERE a > 5;", "FORMAT", "MAP")
+            self.assertEqual(result, [['DONE'], [0]])
+
+    def test_writes_reply_as_usual(self):
+        with DB(self, "M"):
+            done = self.exec_query("M", "CREATE TABLE foo(a INT);", "FORMAT", "MAP")
+            self.assertEqual(done, [['DONE'], [0]])
+
+    def test_invalid_format(self):
+        with DB(self, "M"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("M", "SELECT 1;", "FORMAT", "TABLE")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("M", "SELECT 1;", "FORMAT", "MAP", "READ_ONLY", "INTO", "{M}:rows")
+
+class TestFormatJson(TestRediSQLWithExec):
+    def test_array_of_objects(self):
+        with DB(self, "J"):
+            self.exec_query("J", "CREATE TABLE foo(a INT, b TEXT, c BOOLEAN, d BLOB);")
+            self.exec_query("J", "INSERT INTO foo VALUES(1, 'x', 1, x'00ff'), (2, NULL, 0, NULL);")
+            result = self.exec_query("J", "SELECT * FROM foo ORDER BY a;", "FORMAT", "JSON")
+            self.assertEqual(json.loads(result),
+                             [{"a": 1, "b": "x", "c": True, "d": "AP8="},
+                              {"a": 2, "b": None, "c": False, "d": None}])
+
+    def test_keys_in_column_order(self):
+        with DB(self, "J"):
+            result = self.exec_query("J", "SELECT 1 AS z, 2 AS a, 3 AS m;", "FORMAT", "JSON")
+            self.assertEqual(result, '[{"z":1,"a":2,"m":3}]')
+
+    def test_table(self):
+        with DB(self, "J"):
+            self.exec_query("J", "CREATE TABLE foo(a INT, b REAL);")
+            self.exec_query("J", "INSERT INTO foo VALUES(1, 1.5);")
+            result = self.exec_query("J", "SELECT * FROM foo;", "FORMAT", "JSON_TABLE")
+            self.assertEqual(json.loads(result),
+                             {"columns": ["a", "b"], "types": ["INT", "FLOAT"], "rows": [[1, 1.5]]})
+
+    def test_bigint_as_string(self):
+        with DB(self, "J"):
+            result = self.exec_query("J", "SELECT 9007199254740993 AS big, 42 AS small;", "FORMAT", "JSON", "BIGINT_AS_STRING")
+            self.assertEqual(json.loads(result), [{"big": "9007199254740993", "small": 42}])
+            result = self.exec_query("J", "SELECT 9007199254740993 AS big;", "FORMAT", "JSON")
+            self.assertEqual(result, '[{"big":9007199254740993}]')
+
+    def test_statement(self):
+        with DB(self, "J"):
+            self.exec_query("J", "CREATE TABLE foo(a INT);")
+            self.exec_query("J", "INSERT INTO foo VALUES(1), (2);")
+            self.exec_naked("REDISQL.V2.STATEMENT", "J", "NEW", "select_foo", "SELECT a FROM foo WHERE a > ?1 ORDER BY a;")
+            result = self.exec_naked("REDISQL.V2.EXEC", "J", "STATEMENT", "select_foo", "FORMAT", "JSON", "ARGS", "0")
+            self.assertEqual(json.loads(result), [{"a": 1}, {"a": 2}])
+
+    def test_invalid_options(self):
+        with DB(self, "J"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("J", "SELECT 1;", "BIGINT_AS_STRING")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("J", "SELECT 1;", "FORMAT", "JSON", "CURSOR", "10")
+
+class TestCsv(TestRediSQLWithExec):
+    def test_import_with_header(self):
+        with DB(self, "C"):
+            self.exec_query("C", "CREATE TABLE foo(a INT, b TEXT, c REAL);")
+            csv = 'b,a,c\n"x, y",1,1.5\nz,2,\n'
+            result = self.exec_naked("REDISQL.IMPORT", "C", "TABLE", "foo", "FORMAT", "CSV", "PAYLOAD", csv)
+            self.assertEqual(result, [["imported"], ["INT"], [2]])
+            rows = self.exec_query("C", "SELECT a, b, c, typeof(c) FROM foo ORDER BY a;", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], [1, "x, y", "1.5", "real"], [2, "z", None, "null"]])
+
+    def test_import_options(self):
+        with DB(self, "C"):
+            self.exec_query("C", "CREATE TABLE foo(a, b);")
+            csv = "007;'a;b'\n"
+            self.exec_naked("REDISQL.IMPORT", "C", "TABLE", "foo", "NO_HEADER", "DELIMITER", ";", "QUOTE", "'", "TYPES", "TEXT", "TEXT", "PAYLOAD", csv)
+            rows = self.exec_query("C", "SELECT a, b FROM foo;", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["007", "a;b"]])
+
+    def test_import_is_a_single_transaction(self):
+        with DB(self, "C"):
+            self.exec_query("C", "CREATE TABLE foo(a INT);")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.IMPORT", "C", "TABLE", "foo", "TYPES", "INT", "PAYLOAD", "a\n1\nx\n")
+            done = self.exec_query("C", "SELECT * FROM foo;")
+            self.assertEqual(done, [["DONE"], [0]])
+
+    def test_export(self):
+        with DB(self, "C"):
+            self.exec_query("C", "CREATE TABLE foo(a INT, b TEXT);")
+            self.exec_query("C", "INSERT INTO foo VALUES(1, 'x'), (2, NULL);")
+            csv = self.exec_naked("REDISQL.EXPORT", "C", "QUERY", "SELECT * FROM foo ORDER BY a;", "FORMAT", "CSV")
+            self.assertEqual(csv, "a,b\n1,x\n2,\n")
+            csv = self.exec_naked("REDISQL.EXPORT", "C", "QUERY", "SELECT * FROM foo ORDER BY a;", "NO_HEADER", "DELIMITER", "|")
+            self.assertEqual(csv, "1|x\n2|\n")
+
+    def test_export_and_import_a_file(self):
+        with DB(self, "C"):
+            self.exec_query("C", "CREATE TABLE foo(a INT, b TEXT);")
+            self.exec_query("C", "CREATE TABLE bar(a INT, b TEXT);")
+            self.exec_query("C", "INSERT INTO foo VALUES(1, 'x'), (2, 'y');")
+            # relative to DATA_DIR
+            path = "redisql_test_foo.csv"
+            result = self.exec_naked("REDISQL.EXPORT", "C", "QUERY", "SELECT * FROM foo;", "PATH", path)
+            self.assertEqual(result, [["exported"], ["INT"], [2]])
+            result = self.exec_naked("REDISQL.IMPORT", "C", "TABLE", "bar", "PATH", path)
+            self.assertEqual(result, [["imported"], ["INT"], [2]])
+            rows = self.exec_query("C", "SELECT * FROM bar ORDER BY a;", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], [1, "x"], [2, "y"]])
+
+    def test_files_outside_data_dir(self):
+        with DB(self, "C"):
+            self.exec_query("C", "CREATE TABLE foo(a INT);")
+            for path in ["/etc/passwd", "../foo.csv", "dir/../../foo.csv"]:
+                with self.assertRaises(redis.exceptions.ResponseError):
+                    self.exec_naked("REDISQL.IMPORT", "C", "TABLE", "foo", "PATH", path)
+                with self.assertRaises(redis.exceptions.ResponseError):
+                    self.exec_naked("REDISQL.EXPORT", "C", "QUERY", "SELECT * FROM foo;", "PATH", path)
+
+    def test_links_outside_data_dir(self):
+        directory = self.client.config_get("dir")["dir"]
+        outside = tempfile.mkdtemp()
+        link = os.path.join(directory, "redisql_test_link")
+        os.symlink(outside, link)
+        try:
+            with DB(self, "C"):
+                self.exec_query("C", "CREATE TABLE foo(a INT);")
+                with self.assertRaises(redis.exceptions.ResponseError):
+                    self.exec_naked("REDISQL.EXPORT", "C", "QUERY", "SELECT * FROM foo;", "PATH", "redisql_test_link/foo.csv")
+                self.assertEqual(os.listdir(outside), [])
+                with open(os.path.join(outside, "foo.csv"), "w") as f:
+                    f.write("a\n1\n")
+                with self.assertRaises(redis.exceptions.ResponseError):
+                    self.exec_naked("REDISQL.IMPORT", "C", "TABLE", "foo", "PATH", "redisql_test_link/foo.csv")
+        finally:
+            os.remove(link)
+            shutil.rmtree(outside)
+
+    def test_invalid_options(self):
+        with DB(self, "C"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.IMPORT", "C", "TABLE", "foo")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.IMPORT", "C", "TABLE", "foo", "FORMAT", "JSON", "PAYLOAD", "a\n1\n")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.EXPORT", "C", "QUERY", "DELETE FROM foo;")
+
+class TestDumpRestore(TestRediSQLWithExec):
+    def raw_client(self):
+        kwargs = dict(self.client.connection_pool.connection_kwargs)
+        kwargs["decode_responses"] = False
+        return redis.Redis(**kwargs)
+
+    def fill(self, db):
+        self.exec_query(db, "CREATE TABLE foo(a INT, b TEXT);")
+        self.exec_query(db, "INSERT INTO foo VALUES(1, 'x'), (2, 'y');")
+        self.exec_naked("REDISQL.V2.STATEMENT", db, "NEW", "insert", "INSERT INTO foo VALUES(?1, ?2);")
+
+    def check_restored(self, db):
+        rows = self.exec_query(db, "SELECT * FROM foo ORDER BY a;", "NO_HEADER")
+        self.assertEqual(rows, [["RESULT"], [1, "x"], [2, "y"]])
+        done = self.exec_naked("REDISQL.V2.EXEC", db, "STATEMENT", "insert", "ARGS", "3", "z")
+        self.assertEqual(done, [["DONE"], [1]])
+
+    def test_sql_round_trip(self):
+        with DB(self, "D"):
+            self.fill("D")
+            dump = self.exec_naked("REDISQL.DUMP", "D", "SQL")
+            self.assertIn("CREATE TABLE foo", dump)
+            try:
+                ok = self.exec_naked("REDISQL.RESTORE", "R", dump)
+                self.assertEqual(ok, [["OK"]])
+                self.check_restored("R")
+            finally:
+                self.client.execute_command("DEL", "R")
+
+    def test_sqlite_round_trip(self):
+        with DB(self, "D"):
+            self.fill("D")
+            raw = self.raw_client()
+            image = raw.execute_command("REDISQL.DUMP", "D", "SQLITE")
+            self.assertTrue(image.startswith(b"SQLite format 3\x00"))
+            try:
+                raw.execute_command("REDISQL.RESTORE", "R", image)
+                self.check_restored("R")
+            finally:
+                self.client.execute_command("DEL", "R")
+
+    def test_restore_does_not_publish_in_the_source_stream(self):
+        self.exec_naked("REDISQL.V2.CREATE_DB", "D", "CDC_STREAM", "changes")
+        try:
+            self.exec_query("D", "CREATE TABLE foo(a INT);")
+            dump = self.exec_naked("REDISQL.DUMP", "D", "SQL")
+            published = self.exec_naked("XLEN", "changes")
+            self.exec_naked("REDISQL.RESTORE", "R", dump)
+            self.exec_query("R", "INSERT INTO foo VALUES(1);")
+            self.assertEqual(self.exec_naked("XLEN", "changes"), published)
+        finally:
+            self.exec_naked("DEL", "D", "R", "changes")
+
+    def test_restore_existing_key(self):
+        with DB(self, "D"):
+            self.fill("D")
+            dump = self.exec_naked("REDISQL.DUMP", "D")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.RESTORE", "D", dump)
+
+    def test_invalid_arguments(self):
+        with DB(self, "D"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.DUMP", "D", "JSON")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.RESTORE", "R")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.RESTORE", "R", "NOT SQL AT ALL")
+
+class TestBackupLoad(TestRediSQLWithExec):
+    def test_backup_and_load(self):
+        with DB(self, "B"), DB(self, "L"):
+            self.exec_query("B", "CREATE TABLE foo(a INT, b TEXT);")
+            self.exec_query("B", "INSERT INTO foo VALUES(1, 'x'), (2, 'y');")
+            self.exec_naked("REDISQL.V2.STATEMENT", "B", "NEW", "insert", "INSERT INTO foo VALUES(?1, ?2);")
+            # relative to DATA_DIR
+            path = "redisql_test_backup.sqlite"
+            result = self.exec_naked("REDISQL.BACKUP", "B", "TO", path, "PAGES", "1")
+            self.assertEqual(result[0], ["pages"])
+            self.assertTrue(result[2][0] > 0)
+            result = self.exec_naked("REDISQL.LOAD", "L", "FROM", path)
+            self.assertEqual(result[0], ["pages"])
+            rows = self.exec_query("L", "SELECT * FROM foo ORDER BY a;", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], [1, "x"], [2, "y"]])
+            done = self.exec_naked("REDISQL.V2.EXEC", "L", "STATEMENT", "insert", "ARGS", "3", "z")
+            self.assertEqual(done, [["DONE"], [1]])
+
+    def test_commands_rejected_while_loading(self):
+        with DB(self, "B"), DB(self, "L"):
+            self.exec_query("B", "CREATE TABLE foo(a INT);")
+            self.exec_query("B", "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c LIMIT 500000) INSERT INTO foo SELECT x FROM c;")
+            path = "redisql_test_loading.sqlite"
+            self.exec_naked("REDISQL.BACKUP", "B", "TO", path)
+            loader = redis.Redis(**self.client.connection_pool.connection_kwargs)
+            load = threading.Thread(target=loader.execute_command, args=(
+                "REDISQL.LOAD", "L", "FROM", path, "PAGES", "1"))
+            load.start()
+            try:
+                while len(self.exec_naked("REDISQL.BACKUP.STATUS", "L")) == 2:
+                    time.sleep(0.001)
+                with self.assertRaises(redis.exceptions.ResponseError):
+                    self.exec_naked("REDISQL.V2.EXEC", "L", "COMMAND", "CREATE TABLE bar(a INT);", "NOW")
+            finally:
+                load.join()
+            tables = self.exec_query("L", "SELECT name FROM sqlite_master WHERE name = 'bar';", "NO_HEADER")
+            self.assertEqual(tables, [["RESULT"]])
+
+    def test_status(self):
+        with DB(self, "B"):
+            status = self.exec_naked("REDISQL.BACKUP.STATUS", "B")
+            self.assertEqual(status[0], ["operation", "path", "status", "remaining", "total", "elapsed_ms", "error"])
+            self.assertEqual(len(status), 2)
+            path = "redisql_test_status.sqlite"
+            self.exec_naked("REDISQL.BACKUP", "B", "TO", path)
+            status = self.exec_naked("REDISQL.BACKUP.STATUS", "B")
+            self.assertEqual(status[2][0], "BACKUP")
+            self.assertTrue(status[2][1].endswith(path))
+            self.assertEqual(status[2][2], "done")
+            self.assertEqual(status[2][3], 0)
+
+    def test_load_missing_file(self):
+        with DB(self, "L"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.LOAD", "L", "FROM", "not_a_file.sqlite")
+            status = self.exec_naked("REDISQL.BACKUP.STATUS", "L")
+            self.assertEqual(len(status), 2)
+
+    def test_files_outside_data_dir(self):
+        with DB(self, "B"):
+            for path in ["/tmp/backup.sqlite", "../backup.sqlite"]:
+                with self.assertRaises(redis.exceptions.ResponseError):
+                    self.exec_naked("REDISQL.BACKUP", "B", "TO", path)
+                with self.assertRaises(redis.exceptions.ResponseError):
+                    self.exec_naked("REDISQL.LOAD", "B", "FROM", path)
+
+    def test_invalid_arguments(self):
+        with DB(self, "B"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.BACKUP", "B")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.BACKUP", "B", "TO", "file.sqlite", "PAGES", "0")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.LOAD", "B", "TO", "file.sqlite")
+
+class TestRdbImage(TestRediSQLWithExec):
+    def test_large_database_survives_reload(self):
+        with DB(self, "A"):
+            self.exec_query("A", "CREATE TABLE foo(a INT, b TEXT);")
+            self.exec_query("A", "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 2000) INSERT INTO foo SELECT x, printf('%0100d', x) FROM c;")
+            for _ in self.retry_with_reload():
+                pass
+            time.sleep(0.5)
+            result = self.exec_query("A", "SELECT count(*), sum(a) FROM foo;", "NO_HEADER")
+            self.assertEqual(result, [["RESULT"], [2000, 2001000]])
+
+    def test_no_temporary_files(self):
+        with DB(self, "A"):
+            self.exec_query("A", "CREATE TABLE foo(a INT);")
+            for _ in self.retry_with_reload():
+                pass
+            time.sleep(0.5)
+            directory = self.client.config_get("dir")["dir"]
+            leftovers = [f for f in os.listdir(directory) if f.startswith("rediSQL_rdb_")]
+            self.assertEqual(leftovers, [])
+
+class TestAofRewrite(TestRediSQLWithExec):
+    def raw_client(self):
+        kwargs = dict(self.client.connection_pool.connection_kwargs)
+        kwargs["decode_responses"] = False
+        return redis.Redis(**kwargs)
+
+    def checksum(self, image):
+        h = 0xcbf29ce484222325
+        for b in bytearray(image):
+            h = ((h ^ b) * 0x100000001b3) & 0xffffffffffffffff
+        return h - (1 << 64) if h >= (1 << 63) else h
+
+    def fill(self, db):
+        self.exec_query(db, "CREATE TABLE foo(a INT, b TEXT);")
+        self.exec_query(db, "INSERT INTO foo VALUES(1, 'first\nsecond');")
+        self.exec_naked("REDISQL.V2.STATEMENT", db, "NEW", "insert", "INSERT INTO foo VALUES(?1, ?2);")
+
+    def check_restored(self, db):
+        rows = self.exec_query(db, "SELECT * FROM foo ORDER BY a;", "NO_HEADER")
+        self.assertEqual(rows, [["RESULT"], [1, "first\nsecond"]])
+        done = self.exec_naked("REDISQL.V2.EXEC", db, "STATEMENT", "insert", "ARGS", "2", "z")
+        self.assertEqual(done, [["DONE"], [1]])
+
+    def test_restore_chunks(self):
+        with DB(self, "D"):
+            self.fill("D")
+            raw = self.raw_client()
+            image = raw.execute_command("REDISQL.DUMP", "D", "SQLITE")
+            checksum = self.checksum(image)
+            half = len(image) // 2
+            chunks = [image[:half], image[half:]]
+            try:
+                for index, chunk in enumerate(chunks):
+                    raw.execute_command("REDISQL.RESTORE.CHUNK", "R", index, len(chunks), checksum, chunk)
+                self.check_restored("R")
+            finally:
+                self.client.execute_command("DEL", "R")
+
+    def test_wrong_checksum(self):
+        with DB(self, "D"):
+            self.fill("D")
+            raw = self.raw_client()
+            image = raw.execute_command("REDISQL.DUMP", "D", "SQLITE")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                raw.execute_command("REDISQL.RESTORE.CHUNK", "R", 0, 1, self.checksum(image) + 1, image)
+            self.assertEqual(self.client.execute_command("EXISTS", "R"), 0)
+
+    def test_chunk_out_of_order(self):
+        with DB(self, "D"):
+            self.fill("D")
+            raw = self.raw_client()
+            image = raw.execute_command("REDISQL.DUMP", "D", "SQLITE")
+            checksum = self.checksum(image)
+            with self.assertRaises(redis.exceptions.ResponseError):
+                raw.execute_command("REDISQL.RESTORE.CHUNK", "R", 1, 2, checksum, image)
+            raw.execute_command("REDISQL.RESTORE.CHUNK", "R", 0, 3, checksum, image[:10])
+            with self.assertRaises(redis.exceptions.ResponseError):
+                raw.execute_command("REDISQL.RESTORE.CHUNK", "R", 2, 3, checksum, image[10:])
+            self.assertEqual(self.client.execute_command("EXISTS", "R"), 0)
+
+    def test_rewrite_and_load_aof(self):
+        with DB(self, "A"):
+            self.fill("A")
+            self.client.config_set("appendonly", "yes")
+            try:
+                time.sleep(0.5)
+                while self.client.info("persistence")["aof_rewrite_in_progress"]:
+                    time.sleep(0.1)
+                self.client.execute_command("DEBUG", "LOADAOF")
+                time.sleep(0.5)
+                self.check_restored("A")
+            finally:
+                self.client.config_set("appendonly", "no")
+
+class TestMemoryAndDigest(TestRediSQLWithExec):
+    def test_memory_usage(self):
+        with DB(self, "M"):
+            before = self.client.execute_command("MEMORY", "USAGE", "M")
+            self.assertGreater(before, 0)
+            self.exec_query("M", "CREATE TABLE foo(a INT, b TEXT);")
+            self.exec_query("M", "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 2000) INSERT INTO foo SELECT x, printf('%0100d', x) FROM c;")
+            after = self.client.execute_command("MEMORY", "USAGE", "M")
+            self.assertGreater(after, before)
+
+    def digest(self, db):
+        return self.client.execute_command("DEBUG", "DIGEST-VALUE", db)[0]
+
+    def test_same_content_same_digest(self):
+        with DB(self, "A"), DB(self, "B"):
+            for db in ("A", "B"):
+                self.exec_query(db, "CREATE TABLE foo(a INT, b TEXT);")
+                self.exec_naked("REDISQL.V2.STATEMENT", db, "NEW", "insert", "INSERT INTO foo VALUES(?1, ?2);")
+            self.exec_query("A", "INSERT INTO foo VALUES(1, 'x'), (2, 'y');")
+            self.exec_query("B", "INSERT INTO foo VALUES(2, 'y'), (1, 'x');")
+            self.assertEqual(self.digest("A"), self.digest("B"))
+
+    def test_different_content_different_digest(self):
+        with DB(self, "A"), DB(self, "B"):
+            for db in ("A", "B"):
+                self.exec_query(db, "CREATE TABLE foo(a INT, b TEXT);")
+                self.exec_query(db, "INSERT INTO foo VALUES(1, 'x');")
+            self.assertEqual(self.digest("A"), self.digest("B"))
+            self.exec_query("B", "UPDATE foo SET b = 'z';")
+            self.assertNotEqual(self.digest("A"), self.digest("B"))
+            self.exec_query("B", "UPDATE foo SET b = 'x';")
+            self.exec_naked("REDISQL.V2.STATEMENT", "B", "NEW", "select", "SELECT * FROM foo;")
+            self.assertNotEqual(self.digest("A"), self.digest("B"))
+
+class TestHashVirtualTable(TestRediSQLWithExec):
+    def setUp(self):
+        super(TestHashVirtualTable, self).setUp()
+        self.client.execute_command("HSET", "user:1", "name", "ada", "age", "36")
+        self.client.execute_command("HSET", "user:2", "name", "bob", "age", "41")
+        self.client.execute_command("SET", "user:3", "not a hash")
+
+    def tearDown(self):
+        self.client.execute_command("DEL", "user:1", "user:2", "user:3", "user:4")
+        super(TestHashVirtualTable, self).tearDown()
+
+    def create_table(self, db):
+        self.exec_query(db, "CREATE VIRTUAL TABLE users USING REDISQL_TABLES_BRUTE_HASH('user:*', name, age INT);")
+
+    def test_scan_all_hashes(self):
+        with DB(self, "H"):
+            self.create_table("H")
+            rows = self.exec_query("H", "SELECT \"$id\", name, age FROM users ORDER BY \"$id\";", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["1", "ada", 36], ["2", "bob", 41]])
+
+    def test_lookup_by_id(self):
+        with DB(self, "H"):
+            self.create_table("H")
+            rows = self.exec_query("H", "SELECT name FROM users WHERE \"$id\" = '2';", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["bob"]])
+            rows = self.exec_query("H", "SELECT name FROM users WHERE \"$id\" = '9';", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"]])
+
+    def test_join_with_table(self):
+        with DB(self, "H"):
+            self.create_table("H")
+            self.exec_query("H", "CREATE TABLE orders(user TEXT, total INT);")
+            self.exec_query("H", "INSERT INTO orders VALUES('1', 10), ('1', 5), ('2', 7);")
+            rows = self.exec_query("H", "SELECT name, sum(total) FROM orders JOIN users ON users.\"$id\" = orders.user GROUP BY name ORDER BY name;", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["ada", 15], ["bob", 7]])
+
+    def test_insert_update_delete(self):
+        with DB(self, "H"):
+            self.create_table("H")
+            self.exec_query("H", "INSERT INTO users VALUES('4', 'eve', 29);")
+            self.assertEqual(self.client.hgetall("user:4"), {"name": "eve", "age": "29"})
+            self.exec_query("H", "UPDATE users SET age = age + 1 WHERE \"$id\" = '4';")
+            self.assertEqual(self.client.hget("user:4", "age"), "30")
+            self.exec_query("H", "UPDATE users SET age = NULL WHERE \"$id\" = '4';")
+            self.assertEqual(self.client.hgetall("user:4"), {"name": "eve"})
+            self.exec_query("H", "DELETE FROM users WHERE \"$id\" = '4';")
+            self.assertEqual(self.client.exists("user:4"), 0)
+
+    def test_changes_written_on_commit(self):
+        with DB(self, "H"):
+            self.create_table("H")
+            self.exec_query("H", "BEGIN; INSERT INTO users VALUES('4', 'eve', 29); UPDATE users SET age = age + 1 WHERE \"$id\" = '4'; COMMIT;")
+            self.assertEqual(self.client.hgetall("user:4"), {"name": "eve", "age": "30"})
+
+    def test_rollback_leaves_hashes_untouched(self):
+        with DB(self, "H"):
+            self.create_table("H")
+            self.exec_query("H", "BEGIN; INSERT INTO users VALUES('4', 'eve', 29); DELETE FROM users WHERE \"$id\" = '1'; ROLLBACK;")
+            self.assertEqual(self.client.exists("user:4"), 0)
+            self.assertEqual(self.client.hgetall("user:1"), {"name": "ada", "age": "36"})
+
+    def test_failed_statement_writes_nothing(self):
+        with DB(self, "H"):
+            self.create_table("H")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("H", "INSERT INTO users VALUES('4', 'eve', 29), (NULL, 'bob', 41);")
+            self.assertEqual(self.client.exists("user:4"), 0)
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("H", "INSERT INTO users VALUES('4', 'eve', 29), ('3', 'bob', 41);")
+            self.assertEqual(self.client.exists("user:4"), 0)
+            self.assertEqual(self.client.get("user:3"), "not a hash")
+
+    def test_invalid_arguments(self):
+        with DB(self, "H"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("H", "CREATE VIRTUAL TABLE t USING REDISQL_TABLES_BRUTE_HASH('a:*:*', name);")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("H", "CREATE VIRTUAL TABLE t USING REDISQL_TABLES_BRUTE_HASH('a:*');")
+
+class TestNativeTypesVirtualTables(TestRediSQLWithExec):
+    def setUp(self):
+        super(TestNativeTypesVirtualTables, self).setUp()
+        self.client.execute_command("ZADD", "scores", "10", "ada", "20", "bob", "30", "eve")
+        self.client.execute_command("RPUSH", "queue", "a", "b", "c", "d")
+        self.client.execute_command("XADD", "events", "1-0", "user", "ada", "action", "login")
+        self.client.execute_command("XADD", "events", "2-0", "user", "bob", "action", "logout")
+        self.client.execute_command("XADD", "events", "3-0", "user", "eve", "action", "login")
+
+    def tearDown(self):
+        self.client.execute_command("DEL", "scores", "queue", "events")
+        super(TestNativeTypesVirtualTables, self).tearDown()
+
+    def test_zset(self):
+        with DB(self, "N"):
+            rows = self.exec_query("N", "SELECT member, score FROM redis_zset('scores');", "NO_HEADER")
+            rows = [[member, float(score)] for [member, score] in rows[1:]]
+            self.assertEqual(rows, [["ada", 10.0], ["bob", 20.0], ["eve", 30.0]])
+
+    def test_zset_score_range(self):
+        with DB(self, "N"):
+            rows = self.exec_query("N", "SELECT member FROM redis_zset('scores') WHERE score > 10 AND score <= 30;", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["bob"], ["eve"]])
+
+    def test_list(self):
+        with DB(self, "N"):
+            rows = self.exec_query("N", "SELECT idx, value FROM redis_list('queue');", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], [0, "a"], [1, "b"], [2, "c"], [3, "d"]])
+            rows = self.exec_query("N", "SELECT idx, value FROM redis_list('queue') WHERE idx >= 1 AND idx < 3;", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], [1, "b"], [2, "c"]])
+
+    def test_stream(self):
+        with DB(self, "N"):
+            rows = self.exec_query("N", "SELECT id, json_extract(fields, '$.user') FROM redis_stream('events') WHERE id > '1-0';", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["2-0", "bob"], ["3-0", "eve"]])
+
+    def test_stream_ids_compared_as_text(self):
+        with DB(self, "N"):
+            self.client.execute_command("XADD", "events", "10-0", "user", "ada", "action", "logout")
+            rows = self.exec_query("N", "SELECT id FROM redis_stream('events') WHERE id < '2-0';", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["1-0"], ["10-0"]])
+
+    def test_range_not_a_number(self):
+        with DB(self, "N"):
+            rows = self.exec_query("N", "SELECT member FROM redis_zset('scores') WHERE score < 'x';", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["ada"], ["bob"], ["eve"]])
+            rows = self.exec_query("N", "SELECT member FROM redis_zset('scores') WHERE score > NULL;", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"]])
+            rows = self.exec_query("N", "SELECT value FROM redis_list('queue') WHERE idx < 'x' AND idx >= '2';", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["c"], ["d"]])
+
+    def test_join_with_table(self):
+        with DB(self, "N"):
+            self.exec_query("N", "CREATE TABLE users(name TEXT, team TEXT);")
+            self.exec_query("N", "INSERT INTO users VALUES('ada', 'red'), ('bob', 'blue'), ('eve', 'red');")
+            rows = self.exec_query("N", "SELECT team, sum(score) FROM users JOIN redis_zset('scores') z ON z.member = users.name GROUP BY team ORDER BY team;", "NO_HEADER")
+            rows = [[team, float(total)] for [team, total] in rows[1:]]
+            self.assertEqual(rows, [["blue", 20.0], ["red", 40.0]])
+
+    def test_missing_key(self):
+        with DB(self, "N"):
+            rows = self.exec_query("N", "SELECT * FROM redis_list('nothing');", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"]])
+
+class TestRedisFunctions(TestRediSQLWithExec):
+    def setUp(self):
+        super(TestRedisFunctions, self).setUp()
+        self.client.execute_command("SET", "greeting", "hello")
+        self.client.execute_command("HSET", "user:1", "name", "ada")
+
+    def tearDown(self):
+        self.client.execute_command("DEL", "greeting", "user:1", "visits")
+        super(TestRedisFunctions, self).tearDown()
+
+    def test_get_and_hget(self):
+        with DB(self, "F"):
+            rows = self.exec_query("F", "SELECT redis_get('greeting'), redis_hget('user:1', 'name'), redis_get('nothing');", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["hello", "ada", None]])
+
+    def test_query(self):
+        with DB(self, "F"):
+            rows = self.exec_naked("REDISQL.V2.QUERY", "F", "COMMAND", "SELECT redis_get('greeting');", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["hello"]])
+
+    def test_incr(self):
+        with DB(self, "F"):
+            self.exec_query("F", "CREATE TABLE visits(n INT);")
+            self.exec_query("F", "INSERT INTO visits VALUES(redis_incr('visits'));")
+            self.exec_query("F", "INSERT INTO visits VALUES(redis_incr('visits'));")
+            rows = self.exec_query("F", "SELECT n FROM visits ORDER BY n;", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], [1], [2]])
+            self.assertEqual(self.client.execute_command("GET", "visits"), "2")
+
+    def test_call(self):
+        with DB(self, "F"):
+            rows = self.exec_query("F", "SELECT redis_call('HGETALL', 'user:1'), redis_call('STRLEN', 'greeting');", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ['["name","ada"]', 5]])
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("F", "SELECT redis_call('INCR', 'greeting');")
+
+    def test_call_only_data_commands(self):
+        with DB(self, "F"):
+            for command in [["FLUSHALL"], ["CONFIG", "SET", "maxmemory", "1"], ["REDISQL.V2.EXEC", "F", "COMMAND", "SELECT 1;"]]:
+                arguments = ", ".join("'{}'".format(arg) for arg in command)
+                with self.assertRaises(redis.exceptions.ResponseError):
+                    self.exec_query("F", "SELECT redis_call({});".format(arguments))
+            self.assertEqual(self.client.execute_command("GET", "greeting"), "hello")
+
+    def test_writes_are_not_read_only(self):
+        with DB(self, "F"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.QUERY", "F", "COMMAND", "SELECT redis_incr('visits');")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.QUERY", "F", "COMMAND", "SELECT redis_call('SET', 'visits', '10');")
+            self.assertEqual(self.client.execute_command("GET", "visits"), None)
+
+class TestUserFunctions(TestRediSQLWithExec):
+    def test_scalar(self):
+        with DB(self, "F"):
+            ok = self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "double", "ARITY", "1", "LANGUAGE", "EXPR", "BODY", "$1 * 2")
+            self.assertEqual(ok, [["OK"]])
+            rows = self.exec_query("F", "SELECT double(21), double(NULL);", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], [42, None]])
+            rows = self.exec_naked("REDISQL.V2.QUERY", "F", "COMMAND", "SELECT double(1.5);", "NO_HEADER")
+            self.assertEqual(float(rows[1][0]), 3.0)
+
+    def test_aggregate(self):
+        with DB(self, "F"):
+            self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "total", "ARITY", "1", "AGGREGATE", "BODY", "coalesce(acc, 0) + $1")
+            self.exec_query("F", "CREATE TABLE t(n INT);")
+            self.exec_query("F", "INSERT INTO t VALUES(1), (2), (3);")
+            rows = self.exec_query("F", "SELECT total(n) FROM t;", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], [6]])
+
+    def test_list_and_delete(self):
+        with DB(self, "F"):
+            self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "double", "ARITY", "1", "BODY", "$1 * 2")
+            self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "greet", "ARITY", "1", "BODY", "'hello ' || $1", "NOW")
+            result = self.exec_naked("REDISQL.V2.FUNCTION", "F", "LIST")
+            self.assertEqual(result, [["RESULT"],
+                ["name", "arity", "aggregate", "language", "body"],
+                ["TEXT", "INT", "INT", "TEXT", "TEXT"],
+                ["double", 1, 0, "EXPR", "$1 * 2"],
+                ["greet", 1, 0, "EXPR", "'hello ' || $1"]])
+            self.exec_naked("REDISQL.V2.FUNCTION", "F", "DELETE", "double")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("F", "SELECT double(1);")
+            rows = self.exec_query("F", "SELECT greet('world');", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], ["hello world"]])
+
+    def test_update(self):
+        with DB(self, "F"):
+            self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "f", "ARITY", "1", "BODY", "$1 + 1")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "f", "ARITY", "1", "BODY", "$1 + 2")
+            self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "f", "ARITY", "1", "BODY", "$1 + 2", "CAN_UPDATE")
+            rows = self.exec_query("F", "SELECT f(1);", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], [3]])
+
+    def test_errors(self):
+        with DB(self, "F"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "f", "ARITY", "1", "LANGUAGE", "lua", "BODY", "return 1")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "f", "ARITY", "1", "BODY", "$1 +")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "f", "ARITY", "1", "BODY", "$2")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "f", "ARITY", "1", "BODY", "acc + $1")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.FUNCTION", "F", "DELETE", "f")
+
+    def test_too_deep(self):
+        with DB(self, "F"):
+            for body in ["(" * 100000 + "$1" + ")" * 100000, "-" * 100000 + "$1", "NOT " * 100000 + "$1", " + ".join(["$1"] * 100000)]:
+                with self.assertRaises(redis.exceptions.ResponseError):
+                    self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "f", "ARITY", "1", "BODY", body)
+            ok = self.exec_naked("REDISQL.V2.FUNCTION", "F", "NEW", "f", "ARITY", "1", "BODY", "(" * 50 + "$1 + 1" + ")" * 50)
+            self.assertEqual(ok, [["OK"]])
+            rows = self.exec_query("F", "SELECT f(1);", "NO_HEADER")
+            self.assertEqual(rows, [["RESULT"], [2]])
+
+class TestExtensions(TestRediSQLWithExec):
+    def test_list_empty(self):
+        with DB(self, "E"):
+            result = self.exec_naked("REDISQL.V2.EXTENSION", "E", "LIST")
+            self.assertEqual(result, [["RESULT"], ["name", "path"], ["TEXT", "TEXT"]])
+
+    def test_load_refused(self):
+        with DB(self, "E"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXTENSION", "E", "LOAD", "spatialite")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXTENSION", "E", "LOAD", "../../usr/lib/libsqlite3.so", "NOW")
+            result = self.exec_naked("REDISQL.V2.EXTENSION", "E", "LIST")
+            self.assertEqual(result, [["RESULT"], ["name", "path"], ["TEXT", "TEXT"]])
+
+    def test_load_extension_sql_function_disabled(self):
+        with DB(self, "E"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_query("E", "SELECT load_extension('/usr/lib/libsqlite3.so');")
+
+    def test_wrong_usage(self):
+        with DB(self, "E"):
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXTENSION", "E", "UNLOAD", "spatialite")
+            with self.assertRaises(redis.exceptions.ResponseError):
+                self.exec_naked("REDISQL.V2.EXTENSION", "E", "LOAD")
+
 if __name__ == '__main__':
   import unittest
   unittest.main()